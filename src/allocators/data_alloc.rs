@@ -1,32 +1,24 @@
 use std::sync::{Arc, Mutex};
 
 use crate::allocators::buddy_alloc::*;
+use crate::allocators::journal::*;
 use crate::allocators::*;
+use crate::journal::batch::BatchContext;
+use crate::journal::entry::*;
 
 //-------------------------------------
 
+/// Hands out data blocks from a local pool, that's refilled from the
+/// global allocator.  As with the MetadataAlloc, unused blocks should be
+/// handed back with release().
 pub struct DataAlloc {
-    global_alloc: Arc<Mutex<dyn Allocator>>,
+    global_alloc: SharedJournalAlloc,
     local_alloc: BuddyAllocator,
     prealloc_size: u64,
 }
 
-impl Drop for DataAlloc {
-    fn drop(&mut self) {
-        let mut global_alloc = self.global_alloc.lock().unwrap();
-        for (order, blocks) in self.local_alloc.free_blocks.iter().enumerate() {
-            for &block in blocks {
-                let size = 1 << order;
-                global_alloc
-                    .free(block, size)
-                    .expect("freeing data block failed");
-            }
-        }
-    }
-}
-
 impl DataAlloc {
-    pub fn new(global_alloc: Arc<Mutex<dyn Allocator>>, prealloc_size: u64) -> Self {
+    pub fn new(global_alloc: SharedJournalAlloc, prealloc_size: u64) -> Self {
         let mut global_alloc_locked = global_alloc.lock().unwrap();
         let nr_blocks = global_alloc_locked.nr_blocks();
         drop(global_alloc_locked);
@@ -40,11 +32,11 @@ impl DataAlloc {
     }
 
     /// Preallocate more space from the global allocator
-    fn prealloc(&mut self) -> Result<()> {
+    fn prealloc(&mut self, ctx: &BatchContext) -> Result<()> {
         let (_total, runs) = {
             let mut global_alloc = self.global_alloc.lock().unwrap();
             global_alloc
-                .alloc_many(self.prealloc_size, 0)
+                .alloc_many(ctx, self.prealloc_size, 0)
                 .expect("Failed to preallocate additional space for DataAlloc")
         };
 
@@ -58,11 +50,11 @@ impl DataAlloc {
         Ok(())
     }
 
-    pub fn alloc(&mut self, nr_blocks: u64) -> Result<(u64, Vec<AllocRun>)> {
+    pub fn alloc(&mut self, ctx: &BatchContext, nr_blocks: u64) -> Result<(u64, Vec<AllocRun>)> {
         match self.local_alloc.alloc_many(nr_blocks, 0) {
             Ok(result) => Ok(result),
             Err(MemErr::OutOfSpace) => {
-                self.prealloc()?;
+                self.prealloc(ctx)?;
 
                 // Retry the allocation
                 self.local_alloc.alloc_many(nr_blocks, 0)
@@ -74,28 +66,75 @@ impl DataAlloc {
     pub fn free(&mut self, block: u64, nr_blocks: u64) -> Result<()> {
         self.local_alloc.free(block, nr_blocks)
     }
+
+    /// Returns the preallocated blocks that haven't been used to the
+    /// global allocator.
+    pub fn release(&mut self, ctx: &BatchContext) -> Result<()> {
+        let mut global_alloc = self.global_alloc.lock().unwrap();
+        for (order, blocks) in self.local_alloc.free_blocks.iter().enumerate() {
+            for &block in blocks {
+                global_alloc.free(ctx, block, 1 << order)?;
+            }
+        }
+        self.local_alloc = BuddyAllocator::new_empty(global_alloc.nr_blocks());
+        Ok(())
+    }
 }
 
 // -------------------------------------
 
 #[test]
 fn test_data_alloc() -> Result<()> {
-    let global_alloc = Arc::new(Mutex::new(BuddyAllocator::new(1024 * 256))); // 1GB worth of 4k pages
+    let global_alloc = Arc::new(Mutex::new(JournalAlloc::new(
+        BuddyAllocator::new(1024 * 256), // 1GB worth of 4k pages
+        AllocKind::Data,
+    )));
     let mut data_alloc = DataAlloc::new(global_alloc.clone(), 1024); // Preallocate 4M
+    let ctx = BatchContext::new();
 
     // allocate from prealloc
-    let (total, runs) = data_alloc.alloc(512)?;
+    let (total, runs) = data_alloc.alloc(&ctx, 512)?;
     assert_eq!(total, 512);
 
     // still from prealloc
-    let (total, runs) = data_alloc.alloc(312)?;
+    let (total, runs) = data_alloc.alloc(&ctx, 312)?;
     assert_eq!(total, 312);
 
     // Allocate more than the initial preallocation to trigger additional preallocation
-    let (total, runs) = data_alloc.alloc(512)?;
+    let (total, runs) = data_alloc.alloc(&ctx, 512)?;
     assert_eq!(total, 512);
 
     Ok(())
 }
 
+#[test]
+fn test_data_alloc_release() -> Result<()> {
+    let global_alloc = Arc::new(Mutex::new(JournalAlloc::new(
+        BuddyAllocator::new(4096),
+        AllocKind::Data,
+    )));
+    let mut data_alloc = DataAlloc::new(global_alloc.clone(), 1024);
+
+    let ctx = BatchContext::new();
+    data_alloc.alloc(&ctx, 100)?;
+    data_alloc.release(&ctx)?;
+
+    // The unused 924 blocks are freed, and the frees journalled.
+    let nr_freed: u64 = ctx
+        .end()
+        .unwrap()
+        .iter()
+        .map(|entry| match entry {
+            Entry::FreeData(b, e) => e - b,
+            _ => 0,
+        })
+        .sum();
+    assert_eq!(nr_freed, 924);
+
+    let ctx = BatchContext::new();
+    let (total, _) = global_alloc.lock().unwrap().alloc_many(&ctx, 3996, 0)?;
+    assert_eq!(total, 3996);
+    Ok(())
+}
+
 // -------------------------------------
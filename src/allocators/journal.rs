@@ -2,49 +2,43 @@ use std::sync::{Arc, Mutex};
 
 use crate::allocators::bits::*;
 use crate::allocators::*;
-use crate::journal::batch;
+use crate::journal::batch::BatchContext;
 use crate::journal::entry::*;
 
 //-------------------------------------
 
-fn journal_err() -> MemErr {
-    MemErr::Internal("journal error".to_string())
-}
-
-//-------------------------------------
-
 pub enum AllocKind {
     Metadata,
     Data,
 }
 
+/// Wraps an allocator, recording every change in the caller's batch.  This
+/// can't implement the Allocator trait since every call needs the batch.
 pub struct JournalAlloc<A: Allocator> {
     kind: AllocKind,
     inner: A,
 }
 
+/// The journalled allocators are shared by all the thins.
+pub type SharedJournalAlloc = Arc<Mutex<JournalAlloc<BuddyAllocator>>>;
+
+use AllocKind::*;
+
 impl<A: Allocator> JournalAlloc<A> {
     pub fn new(inner: A, kind: AllocKind) -> Self {
         Self { kind, inner }
     }
 
-    fn add_entry(&self, e: Entry) -> Result<()> {
-        batch::add_entry(e).map_err(|_| journal_err())
-    }
-
-    fn add_entries(&self, es: &[Entry]) -> Result<()> {
-        batch::add_entries(es).map_err(|_| journal_err())
-    }
-}
-
-use AllocKind::*;
-
-impl<A: Allocator> Allocator for JournalAlloc<A> {
-    fn nr_blocks(&self) -> u64 {
+    pub fn nr_blocks(&self) -> u64 {
         self.inner.nr_blocks()
     }
 
-    fn alloc_many(&mut self, nr_blocks: u64, min_order: usize) -> Result<(u64, Vec<AllocRun>)> {
+    pub fn alloc_many(
+        &mut self,
+        ctx: &BatchContext,
+        nr_blocks: u64,
+        min_order: usize,
+    ) -> Result<(u64, Vec<AllocRun>)> {
         let (total, runs) = self.inner.alloc_many(nr_blocks, min_order)?;
 
         let mut entries = Vec::new();
@@ -56,47 +50,57 @@ impl<A: Allocator> Allocator for JournalAlloc<A> {
             entries.push(entry);
         }
 
-        self.add_entries(&entries)?;
+        ctx.add_entries(&entries);
         Ok((total, runs))
     }
 
-    fn alloc(&mut self, nr_blocks: u64) -> Result<u64> {
+    pub fn alloc(&mut self, ctx: &BatchContext, nr_blocks: u64) -> Result<u64> {
         let b = self.inner.alloc(nr_blocks)?;
 
         let e = match self.kind {
             Metadata => Entry::AllocMetadata(b as u32, (b + nr_blocks) as u32),
             Data => Entry::AllocData(b, b + nr_blocks),
         };
-        self.add_entry(e)?;
+        ctx.add_entry(e);
 
         Ok(b)
     }
 
-    fn alloc_specific(&mut self, block: u64, nr_blocks: u64) -> Result<()> {
+    pub fn alloc_specific(&mut self, block: u64, nr_blocks: u64) -> Result<()> {
         // We don't journal this since it's only used by journal replay.
         self.inner.alloc_specific(block, nr_blocks)
     }
 
-    fn free(&mut self, block: u64, nr_blocks: u64) -> Result<()> {
+    pub fn free(&mut self, ctx: &BatchContext, block: u64, nr_blocks: u64) -> Result<()> {
         self.inner.free(block, nr_blocks)?;
 
         let e = match self.kind {
             Metadata => Entry::FreeMetadata(block as u32, (block + nr_blocks) as u32),
             Data => Entry::FreeData(block, block + nr_blocks),
         };
-        self.add_entry(e)?;
+        ctx.add_entry(e);
 
         Ok(())
     }
 
-    fn grow(&mut self, nr_extra_blocks: u64) -> Result<()> {
+    /// Used by journal replay.
+    pub fn free_unjournalled(&mut self, block: u64, nr_blocks: u64) -> Result<()> {
+        self.inner.free(block, nr_blocks)
+    }
+
+    /// Used by journal replay.
+    pub fn grow_unjournalled(&mut self, nr_extra_blocks: u64) -> Result<()> {
+        self.inner.grow(nr_extra_blocks)
+    }
+
+    pub fn grow(&mut self, ctx: &BatchContext, nr_extra_blocks: u64) -> Result<()> {
         self.inner.grow(nr_extra_blocks)?;
 
         let e = match self.kind {
             Metadata => Entry::GrowMetadata(nr_extra_blocks as u32),
             Data => Entry::GrowData(nr_extra_blocks),
         };
-        self.add_entry(e)?;
+        ctx.add_entry(e);
 
         Ok(())
     }
@@ -2,34 +2,28 @@ use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 
 use crate::allocators::buddy_alloc::*;
+use crate::allocators::journal::*;
 use crate::allocators::*;
 use crate::block_cache::MetadataBlock;
+use crate::journal::batch::BatchContext;
+use crate::journal::entry::*;
 
 //-------------------------------------
 
 /// A sub allocator that wraps the global metadata allocator.
 /// Each active thin volume will have one of these to improve
-/// metadata locality.
+/// metadata locality.  Preallocated blocks that are never used should be
+/// handed back with release(), which journals the free.  If that isn't
+/// done they stay allocated, as the journal says they are, until the
+/// garbage collector reclaims them.
 pub struct MetadataAlloc {
-    global_alloc: Arc<Mutex<dyn Allocator>>,
+    global_alloc: SharedJournalAlloc,
     prealloc_count: u64,
     free_list: VecDeque<MetadataBlock>,
 }
 
-impl Drop for MetadataAlloc {
-    fn drop(&mut self) {
-        let mut global_alloc = self.global_alloc.lock().unwrap();
-
-        for b in &self.free_list {
-            global_alloc
-                .free(*b as u64, 1)
-                .expect("freeing metadata block failed");
-        }
-    }
-}
-
 impl MetadataAlloc {
-    pub fn new(global_alloc: Arc<Mutex<dyn Allocator>>, prealloc_size: u64) -> Self {
+    pub fn new(global_alloc: SharedJournalAlloc, prealloc_size: u64) -> Self {
         Self {
             global_alloc,
             prealloc_count: prealloc_size,
@@ -37,20 +31,30 @@ impl MetadataAlloc {
         }
     }
 
-    pub fn alloc(&mut self) -> Result<MetadataBlock> {
+    pub fn alloc(&mut self, ctx: &BatchContext) -> Result<MetadataBlock> {
         if self.free_list.is_empty() {
-            self.prealloc()?;
+            self.prealloc(ctx)?;
         }
 
         let b = self.free_list.pop_front().unwrap();
         Ok(b)
     }
 
+    /// Returns the preallocated blocks that haven't been used to the
+    /// global allocator.
+    pub fn release(&mut self, ctx: &BatchContext) -> Result<()> {
+        let mut global_alloc = self.global_alloc.lock().unwrap();
+        while let Some(b) = self.free_list.pop_front() {
+            global_alloc.free(ctx, b as u64, 1)?;
+        }
+        Ok(())
+    }
+
     // Succeeds if _any_ blocks were pre-allocated.
-    fn prealloc(&mut self) -> Result<()> {
+    fn prealloc(&mut self, ctx: &BatchContext) -> Result<()> {
         let mut global_alloc = self.global_alloc.lock().unwrap();
 
-        let (total, runs) = global_alloc.alloc_many(self.prealloc_count, 0)?;
+        let (total, runs) = global_alloc.alloc_many(ctx, self.prealloc_count, 0)?;
 
         for (b, e) in runs {
             for block in b..e {
@@ -66,14 +70,49 @@ impl MetadataAlloc {
 
 #[test]
 fn test_prealloc() -> Result<()> {
-    let global_alloc = Arc::new(Mutex::new(BuddyAllocator::new(128)));
+    let global_alloc = Arc::new(Mutex::new(JournalAlloc::new(
+        BuddyAllocator::new(128),
+        AllocKind::Metadata,
+    )));
     let mut metadata_alloc = MetadataAlloc::new(global_alloc.clone(), 10);
 
     // Pre-allocate blocks
-    metadata_alloc.prealloc()?;
+    let ctx = BatchContext::new();
+    metadata_alloc.prealloc(&ctx)?;
 
-    // Check that the free_list is populated
+    // Check that the free_list is populated, and the allocation journalled
     assert_eq!(metadata_alloc.free_list.len(), 10);
+    assert!(!ctx.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_release() -> Result<()> {
+    let global_alloc = Arc::new(Mutex::new(JournalAlloc::new(
+        BuddyAllocator::new(128),
+        AllocKind::Metadata,
+    )));
+    let mut metadata_alloc = MetadataAlloc::new(global_alloc.clone(), 10);
+
+    let ctx = BatchContext::new();
+    metadata_alloc.alloc(&ctx)?;
+    metadata_alloc.release(&ctx)?;
+    assert!(metadata_alloc.free_list.is_empty());
+
+    // The nine unused blocks are freed, and the frees journalled.
+    let entries = ctx.end().unwrap();
+    let nr_freed: u32 = entries
+        .iter()
+        .map(|entry| match entry {
+            Entry::FreeMetadata(b, e) => e - b,
+            _ => 0,
+        })
+        .sum();
+    assert_eq!(nr_freed, 9);
+
+    let ctx = BatchContext::new();
+    let (total, _) = global_alloc.lock().unwrap().alloc_many(&ctx, 127, 0)?;
+    assert_eq!(total, 127);
     Ok(())
 }
 
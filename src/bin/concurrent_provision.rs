@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use thinp_userland::thin::Pool;
+
+//-------------------------------------------------------------------------
+
+fn usage(prog: &str) {
+    eprintln!("Usage: {} [options]", prog);
+    eprintln!();
+    eprintln!("Provisions a thin per thread, in a scratch pool, and reports how");
+    eprintln!("the throughput scales with the nr of threads.  Every other block is");
+    eprintln!("provisioned, one write at a time, so each write inserts a new mapping.");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --writes <n>     writes per thin (default 20000)");
+    eprintln!("  --threads <t,..> thread counts to compare with 1 (default 2,4,8)");
+}
+
+struct Args {
+    nr_writes: u64,
+    nr_threads: Vec<u64>,
+}
+
+fn parse_threads(s: &str) -> Result<Vec<u64>> {
+    s.split(',')
+        .map(|t| t.parse().map_err(|_| anyhow!("bad thread count '{}'", t)))
+        .collect()
+}
+
+fn parse_args(args: &[String]) -> Result<Args> {
+    let mut nr_writes = 20_000;
+    let mut nr_threads = vec![2, 4, 8];
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow!("{} needs a value", arg))
+                .cloned()
+        };
+
+        match arg.as_str() {
+            "--writes" => nr_writes = value()?.parse()?,
+            "--threads" => nr_threads = parse_threads(&value()?)?,
+            _ => return Err(anyhow!("unexpected argument {}", arg)),
+        }
+    }
+
+    Ok(Args {
+        nr_writes,
+        nr_threads,
+    })
+}
+
+// Each thread provisions its own thin.  Returns the elapsed time.
+fn provision(nr_threads: u64, nr_writes: u64) -> Result<Duration> {
+    let dir = TempDir::new()?;
+    let pool = Pool::create(dir.path(), 16384, 256_000_000)?;
+    let mut thins = Vec::new();
+    for _ in 0..nr_threads {
+        thins.push(pool.create_thin(nr_writes * 2)?);
+    }
+
+    let start = Instant::now();
+    thread::scope(|s| -> Result<()> {
+        let mut handles = Vec::new();
+        for id in thins {
+            let pool = &pool;
+            handles.push(s.spawn(move || -> Result<()> {
+                let mut thin = pool.open_thin(id);
+                for b in 0..nr_writes {
+                    pool.get_write_mapping(&mut thin, b * 2, b * 2 + 1)?;
+                }
+                pool.flush(&thin)
+            }));
+        }
+
+        for h in handles {
+            h.join().unwrap()?;
+        }
+        Ok(())
+    })?;
+
+    Ok(start.elapsed())
+}
+
+fn measure(args: &Args) -> Result<()> {
+    let serial = provision(1, args.nr_writes)?;
+    println!("1 thin: {:?} for {} writes", serial, args.nr_writes);
+
+    for nr_threads in &args.nr_threads {
+        let elapsed = provision(*nr_threads, args.nr_writes)?;
+        let speedup = (serial.as_secs_f64() * *nr_threads as f64) / elapsed.as_secs_f64();
+        println!(
+            "{} thins: {:?} for {} writes, {:.2}x the throughput of 1",
+            nr_threads,
+            elapsed,
+            args.nr_writes * nr_threads,
+            speedup
+        );
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let argv: Vec<String> = env::args().collect();
+
+    let args = match parse_args(&argv[1..]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&argv[0]);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = measure(&args) {
+        eprintln!("Error measuring concurrent provisioning: {:?}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
@@ -1,26 +1,163 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::env;
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thinp_userland::journal::entry::ENTRY_KINDS;
+use thinp_userland::journal::inspect::*;
 use thinp_userland::journal::*;
 
 //-------------------------------------------------------------------------
 
-fn dump<P: AsRef<Path>>(p: P) -> Result<()> {
+fn usage(prog: &str) {
+    eprintln!("Usage: {} [options] <path_to_journal>", prog);
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --block <n>[,<n>...]   only show entries that refer to these metadata blocks");
+    eprintln!("  --kind <k>[,<k>...]    only show entries of these kinds");
+    eprintln!("  --slabs <b>[..<e>]     only show this slab, or the slabs in [b, e)");
+    eprintln!("  --batches              show the batch boundaries");
+    eprintln!("  --summary              print counts rather than the entries");
+    eprintln!("  --json                 output json, one object per line");
+    eprintln!("  --follow               keep printing entries as the journal grows");
+    eprintln!(
+        "  --repair               salvage a damaged journal into <path_to_journal>.repaired,"
+    );
+    eprintln!("                         and dump that; the original is left untouched and bad");
+    eprintln!("                         slabs are saved in <path_to_journal>.quarantine");
+    eprintln!();
+    eprintln!("Kinds: {}", ENTRY_KINDS.join(" "));
+}
+
+fn parse_slabs(s: &str) -> Result<Range<u32>> {
+    match s.split_once("..") {
+        Some((b, e)) => Ok(b.parse()?..e.parse()?),
+        None => {
+            let b: u32 = s.parse()?;
+            Ok(b..b + 1)
+        }
+    }
+}
+
+struct Args {
+    path: String,
+    opts: DumpOptions,
+    follow: bool,
+    repair: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Args> {
+    let mut opts = DumpOptions::default();
+    let mut path = None;
+    let mut follow = false;
+    let mut repair = false;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow!("{} needs a value", arg))
+                .cloned()
+        };
+
+        match arg.as_str() {
+            "--block" => {
+                for b in value()?.split(',') {
+                    opts.filter.blocks.insert(b.parse()?);
+                }
+            }
+            "--kind" => {
+                for k in value()?.split(',') {
+                    if !ENTRY_KINDS.contains(&k) {
+                        return Err(anyhow!("unknown entry kind '{}'", k));
+                    }
+                    opts.filter.kinds.insert(k.to_string());
+                }
+            }
+            "--slabs" => opts.filter.slabs = Some(parse_slabs(&value()?)?),
+            "--batches" => opts.batches = true,
+            "--summary" => opts.summary = true,
+            "--json" => opts.format = OutputFormat::Json,
+            "--follow" => follow = true,
+            "--repair" => repair = true,
+            _ if arg.starts_with("--") => return Err(anyhow!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(anyhow!("unexpected argument {}", arg)),
+        }
+    }
+
+    let path = path.ok_or_else(|| anyhow!("no journal given"))?;
+    if follow && opts.summary {
+        return Err(anyhow!("--follow and --summary can't be used together"));
+    }
+
+    Ok(Args {
+        path,
+        opts,
+        follow,
+        repair,
+    })
+}
+
+fn dump<P: AsRef<Path>>(p: P, opts: &DumpOptions) -> Result<()> {
     let mut journal = Journal::open(p, false)?;
-    journal.dump(&mut std::io::stdout())?;
+    inspect::dump(&mut journal, opts, &mut std::io::stdout())?;
     Ok(())
 }
 
+fn follow<P: AsRef<Path>>(p: P, opts: &DumpOptions) -> Result<()> {
+    let interval = Duration::from_millis(100);
+    inspect::follow(p, opts, interval, &mut std::io::stdout())
+}
+
+// Returns the path of the repaired copy.
+fn repair<P: AsRef<Path>>(p: P) -> Result<PathBuf> {
+    let mut quarantine = p.as_ref().to_path_buf();
+    quarantine.set_extension("quarantine");
+    let mut output = p.as_ref().to_path_buf();
+    output.set_extension("repaired");
+    let opts = RepairOptions {
+        quarantine: Some(quarantine),
+        output: Some(output.clone()),
+        ..Default::default()
+    };
+
+    let report = Journal::repair(p, &opts)?;
+    eprint!("{}", report);
+    eprintln!("repaired journal written to {}", output.display());
+    Ok(output)
+}
+
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let argv: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <path_to_journal>", args[0]);
-        std::process::exit(1);
+    let args = match parse_args(&argv[1..]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&argv[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let mut path = PathBuf::from(&args.path);
+    if args.repair {
+        match repair(&path) {
+            Ok(output) => path = output,
+            Err(e) => {
+                eprintln!("Error repairing journal: {:?}", e);
+                std::process::exit(1);
+            }
+        }
     }
 
-    let path = &args[1];
-    if let Err(e) = dump(path) {
+    let r = if args.follow {
+        follow(&path, &args.opts)
+    } else {
+        dump(&path, &args.opts)
+    };
+
+    if let Err(e) = r {
         eprintln!("Error dumping journal: {:?}", e);
         std::process::exit(1);
     }
@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use std::path::Path;
+use std::time::Instant;
+use tempfile::TempDir;
+use thinp_userland::slab::*;
+
+//-------------------------------------------------------------------------
+
+fn usage(prog: &str) {
+    eprintln!("Usage: {} [options] <path_to_journal>", prog);
+    eprintln!();
+    eprintln!("Recompresses the slabs of a journal with various settings, and reports");
+    eprintln!("the ratio achieved.  The dictionary is trained on the first half of the");
+    eprintln!("slabs, and everything is measured on the second half.");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --levels <l>[,<l>...]  zstd levels to try (default 1,3,9,19)");
+    eprintln!("  --dict-len <n>         max size of the dictionary (default 16384)");
+}
+
+struct Args {
+    path: String,
+    levels: Vec<i32>,
+    dict_len: usize,
+}
+
+fn parse_args(args: &[String]) -> Result<Args> {
+    let mut path = None;
+    let mut levels = vec![1, 3, 9, 19];
+    let mut dict_len = 16 * 1024;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow!("{} needs a value", arg))
+                .cloned()
+        };
+
+        match arg.as_str() {
+            "--levels" => {
+                levels.clear();
+                for l in value()?.split(',') {
+                    levels.push(l.parse()?);
+                }
+            }
+            "--dict-len" => dict_len = value()?.parse()?,
+            _ if arg.starts_with("--") => return Err(anyhow!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(anyhow!("unexpected argument {}", arg)),
+        }
+    }
+
+    let path = path.ok_or_else(|| anyhow!("no journal given"))?;
+    Ok(Args {
+        path,
+        levels,
+        dict_len,
+    })
+}
+
+struct Sample {
+    level: i32,
+    dict: bool,
+
+    // Including the file header, so the dictionary isn't free.
+    nr_bytes: u64,
+    secs: f64,
+}
+
+fn recompress(dir: &Path, slabs: &[Vec<u8>], level: i32, dict: Option<&[u8]>) -> Result<Sample> {
+    let path = dir.join(format!("slabs-{}-{}", level, dict.is_some()));
+    let mut builder = SlabFileBuilder::create(&path)
+        .compressed(true)
+        .compression_level(level)
+        .queue_depth(4);
+    if let Some(dict) = dict {
+        builder = builder.dictionary(dict.to_vec());
+    }
+
+    let start = Instant::now();
+    let mut slab = builder.build()?;
+    for data in slabs {
+        slab.write_slab(data)?;
+    }
+    slab.close()?;
+    let secs = start.elapsed().as_secs_f64();
+
+    Ok(Sample {
+        level,
+        dict: dict.is_some(),
+        nr_bytes: std::fs::metadata(&path)?.len(),
+        secs,
+    })
+}
+
+fn bench(args: &Args) -> Result<()> {
+    let mut journal = SlabFileBuilder::open(&args.path).build()?;
+    let nr_slabs = journal.get_nr_slabs() as u32;
+    if nr_slabs < 2 {
+        return Err(anyhow!(
+            "journal needs at least 2 slabs, it has {}",
+            nr_slabs
+        ));
+    }
+
+    let nr_training = nr_slabs / 2;
+    let dict = journal.train_dictionary(0..nr_training, args.dict_len)?;
+
+    let mut slabs = Vec::new();
+    for s in nr_training..nr_slabs {
+        slabs.push(journal.read(s)?.to_vec());
+    }
+    let raw_bytes: u64 = slabs.iter().map(|s| s.len() as u64).sum();
+
+    println!(
+        "{} slabs: trained on {}, measuring {} ({} bytes uncompressed)",
+        nr_slabs,
+        nr_training,
+        slabs.len(),
+        raw_bytes
+    );
+    println!("dictionary: {} bytes", dict.len());
+    println!();
+    println!(
+        "{:>5} {:>5} {:>12} {:>8} {:>10}",
+        "level", "dict", "bytes", "ratio", "MB/s"
+    );
+
+    let dir = TempDir::new()?;
+    for level in &args.levels {
+        for d in [None, Some(&dict[..])] {
+            let sample = recompress(dir.path(), &slabs, *level, d)?;
+            println!(
+                "{:>5} {:>5} {:>12} {:>8.2} {:>10.1}",
+                sample.level,
+                if sample.dict { "yes" } else { "no" },
+                sample.nr_bytes,
+                raw_bytes as f64 / sample.nr_bytes as f64,
+                raw_bytes as f64 / (1024.0 * 1024.0) / sample.secs
+            );
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let argv: Vec<String> = env::args().collect();
+
+    let args = match parse_args(&argv[1..]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&argv[0]);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = bench(&args) {
+        eprintln!("Error benchmarking journal compression: {:?}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
@@ -0,0 +1,95 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use tempfile::TempDir;
+use thinp::io_engine::*;
+use thinp_userland::compressed_io::*;
+
+//-------------------------------------------------------------------------
+
+fn usage(prog: &str) {
+    eprintln!("Usage: {} [options] <node_file>", prog);
+    eprintln!();
+    eprintln!("Copies the nodes in a node file into a compressed node store, and");
+    eprintln!("reports the space saved.  Unused (all zero) blocks are skipped.");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --level <l>  zstd level (default 3)");
+}
+
+struct Args {
+    path: String,
+    level: i32,
+}
+
+fn parse_args(args: &[String]) -> Result<Args> {
+    let mut path = None;
+    let mut level = 3;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow!("{} needs a value", arg))
+                .cloned()
+        };
+
+        match arg.as_str() {
+            "--level" => level = value()?.parse()?,
+            _ if arg.starts_with("--") => return Err(anyhow!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(anyhow!("unexpected argument {}", arg)),
+        }
+    }
+
+    let path = path.ok_or_else(|| anyhow!("no node file given"))?;
+    Ok(Args { path, level })
+}
+
+fn measure(args: &Args) -> Result<()> {
+    let input = SyncIoEngine::new(&args.path, false)?;
+    let nr_blocks = input.get_nr_blocks();
+
+    let dir = TempDir::new()?;
+    let store = CompressedIoEngine::format(dir.path().join("store"), nr_blocks, args.level)?;
+
+    let mut nr_used = 0;
+    for b in 0..nr_blocks {
+        let block = input.read(b)?;
+        if block.get_data().iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        store.write(&block)?;
+        nr_used += 1;
+    }
+    store.flush()?;
+
+    let stats = store.stats();
+    println!("blocks in node file: {}", nr_blocks);
+    println!("blocks in use:       {}", nr_used);
+    println!("compressed bytes:    {}", stats.nr_compressed_bytes);
+    println!("pages used:          {}", stats.nr_pages);
+    println!("blocks per page:     {:.2}", stats.ratio());
+    println!("store file pages:    {}", stats.nr_file_pages);
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let argv: Vec<String> = env::args().collect();
+
+    let args = match parse_args(&argv[1..]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&argv[0]);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = measure(&args) {
+        eprintln!("Error measuring node compression: {:?}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use thinp_userland::thin::rebuild::*;
+use thinp_userland::thin::*;
+
+//-------------------------------------------------------------------------
+
+fn usage(prog: &str) {
+    eprintln!(
+        "Usage: {} [options] <journal> <scratch_node_file> <nr_metadata_blocks> <nr_data_blocks>",
+        prog
+    );
+    eprintln!();
+    eprintln!("Reconstructs the pool as it was at an earlier point in the journal.");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --slab <s>             replay up to and including slab s");
+    eprintln!("  --batch <s>.<b>        replay up to and including batch b of slab s");
+    eprintln!("  --thin <id>            print the mappings of this thin");
+    eprintln!("  --range <b>..<e>       only print the mappings in [b, e)");
+}
+
+fn parse_range(s: &str) -> Result<(u64, u64)> {
+    let (b, e) = s
+        .split_once("..")
+        .ok_or_else(|| anyhow!("bad range '{}'", s))?;
+    Ok((b.parse()?, e.parse()?))
+}
+
+fn parse_batch(s: &str) -> Result<ReplayPoint> {
+    let (slab, batch) = s
+        .split_once('.')
+        .ok_or_else(|| anyhow!("bad batch '{}'", s))?;
+    Ok(ReplayPoint::Batch(slab.parse()?, batch.parse()?))
+}
+
+struct Args {
+    journal: String,
+    node_file: String,
+    nr_metadata_blocks: u64,
+    nr_data_blocks: u64,
+    point: ReplayPoint,
+    thin: Option<u64>,
+    range: (u64, u64),
+}
+
+fn parse_args(args: &[String]) -> Result<Args> {
+    let mut point = ReplayPoint::End;
+    let mut thin = None;
+    let mut range = (0, u64::MAX);
+    let mut positional = Vec::new();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .ok_or_else(|| anyhow!("{} needs a value", arg))
+                .cloned()
+        };
+
+        match arg.as_str() {
+            "--slab" => point = ReplayPoint::Slab(value()?.parse()?),
+            "--batch" => point = parse_batch(&value()?)?,
+            "--thin" => thin = Some(value()?.parse()?),
+            "--range" => range = parse_range(&value()?)?,
+            _ if arg.starts_with("--") => return Err(anyhow!("unknown option {}", arg)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    if positional.len() != 4 {
+        return Err(anyhow!("wrong number of arguments"));
+    }
+
+    Ok(Args {
+        journal: positional[0].clone(),
+        node_file: positional[1].clone(),
+        nr_metadata_blocks: positional[2].parse()?,
+        nr_data_blocks: positional[3].parse()?,
+        point,
+        thin,
+        range,
+    })
+}
+
+fn print_pool(pool: &Pool, args: &Args) -> Result<()> {
+    match args.thin {
+        None => {
+            for id in pool.thin_ids()? {
+                println!("thin {}", id);
+            }
+        }
+        Some(id) => {
+            let mut dev = pool.open_thin(id);
+            let (b, e) = args.range;
+            for (vbegin, m) in pool.get_read_mapping(&mut dev, b, e)? {
+                println!(
+                    "{}..{} -> {}..{} (snap_time {})",
+                    vbegin,
+                    vbegin + m.len(),
+                    m.b,
+                    m.e,
+                    m.snap_time
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let argv: Vec<String> = env::args().collect();
+
+    let args = match parse_args(&argv[1..]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&argv[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let r = open_pool_at(
+        &args.journal,
+        &args.node_file,
+        args.nr_metadata_blocks,
+        args.nr_data_blocks,
+        args.point,
+    )
+    .and_then(|pool| print_pool(&pool, &args));
+
+    if let Err(e) = r {
+        eprintln!("Error reconstructing pool: {:?}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Result};
+use std::env;
+use thinp_userland::thin::rebuild::*;
+
+//-------------------------------------------------------------------------
+
+fn usage(prog: &str) {
+    eprintln!(
+        "Usage: {} <journal> <new_node_file> <nr_metadata_blocks> <nr_data_blocks>",
+        prog
+    );
+    eprintln!();
+    eprintln!("Replays the whole journal into a new node file, and checks the result.");
+}
+
+struct Args {
+    journal: String,
+    node_file: String,
+    nr_metadata_blocks: u64,
+    nr_data_blocks: u64,
+}
+
+fn parse_args(args: &[String]) -> Result<Args> {
+    if args.len() != 4 {
+        return Err(anyhow!("wrong number of arguments"));
+    }
+
+    Ok(Args {
+        journal: args[0].clone(),
+        node_file: args[1].clone(),
+        nr_metadata_blocks: args[2].parse()?,
+        nr_data_blocks: args[3].parse()?,
+    })
+}
+
+fn print_report(report: &RebuildReport) {
+    println!("entries replayed: {}", report.nr_entries);
+    println!(
+        "info root: {} (seq {})",
+        report.info_root.loc, report.info_root.seq_nr
+    );
+    for (id, nr) in &report.thins {
+        println!("thin {}: {} mappings", id, nr);
+    }
+    if let Some(bad) = &report.bad_batch {
+        println!("replay stopped early: {}", bad);
+    }
+}
+
+fn main() -> Result<()> {
+    let argv: Vec<String> = env::args().collect();
+
+    let args = match parse_args(&argv[1..]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&argv[0]);
+            std::process::exit(1);
+        }
+    };
+
+    match rebuild_node_file(
+        &args.journal,
+        &args.node_file,
+        args.nr_metadata_blocks,
+        args.nr_data_blocks,
+    ) {
+        Ok(report) => print_report(&report),
+        Err(e) => {
+            eprintln!("Error rebuilding node file: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
@@ -36,8 +36,8 @@ struct EntryInner {
     block: Block,
 }
 
-// FIXME: why do we need to lock the inner, if there's a big lock on the BlockCache as a whole?
-// Nothing in CacheEntry is public.
+// Each entry has its own mutex so threads waiting for a block lock can do
+// so without holding the big lock on the BlockCache as a whole.
 struct CacheEntry {
     inner: Mutex<EntryInner>,
     cond: Condvar,
@@ -120,7 +120,8 @@ impl CacheEntry {
         }
     }
 
-    fn unlock(&self) {
+    // Returns true if the block is no longer held by anyone.
+    fn unlock(&self) -> bool {
         use LockState::*;
 
         let mut inner = self.inner.lock().unwrap();
@@ -140,6 +141,25 @@ impl CacheEntry {
             }
         }
         self.cond.notify_all();
+        inner.lock == Unlocked
+    }
+
+    // Blocks until a shared lock could be granted.  The caller must retry
+    // the lock via the cache since the entry may have been evicted in the
+    // meantime.
+    fn wait_shared(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        while let LockState::Exclusive(_) = inner.lock {
+            inner = self.cond.wait(inner).unwrap();
+        }
+    }
+
+    // Blocks until an exclusive lock could be granted.
+    fn wait_exclusive(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.lock != LockState::Unlocked {
+            inner = self.cond.wait(inner).unwrap();
+        }
     }
 }
 
@@ -244,8 +264,11 @@ impl BlockCacheInner {
 
     fn unlock(&mut self, loc: u32) -> Result<()> {
         let entry = self.cache.get_mut(&loc).unwrap();
-        entry.unlock();
-        self.insert_lru_(loc)?;
+
+        // The lru only contains blocks that are not held.
+        if entry.unlock() {
+            self.insert_lru_(loc)?;
+        }
         Ok(())
     }
 
@@ -488,10 +511,10 @@ impl BlockCache {
     pub fn shared_lock(self: &Arc<Self>, loc: u32) -> Result<SharedProxy> {
         use LockResult::*;
 
-        let mut inner = self.inner.lock().unwrap();
-
         loop {
-            match inner.shared_lock(loc)? {
+            // The big lock must be dropped before we wait on the entry.
+            let r = self.inner.lock().unwrap().shared_lock(loc)?;
+            match r {
                 Locked(entry) => {
                     let proxy_ = SharedProxy_ {
                         loc,
@@ -507,7 +530,7 @@ impl BlockCache {
 
                     return Ok(proxy);
                 }
-                Busy(entry) => self.wait_on_entry_(&entry),
+                Busy(entry) => entry.wait_shared(),
             }
         }
     }
@@ -515,10 +538,10 @@ impl BlockCache {
     pub fn exclusive_lock(self: &Arc<Self>, loc: u32) -> Result<ExclusiveProxy> {
         use LockResult::*;
 
-        let mut inner = self.inner.lock().unwrap();
-
         loop {
-            match inner.exclusive_lock(loc)? {
+            // The big lock must be dropped before we wait on the entry.
+            let r = self.inner.lock().unwrap().exclusive_lock(loc)?;
+            match r {
                 Locked(entry) => {
                     let proxy_ = ExclusiveProxy_ {
                         loc,
@@ -534,7 +557,7 @@ impl BlockCache {
 
                     return Ok(proxy);
                 }
-                Busy(entry) => self.wait_on_entry_(&entry),
+                Busy(entry) => entry.wait_exclusive(),
             }
         }
     }
@@ -543,10 +566,10 @@ impl BlockCache {
     pub fn zero_lock(self: &Arc<Self>, loc: u32) -> Result<ExclusiveProxy> {
         use LockResult::*;
 
-        let mut inner = self.inner.lock().unwrap();
-
         loop {
-            match inner.zero_lock(loc)? {
+            // The big lock must be dropped before we wait on the entry.
+            let r = self.inner.lock().unwrap().zero_lock(loc)?;
+            match r {
                 Locked(entry) => {
                     let proxy_ = ExclusiveProxy_ {
                         loc,
@@ -562,7 +585,7 @@ impl BlockCache {
 
                     return Ok(proxy);
                 }
-                Busy(entry) => self.wait_on_entry_(&entry),
+                Busy(entry) => entry.wait_exclusive(),
             }
         }
     }
@@ -578,12 +601,6 @@ impl BlockCache {
         let mut inner = self.inner.lock().unwrap();
         inner.unlock(loc).expect("unlock failed");
     }
-
-    // Do not call this with the top level cache lock held
-    fn wait_on_entry_(&self, entry: &CacheEntry) {
-        let inner = entry.inner.lock().unwrap();
-        let _guard = entry.cond.wait(inner).unwrap();
-    }
 }
 
 //-------------------------------------------------------------------------
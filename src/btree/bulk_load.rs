@@ -0,0 +1,171 @@
+use anyhow::{anyhow, ensure, Result};
+use std::sync::Arc;
+
+use crate::block_cache::*;
+use crate::btree::node::*;
+use crate::btree::nodes::journal::*;
+use crate::btree::remove::merge_or_borrow;
+use crate::btree::transaction_manager::*;
+use crate::journal::batch::BatchContext;
+use crate::packed_array::*;
+
+use crate::btree::BTree;
+
+//-------------------------------------------------------------------------
+
+// The most entries we try to append to a node in one go.  Each append that
+// succeeds is a single journal entry.
+const BATCH_SIZE: usize = 256;
+
+// Packs a sorted sequence of entries into new nodes, filling each one
+// before moving on to the next.
+struct LevelBuilder<'a, NV: Serializable, N: NodeW<NV, ExclusiveProxy>> {
+    tm: &'a TransactionManager,
+    ctx: &'a BatchContext,
+    is_leaf: bool,
+
+    last_key: Option<Key>,
+    keys: Vec<Key>,
+    values: Vec<NV>,
+
+    // We hang on to the last two nodes so the final one can take entries
+    // from its neighbour if it ends up underfull.
+    prev: Option<JournalNode<N, NV, ExclusiveProxy>>,
+    current: Option<JournalNode<N, NV, ExclusiveProxy>>,
+
+    // key_min and ptr of the nodes that are complete.
+    nodes: Vec<(Key, NodePtr)>,
+}
+
+impl<'a, NV: Serializable, N: NodeW<NV, ExclusiveProxy>> LevelBuilder<'a, NV, N> {
+    fn new(tm: &'a TransactionManager, ctx: &'a BatchContext, is_leaf: bool) -> Self {
+        Self {
+            tm,
+            ctx,
+            is_leaf,
+            last_key: None,
+            keys: Vec::with_capacity(BATCH_SIZE),
+            values: Vec::with_capacity(BATCH_SIZE),
+            prev: None,
+            current: None,
+            nodes: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, key: Key, value: NV) -> Result<()> {
+        if let Some(last) = self.last_key {
+            ensure!(key > last, "bulk_load keys out of order: {}, {}", last, key);
+        }
+        self.last_key = Some(key);
+
+        self.keys.push(key);
+        self.values.push(value);
+        if self.keys.len() == BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn next_node(&mut self) -> Result<()> {
+        let node = self.tm.new_node(self.ctx, None, self.is_leaf, 0)?;
+        if let Some(prev) = self.prev.take() {
+            self.nodes.push((prev.get_key(0), prev.n_ptr()));
+        }
+        self.prev = self.current.replace(node);
+        Ok(())
+    }
+
+    // Appends the buffered entries, halving the batch when it doesn't fit
+    // and moving on to a new node once not even a single entry does.
+    fn flush(&mut self) -> Result<()> {
+        use NodeInsertOutcome::*;
+
+        let mut count = self.keys.len();
+        while !self.keys.is_empty() {
+            if self.current.is_none() {
+                self.next_node()?;
+            }
+            let node = self.current.as_mut().unwrap();
+
+            match node.append(&self.keys[0..count], &self.values[0..count]) {
+                Success => {
+                    self.keys.drain(0..count);
+                    self.values.drain(0..count);
+                    count = self.keys.len();
+                }
+                NoSpace if count > 1 => {
+                    count /= 2;
+                }
+                NoSpace => {
+                    if node.is_empty() {
+                        return Err(anyhow!("bulk_load entry too large for a node"));
+                    }
+                    self.next_node()?;
+                    count = self.keys.len();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the key_min and ptr of each node, in order.
+    fn complete(mut self) -> Result<Vec<(Key, NodePtr)>> {
+        self.flush()?;
+
+        if let Some(mut current) = self.current.take() {
+            if let Some(mut prev) = self.prev.take() {
+                if current.is_underfull() {
+                    merge_or_borrow(&mut prev, &mut current)?;
+                }
+                self.nodes.push((prev.get_key(0), prev.n_ptr()));
+            }
+
+            if !current.is_empty() {
+                self.nodes.push((current.get_key(0), current.n_ptr()));
+            }
+        }
+        Ok(self.nodes)
+    }
+}
+
+//-------------------------------------------------------------------------
+
+impl<
+        V: Serializable + Copy,
+        INodeR: NodeR<NodePtr, SharedProxy>,
+        INodeW: NodeW<NodePtr, ExclusiveProxy>,
+        LNodeR: NodeR<V, SharedProxy>,
+        LNodeW: NodeW<V, ExclusiveProxy>,
+    > BTree<V, INodeR, INodeW, LNodeR, LNodeW>
+{
+    /// Builds a tree from entries sorted by key, without any duplicates.
+    /// Leaves are packed left to right and the internal levels built
+    /// above them, which is much quicker than inserting each entry.
+    pub fn bulk_load(
+        tm: Arc<TransactionManager>,
+        ctx: &BatchContext,
+        entries: impl Iterator<Item = (Key, V)>,
+    ) -> Result<Self> {
+        let mut leaves = LevelBuilder::<V, LNodeW>::new(&tm, ctx, true);
+        for (k, v) in entries {
+            leaves.push(k, v)?;
+        }
+        let mut nodes = leaves.complete()?;
+
+        if nodes.is_empty() {
+            return Self::empty_tree(tm, ctx);
+        }
+
+        while nodes.len() > 1 {
+            let mut level = LevelBuilder::<NodePtr, INodeW>::new(&tm, ctx, false);
+            for (k, n_ptr) in nodes {
+                level.push(k, n_ptr)?;
+            }
+            nodes = level.complete()?;
+        }
+
+        Ok(Self::open_tree(tm, nodes[0].1))
+    }
+}
+
+//-------------------------------------------------------------------------
@@ -43,6 +43,23 @@ impl<
         Ok(())
     }
 
+    // Only the root may be underfull, removals merge or rebalance the
+    // others.
+    fn check_fill_<NV: Serializable, Node: NodeR<NV, SharedProxy>>(
+        node: &Node,
+        is_root: bool,
+    ) -> Result<()> {
+        if !is_root {
+            ensure!(
+                !node.is_underfull(),
+                "node {} is underfull ({} bytes used)",
+                node.n_ptr().loc,
+                node.used_space()
+            );
+        }
+        Ok(())
+    }
+
     fn check_(
         &self,
         n_ptr: NodePtr,
@@ -55,10 +72,19 @@ impl<
         ensure!(!seen.contains(&n_ptr.loc));
         seen.insert(n_ptr.loc);
 
+        let is_root = n_ptr == self.root;
         if self.tm.is_internal(n_ptr)? {
             let node: INodeR = self.tm.read(n_ptr)?;
 
             Self::check_keys_(&node, key_min, key_max)?;
+            Self::check_fill_(&node, is_root)?;
+            if is_root {
+                ensure!(
+                    node.nr_entries() > 1,
+                    "internal root has {} entries",
+                    node.nr_entries()
+                );
+            }
 
             for i in 0..node.nr_entries() {
                 let kmin = node.get_key(i);
@@ -74,6 +100,7 @@ impl<
         } else {
             let node: LNodeR = self.tm.read(n_ptr)?;
             Self::check_keys_(&node, key_min, key_max)?;
+            Self::check_fill_(&node, is_root)?;
             total += node.nr_entries() as u64;
         }
 
@@ -0,0 +1,201 @@
+use anyhow::Result;
+
+use crate::block_cache::*;
+use crate::btree::insert::merge_entries;
+use crate::btree::node::*;
+use crate::btree::range_value::RangeValue;
+use crate::btree::BTree;
+use crate::journal::batch::BatchContext;
+use crate::packed_array::*;
+
+//-------------------------------------------------------------------------
+
+// The most runs we find before merging them.  The cursor can't be held
+// while the tree is changed, so we scan a batch at a time.
+const BATCH_SIZE: usize = 1024;
+
+/// What compact() found.  For a mapping tree the mean run length is the
+/// mean nr of blocks per mapping, which metadata size is inversely
+/// proportional to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactStats {
+    pub nr_keys: u64,
+    pub nr_entries_before: u64,
+    pub nr_entries_after: u64,
+}
+
+impl CompactStats {
+    pub fn run_length_before(&self) -> f64 {
+        self.nr_keys as f64 / self.nr_entries_before.max(1) as f64
+    }
+
+    pub fn run_length_after(&self) -> f64 {
+        self.nr_keys as f64 / self.nr_entries_after.max(1) as f64
+    }
+
+    /// How many times longer the runs are, eg, 2.0 if the nr of entries
+    /// has halved.
+    pub fn improvement(&self) -> f64 {
+        self.nr_entries_before as f64 / self.nr_entries_after.max(1) as f64
+    }
+}
+
+// Runs to merge, and the key to carry on scanning from, if any.
+type Runs<V> = (Vec<(Key, V)>, Option<Key>);
+
+// Whether a pair of leaves using this much space should fit in one node.
+fn fits_in_one(left: usize, right: usize) -> bool {
+    left + right <= NODE_SIZE
+}
+
+impl<
+        V: Serializable + Copy + RangeValue,
+        INodeR: NodeR<NodePtr, SharedProxy>,
+        INodeW: NodeW<NodePtr, ExclusiveProxy>,
+        LNodeR: NodeR<V, SharedProxy>,
+        LNodeW: NodeW<V, ExclusiveProxy>,
+    > BTree<V, INodeR, INodeW, LNodeR, LNodeW>
+{
+    // Scans from key, returning the runs of two or more entries that can
+    // be merged, and the key to carry on from if the scan stopped early.
+    fn find_runs(&self, key: Key, stats: &mut CompactStats) -> Result<Runs<V>> {
+        let mut runs = Vec::new();
+
+        // The current run, and the nr of entries in it.
+        let mut run: Option<((Key, V), usize)> = None;
+        for r in self.cursor(key, Key::MAX) {
+            let (k, v) = r?;
+            if let Some((entry, len)) = run.take() {
+                if let Some(merged) = merge_entries(&entry, &(k, v)) {
+                    stats.nr_keys += v.range_len();
+                    stats.nr_entries_before += 1;
+                    run = Some((merged, len + 1));
+                    continue;
+                }
+
+                stats.nr_entries_after += 1;
+                if len > 1 {
+                    runs.push(entry);
+                    if runs.len() == BATCH_SIZE {
+                        return Ok((runs, Some(k)));
+                    }
+                }
+            }
+
+            stats.nr_keys += v.range_len();
+            stats.nr_entries_before += 1;
+            run = Some(((k, v), 1));
+        }
+
+        if let Some((entry, len)) = run {
+            stats.nr_entries_after += 1;
+            if len > 1 {
+                runs.push(entry);
+            }
+        }
+        Ok((runs, None))
+    }
+
+    // Merges neighbouring leaves below n_ptr that will fit in a single
+    // node.  Returns the node's new location if anything changed.
+    fn repack(&mut self, ctx: &BatchContext, n_ptr: NodePtr) -> Result<Option<NodePtr>> {
+        // We may need to shadow the node, so don't hold on to it.
+        let children = {
+            let node: INodeR = self.tm.read(n_ptr)?;
+            node.get_entries(0, node.nr_entries()).1
+        };
+        if children.is_empty() {
+            return Ok(None);
+        }
+
+        if self.tm.is_internal(children[0])? {
+            let mut node = None;
+            for (i, child) in children.iter().enumerate() {
+                if let Some(new_child) = self.repack(ctx, *child)? {
+                    if node.is_none() {
+                        node = Some(self.tm.shadow::<NodePtr, INodeW>(
+                            ctx,
+                            self.local_alloc(),
+                            n_ptr,
+                            self.snap_time,
+                        )?);
+                    }
+                    let node = node.as_mut().unwrap();
+                    let (key, loc) = (node.get_key(i), node.n_ptr().loc);
+                    node.overwrite(i, key, &new_child).into_result(loc)?;
+                }
+            }
+
+            // The children may have lost so many entries they're underfull.
+            return match node {
+                Some(mut node) => {
+                    self.rebalance_children(ctx, &mut node)?;
+                    Ok(Some(node.n_ptr()))
+                }
+                None => Ok(None),
+            };
+        }
+
+        let mut used = Vec::with_capacity(children.len());
+        for child in &children {
+            used.push(self.tm.read::<V, LNodeR>(*child)?.used_space());
+        }
+        if !used.windows(2).any(|w| fits_in_one(w[0], w[1])) {
+            return Ok(None);
+        }
+
+        let mut node =
+            self.tm
+                .shadow::<NodePtr, INodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
+        let mut idx = 0;
+        while idx + 1 < node.nr_entries() {
+            let left = self.tm.read::<V, LNodeR>(node.get_value(idx))?.used_space();
+            let right = self
+                .tm
+                .read::<V, LNodeR>(node.get_value(idx + 1))?
+                .used_space();
+
+            // Keep merging into the same leaf until it's full.
+            if !(fits_in_one(left, right)
+                && self.rebalance_pair::<V, LNodeW>(ctx, &mut node, idx)?)
+            {
+                idx += 1;
+            }
+        }
+        Ok(Some(node.n_ptr()))
+    }
+
+    /// Merges runs of neighbouring entries, eg, mappings that are
+    /// contiguous in both the virtual and data devices, into single
+    /// entries.  Then neighbouring leaves that fit in one node are merged.
+    /// Nodes that don't change aren't copied, so stay shared with any
+    /// snapshots.
+    pub fn compact(&mut self, ctx: &BatchContext) -> Result<CompactStats> {
+        let mut stats = CompactStats::default();
+
+        let mut key = 0;
+        loop {
+            let (runs, next) = self.find_runs(key, &mut stats)?;
+
+            // insert_range() replaces the entries each run covers.
+            for (k, v) in runs {
+                self.insert_range(ctx, k, &v)?;
+            }
+
+            match next {
+                Some(next) => key = next,
+                None => break,
+            }
+        }
+
+        if self.tm.is_internal(self.root)? {
+            if let Some(root) = self.repack(ctx, self.root)? {
+                self.root = self.shrink_root(ctx, root)?;
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+//-------------------------------------------------------------------------
@@ -1,10 +1,12 @@
 use anyhow::Result;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use crate::allocators::metadata_alloc::MetadataAlloc;
 use crate::block_cache::*;
 use crate::btree::node::*;
 use crate::btree::nodes::journal::*;
 use crate::btree::transaction_manager::*;
+use crate::journal::batch::BatchContext;
 use crate::packed_array::*;
 
 use crate::btree::BTree;
@@ -24,6 +26,7 @@ impl<
             tm,
             root,
             snap_time: 0,
+            metadata_alloc: None,
             phantom_v: std::marker::PhantomData,
             phantom_inode_r: std::marker::PhantomData,
             phantom_inode_w: std::marker::PhantomData,
@@ -32,14 +35,15 @@ impl<
         }
     }
 
-    pub fn empty_tree(tm: Arc<TransactionManager>) -> Result<Self> {
-        let node = tm.new_node::<V, LNodeW>(true)?;
+    pub fn empty_tree(tm: Arc<TransactionManager>, ctx: &BatchContext) -> Result<Self> {
+        let node = tm.new_node::<V, LNodeW>(ctx, None, true, 0)?;
         let root = node.n_ptr();
 
         Ok(Self {
             tm,
             root,
             snap_time: 0,
+            metadata_alloc: None,
             phantom_v: std::marker::PhantomData,
             phantom_inode_r: std::marker::PhantomData,
             phantom_inode_w: std::marker::PhantomData,
@@ -48,6 +52,9 @@ impl<
         })
     }
 
+    /// Returns a snapshot that shares all of this tree's nodes.  Both
+    /// trees copy any node older than `snap_time` before changing it, so
+    /// `snap_time` must be later than that of any node in the tree.
     pub fn snap(&mut self, snap_time: u32) -> Self {
         self.snap_time = snap_time;
 
@@ -55,6 +62,7 @@ impl<
             tm: self.tm.clone(),
             root: self.root,
             snap_time,
+            metadata_alloc: self.metadata_alloc.clone(),
             phantom_v: std::marker::PhantomData,
             phantom_inode_r: std::marker::PhantomData,
             phantom_inode_w: std::marker::PhantomData,
@@ -67,38 +75,145 @@ impl<
         self.root
     }
 
+    /// Allocate new nodes from a per-thin allocator rather than the
+    /// global one.
+    pub fn with_metadata_alloc(mut self, alloc: Arc<Mutex<MetadataAlloc>>) -> Self {
+        self.metadata_alloc = Some(alloc);
+        self
+    }
+
+    /// Sets the snap_time of a tree that's been opened, which decides
+    /// which nodes get copied when they're changed.
+    pub fn with_snap_time(mut self, snap_time: u32) -> Self {
+        self.snap_time = snap_time;
+        self
+    }
+
+    pub fn snap_time(&self) -> u32 {
+        self.snap_time
+    }
+
+    pub(crate) fn local_alloc(&self) -> Option<&Mutex<MetadataAlloc>> {
+        self.metadata_alloc.as_deref()
+    }
+
     //-------------------------------
 
-    // Call this when recursing back up the spine
-    pub fn node_insert_result<N: NodeW<NodePtr, ExclusiveProxy>>(
+    // Splits a node that had no space for a change.  The change is
+    // abandoned, so this returns a Retry, and the op has to be repeated
+    // from the root once it's been applied (see retry_root()).
+    pub(crate) fn split<NV: Serializable, N: NodeW<NV, ExclusiveProxy>>(
+        &self,
+        node: &mut JournalNode<N, NV, ExclusiveProxy>,
+    ) -> Result<NodeResult> {
+        Ok(split_node(self.tm.as_ref(), self.local_alloc(), self.snap_time, node)?.retry())
+    }
+
+    // Returns a new root with the pair of nodes as its children.
+    pub(crate) fn grow_root(
+        &self,
+        ctx: &BatchContext,
+        left: &NodeInfo,
+        right: &NodeInfo,
+    ) -> Result<NodePtr> {
+        let mut parent: JournalNode<INodeW, NodePtr, ExclusiveProxy> =
+            self.tm
+                .new_node(ctx, self.local_alloc(), false, self.snap_time)?;
+        let loc = parent.n_ptr().loc;
+        parent
+            .append(
+                &[left.key_min.unwrap(), right.key_min.unwrap()],
+                &[left.n_ptr, right.n_ptr],
+            )
+            .into_result(loc)?;
+        Ok(parent.n_ptr())
+    }
+
+    // Returns the root to repeat an op on after it gave a Retry.
+    pub(crate) fn retry_root(
+        &self,
+        ctx: &BatchContext,
+        left: &NodeInfo,
+        right: Option<&NodeInfo>,
+    ) -> Result<NodePtr> {
+        match right {
+            Some(right) => self.grow_root(ctx, left, right),
+            None => Ok(left.n_ptr),
+        }
+    }
+
+    fn insert_single<N: NodeW<NodePtr, ExclusiveProxy>>(
         &mut self,
         node: &mut JournalNode<N, NodePtr, ExclusiveProxy>,
         idx: usize,
-        res: &NodeResult,
+        child: &NodeInfo,
     ) -> Result<NodeResult> {
-        use NodeResult::*;
-
-        match res {
-            Single(NodeInfo { key_min: None, .. }) => {
-                node.remove_at(idx);
-                Ok(NodeResult::single(node))
-            }
-            Single(NodeInfo {
+        match child {
+            NodeInfo { key_min: None, .. } => match node.remove_at(idx) {
+                NodeInsertOutcome::Success => Ok(NodeResult::single(node)),
+                NodeInsertOutcome::NoSpace => self.split(node),
+            },
+            NodeInfo {
                 key_min: Some(new_key),
                 n_ptr,
-            }) => {
+            } => {
                 // This check is worth it to save journal entries.
-                if node.get_key(idx) != *new_key || node.get_value(idx) != *n_ptr {
-                    node.overwrite(idx, *new_key, n_ptr);
+                if node.get_key(idx) == *new_key && node.get_value(idx) == *n_ptr {
+                    return Ok(NodeResult::single(node));
                 }
-                Ok(NodeResult::single(node))
+
+                // A node that isn't a fixed size may need more room for
+                // the new key.
+                ensure_space(
+                    self.tm.as_ref(),
+                    self.local_alloc(),
+                    self.snap_time,
+                    node,
+                    idx,
+                    |node, idx| node.overwrite(idx, *new_key, n_ptr),
+                )
             }
-            Pair(left, right) => {
-                node.overwrite(idx, left.key_min.unwrap(), &left.n_ptr);
-                ensure_space(self.tm.as_ref(), node, idx, |node, idx| {
+        }
+    }
+
+    fn insert_pair<N: NodeW<NodePtr, ExclusiveProxy>>(
+        &mut self,
+        node: &mut JournalNode<N, NodePtr, ExclusiveProxy>,
+        idx: usize,
+        left: &NodeInfo,
+        right: &NodeInfo,
+    ) -> Result<NodeResult> {
+        // The child has already split, so both changes have to be made.
+        // Redoing the overwrite after a split does no harm.
+        ensure_space(
+            self.tm.as_ref(),
+            self.local_alloc(),
+            self.snap_time,
+            node,
+            idx,
+            |node, idx| match node.overwrite(idx, left.key_min.unwrap(), &left.n_ptr) {
+                NodeInsertOutcome::Success => {
                     node.insert(idx + 1, right.key_min.unwrap(), &right.n_ptr)
-                })
-            }
+                }
+                outcome => outcome,
+            },
+        )
+    }
+
+    // Call this when recursing back up the spine
+    pub fn node_insert_result<N: NodeW<NodePtr, ExclusiveProxy>>(
+        &mut self,
+        node: &mut JournalNode<N, NodePtr, ExclusiveProxy>,
+        idx: usize,
+        res: &NodeResult,
+    ) -> Result<NodeResult> {
+        use NodeResult::*;
+
+        match res {
+            Single(child) => self.insert_single(node, idx, child),
+            Pair(left, right) => self.insert_pair(node, idx, left, right),
+            Retry(left, None) => Ok(self.insert_single(node, idx, left)?.retry()),
+            Retry(left, Some(right)) => Ok(self.insert_pair(node, idx, left, right)?.retry()),
         }
     }
 }
@@ -1,298 +1,308 @@
-// FIXME: I'm not sure we still need the cursor, keeping this code just in case
+use anyhow::Result;
 
-/*
-struct Frame {
-    is_leaf: bool,
-    loc: MetadataBlock,
+use crate::block_cache::*;
+use crate::btree::node::*;
+use crate::btree::range_value::RangeValue;
+use crate::btree::BTree;
+use crate::packed_array::*;
+
+//-------------------------------------------------------------------------
 
-    // Index into the current node
-    index: usize,
+// An internal node on the path from the root to the current leaf.
+struct Frame {
+    n_ptr: NodePtr,
 
-    // Nr entries in current node
+    // Index of the child we descended into.
+    idx: usize,
     nr_entries: usize,
 }
 
-pub struct Cursor<
-    'a,
-    V: Serializable + Copy,
-    INode: NodeW<MetadataBlock, WriteProxy>,
-    LNode: NodeW<V, WriteProxy>,
-> {
-    tree: &'a BTree<V, INode, LNode>,
+// One end of the cursor.  Only the internal nodes' locations are kept, the
+// current leaf's entries are copied out so no locks are held between
+// calls.
+struct Spine<V> {
+    frames: Vec<Frame>,
+    keys: Vec<Key>,
+    values: Vec<V>,
+
+    // Index of the next entry to return from the leaf.  Runs off either
+    // end of the leaf, depending on the direction.
+    idx: isize,
+}
 
-    // Holds pairs of (loc, index, nr_entries)
-    stack: Option<Vec<Frame>>,
+/// Iterates the entries of a tree that overlap [key_begin, key_end), in
+/// either direction.  Entries are read a leaf at a time, and range
+/// values that straddle the ends are clipped to the range.
+pub struct Cursor<'a, V: Serializable + Copy, INodeR, INodeW, LNodeR, LNodeW> {
+    tree: &'a BTree<V, INodeR, INodeW, LNodeR, LNodeW>,
+    key_begin: Key,
+    key_end: Key,
+
+    front: Option<Spine<V>>,
+    back: Option<Spine<V>>,
+
+    // The unclipped key of the last entry taken from each end, so the
+    // ends can tell when they've met.
+    front_key: Option<Key>,
+    back_key: Option<Key>,
+    done: bool,
 }
 
-fn next_<
-    V: Serializable + Copy,
-    INode: NodeW<MetadataBlock, WriteProxy>,
-    LNode: NodeW<V, WriteProxy>,
->(
-    tree: &BTree<V, INode, LNode>,
-    stack: &mut Vec<Frame>,
-) -> Result<bool> {
-    if stack.is_empty() {
-        return Ok(false);
+impl<
+        'a,
+        V: Serializable + Copy + RangeValue,
+        INodeR: NodeR<NodePtr, SharedProxy>,
+        INodeW: NodeW<NodePtr, ExclusiveProxy>,
+        LNodeR: NodeR<V, SharedProxy>,
+        LNodeW: NodeW<V, ExclusiveProxy>,
+    > Cursor<'a, V, INodeR, INodeW, LNodeR, LNodeW>
+{
+    fn new(
+        tree: &'a BTree<V, INodeR, INodeW, LNodeR, LNodeW>,
+        key_begin: Key,
+        key_end: Key,
+    ) -> Self {
+        Self {
+            tree,
+            key_begin,
+            key_end,
+            front: None,
+            back: None,
+            front_key: None,
+            back_key: None,
+            done: key_begin >= key_end,
+        }
     }
 
-    let frame = stack.last_mut().unwrap();
-
-    frame.index += 1;
-    if frame.index >= frame.nr_entries {
-        // We need to move to the next node.
-        stack.pop();
-        if !next_::<V, INode, LNode>(tree, stack)? {
-            return Ok(false);
+    // Follows the children that may hold `key` down to a leaf, pushing the
+    // internal nodes onto `frames`.  Returns the leaf.
+    fn descend(&self, mut n_ptr: NodePtr, key: Key, frames: &mut Vec<Frame>) -> Result<LNodeR> {
+        let tm = &self.tree.tm;
+        while tm.is_internal(n_ptr)? {
+            let node: INodeR = tm.read(n_ptr)?;
+            let idx = node.lower_bound(key).max(0) as usize;
+            frames.push(Frame {
+                n_ptr,
+                idx,
+                nr_entries: node.nr_entries(),
+            });
+            n_ptr = node.get_value(idx);
         }
-
-        let frame = stack.last().unwrap();
-        let node = INode::open(frame.loc,
-        let n = tree.read_node::<MetadataBlock>(frame.loc)?;
-
-        let loc = n.values.get(frame.index);
-        let n = tree.read_node::<NV>(loc)?;
-
-        stack.push(Frame {
-            loc,
-            index: 0,
-            nr_entries: n.nr_entries.get() as usize,
-        });
+        tm.read(n_ptr)
     }
 
-    Ok(true)
-}
-
-fn prev_<
-    TreeV: Serializable + Copy,
-    NV: Serializable,
-    INode: NodeW<MetadataBlock, WriteProxy>,
-    LNode: NodeW<TreeV, WriteProxy>,
->(
-    tree: &BTree<TreeV, INode, LNode>,
-    stack: &mut Vec<Frame>,
-) -> Result<bool> {
-    if stack.is_empty() {
-        return Ok(false);
-    }
-    let frame = stack.last_mut().unwrap();
-    if frame.index == 0 {
-        // We need to move to the previous node.
-        stack.pop();
-        if !prev_::<TreeV, MetadataBlock, INode, LNode>(tree, stack)? {
-            return Ok(false);
+    fn load_leaf(node: LNodeR, frames: Vec<Frame>, idx: isize) -> Spine<V> {
+        let (keys, values) = node.get_entries(0, node.nr_entries());
+        Spine {
+            frames,
+            keys,
+            values,
+            idx,
         }
-        let frame = stack.last().unwrap();
-        let n = tree.read_node::<MetadataBlock>(frame.loc)?;
-        let loc = n.values.get(frame.index);
-        let n = tree.read_node::<NV>(loc)?;
-        stack.push(Frame {
-            loc,
-            index: n.nr_entries.get() as usize - 1,
-            nr_entries: n.nr_entries.get() as usize,
-        });
-    } else {
-        frame.index -= 1;
     }
 
-    Ok(true)
-}
-
-impl<
-        'a,
-        V: Serializable + Copy,
-        INode: NodeW<MetadataBlock, WriteProxy>,
-        LNode: NodeW<V, WriteProxy>,
-    > Cursor<'a, V, INode, LNode>
-{
-    fn new(tree: &'a BTree<V, INode, LNode>, key: u32) -> Result<Self> {
-        let mut stack = Vec::new();
-        let mut loc = tree.root();
+    // Moves to the first entry of the next leaf along, or the last entry
+    // of the previous one.  Returns false if there are no more leaves.
+    fn next_leaf(&self, spine: &mut Spine<V>, forward: bool) -> Result<bool> {
+        let tm = &self.tree.tm;
 
         loop {
-            if tree.is_leaf(loc)? {
-                let n = tree.read_node::<V>(loc)?;
-                let nr_entries = n.nr_entries.get() as usize;
-                if nr_entries == 0 {
-                    eprintln!("empty cursor");
-                    return Ok(Self { tree, stack: None });
-                }
-
-                let mut idx = n.keys.bsearch(&key);
-                if idx < 0 {
-                    idx = 0;
-                }
+            let Some(frame) = spine.frames.last_mut() else {
+                return Ok(false);
+            };
+
+            if forward && frame.idx + 1 < frame.nr_entries {
+                frame.idx += 1;
+            } else if !forward && frame.idx > 0 {
+                frame.idx -= 1;
+            } else {
+                spine.frames.pop();
+                continue;
+            }
 
-                stack.push(Frame {
-                    loc,
-                    index: idx as usize,
+            let node: INodeR = tm.read(frame.n_ptr)?;
+            let mut n_ptr = node.get_value(frame.idx);
+            drop(node);
+
+            // Take the leftmost, or rightmost, path down to a leaf.
+            while tm.is_internal(n_ptr)? {
+                let node: INodeR = tm.read(n_ptr)?;
+                let nr_entries = node.nr_entries();
+                let idx = if forward { 0 } else { nr_entries - 1 };
+                spine.frames.push(Frame {
+                    n_ptr,
+                    idx,
                     nr_entries,
                 });
-
-                return Ok(Self {
-                    tree,
-                    stack: Some(stack),
-                });
+                n_ptr = node.get_value(idx);
             }
 
-            let n = tree.read_node::<MetadataBlock>(loc)?;
-            let nr_entries = n.nr_entries.get() as usize;
+            let node: LNodeR = tm.read(n_ptr)?;
+            let (keys, values) = node.get_entries(0, node.nr_entries());
+            spine.idx = if forward { 0 } else { keys.len() as isize - 1 };
+            spine.keys = keys;
+            spine.values = values;
 
-            let mut idx = n.keys.bsearch(&key);
-            if idx < 0 {
-                idx = 0;
+            if !spine.keys.is_empty() {
+                return Ok(true);
             }
-
-            // we cannot have an internal node without entries
-            stack.push(Frame {
-                loc,
-                index: idx as usize,
-                nr_entries,
-            });
-
-            loc = n.values.get(idx as usize);
         }
     }
 
-    /// Returns (key, value) for the current position.  Returns None
-    /// if the cursor has run out of values.
-    pub fn get(&self) -> Result<Option<(u32, V)>> {
-        match &self.stack {
-            None => Ok(None),
-            Some(stack) => {
-                let frame = stack.last().unwrap();
-
-                // FIXME: cache nodes in frame
-                let n = self.tree.read_node::<V>(frame.loc)?;
-                let k = n.keys.get(frame.index);
-                let v = n.values.get(frame.index);
-                Ok(Some((k, v)))
-            }
+    // Returns the next unclipped entry from one end of the cursor.
+    fn step(&self, spine: &mut Spine<V>, forward: bool) -> Result<Option<(Key, V)>> {
+        let in_leaf = spine.idx >= 0 && (spine.idx as usize) < spine.keys.len();
+        if !in_leaf && !self.next_leaf(spine, forward)? {
+            return Ok(None);
         }
+
+        let idx = spine.idx as usize;
+        spine.idx += if forward { 1 } else { -1 };
+        Ok(Some((spine.keys[idx], spine.values[idx])))
     }
 
-    // Move cursor to the next entry.  Returns false if there are no more, and
-    // invalidates the cursor.
-    pub fn next_entry(&mut self) -> Result<bool> {
-        match &mut self.stack {
-            None => Ok(false),
-            Some(stack) => {
-                if !next_::<V, V>(self.tree, stack)? {
-                    self.stack = None;
-                    Ok(false)
-                } else {
-                    Ok(true)
-                }
+    fn clip(&self, k: Key, v: &V) -> Option<(Key, V)> {
+        let (k, v) = if k < self.key_begin {
+            v.select_geq(k, self.key_begin)?
+        } else {
+            (k, *v)
+        };
+        v.select_lt(k, self.key_end)
+    }
+
+    fn next_(&mut self) -> Result<Option<(Key, V)>> {
+        let mut spine = match self.front.take() {
+            Some(spine) => spine,
+            None => {
+                let mut frames = Vec::new();
+                let node = self.descend(self.tree.root, self.key_begin, &mut frames)?;
+                let idx = node.lower_bound(self.key_begin).max(0);
+                Self::load_leaf(node, frames, idx)
+            }
+        };
+
+        let mut result = None;
+        while let Some((k, v)) = self.step(&mut spine, true)? {
+            if k >= self.key_end || self.back_key.is_some_and(|b| k >= b) {
+                break;
+            }
+            self.front_key = Some(k);
+
+            // The first entry may end before the range starts.
+            if let Some(kv) = self.clip(k, &v) {
+                result = Some(kv);
+                break;
             }
         }
+
+        self.front = Some(spine);
+        Ok(result)
     }
 
-    // Move cursor to the previous entry.  Returns false if there are no more, and
-    // invalidates the cursor.
-    pub fn prev_entry(&mut self) -> Result<bool> {
-        match &mut self.stack {
-            None => Ok(false),
-            Some(stack) => {
-                if !prev_::<V, V>(self.tree, stack)? {
-                    self.stack = None;
-                    Ok(false)
-                } else {
-                    Ok(true)
+    fn next_back_(&mut self) -> Result<Option<(Key, V)>> {
+        let mut spine = match self.back.take() {
+            Some(spine) => spine,
+            None => {
+                // The last key that could be in the range.
+                let key = self.key_end - 1;
+                let mut frames = Vec::new();
+                let node = self.descend(self.tree.root, key, &mut frames)?;
+                let idx = node.lower_bound(key);
+                Self::load_leaf(node, frames, idx)
+            }
+        };
+
+        let mut result = None;
+        if self.back_key.is_none_or(|b| b > self.key_begin) {
+            while let Some((k, v)) = self.step(&mut spine, false)? {
+                if self.front_key.is_some_and(|f| k <= f) {
+                    break;
+                }
+                self.back_key = Some(k);
+
+                // Only the first entry below key_begin can overlap the
+                // range.
+                result = self.clip(k, &v);
+                if result.is_some() || k < self.key_begin {
+                    break;
                 }
             }
         }
+
+        self.back = Some(spine);
+        Ok(result)
     }
 
-    /// Returns true if the cursor is at the first entry.
-    pub fn is_first(&self) -> bool {
-        match &self.stack {
-            None => false,
-            Some(stack) => {
-                for frame in stack.iter() {
-                    if frame.index != 0 {
-                        return false;
-                    }
-                }
-                true
+    fn finish(&mut self, r: Result<Option<(Key, V)>>) -> Option<Result<(Key, V)>> {
+        match r {
+            Ok(Some(kv)) => Some(Ok(kv)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
             }
         }
     }
 }
-*/
-/*
-#[test]
-fn empty_cursor() -> Result<()> {
-    let mut fix = Fixture::new(16, 1024)?;
-    fix.commit()?;
-
-    let c = fix.tree.cursor(0)?;
-    ensure!(c.get()?.is_none());
-    Ok(())
-}
-
-#[test]
-fn populated_cursor() -> Result<()> {
-    let mut fix = Fixture::new(1024, 102400)?;
-    fix.commit()?;
-
-    // build a big btree
-    let count = 1000;
-    for i in 0..count {
-        fix.insert(i * 3, &mk_value(i * 3))?;
-    }
-    eprintln!("built tree");
 
-    let first_key = 601;
-    let mut c = fix.tree.cursor(first_key)?;
-
-    let mut expected_key = (first_key / 3) * 3;
-    loop {
-        let (k, _v) = c.get()?.unwrap();
-        ensure!(k == expected_key);
-        expected_key += 3;
+impl<
+        V: Serializable + Copy + RangeValue,
+        INodeR: NodeR<NodePtr, SharedProxy>,
+        INodeW: NodeW<NodePtr, ExclusiveProxy>,
+        LNodeR: NodeR<V, SharedProxy>,
+        LNodeW: NodeW<V, ExclusiveProxy>,
+    > Iterator for Cursor<'_, V, INodeR, INodeW, LNodeR, LNodeW>
+{
+    type Item = Result<(Key, V)>;
 
-        if !c.next_entry()? {
-            ensure!(expected_key == count * 3);
-            break;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
         }
+        let r = self.next_();
+        self.finish(r)
     }
-
-    Ok(())
 }
 
-#[test]
-fn cursor_prev() -> Result<()> {
-    let mut fix = Fixture::new(1024, 102400)?;
-    fix.commit()?;
-
-    // build a big btree
-    let count = 1000;
-    for i in 0..count {
-        fix.insert(i * 3, &mk_value(i * 3))?;
+impl<
+        V: Serializable + Copy + RangeValue,
+        INodeR: NodeR<NodePtr, SharedProxy>,
+        INodeW: NodeW<NodePtr, ExclusiveProxy>,
+        LNodeR: NodeR<V, SharedProxy>,
+        LNodeW: NodeW<V, ExclusiveProxy>,
+    > DoubleEndedIterator for Cursor<'_, V, INodeR, INodeW, LNodeR, LNodeW>
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let r = self.next_back_();
+        self.finish(r)
     }
-    eprintln!("built tree");
-
-    let first_key = 601;
-    let mut c = fix.tree.cursor(first_key)?;
-
-    let mut expected_key = (first_key / 3) * 3;
-    loop {
-        let (k, _v) = c.get()?.unwrap();
-        ensure!(k == expected_key);
-
-        c.prev_entry()?;
-        let (k, _v) = c.get()?.unwrap();
-        ensure!(k == expected_key - 3);
-        c.next_entry()?;
+}
 
-        expected_key += 3;
+//-------------------------------------------------------------------------
 
-        if !c.next_entry()? {
-            ensure!(expected_key == count * 3);
-            break;
-        }
+impl<
+        V: Serializable + Copy + RangeValue,
+        INodeR: NodeR<NodePtr, SharedProxy>,
+        INodeW: NodeW<NodePtr, ExclusiveProxy>,
+        LNodeR: NodeR<V, SharedProxy>,
+        LNodeW: NodeW<V, ExclusiveProxy>,
+    > BTree<V, INodeR, INodeW, LNodeR, LNodeW>
+{
+    /// Returns a cursor over the entries that overlap [key_begin, key_end).
+    /// Nothing is read until the cursor is advanced.
+    pub fn cursor(
+        &self,
+        key_begin: Key,
+        key_end: Key,
+    ) -> Cursor<'_, V, INodeR, INodeW, LNodeR, LNodeW> {
+        Cursor::new(self, key_begin, key_end)
     }
-
-    Ok(())
 }
-*/
+
+//-------------------------------------------------------------------------
@@ -1,10 +1,11 @@
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use std::slice;
 
 use crate::block_cache::*;
 use crate::btree::node::*;
-use crate::btree::nodes::journal::*;
+use crate::btree::range_value::RangeValue;
 use crate::btree::transaction_manager::*;
+use crate::journal::batch::BatchContext;
 use crate::packed_array::*;
 
 use crate::btree::BTree;
@@ -19,8 +20,16 @@ impl<
         LNodeW: NodeW<V, ExclusiveProxy>,
     > BTree<V, INodeR, INodeW, LNodeR, LNodeW>
 {
-    fn insert_into_internal(&mut self, n_ptr: NodePtr, key: Key, value: &V) -> Result<NodeResult> {
-        let mut node = self.tm.shadow::<NodePtr, INodeW>(n_ptr, 0)?;
+    fn insert_into_internal(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+        value: &V,
+    ) -> Result<NodeResult> {
+        let mut node =
+            self.tm
+                .shadow::<NodePtr, INodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
 
         let mut idx = node.lower_bound(key);
         if idx < 0 {
@@ -33,65 +42,321 @@ impl<
 
         let idx = idx as usize;
         let child_loc = node.get_value(idx);
-        let res = self.insert_recursive(child_loc, key, value)?;
+        let res = self.insert_recursive(ctx, child_loc, key, value)?;
         self.node_insert_result(&mut node, idx, &res)
     }
 
-    fn insert_into_leaf(&mut self, n_ptr: NodePtr, key: Key, value: &V) -> Result<NodeResult> {
-        let mut node = self.tm.shadow::<V, LNodeW>(n_ptr, 0)?;
+    fn insert_into_leaf(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+        value: &V,
+    ) -> Result<NodeResult> {
+        let mut node =
+            self.tm
+                .shadow::<V, LNodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
         let idx = node.lower_bound(key);
 
         if idx < 0 {
-            ensure_space(self.tm.as_ref(), &mut node, idx as usize, |node, _idx| {
-                node.prepend(slice::from_ref(&key), slice::from_ref(value))
-            })
+            // Pass idx 0, so if the node splits the key still goes in
+            // the left hand node.
+            ensure_space(
+                self.tm.as_ref(),
+                self.local_alloc(),
+                self.snap_time,
+                &mut node,
+                0,
+                |node, _idx| node.prepend(slice::from_ref(&key), slice::from_ref(value)),
+            )
         } else if idx as usize >= node.nr_entries() {
-            ensure_space(self.tm.as_ref(), &mut node, idx as usize, |node, _idx| {
-                node.append(slice::from_ref(&key), slice::from_ref(value))
-            })
+            ensure_space(
+                self.tm.as_ref(),
+                self.local_alloc(),
+                self.snap_time,
+                &mut node,
+                idx as usize,
+                |node, _idx| node.append(slice::from_ref(&key), slice::from_ref(value)),
+            )
         } else if node.get_key(idx as usize) == key {
             // overwrite
-            ensure_space(self.tm.as_ref(), &mut node, idx as usize, |node, idx| {
-                node.overwrite(idx, key, value)
-            })
+            ensure_space(
+                self.tm.as_ref(),
+                self.local_alloc(),
+                self.snap_time,
+                &mut node,
+                idx as usize,
+                |node, idx| node.overwrite(idx, key, value),
+            )
         } else {
-            ensure_space(self.tm.as_ref(), &mut node, idx as usize, |node, idx| {
-                node.insert(idx + 1, key, value)
-            })
+            ensure_space(
+                self.tm.as_ref(),
+                self.local_alloc(),
+                self.snap_time,
+                &mut node,
+                idx as usize,
+                |node, idx| node.insert(idx + 1, key, value),
+            )
         }
     }
 
-    fn insert_recursive(&mut self, n_ptr: NodePtr, key: Key, value: &V) -> Result<NodeResult> {
+    fn insert_recursive(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+        value: &V,
+    ) -> Result<NodeResult> {
         if self.tm.is_internal(n_ptr)? {
-            self.insert_into_internal(n_ptr, key, value)
+            self.insert_into_internal(ctx, n_ptr, key, value)
         } else {
-            self.insert_into_leaf(n_ptr, key, value)
+            self.insert_into_leaf(ctx, n_ptr, key, value)
         }
     }
 
     // Returns the new root
-    pub fn insert_(&mut self, root: NodePtr, key: Key, value: &V) -> Result<NodePtr> {
+    pub fn insert_(
+        &mut self,
+        ctx: &BatchContext,
+        root: NodePtr,
+        key: Key,
+        value: &V,
+    ) -> Result<NodePtr> {
         use NodeResult::*;
 
-        match self.insert_recursive(root, key, value)? {
-            Single(NodeInfo { n_ptr, .. }) => Ok(n_ptr),
-            Pair(left, right) => {
-                let mut parent: JournalNode<INodeW, NodePtr, ExclusiveProxy> =
-                    self.tm.new_node(false)?;
-                parent.append(
-                    &[left.key_min.unwrap(), right.key_min.unwrap()],
-                    &[left.n_ptr, right.n_ptr],
-                );
-                Ok(parent.n_ptr())
+        let mut root = root;
+        loop {
+            match self.insert_recursive(ctx, root, key, value)? {
+                Single(NodeInfo { n_ptr, .. }) => return Ok(n_ptr),
+                Pair(left, right) => return self.grow_root(ctx, &left, &right),
+                Retry(left, right) => root = self.retry_root(ctx, &left, right.as_ref())?,
             }
         }
     }
 
     // FIXME: merge with insert_
-    pub fn insert(&mut self, key: Key, value: &V) -> Result<()> {
-        self.root = self.insert_(self.root, key, value)?;
+    pub fn insert(&mut self, ctx: &BatchContext, key: Key, value: &V) -> Result<()> {
+        self.root = self.insert_(ctx, self.root, key, value)?;
         Ok(())
     }
 }
 
 //-------------------------------------------------
+
+// Inserts entries at idx, either all of them or none.
+fn insert_entries<V: Serializable, N: NodeW<V, ExclusiveProxy>>(
+    node: &mut N,
+    idx: usize,
+    entries: &[(Key, V)],
+) -> NodeInsertOutcome {
+    use NodeInsertOutcome::*;
+
+    for (i, (k, v)) in entries.iter().enumerate() {
+        if let NoSpace = node.insert(idx + i, *k, v) {
+            // The inserts were logged, or fitted, so there's room to undo
+            // them.
+            if i > 0 {
+                node.erase(idx, idx + i);
+            }
+            return NoSpace;
+        }
+    }
+    Success
+}
+
+// Merges two entries if the first ends where the second begins.
+pub(crate) fn merge_entries<V: RangeValue>(lhs: &(Key, V), rhs: &(Key, V)) -> Option<(Key, V)> {
+    if lhs.0 + lhs.1.range_len() == rhs.0 {
+        lhs.1.merge(&rhs.1).map(|v| (lhs.0, v))
+    } else {
+        None
+    }
+}
+
+impl<
+        V: Serializable + Copy + RangeValue,
+        INodeR: NodeR<NodePtr, SharedProxy>,
+        INodeW: NodeW<NodePtr, ExclusiveProxy>,
+        LNodeR: NodeR<V, SharedProxy>,
+        LNodeW: NodeW<V, ExclusiveProxy>,
+    > BTree<V, INodeR, INodeW, LNodeR, LNodeW>
+{
+    fn insert_range_internal(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+        key_end: Key,
+        value: &V,
+    ) -> Result<NodeResult> {
+        use NodeResult::*;
+
+        let mut node =
+            self.tm
+                .shadow::<NodePtr, INodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
+
+        // The new entry goes in the child at idx.  Any later children that
+        // overlap the range are completely covered, apart from the last.
+        let idx = node.lower_bound(key).max(0) as usize;
+        let last = node.lower_bound(key_end - 1).max(0) as usize;
+        if last > idx {
+            let res = self.remove_lt_recurse(ctx, node.get_value(last), key_end)?;
+            if let r @ (Pair(..) | Retry(..)) = self.node_insert_result(&mut node, last, &res)? {
+                return Ok(r.retry());
+            }
+            if last > idx + 1 {
+                if let NodeInsertOutcome::NoSpace = node.erase(idx + 1, last) {
+                    return self.split(&mut node);
+                }
+            }
+            if let Some(k) = Self::shrunk_child(&res) {
+                self.rebalance_key(ctx, &mut node, k)?;
+            }
+        }
+
+        // Rebalancing may have moved the child.
+        let idx = node.lower_bound(key).max(0) as usize;
+        let res = self.insert_range_recurse(ctx, node.get_value(idx), key, key_end, value)?;
+        match (self.node_insert_result(&mut node, idx, &res)?, res) {
+            (
+                Single(_),
+                Single(NodeInfo {
+                    key_min: Some(k), ..
+                }),
+            ) => {
+                // Entries may have been replaced by fewer, merged ones.
+                self.rebalance_key(ctx, &mut node, k)?;
+                Ok(NodeResult::single(&node))
+            }
+            (r, _) => Ok(r),
+        }
+    }
+
+    fn insert_range_leaf(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+        key_end: Key,
+        value: &V,
+    ) -> Result<NodeResult> {
+        let mut node =
+            self.tm
+                .shadow::<V, LNodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
+        let nr_entries = node.nr_entries();
+        let entry = |idx: usize| (node.get_key(idx), node.get_value(idx));
+
+        // The entries from idx_b to idx_e overlap the range, and will be
+        // replaced by what's left of them either side of it, and the new
+        // entry.
+        let mut idx_b = node.lower_bound(key).max(0) as usize;
+        if idx_b < nr_entries {
+            let (k, v) = entry(idx_b);
+            if k < key && v.select_geq(k, key).is_none() {
+                idx_b += 1;
+            }
+        }
+        let mut idx_e = ((node.lower_bound(key_end - 1) + 1) as usize).max(idx_b);
+
+        let mut entries = Vec::with_capacity(3);
+        let mut before = None;
+        let mut after = None;
+        if idx_b < idx_e {
+            let (k, v) = entry(idx_b);
+            if k < key {
+                before = v.select_lt(k, key);
+            }
+            let (k, v) = entry(idx_e - 1);
+            after = v.select_geq(k, key_end);
+        }
+
+        // Merge with the neighbours, or what's left of the entries being
+        // overwritten.
+        let mut new = (key, *value);
+        match before {
+            Some(before) => match merge_entries(&before, &new) {
+                Some(merged) => new = merged,
+                None => entries.push(before),
+            },
+            None if idx_b > 0 => {
+                if let Some(merged) = merge_entries(&entry(idx_b - 1), &new) {
+                    new = merged;
+                    idx_b -= 1;
+                }
+            }
+            None => {}
+        }
+
+        match after {
+            Some(after) => match merge_entries(&new, &after) {
+                Some(merged) => entries.push(merged),
+                None => entries.extend([new, after]),
+            },
+            None if idx_e < nr_entries => match merge_entries(&new, &entry(idx_e)) {
+                Some(merged) => {
+                    entries.push(merged);
+                    idx_e += 1;
+                }
+                None => entries.push(new),
+            },
+            None => entries.push(new),
+        }
+
+        if idx_b < idx_e {
+            if let NodeInsertOutcome::NoSpace = node.erase(idx_b, idx_e) {
+                return self.split(&mut node);
+            }
+        }
+        ensure_space(
+            self.tm.as_ref(),
+            self.local_alloc(),
+            self.snap_time,
+            &mut node,
+            idx_b,
+            |node, idx| insert_entries(node, idx, &entries),
+        )
+    }
+
+    fn insert_range_recurse(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+        key_end: Key,
+        value: &V,
+    ) -> Result<NodeResult> {
+        if self.tm.is_internal(n_ptr)? {
+            self.insert_range_internal(ctx, n_ptr, key, key_end, value)
+        } else {
+            self.insert_range_leaf(ctx, n_ptr, key, key_end, value)
+        }
+    }
+
+    /// Inserts a range value, trimming or splitting any entries it
+    /// overlaps, in a single pass down the tree.  The new entry is merged
+    /// with its neighbours in the leaf if possible.
+    pub fn insert_range(&mut self, ctx: &BatchContext, key: Key, value: &V) -> Result<()> {
+        use NodeResult::*;
+
+        let key_end = key + value.range_len();
+        ensure!(key_end > key, "insert_range of an empty range at {}", key);
+
+        // A node that had to split may not have finished the insert, so we
+        // repeat it until it goes through without a Retry.
+        loop {
+            match self.insert_range_recurse(ctx, self.root, key, key_end, value)? {
+                Single(NodeInfo { n_ptr, .. }) => {
+                    self.root = self.shrink_root(ctx, n_ptr)?;
+                    return Ok(());
+                }
+                Pair(left, right) => {
+                    self.root = self.grow_root(ctx, &left, &right)?;
+                    return Ok(());
+                }
+                Retry(left, right) => self.root = self.retry_root(ctx, &left, right.as_ref())?,
+            }
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
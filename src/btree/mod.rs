@@ -1,7 +1,8 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{self, Read, Write};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use crate::allocators::metadata_alloc::MetadataAlloc;
 use crate::block_cache::MetadataBlock;
 use crate::btree::transaction_manager::*;
 use crate::packed_array::*;
@@ -41,6 +42,10 @@ pub struct BTree<V: Serializable + Copy, INodeR, INodeW, LNodeR, LNodeW> {
     root: NodePtr,
     snap_time: u32,
 
+    // Optional per-thin allocator for new nodes.  If this isn't set
+    // nodes come from the global metadata allocator.
+    metadata_alloc: Option<Arc<Mutex<MetadataAlloc>>>,
+
     phantom_v: std::marker::PhantomData<V>,
     phantom_inode_r: std::marker::PhantomData<INodeR>,
     phantom_inode_w: std::marker::PhantomData<INodeW>,
@@ -48,8 +53,11 @@ pub struct BTree<V: Serializable + Copy, INodeR, INodeW, LNodeR, LNodeW> {
     phantom_lnode_w: std::marker::PhantomData<LNodeW>,
 }
 
+mod bulk_load;
 mod check;
+pub mod compact;
 mod core;
+pub mod cursor;
 mod insert;
 mod lookup;
 pub mod node;
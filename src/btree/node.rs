@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
 
@@ -34,6 +34,10 @@ impl From<u16> for BTreeFlags {
 pub const NODE_SIZE: usize = 4096;
 pub const NODE_HEADER_SIZE: usize = 16;
 
+// Non-root nodes with less than this many bytes in use are merged with,
+// or take entries from, a sibling when entries are removed.
+pub const MIN_NODE_FILL: usize = NODE_SIZE / 4;
+
 // We have a standard node header that is the same for all
 // implementations.
 pub struct NodeHeader {
@@ -100,9 +104,15 @@ impl NodeInfo {
 // middle of an existing entry.  So, like for insert, we have a way of
 // returning more than one new block.  If a pair is returned then the
 // first one corresponds to the idx of the original block.
+//
+// A node with no space for a change may have to split without making it
+// (see BTree::split()).  This gives a Retry, holding the node, and its new
+// sibling if it has one.  Parents apply a Retry like a Single or Pair, but
+// pass it on as a Retry, so it reaches the root and the op is repeated.
 pub enum NodeResult {
     Single(NodeInfo),
     Pair(NodeInfo, NodeInfo),
+    Retry(NodeInfo, Option<NodeInfo>),
 }
 
 impl NodeResult {
@@ -113,6 +123,15 @@ impl NodeResult {
     pub fn pair<V: Serializable, Data: Readable, N: NodeR<V, Data>>(n1: &N, n2: &N) -> Self {
         NodeResult::Pair(NodeInfo::new(n1), NodeInfo::new(n2))
     }
+
+    // Marks a result as coming from an abandoned change.
+    pub fn retry(self) -> Self {
+        match self {
+            NodeResult::Single(n) => NodeResult::Retry(n, None),
+            NodeResult::Pair(n1, n2) => NodeResult::Retry(n1, Some(n2)),
+            r => r,
+        }
+    }
 }
 
 //-------------------------------------------------------------------------
@@ -133,6 +152,13 @@ pub trait NodeR<V: Serializable, Data: Readable>: Sized {
     fn get_entries(&self, b_idx: usize, e_idx: usize) -> (Vec<Key>, Vec<V>);
     fn get_flags(&self) -> BTreeFlags;
 
+    /// The nr of bytes in use, including the header.
+    fn used_space(&self) -> usize;
+
+    fn is_underfull(&self) -> bool {
+        self.used_space() < MIN_NODE_FILL
+    }
+
     fn is_internal(&self) -> bool {
         self.get_flags() == BTreeFlags::Internal
     }
@@ -148,6 +174,17 @@ pub enum NodeInsertOutcome {
     NoSpace,
 }
 
+impl NodeInsertOutcome {
+    /// For changes the node should always have room for, eg, erasing
+    /// entries from an internal node.
+    pub fn into_result(self, loc: MetadataBlock) -> Result<()> {
+        match self {
+            NodeInsertOutcome::Success => Ok(()),
+            NodeInsertOutcome::NoSpace => Err(anyhow!("no space to change node {}", loc)),
+        }
+    }
+}
+
 pub trait NodeW<V: Serializable, Data: Writeable>: NodeR<V, Data> {
     /// Initialises a fresh, empty node.
     fn init(loc: MetadataBlock, data: Data, is_leaf: bool) -> Result<()>;
@@ -156,26 +193,35 @@ pub trait NodeW<V: Serializable, Data: Writeable>: NodeR<V, Data> {
     fn insert(&mut self, idx: usize, k: Key, value: &V) -> NodeInsertOutcome;
     fn prepend(&mut self, keys: &[Key], values: &[V]) -> NodeInsertOutcome;
     fn append(&mut self, keys: &[Key], values: &[V]) -> NodeInsertOutcome;
-    fn erase(&mut self, b_idx: usize, e_idx: usize);
+
+    /// Erasing entries can need space too, eg, a LogNode may have to
+    /// compact its log.  If so the node is left untouched, and the caller
+    /// has to split it.
+    fn erase(&mut self, b_idx: usize, e_idx: usize) -> NodeInsertOutcome;
 
     // FIXME: inconsistent naming in the next two
-    fn shift_left(&mut self, count: usize) -> (Vec<Key>, Vec<V>) {
+    // These return None if the entries couldn't be erased.
+    fn shift_left(&mut self, count: usize) -> Option<(Vec<Key>, Vec<V>)> {
         let r = self.get_entries(0, count);
-        self.erase(0, count);
-        r
+        match self.erase(0, count) {
+            NodeInsertOutcome::Success => Some(r),
+            NodeInsertOutcome::NoSpace => None,
+        }
     }
 
-    fn remove_right(&mut self, count: usize) -> (Vec<Key>, Vec<V>) {
+    fn remove_right(&mut self, count: usize) -> Option<(Vec<Key>, Vec<V>)> {
         let e_idx = self.nr_entries();
         let b_idx = e_idx - count;
         let r = self.get_entries(b_idx, e_idx);
-        self.erase(b_idx, e_idx);
-        r
+        match self.erase(b_idx, e_idx) {
+            NodeInsertOutcome::Success => Some(r),
+            NodeInsertOutcome::NoSpace => None,
+        }
     }
 
     // FIXME: rename to remove()
-    fn remove_at(&mut self, idx: usize) {
-        self.erase(idx, idx + 1);
+    fn remove_at(&mut self, idx: usize) -> NodeInsertOutcome {
+        self.erase(idx, idx + 1)
     }
 }
 
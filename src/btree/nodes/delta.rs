@@ -0,0 +1,884 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::marker::PhantomData;
+
+use crate::block_cache::*;
+use crate::btree::node::*;
+use crate::byte_types::*;
+use crate::packed_array::*;
+
+//-------------------------------------------------------------------------
+
+// Keys in a node are sorted, and usually close together, so rather than
+// storing each as a full u64 we store a base key, and deltas from it that
+// are 2, 4 or 8 bytes wide.  The width is chosen per node:
+//
+//    header | base u64 | width u16 | value_size u16 | pad u32 | deltas | values
+//
+// The values start after room for as many deltas as the node can hold at
+// this width, so changing the width repacks the whole node.  Recording
+// the value size means the node can be replayed without knowing the
+// value type.
+//
+// Unlike a SimpleNode, even an overwrite can fail, if the new key needs
+// wider deltas that don't fit.  So trees only use these for leaves, where
+// every change can split the node.
+
+pub const DELTA_NODE_KIND: u16 = 3;
+
+const FLAGS_OFFSET: usize = 8;
+const NR_ENTRIES_OFFSET: usize = 12;
+const BASE_OFFSET: usize = NODE_HEADER_SIZE;
+const WIDTH_OFFSET: usize = BASE_OFFSET + 8;
+const VALUE_SIZE_OFFSET: usize = WIDTH_OFFSET + 2;
+const DELTA_HEADER_SIZE: usize = VALUE_SIZE_OFFSET + 6;
+
+const WIDTHS: [usize; 3] = [2, 4, 8];
+
+fn max_delta(width: usize) -> u64 {
+    if width >= 8 {
+        u64::MAX
+    } else {
+        (1 << (width * 8)) - 1
+    }
+}
+
+// The narrowest (base, width) that can hold all the keys.
+fn choose_encoding(keys: &[Key]) -> (Key, usize) {
+    let (Some(min), Some(max)) = (keys.iter().min(), keys.iter().max()) else {
+        return (0, WIDTHS[0]);
+    };
+    let width = WIDTHS
+        .iter()
+        .cloned()
+        .find(|w| max - min <= max_delta(*w))
+        .unwrap();
+    (*min, width)
+}
+
+// The entries of the node as keys, and values concatenated.
+type RawEntries = (Vec<Key>, Vec<u8>);
+
+// Everything that doesn't depend on the value type, shared by DeltaNode
+// and DeltaNodeReplay.
+struct RawDeltaNode<Data> {
+    loc: u32,
+    data: Data,
+    value_size: usize,
+}
+
+impl<Data: Readable> RawDeltaNode<Data> {
+    fn open(loc: u32, data: Data) -> Result<Self> {
+        let hdr = read_node_header(&mut data.r())?;
+        if hdr.kind != DELTA_NODE_KIND {
+            return Err(anyhow!(
+                "node {} is of kind {}, not a delta node",
+                loc,
+                hdr.kind
+            ));
+        }
+
+        let mut r = &data.r()[WIDTH_OFFSET..];
+        let width = r.read_u16::<LittleEndian>()? as usize;
+        let value_size = r.read_u16::<LittleEndian>()? as usize;
+        if !WIDTHS.contains(&width) {
+            return Err(anyhow!("delta node {} has bad width {}", loc, width));
+        }
+
+        let node = Self {
+            loc,
+            data,
+            value_size,
+        };
+        if hdr.nr_entries as usize > node.capacity(width) {
+            return Err(anyhow!(
+                "delta node {} has too many entries ({})",
+                loc,
+                hdr.nr_entries
+            ));
+        }
+        Ok(node)
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        let mut r = &self.data.r()[offset..];
+        r.read_u16::<LittleEndian>().unwrap()
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        let mut r = &self.data.r()[offset..];
+        r.read_u32::<LittleEndian>().unwrap()
+    }
+
+    fn nr_entries(&self) -> usize {
+        self.read_u32(NR_ENTRIES_OFFSET) as usize
+    }
+
+    fn base(&self) -> Key {
+        let mut r = &self.data.r()[BASE_OFFSET..];
+        r.read_u64::<LittleEndian>().unwrap()
+    }
+
+    fn width(&self) -> usize {
+        self.read_u16(WIDTH_OFFSET) as usize
+    }
+
+    // This uses the size of the data, rather than NODE_SIZE, so the node
+    // can live inside another (eg, a LogNode).
+    fn capacity(&self, width: usize) -> usize {
+        (self.data.r().len() - DELTA_HEADER_SIZE) / (width + self.value_size)
+    }
+
+    fn key_offset(&self, width: usize, idx: usize) -> usize {
+        DELTA_HEADER_SIZE + idx * width
+    }
+
+    fn value_offset(&self, width: usize, idx: usize) -> usize {
+        self.key_offset(width, self.capacity(width)) + idx * self.value_size
+    }
+
+    fn key(&self, idx: usize) -> Key {
+        let width = self.width();
+        let b = self.key_offset(width, idx);
+        let mut bytes = [0; 8];
+        bytes[..width].copy_from_slice(&self.data.r()[b..b + width]);
+        self.base() + u64::from_le_bytes(bytes)
+    }
+
+    fn value(&self, idx: usize) -> &[u8] {
+        let b = self.value_offset(self.width(), idx);
+        &self.data.r()[b..b + self.value_size]
+    }
+
+    fn lower_bound(&self, key: Key) -> isize {
+        let mut lo = -1;
+        let mut hi = self.nr_entries() as isize;
+        while (hi - lo) > 1 {
+            let mid = lo + ((hi - lo) / 2);
+            let mid_key = self.key(mid as usize);
+
+            if mid_key == key {
+                return mid;
+            }
+
+            if mid_key < key {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        lo
+    }
+
+    fn entries(&self, b: usize, e: usize) -> RawEntries {
+        let keys = (b..e).map(|i| self.key(i)).collect();
+        let width = self.width();
+        let values =
+            self.data.r()[self.value_offset(width, b)..self.value_offset(width, e)].to_vec();
+        (keys, values)
+    }
+
+    fn can_encode(&self, keys: &[Key]) -> bool {
+        let (base, width) = (self.base(), self.width());
+        keys.iter()
+            .all(|k| *k >= base && k - base <= max_delta(width))
+    }
+
+    fn has_space(&self, count: usize) -> bool {
+        self.nr_entries() + count <= self.capacity(self.width())
+    }
+}
+
+impl<Data: Writeable> RawDeltaNode<Data> {
+    fn init(mut data: Data, is_leaf: bool, value_size: usize) -> Result<()> {
+        let mut w = std::io::Cursor::new(data.rw());
+        let hdr = NodeHeader {
+            seq_nr: 0,
+            snap_time: 0,
+            flags: if is_leaf {
+                BTreeFlags::Leaf
+            } else {
+                BTreeFlags::Internal
+            },
+            kind: DELTA_NODE_KIND,
+            nr_entries: 0,
+        };
+        write_node_header(&mut w, &hdr)?;
+        w.write_u64::<LittleEndian>(0)?;
+        w.write_u16::<LittleEndian>(WIDTHS[0] as u16)?;
+        w.write_u16::<LittleEndian>(value_size as u16)?;
+        Ok(())
+    }
+
+    fn set_nr_entries(&mut self, n: usize) {
+        let mut w = &mut self.data.rw()[NR_ENTRIES_OFFSET..];
+        w.write_u32::<LittleEndian>(n as u32).unwrap();
+    }
+
+    fn set_entry(&mut self, idx: usize, key: Key, value: &[u8]) {
+        let width = self.width();
+        let delta = (key - self.base()).to_le_bytes();
+        let k_off = self.key_offset(width, idx);
+        let v_off = self.value_offset(width, idx);
+        let data = self.data.rw();
+        data[k_off..k_off + width].copy_from_slice(&delta[..width]);
+        data[v_off..v_off + value.len()].copy_from_slice(value);
+    }
+
+    fn set_entries(&mut self, idx: usize, keys: &[Key], values: &[u8]) {
+        let vs = self.value_size;
+        for (i, k) in keys.iter().enumerate() {
+            self.set_entry(idx + i, *k, &values[i * vs..(i + 1) * vs]);
+        }
+    }
+
+    // Moves entries [b, nr_entries) to start at idx dest.
+    fn move_entries(&mut self, b: usize, dest: usize) {
+        let width = self.width();
+        let nr_entries = self.nr_entries();
+        let (kb, ke, kd) = (
+            self.key_offset(width, b),
+            self.key_offset(width, nr_entries),
+            self.key_offset(width, dest),
+        );
+        let (vb, ve, vd) = (
+            self.value_offset(width, b),
+            self.value_offset(width, nr_entries),
+            self.value_offset(width, dest),
+        );
+
+        let data = self.data.rw();
+        data.copy_within(kb..ke, kd);
+        data.copy_within(vb..ve, vd);
+    }
+
+    // Rewrites the node with the narrowest encoding for these entries.
+    // Leaves the node untouched if they don't fit.
+    fn repack(&mut self, keys: &[Key], values: &[u8]) -> NodeInsertOutcome {
+        let (base, width) = choose_encoding(keys);
+        if keys.len() > self.capacity(width) {
+            return NodeInsertOutcome::NoSpace;
+        }
+
+        {
+            let data = self.data.rw();
+            data[DELTA_HEADER_SIZE..].fill(0);
+            let mut w = &mut data[BASE_OFFSET..];
+            w.write_u64::<LittleEndian>(base).unwrap();
+            w.write_u16::<LittleEndian>(width as u16).unwrap();
+        }
+        self.set_nr_entries(keys.len());
+        self.set_entries(0, keys, values);
+        NodeInsertOutcome::Success
+    }
+
+    // Repacks with entries [b, e) replaced.
+    fn splice(&mut self, b: usize, e: usize, keys: &[Key], values: &[u8]) -> NodeInsertOutcome {
+        let nr_entries = self.nr_entries();
+        let (mut all_keys, mut all_values) = self.entries(0, nr_entries);
+        all_keys.splice(b..e, keys.iter().cloned());
+        all_values.splice(
+            b * self.value_size..e * self.value_size,
+            values.iter().cloned(),
+        );
+        self.repack(&all_keys, &all_values)
+    }
+
+    fn overwrite(&mut self, idx: usize, key: Key, value: &[u8]) -> NodeInsertOutcome {
+        if self.can_encode(&[key]) {
+            self.set_entry(idx, key, value);
+            NodeInsertOutcome::Success
+        } else {
+            self.splice(idx, idx + 1, &[key], value)
+        }
+    }
+
+    fn insert(&mut self, idx: usize, key: Key, value: &[u8]) -> NodeInsertOutcome {
+        if self.can_encode(&[key]) && self.has_space(1) {
+            self.move_entries(idx, idx + 1);
+            self.set_nr_entries(self.nr_entries() + 1);
+            self.set_entry(idx, key, value);
+            NodeInsertOutcome::Success
+        } else {
+            self.splice(idx, idx, &[key], value)
+        }
+    }
+
+    fn prepend(&mut self, keys: &[Key], values: &[u8]) -> NodeInsertOutcome {
+        if self.can_encode(keys) && self.has_space(keys.len()) {
+            self.move_entries(0, keys.len());
+            self.set_nr_entries(self.nr_entries() + keys.len());
+            self.set_entries(0, keys, values);
+            NodeInsertOutcome::Success
+        } else {
+            self.splice(0, 0, keys, values)
+        }
+    }
+
+    fn append(&mut self, keys: &[Key], values: &[u8]) -> NodeInsertOutcome {
+        let nr_entries = self.nr_entries();
+        if self.can_encode(keys) && self.has_space(keys.len()) {
+            self.set_nr_entries(nr_entries + keys.len());
+            self.set_entries(nr_entries, keys, values);
+            NodeInsertOutcome::Success
+        } else {
+            self.splice(nr_entries, nr_entries, keys, values)
+        }
+    }
+
+    fn erase(&mut self, b: usize, e: usize) {
+        self.move_entries(e, b);
+        self.set_nr_entries(self.nr_entries() - (e - b));
+
+        // Removing the outliers may let us go back to narrower deltas.
+        // Narrower always holds more entries, so this can't fail.
+        let nr_entries = self.nr_entries();
+        if self.width() > WIDTHS[0] {
+            let ends: Vec<Key> = if nr_entries == 0 {
+                Vec::new()
+            } else {
+                vec![self.key(0), self.key(nr_entries - 1)]
+            };
+            if choose_encoding(&ends).1 < self.width() {
+                let (keys, values) = self.entries(0, nr_entries);
+                self.repack(&keys, &values);
+            }
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+
+/// A node that stores its keys as deltas from a base key, so holds many
+/// more entries than a `SimpleNode` when the keys are close together.
+pub struct DeltaNode<V, Data> {
+    raw: RawDeltaNode<Data>,
+    phantom: PhantomData<V>,
+}
+
+impl<V: Serializable, Data: Readable> DeltaNode<V, Data> {
+    /// The nr of bytes per key currently used.
+    pub fn key_width(&self) -> usize {
+        self.raw.width()
+    }
+
+    /// The most entries the node can hold with its current key width.
+    pub fn max_entries(&self) -> usize {
+        self.raw.capacity(self.raw.width())
+    }
+
+    fn unpack_values(&self, values: &[u8], nr: usize) -> Vec<V> {
+        let mut r = values;
+        (0..nr).map(|_| V::unpack(&mut r).unwrap()).collect()
+    }
+}
+
+fn pack_value<V: Serializable>(value: &V) -> Vec<u8> {
+    let mut w = Vec::with_capacity(V::packed_len());
+    value.pack(&mut w).unwrap();
+    w
+}
+
+fn pack_values<V: Serializable>(values: &[V]) -> Vec<u8> {
+    let mut w = Vec::with_capacity(values.len() * V::packed_len());
+    for v in values {
+        v.pack(&mut w).unwrap();
+    }
+    w
+}
+
+impl<V: Serializable, Data: Readable> NodeR<V, Data> for DeltaNode<V, Data> {
+    fn open(loc: MetadataBlock, data: Data) -> Result<Self> {
+        let raw = RawDeltaNode::open(loc, data)?;
+        if raw.value_size != V::packed_len() {
+            return Err(anyhow!(
+                "delta node {} holds {} byte values, expected {}",
+                loc,
+                raw.value_size,
+                V::packed_len()
+            ));
+        }
+        Ok(Self {
+            raw,
+            phantom: PhantomData,
+        })
+    }
+
+    fn n_ptr(&self) -> NodePtr {
+        NodePtr {
+            loc: self.raw.loc,
+            seq_nr: self.raw.read_u32(0),
+        }
+    }
+
+    fn nr_entries(&self) -> usize {
+        self.raw.nr_entries()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nr_entries() == 0
+    }
+
+    fn get_key(&self, idx: usize) -> Key {
+        assert!(idx < self.nr_entries());
+        self.raw.key(idx)
+    }
+
+    fn get_key_safe(&self, idx: usize) -> Option<Key> {
+        if idx < self.nr_entries() {
+            Some(self.raw.key(idx))
+        } else {
+            None
+        }
+    }
+
+    fn get_value(&self, idx: usize) -> V {
+        assert!(idx < self.nr_entries());
+        V::unpack(&mut self.raw.value(idx)).unwrap()
+    }
+
+    fn get_value_safe(&self, idx: usize) -> Option<V> {
+        if idx < self.nr_entries() {
+            Some(self.get_value(idx))
+        } else {
+            None
+        }
+    }
+
+    fn lower_bound(&self, key: Key) -> isize {
+        self.raw.lower_bound(key)
+    }
+
+    fn get_entries(&self, b_idx: usize, e_idx: usize) -> (Vec<Key>, Vec<V>) {
+        let (keys, values) = self.raw.entries(b_idx, e_idx);
+        let values = self.unpack_values(&values, keys.len());
+        (keys, values)
+    }
+
+    fn get_flags(&self) -> BTreeFlags {
+        BTreeFlags::from(self.raw.read_u16(FLAGS_OFFSET))
+    }
+
+    fn used_space(&self) -> usize {
+        DELTA_HEADER_SIZE + self.nr_entries() * (self.raw.width() + self.raw.value_size)
+    }
+}
+
+impl<V: Serializable, Data: Writeable> NodeW<V, Data> for DeltaNode<V, Data> {
+    fn init(_loc: MetadataBlock, data: Data, is_leaf: bool) -> Result<()> {
+        RawDeltaNode::init(data, is_leaf, V::packed_len())
+    }
+
+    fn overwrite(&mut self, idx: usize, k: Key, value: &V) -> NodeInsertOutcome {
+        self.raw.overwrite(idx, k, &pack_value(value))
+    }
+
+    fn insert(&mut self, idx: usize, k: Key, value: &V) -> NodeInsertOutcome {
+        self.raw.insert(idx, k, &pack_value(value))
+    }
+
+    fn prepend(&mut self, keys: &[Key], values: &[V]) -> NodeInsertOutcome {
+        self.raw.prepend(keys, &pack_values(values))
+    }
+
+    fn append(&mut self, keys: &[Key], values: &[V]) -> NodeInsertOutcome {
+        self.raw.append(keys, &pack_values(values))
+    }
+
+    fn erase(&mut self, b_idx: usize, e_idx: usize) -> NodeInsertOutcome {
+        self.raw.erase(b_idx, e_idx);
+        NodeInsertOutcome::Success
+    }
+}
+
+//-------------------------------------------------------------------------
+
+/// Applies journalled ops to a DeltaNode during replay.  The value size
+/// is read from the node.
+pub struct DeltaNodeReplay<Data> {
+    raw: RawDeltaNode<Data>,
+}
+
+impl<Data: Writeable> DeltaNodeReplay<Data> {
+    pub fn new(loc: u32, data: Data) -> Result<Self> {
+        Ok(Self {
+            raw: RawDeltaNode::open(loc, data)?,
+        })
+    }
+
+    fn check_values(&self, values: &[Vec<u8>]) -> Result<Vec<u8>> {
+        for v in values {
+            if v.len() != self.raw.value_size {
+                return Err(anyhow!(
+                    "node {}: value is {} bytes, expected {}",
+                    self.raw.loc,
+                    v.len(),
+                    self.raw.value_size
+                ));
+            }
+        }
+        Ok(values.concat())
+    }
+
+    fn check(&self, outcome: NodeInsertOutcome) -> Result<()> {
+        match outcome {
+            NodeInsertOutcome::Success => Ok(()),
+            NodeInsertOutcome::NoSpace => Err(anyhow!("node {}: no space", self.raw.loc)),
+        }
+    }
+}
+
+impl<Data: Writeable> ReplayableNode for DeltaNodeReplay<Data> {
+    fn get_kind(&self) -> u16 {
+        DELTA_NODE_KIND
+    }
+
+    fn get_loc(&self) -> u32 {
+        self.raw.loc
+    }
+
+    fn get_seq_nr(&self) -> u32 {
+        self.raw.read_u32(0)
+    }
+
+    fn apply_overwrite(&mut self, idx: u32, key: Key, value: &[u8]) -> Result<()> {
+        if idx as usize >= self.raw.nr_entries() {
+            return Err(anyhow!("node {}: overwrite of idx {}", self.raw.loc, idx));
+        }
+        let value = self.check_values(&[value.to_vec()])?;
+        let r = self.raw.overwrite(idx as usize, key, &value);
+        self.check(r)
+    }
+
+    fn apply_insert(&mut self, idx: u32, key: Key, value: &[u8]) -> Result<()> {
+        if idx as usize > self.raw.nr_entries() {
+            return Err(anyhow!("node {}: insert at idx {}", self.raw.loc, idx));
+        }
+        let value = self.check_values(&[value.to_vec()])?;
+        let r = self.raw.insert(idx as usize, key, &value);
+        self.check(r)
+    }
+
+    fn apply_prepend(&mut self, keys: &[Key], values: &[Vec<u8>]) -> Result<()> {
+        let values = self.check_values(values)?;
+        let r = self.raw.prepend(keys, &values);
+        self.check(r)
+    }
+
+    fn apply_append(&mut self, keys: &[Key], values: &[Vec<u8>]) -> Result<()> {
+        let values = self.check_values(values)?;
+        let r = self.raw.append(keys, &values);
+        self.check(r)
+    }
+
+    fn apply_erase(&mut self, idx_b: u32, idx_e: u32) -> Result<()> {
+        let (b, e) = (idx_b as usize, idx_e as usize);
+        let nr_entries = self.raw.nr_entries();
+        if b > e || e > nr_entries {
+            return Err(anyhow!(
+                "node {}: erase of [{}, {}) from {} entries",
+                self.raw.loc,
+                b,
+                e,
+                nr_entries
+            ));
+        }
+
+        self.raw.erase(b, e);
+        Ok(())
+    }
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use rand::Rng;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+    use thinp::io_engine::*;
+
+    use crate::allocators::*;
+    use crate::btree::transaction_manager::*;
+    use crate::core::CoreIoEngine;
+    use crate::journal::batch::BatchContext;
+    use crate::journal::Journal;
+    use crate::thin::mapping::{DeltaMappingTree, Mapping};
+
+    fn mk_cache() -> Result<Arc<BlockCache>> {
+        let engine: Arc<dyn IoEngine> = Arc::new(CoreIoEngine::new(16));
+        Ok(Arc::new(BlockCache::new(engine, 16)?))
+    }
+
+    fn mk_node<V: Serializable>(
+        cache: &Arc<BlockCache>,
+        loc: u32,
+    ) -> Result<DeltaNode<V, ExclusiveProxy>> {
+        let data = cache.zero_lock(loc)?;
+        DeltaNode::<V, ExclusiveProxy>::init(loc, data.clone(), true)?;
+        DeltaNode::open(loc, data)
+    }
+
+    fn mk_mapping(b: u64) -> Mapping {
+        Mapping {
+            b,
+            e: b + 1,
+            snap_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_choose_encoding() {
+        assert_eq!(choose_encoding(&[]), (0, 2));
+        assert_eq!(choose_encoding(&[100, 100 + 0xffff]), (100, 2));
+        assert_eq!(choose_encoding(&[100, 100 + 0x10000]), (100, 4));
+        assert_eq!(choose_encoding(&[5, 1 << 40]), (5, 8));
+        assert_eq!(choose_encoding(&[0, u64::MAX]), (0, 8));
+    }
+
+    #[test]
+    fn test_capacity() -> Result<()> {
+        let cache = mk_cache()?;
+        let mut node = mk_node::<Mapping>(&cache, 0)?;
+
+        let mut k = 1 << 40;
+        while let NodeInsertOutcome::Success = node.append(&[k], &[mk_mapping(k)]) {
+            k += 16;
+        }
+
+        // A SimpleNode<Mapping> holds 145.
+        assert_eq!(node.key_width(), 2);
+        assert_eq!(node.nr_entries(), node.max_entries());
+        assert!(
+            node.nr_entries() > 180,
+            "only {} entries",
+            node.nr_entries()
+        );
+
+        // A key far away forces wider deltas, which don't fit.
+        let nr = node.nr_entries();
+        assert!(matches!(
+            node.append(&[k << 8], &[mk_mapping(0)]),
+            NodeInsertOutcome::NoSpace
+        ));
+        assert_eq!(node.key_width(), 2);
+        assert_eq!(node.nr_entries(), nr);
+
+        // Making room lets it widen ...
+        node.erase(0, nr / 2);
+        node.append(&[k << 8], &[mk_mapping(0)]);
+        assert_eq!(node.key_width(), 8);
+
+        // ... and removing the outlier narrows it again.
+        node.remove_right(1);
+        assert_eq!(node.key_width(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwrite_no_space() -> Result<()> {
+        let cache = mk_cache()?;
+        let mut node = mk_node::<Mapping>(&cache, 0)?;
+
+        let mut k = 1 << 40;
+        while let NodeInsertOutcome::Success = node.append(&[k], &[mk_mapping(k)]) {
+            k += 16;
+        }
+        assert_eq!(node.key_width(), 2);
+
+        // Overwriting the first key with one far below the others needs
+        // wider deltas, which don't fit.
+        let nr = node.nr_entries();
+        let (keys, values) = node.get_entries(0, nr);
+        assert!(matches!(
+            node.overwrite(0, 5, &mk_mapping(5)),
+            NodeInsertOutcome::NoSpace
+        ));
+        assert_eq!(node.key_width(), 2);
+        assert_eq!(node.get_entries(0, nr), (keys, values));
+
+        node.erase(nr / 2, nr);
+        assert!(matches!(
+            node.overwrite(0, 5, &mk_mapping(5)),
+            NodeInsertOutcome::Success
+        ));
+        assert_eq!(node.key_width(), 8);
+        assert_eq!(node.get_key(0), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_ops() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let cache = mk_cache()?;
+        let mut node = mk_node::<u64>(&cache, 0)?;
+        let mut model: Vec<(Key, u64)> = Vec::new();
+
+        // Mostly close together, with the odd outlier to force wider keys.
+        let mut rand_key = |rng: &mut rand::rngs::ThreadRng| match rng.gen_range(0..20) {
+            0 => rng.gen::<u64>(),
+            1 => rng.gen_range(0..1 << 24),
+            _ => 1_000_000 + rng.gen_range(0..20_000),
+        };
+
+        for _ in 0..5000 {
+            let nr = model.len();
+            match rng.gen_range(0..6) {
+                0 | 1 => {
+                    let k = rand_key(&mut rng);
+                    let v = rng.gen();
+                    let idx = (node.lower_bound(k) + 1) as usize;
+                    if model.iter().any(|(mk, _)| *mk == k) {
+                        continue;
+                    }
+                    if let NodeInsertOutcome::Success = node.insert(idx, k, &v) {
+                        model.insert(idx, (k, v));
+                    }
+                }
+                2 if nr > 0 => {
+                    let idx = rng.gen_range(0..nr);
+                    let (k, _) = model[idx];
+                    let v = rng.gen();
+                    if let NodeInsertOutcome::Success = node.overwrite(idx, k, &v) {
+                        model[idx] = (k, v);
+                    }
+                }
+                3 if nr > 0 => {
+                    let b = rng.gen_range(0..nr);
+                    let e = rng.gen_range(b..=nr.min(b + 40));
+                    node.erase(b, e);
+                    model.drain(b..e);
+                }
+                4 if nr > 0 => {
+                    let count = rng.gen_range(0..=nr.min(100));
+                    let (keys, values) = node.remove_right(count).unwrap();
+                    let removed = model.split_off(nr - count);
+                    assert_eq!(keys.into_iter().zip(values).collect::<Vec<_>>(), removed);
+                }
+                5 if nr > 0 => {
+                    let count = rng.gen_range(0..=nr.min(100));
+                    let (keys, values) = node.shift_left(count).unwrap();
+                    let removed: Vec<_> = model.drain(0..count).collect();
+                    assert_eq!(keys.into_iter().zip(values).collect::<Vec<_>>(), removed);
+                }
+                _ => {}
+            }
+
+            assert_eq!(node.nr_entries(), model.len());
+            let keys: Vec<Key> = model.iter().map(|(k, _)| *k).collect();
+            assert_eq!(node.key_width(), choose_encoding(&keys).1);
+            if !model.is_empty() {
+                let idx = rng.gen_range(0..model.len());
+                assert_eq!(node.get_key(idx), model[idx].0);
+                assert_eq!(node.get_value(idx), model[idx].1);
+                assert_eq!(node.lower_bound(model[idx].0), idx as isize);
+            }
+        }
+
+        drop(node);
+        let node = DeltaNode::<u64, SharedProxy>::open(0, cache.shared_lock(0)?)?;
+        let (keys, values) = node.get_entries(0, node.nr_entries());
+        assert_eq!(keys.into_iter().zip(values).collect::<Vec<_>>(), model);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_checks_value_size() -> Result<()> {
+        let cache = mk_cache()?;
+        mk_node::<u64>(&cache, 0)?;
+        assert!(DeltaNode::<Mapping, SharedProxy>::open(0, cache.shared_lock(0)?).is_err());
+        Ok(())
+    }
+
+    fn pack(v: &u64) -> Vec<u8> {
+        v.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_replay() -> Result<()> {
+        let cache = mk_cache()?;
+        let mut node = mk_node::<u64>(&cache, 0)?;
+        mk_node::<u64>(&cache, 1)?;
+        let mut replay = DeltaNodeReplay::new(1, cache.exclusive_lock(1)?)?;
+
+        let keys: Vec<Key> = (0..400).map(|i| 70_000 + i * 3).collect();
+        let values: Vec<u64> = (0..400).collect();
+        let packed: Vec<_> = values.iter().map(pack).collect();
+
+        node.append(&keys[200..300], &values[200..300]);
+        replay.apply_append(&keys[200..300], &packed[200..300])?;
+        node.prepend(&keys[100..200], &values[100..200]);
+        replay.apply_prepend(&keys[100..200], &packed[100..200])?;
+
+        // Widens the keys.
+        node.insert(0, 5, &values[0]);
+        replay.apply_insert(0, 5, &packed[0])?;
+        node.overwrite(50, keys[150], &values[1]);
+        replay.apply_overwrite(50, keys[150], &packed[1])?;
+
+        // Narrows them again.
+        node.erase(0, 60);
+        replay.apply_erase(0, 60)?;
+        assert_eq!(node.key_width(), 2);
+
+        drop(node);
+        drop(replay);
+        assert_eq!(cache.shared_lock(0)?.r(), cache.shared_lock(1)?.r());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree() -> Result<()> {
+        let dir = TempDir::new()?;
+        let journal = Arc::new(Mutex::new(Journal::create(dir.path().join("journal"))?));
+        let mk_tm = |engine: Arc<dyn IoEngine>| -> Result<Arc<TransactionManager>> {
+            Ok(Arc::new(TransactionManager::new(
+                journal.clone(),
+                Arc::new(BlockCache::new(engine, 16)?),
+                BuddyAllocator::new(1024),
+                BuddyAllocator::new(1 << 32),
+            )))
+        };
+
+        let tm = mk_tm(Arc::new(CoreIoEngine::new(1024)))?;
+        let ctx = BatchContext::new();
+        let mut tree = DeltaMappingTree::empty_tree(tm.clone(), &ctx)?;
+
+        // Single blocks spaced apart, so neighbours never merge, with a
+        // few far away to force wide keys.
+        let mut rng = rand::thread_rng();
+        let entries: Vec<(Key, Mapping)> = (0..5000)
+            .map(|i| {
+                let k = if i % 500 == 0 { 1 << 40 } else { 0 } + i * 8;
+                (k, mk_mapping(rng.gen_range(0..1 << 30)))
+            })
+            .collect();
+        for (k, v) in &entries {
+            tree.insert(&ctx, *k, v)?;
+        }
+        for (k, _) in entries.iter().step_by(3) {
+            tree.remove(&ctx, *k)?;
+        }
+        assert_eq!(
+            tree.check()?,
+            entries.len() as u64 - entries.len().div_ceil(3) as u64
+        );
+
+        // Replaying the journal onto a blank node file gives the same tree.
+        let tm2 = mk_tm(Arc::new(CoreIoEngine::new(1024)))?;
+        tm2.replay_entries(&ctx.end()?)?;
+        let tree2 = DeltaMappingTree::open_tree(tm2, tree.root());
+
+        for (i, (k, v)) in entries.iter().enumerate() {
+            let expected = if i % 3 == 0 { None } else { Some(*v) };
+            assert_eq!(tree.lookup(*k)?, expected);
+            assert_eq!(tree2.lookup(*k)?, expected);
+        }
+        Ok(())
+    }
+}
+
+//-------------------------------------------------------------------------
@@ -5,7 +5,7 @@ use crate::block_cache::MetadataBlock;
 use crate::btree::node::*;
 use crate::btree::transaction_manager::*;
 use crate::byte_types::*;
-use crate::journal::batch;
+use crate::journal::batch::BatchContext;
 use crate::journal::entry::*;
 use crate::packed_array::*;
 
@@ -27,20 +27,27 @@ fn to_bytes_many<V: Serializable>(values: &[V]) -> Bytes {
 
 //-------------------------------------------------------------------------
 
+/// Wraps a node, recording every mutation in a batch.
 pub struct JournalNode<N, V, Data> {
+    ctx: BatchContext,
     node: N,
     phantom_v: std::marker::PhantomData<V>,
     phantom_data: std::marker::PhantomData<Data>,
 }
 
 impl<N, V, Data> JournalNode<N, V, Data> {
-    pub fn new(node: N) -> Self {
+    pub fn new(ctx: BatchContext, node: N) -> Self {
         Self {
+            ctx,
             node,
             phantom_v: std::marker::PhantomData,
             phantom_data: std::marker::PhantomData,
         }
     }
+
+    pub fn batch(&self) -> &BatchContext {
+        &self.ctx
+    }
 }
 
 impl<N, V, Data> NodeR<V, Data> for JournalNode<N, V, Data>
@@ -92,6 +99,10 @@ where
     fn get_flags(&self) -> BTreeFlags {
         self.node.get_flags()
     }
+
+    fn used_space(&self) -> usize {
+        self.node.used_space()
+    }
 }
 
 impl<N, V, Data> NodeW<V, Data> for JournalNode<N, V, Data>
@@ -101,45 +112,63 @@ where
     Data: Writeable,
 {
     fn init(loc: MetadataBlock, data: Data, is_leaf: bool) -> Result<()> {
-        // FIXME: write to journal
+        // The tm journals new nodes, since it knows the block is fresh.
         N::init(loc, data, is_leaf)
     }
 
+    // Ops that fail for lack of space are retried by ensure_space() after
+    // redistributing, or abandoned by split_node(), so we only journal
+    // those that succeed.
     fn overwrite(&mut self, idx: usize, k: Key, value: &V) -> NodeInsertOutcome {
         let loc = self.node.n_ptr().loc;
-        let op = Entry::Overwrite(loc, idx as u32, k, to_bytes(value));
-        batch::add_entry(op).unwrap();
-        self.node.overwrite(idx, k, value)
+        let r = self.node.overwrite(idx, k, value);
+        if let NodeInsertOutcome::Success = r {
+            self.ctx
+                .add_entry(Entry::Overwrite(loc, idx as u32, k, to_bytes(value)));
+        }
+        r
     }
 
     fn insert(&mut self, idx: usize, k: Key, value: &V) -> NodeInsertOutcome {
         let loc = self.node.n_ptr().loc;
-        let op = Entry::Insert(loc, idx as u32, k, to_bytes(value));
-        batch::add_entry(op).unwrap();
-        self.node.insert(idx, k, value)
+        let r = self.node.insert(idx, k, value);
+        if let NodeInsertOutcome::Success = r {
+            self.ctx
+                .add_entry(Entry::Insert(loc, idx as u32, k, to_bytes(value)));
+        }
+        r
     }
 
     fn prepend(&mut self, keys: &[Key], values: &[V]) -> NodeInsertOutcome {
         let loc = self.node.n_ptr().loc;
-        let serialized_values = values.iter().map(|v| to_bytes(v)).collect();
-        let op = Entry::Prepend(loc, keys.to_vec(), serialized_values);
-        batch::add_entry(op).unwrap();
-        self.node.prepend(keys, values)
+        let r = self.node.prepend(keys, values);
+        if let NodeInsertOutcome::Success = r {
+            let serialized_values = values.iter().map(|v| to_bytes(v)).collect();
+            self.ctx
+                .add_entry(Entry::Prepend(loc, keys.to_vec(), serialized_values));
+        }
+        r
     }
 
     fn append(&mut self, keys: &[Key], values: &[V]) -> NodeInsertOutcome {
         let loc = self.node.n_ptr().loc;
-        let serialized_values = values.iter().map(|v| to_bytes(v)).collect();
-        let op = Entry::Append(loc, keys.to_vec(), serialized_values);
-        batch::add_entry(op).unwrap();
-        self.node.append(keys, values)
+        let r = self.node.append(keys, values);
+        if let NodeInsertOutcome::Success = r {
+            let serialized_values = values.iter().map(|v| to_bytes(v)).collect();
+            self.ctx
+                .add_entry(Entry::Append(loc, keys.to_vec(), serialized_values));
+        }
+        r
     }
 
-    fn erase(&mut self, b_idx: usize, e_idx: usize) {
+    fn erase(&mut self, b_idx: usize, e_idx: usize) -> NodeInsertOutcome {
         let loc = self.node.n_ptr().loc;
-        let op = Entry::Erase(loc, b_idx as u32, e_idx as u32);
-        batch::add_entry(op).unwrap();
-        self.node.erase(b_idx, e_idx)
+        let r = self.node.erase(b_idx, e_idx);
+        if let NodeInsertOutcome::Success = r {
+            self.ctx
+                .add_entry(Entry::Erase(loc, b_idx as u32, e_idx as u32));
+        }
+        r
     }
 }
 
@@ -0,0 +1,868 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::marker::PhantomData;
+
+use crate::block_cache::*;
+use crate::btree::node::*;
+use crate::btree::nodes::replay::*;
+use crate::byte_types::*;
+use crate::packed_array::*;
+
+//-------------------------------------------------------------------------
+
+// Wraps a node that is expensive to change, such as a ProgramNode, and
+// appends changes to a log at the back of the block rather than applying
+// them.  The log is only folded into the inner node once it fills up, so
+// small changes don't each recompile the node (see doc/metadata-use.md).
+//
+//    header | inner node | log
+//
+// The header is the standard one, holding the nr of entries after the
+// log has been applied.  The inner node has its own header, and must lay
+// itself out within the INNER_SIZE bytes it's given.  The log is a u16
+// of the bytes used, followed by the ops in the order they were called.
+
+pub const LOG_NODE_KIND: u16 = 2;
+
+const NR_ENTRIES_OFFSET: usize = 12;
+
+const LOG_SIZE: usize = 1024;
+const INNER_SIZE: usize = NODE_SIZE - NODE_HEADER_SIZE - LOG_SIZE;
+const LOG_OFFSET: usize = NODE_SIZE - LOG_SIZE;
+const LOG_HEADER_SIZE: usize = 2;
+
+// Compacting may not succeed if inserts have overfilled the inner node,
+// so inserts leave this much of the log free for erases and overwrites.
+// Once that's used up they can fail too, and the node has to be split.
+const LOG_RESERVE: usize = 128;
+
+const OP_OVERWRITE: u8 = 0;
+const OP_INSERT: u8 = 1;
+const OP_ERASE: u8 = 2;
+
+enum LogOp<V> {
+    Overwrite(usize, Key, V),
+    Insert(usize, Key, V),
+    Erase(usize, usize),
+}
+
+impl<V: Serializable + Copy> LogOp<V> {
+    fn pack(&self, w: &mut Vec<u8>) {
+        match self {
+            LogOp::Overwrite(idx, k, v) | LogOp::Insert(idx, k, v) => {
+                let op = if let LogOp::Overwrite(..) = self {
+                    OP_OVERWRITE
+                } else {
+                    OP_INSERT
+                };
+                w.write_u8(op).unwrap();
+                w.write_u32::<LittleEndian>(*idx as u32).unwrap();
+                w.write_u64::<LittleEndian>(*k).unwrap();
+                v.pack(w).unwrap();
+            }
+            LogOp::Erase(b, e) => {
+                w.write_u8(OP_ERASE).unwrap();
+                w.write_u32::<LittleEndian>(*b as u32).unwrap();
+                w.write_u32::<LittleEndian>(*e as u32).unwrap();
+            }
+        }
+    }
+
+    fn unpack(r: &mut &[u8]) -> Result<Self> {
+        let op = r.read_u8()?;
+        let idx = r.read_u32::<LittleEndian>()? as usize;
+        match op {
+            OP_OVERWRITE => Ok(LogOp::Overwrite(
+                idx,
+                r.read_u64::<LittleEndian>()?,
+                V::unpack(r)?,
+            )),
+            OP_INSERT => Ok(LogOp::Insert(
+                idx,
+                r.read_u64::<LittleEndian>()?,
+                V::unpack(r)?,
+            )),
+            OP_ERASE => Ok(LogOp::Erase(idx, r.read_u32::<LittleEndian>()? as usize)),
+            _ => Err(anyhow!("unknown log op {}", op)),
+        }
+    }
+
+    // Checks the op makes sense for a node with nr_entries, and returns
+    // the nr of entries after it.
+    fn check(&self, nr_entries: usize) -> Option<usize> {
+        match self {
+            LogOp::Overwrite(idx, _, _) if *idx < nr_entries => Some(nr_entries),
+            LogOp::Insert(idx, _, _) if *idx <= nr_entries => Some(nr_entries + 1),
+            LogOp::Erase(b, e) if b <= e && *e <= nr_entries => Some(nr_entries - (e - b)),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, entries: &mut Vec<(Key, V)>) {
+        match self {
+            LogOp::Overwrite(idx, k, v) => entries[*idx] = (*k, *v),
+            LogOp::Insert(idx, k, v) => entries.insert(*idx, (*k, *v)),
+            LogOp::Erase(b, e) => {
+                entries.drain(*b..*e);
+            }
+        }
+    }
+}
+
+// Where an entry of the node, with the log applied, comes from.
+enum Source<V> {
+    Inner(usize),
+    Log(Key, V),
+}
+
+//-------------------------------------------------------------------------
+
+/// A node that logs changes to an inner node, see above.  Readers see
+/// the entries with the log applied.
+pub struct LogNode<V, N, Data> {
+    loc: u32,
+    data: Data,
+    inner: N,
+    phantom: PhantomData<V>,
+
+    // The decoded log.  Lookups walk it back to an entry of the inner
+    // node, so the inner node's entries are never copied out.
+    ops: Vec<LogOp<V>>,
+}
+
+/// Applies journalled ops to a LogNode during replay.
+pub type LogNodeReplay<V, N, Data> = NodeReplay<V, LogNode<V, N, Data>, Data>;
+
+fn inner_data<Data: Readable>(data: &Data) -> Data {
+    let (_, rest) = data.split_at(NODE_HEADER_SIZE);
+    rest.split_at(INNER_SIZE).0
+}
+
+/// Returns the kind of node wrapped by the log node in data.
+pub fn read_inner_kind<Data: Readable>(data: &Data) -> Result<u16> {
+    let hdr = read_node_header(&mut &data.r()[NODE_HEADER_SIZE..])?;
+    Ok(hdr.kind)
+}
+
+impl<V: Serializable + Copy, N: NodeR<V, Data>, Data: Readable> LogNode<V, N, Data> {
+    fn log_used(&self) -> usize {
+        let mut r = &self.data.r()[LOG_OFFSET..];
+        r.read_u16::<LittleEndian>().unwrap() as usize
+    }
+
+    fn is_compact(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    fn read_ops(
+        loc: u32,
+        data: &Data,
+        nr_inner: usize,
+        nr_entries: usize,
+    ) -> Result<Vec<LogOp<V>>> {
+        let used = {
+            let mut r = &data.r()[LOG_OFFSET..];
+            r.read_u16::<LittleEndian>()? as usize
+        };
+        if LOG_HEADER_SIZE + used > LOG_SIZE {
+            return Err(anyhow!("log node {} has a log of {} bytes", loc, used));
+        }
+
+        let b = LOG_OFFSET + LOG_HEADER_SIZE;
+        let mut r = &data.r()[b..b + used];
+        let mut ops = Vec::new();
+        let mut nr = nr_inner;
+        while !r.is_empty() {
+            let op =
+                LogOp::unpack(&mut r).map_err(|e| anyhow!("log node {} is corrupt: {}", loc, e))?;
+            nr = op
+                .check(nr)
+                .ok_or_else(|| anyhow!("log node {} has a bad op at entry {}", loc, ops.len()))?;
+            ops.push(op);
+        }
+
+        if nr != nr_entries {
+            return Err(anyhow!(
+                "log node {} has {} entries, but its log gives {}",
+                loc,
+                nr_entries,
+                nr
+            ));
+        }
+        Ok(ops)
+    }
+
+    // Walks the log backwards to find where entry idx came from.
+    fn source(&self, mut idx: usize) -> Source<V> {
+        for op in self.ops.iter().rev() {
+            match op {
+                LogOp::Overwrite(i, k, v) if *i == idx => return Source::Log(*k, *v),
+                LogOp::Insert(i, k, v) if *i == idx => return Source::Log(*k, *v),
+                LogOp::Insert(i, _, _) if *i < idx => idx -= 1,
+                LogOp::Erase(b, e) if *b <= idx => idx += e - b,
+                _ => {}
+            }
+        }
+        Source::Inner(idx)
+    }
+
+    // All the entries, with the log applied.
+    fn entries(&self) -> Vec<(Key, V)> {
+        let (keys, values) = self.inner.get_entries(0, self.inner.nr_entries());
+        let mut entries: Vec<_> = keys.into_iter().zip(values).collect();
+        for op in &self.ops {
+            op.apply(&mut entries);
+        }
+        entries
+    }
+
+    /// The nr of bytes of log in use.
+    pub fn log_len(&self) -> usize {
+        self.log_used()
+    }
+}
+
+impl<V: Serializable + Copy, N: NodeR<V, Data>, Data: Readable> NodeR<V, Data>
+    for LogNode<V, N, Data>
+{
+    fn open(loc: MetadataBlock, data: Data) -> Result<Self> {
+        let hdr = read_node_header(&mut data.r())?;
+        if hdr.kind != LOG_NODE_KIND {
+            return Err(anyhow!(
+                "node {} is of kind {}, not a log node",
+                loc,
+                hdr.kind
+            ));
+        }
+        let inner = N::open(loc, inner_data(&data))?;
+        let ops = Self::read_ops(loc, &data, inner.nr_entries(), hdr.nr_entries as usize)?;
+
+        Ok(Self {
+            loc,
+            data,
+            inner,
+            phantom: PhantomData,
+            ops,
+        })
+    }
+
+    fn n_ptr(&self) -> NodePtr {
+        let hdr = read_node_header(&mut self.data.r()).unwrap();
+        NodePtr {
+            loc: self.loc,
+            seq_nr: hdr.seq_nr,
+        }
+    }
+
+    fn nr_entries(&self) -> usize {
+        let hdr = read_node_header(&mut self.data.r()).unwrap();
+        hdr.nr_entries as usize
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nr_entries() == 0
+    }
+
+    fn get_key(&self, idx: usize) -> Key {
+        match self.source(idx) {
+            Source::Inner(idx) => self.inner.get_key(idx),
+            Source::Log(k, _) => k,
+        }
+    }
+
+    fn get_key_safe(&self, idx: usize) -> Option<Key> {
+        if idx < self.nr_entries() {
+            Some(self.get_key(idx))
+        } else {
+            None
+        }
+    }
+
+    fn get_value(&self, idx: usize) -> V {
+        match self.source(idx) {
+            Source::Inner(idx) => self.inner.get_value(idx),
+            Source::Log(_, v) => v,
+        }
+    }
+
+    fn get_value_safe(&self, idx: usize) -> Option<V> {
+        if idx < self.nr_entries() {
+            Some(self.get_value(idx))
+        } else {
+            None
+        }
+    }
+
+    fn lower_bound(&self, key: Key) -> isize {
+        if self.is_compact() {
+            self.inner.lower_bound(key)
+        } else {
+            // The keys are sorted, so we search for the first one above key.
+            let (mut lo, mut hi) = (0, self.nr_entries());
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if self.get_key(mid) <= key {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            lo as isize - 1
+        }
+    }
+
+    fn get_entries(&self, b_idx: usize, e_idx: usize) -> (Vec<Key>, Vec<V>) {
+        if self.is_compact() {
+            self.inner.get_entries(b_idx, e_idx)
+        } else {
+            (b_idx..e_idx)
+                .map(|idx| match self.source(idx) {
+                    Source::Inner(idx) => (self.inner.get_key(idx), self.inner.get_value(idx)),
+                    Source::Log(k, v) => (k, v),
+                })
+                .unzip()
+        }
+    }
+
+    fn get_flags(&self) -> BTreeFlags {
+        let hdr = read_node_header(&mut self.data.r()).unwrap();
+        hdr.flags
+    }
+
+    // The inner node is out of date if there's a log, but this is only
+    // a guide to when to merge nodes.
+    fn used_space(&self) -> usize {
+        NODE_HEADER_SIZE + self.inner.used_space() + self.log_used()
+    }
+}
+
+//-------------------------------------------------------------------------
+
+impl<V: Serializable + Copy, N: NodeW<V, Data>, Data: Writeable> LogNode<V, N, Data> {
+    fn set_nr_entries(&mut self, nr_entries: usize) {
+        let mut w = &mut self.data.rw()[NR_ENTRIES_OFFSET..];
+        w.write_u32::<LittleEndian>(nr_entries as u32).unwrap();
+    }
+
+    fn set_log_used(&mut self, used: usize) {
+        let mut w = &mut self.data.rw()[LOG_OFFSET..];
+        w.write_u16::<LittleEndian>(used as u16).unwrap();
+    }
+
+    // Appends op to the log, as long as the log stays within limit bytes.
+    fn log(&mut self, op: LogOp<V>, limit: usize) -> bool {
+        let mut bytes = Vec::new();
+        op.pack(&mut bytes);
+        let used = self.log_used();
+        if LOG_HEADER_SIZE + used + bytes.len() > limit {
+            return false;
+        }
+
+        let Some(nr_entries) = op.check(self.nr_entries()) else {
+            return false;
+        };
+        let b = LOG_OFFSET + LOG_HEADER_SIZE + used;
+        self.data.rw()[b..b + bytes.len()].copy_from_slice(&bytes);
+        self.set_log_used(used + bytes.len());
+        self.set_nr_entries(nr_entries);
+        self.ops.push(op);
+        true
+    }
+
+    // Rewrites the inner node with the merged entries, after f has
+    // changed them, and empties the log.  The node is left untouched if
+    // they don't fit.
+    fn compact<F: FnOnce(&mut Vec<(Key, V)>)>(&mut self, f: F) -> NodeInsertOutcome {
+        let mut entries = self.entries();
+        f(&mut entries);
+
+        let saved = self.data.r().to_vec();
+        self.inner.erase(0, self.inner.nr_entries());
+        let (keys, values): (Vec<Key>, Vec<V>) = entries.iter().cloned().unzip();
+        if !keys.is_empty() {
+            if let NodeInsertOutcome::NoSpace = self.inner.append(&keys, &values) {
+                self.data.rw().copy_from_slice(&saved);
+                self.inner = N::open(self.loc, inner_data(&self.data)).unwrap();
+                return NodeInsertOutcome::NoSpace;
+            }
+        }
+
+        self.set_log_used(0);
+        self.set_nr_entries(entries.len());
+        self.ops.clear();
+        NodeInsertOutcome::Success
+    }
+}
+
+impl<V: Serializable + Copy, N: NodeW<V, Data>, Data: Writeable> NodeW<V, Data>
+    for LogNode<V, N, Data>
+{
+    fn init(loc: MetadataBlock, mut data: Data, is_leaf: bool) -> Result<()> {
+        let mut w = std::io::Cursor::new(data.rw());
+        let hdr = NodeHeader {
+            seq_nr: 0,
+            snap_time: 0,
+            flags: if is_leaf {
+                BTreeFlags::Leaf
+            } else {
+                BTreeFlags::Internal
+            },
+            kind: LOG_NODE_KIND,
+            nr_entries: 0,
+        };
+        write_node_header(&mut w, &hdr)?;
+        (&mut data.rw()[LOG_OFFSET..]).write_u16::<LittleEndian>(0)?;
+
+        N::init(loc, inner_data(&data), is_leaf)
+    }
+
+    fn overwrite(&mut self, idx: usize, k: Key, value: &V) -> NodeInsertOutcome {
+        if self.log(LogOp::Overwrite(idx, k, *value), LOG_SIZE) {
+            NodeInsertOutcome::Success
+        } else {
+            self.compact(|entries| entries[idx] = (k, *value))
+        }
+    }
+
+    fn insert(&mut self, idx: usize, k: Key, value: &V) -> NodeInsertOutcome {
+        if self.log(LogOp::Insert(idx, k, *value), LOG_SIZE - LOG_RESERVE) {
+            NodeInsertOutcome::Success
+        } else {
+            self.compact(|entries| entries.insert(idx, (k, *value)))
+        }
+    }
+
+    // Prepend and append move many entries at once, so there's no point
+    // logging them.
+    fn prepend(&mut self, keys: &[Key], values: &[V]) -> NodeInsertOutcome {
+        self.compact(|entries| {
+            let tail = std::mem::take(entries);
+            entries.extend(keys.iter().cloned().zip(values.iter().cloned()));
+            entries.extend(tail);
+        })
+    }
+
+    fn append(&mut self, keys: &[Key], values: &[V]) -> NodeInsertOutcome {
+        self.compact(|entries| entries.extend(keys.iter().cloned().zip(values.iter().cloned())))
+    }
+
+    // Compacting fails if logged inserts have left more entries than the
+    // inner node can hold, so the caller has to split the node.
+    fn erase(&mut self, idx_b: usize, idx_e: usize) -> NodeInsertOutcome {
+        if idx_b == idx_e || self.log(LogOp::Erase(idx_b, idx_e), LOG_SIZE) {
+            return NodeInsertOutcome::Success;
+        }
+
+        self.compact(|entries| {
+            entries.drain(idx_b..idx_e);
+        })
+    }
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use rand::Rng;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+    use thinp::io_engine::*;
+
+    use crate::allocators::*;
+    use crate::btree::transaction_manager::*;
+    use crate::core::CoreIoEngine;
+    use crate::journal::batch::BatchContext;
+    use crate::journal::Journal;
+    use crate::thin::mapping::{
+        LogProgramMappingTree, LogProgramNode, LogProgramNodeReplay, Mapping,
+    };
+
+    type Entries = Vec<(Key, Mapping)>;
+
+    fn mk_cache() -> Result<Arc<BlockCache>> {
+        let engine: Arc<dyn IoEngine> = Arc::new(CoreIoEngine::new(16));
+        Ok(Arc::new(BlockCache::new(engine, 16)?))
+    }
+
+    fn mk_node(cache: &Arc<BlockCache>, loc: u32) -> Result<LogProgramNode<ExclusiveProxy>> {
+        let data = cache.zero_lock(loc)?;
+        LogProgramNode::init(loc, data.clone(), true)?;
+        LogProgramNode::open(loc, data)
+    }
+
+    fn mk_mapping(rng: &mut impl Rng) -> Mapping {
+        let b = rng.gen_range(0..4096) * 8;
+        Mapping {
+            b,
+            e: b + rng.gen_range(1..5) * 8,
+            snap_time: 0,
+        }
+    }
+
+    fn mk_key(model: &Entries, rng: &mut impl Rng) -> Option<Key> {
+        let k = rng.gen_range(0..100_000) * 8;
+        if model.iter().any(|(mk, _)| *mk == k) {
+            None
+        } else {
+            Some(k)
+        }
+    }
+
+    fn node_entries<Data: Readable>(node: &LogProgramNode<Data>) -> Entries {
+        let (keys, values) = node.get_entries(0, node.nr_entries());
+        keys.into_iter().zip(values).collect()
+    }
+
+    // Applies a random op to both the node and model, and returns the op
+    // in a form that can be replayed.
+    enum Op {
+        Overwrite(usize, Key, Mapping),
+        Insert(usize, Key, Mapping),
+        Append(Vec<Key>, Vec<Mapping>),
+        Erase(usize, usize),
+    }
+
+    fn random_op(
+        node: &mut LogProgramNode<ExclusiveProxy>,
+        model: &mut Entries,
+        rng: &mut impl Rng,
+    ) -> Option<Op> {
+        let nr = model.len();
+        match rng.gen_range(0..10) {
+            0..=4 => {
+                let k = mk_key(model, rng)?;
+                let v = mk_mapping(rng);
+                let idx = (node.lower_bound(k) + 1) as usize;
+                if let NodeInsertOutcome::NoSpace = node.insert(idx, k, &v) {
+                    return None;
+                }
+                model.insert(idx, (k, v));
+                Some(Op::Insert(idx, k, v))
+            }
+            5 | 6 if nr > 0 => {
+                let idx = rng.gen_range(0..nr);
+                let (k, _) = model[idx];
+                let v = mk_mapping(rng);
+                if let NodeInsertOutcome::NoSpace = node.overwrite(idx, k, &v) {
+                    return None;
+                }
+                model[idx] = (k, v);
+                Some(Op::Overwrite(idx, k, v))
+            }
+            7 | 8 if nr > 0 => {
+                let b = rng.gen_range(0..nr);
+                let e = rng.gen_range(b..=nr.min(b + 20));
+                if let NodeInsertOutcome::NoSpace = node.erase(b, e) {
+                    return None;
+                }
+                model.drain(b..e);
+                Some(Op::Erase(b, e))
+            }
+            9 => {
+                let k = model.last().map_or(0, |(k, _)| k + 1_000_000);
+                let v = mk_mapping(rng);
+                if let NodeInsertOutcome::NoSpace = node.append(&[k], &[v]) {
+                    return None;
+                }
+                model.push((k, v));
+                Some(Op::Append(vec![k], vec![v]))
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_random_ops() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let cache = mk_cache()?;
+        let mut node = mk_node(&cache, 0)?;
+        let mut model = Vec::new();
+
+        let mut nr_logged = 0;
+        let mut nr_compactions = 0;
+        for _ in 0..5000 {
+            let log_len = node.log_len();
+            random_op(&mut node, &mut model, &mut rng);
+            if node.log_len() > log_len {
+                nr_logged += 1;
+            } else if node.log_len() < log_len {
+                nr_compactions += 1;
+            }
+
+            assert_eq!(node.nr_entries(), model.len());
+            if !model.is_empty() {
+                let idx = rng.gen_range(0..model.len());
+                assert_eq!(node.get_key(idx), model[idx].0);
+                assert_eq!(node.get_value(idx), model[idx].1);
+                assert_eq!(node.lower_bound(model[idx].0), idx as isize);
+            }
+        }
+        assert!(nr_logged > nr_compactions * 3);
+        assert!(nr_compactions > 0);
+
+        // Reopening reads the log back.
+        drop(node);
+        let node = LogProgramNode::open(0, cache.shared_lock(0)?)?;
+        assert_eq!(node_entries(&node), model);
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_log() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let cache = mk_cache()?;
+        let mut node = mk_node(&cache, 0)?;
+        let mut model = Vec::new();
+        while node.log_len() == 0 || model.len() < 10 {
+            random_op(&mut node, &mut model, &mut rng);
+        }
+        drop(node);
+
+        // An unknown op
+        let mut data = cache.exclusive_lock(0)?;
+        data.rw()[LOG_OFFSET + LOG_HEADER_SIZE] = 0xff;
+        drop(data);
+        let err = LogProgramNode::open(0, cache.shared_lock(0)?)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("corrupt"));
+
+        // An op that refers to entries that aren't there
+        let mut data = cache.exclusive_lock(0)?;
+        let mut w = Vec::new();
+        LogOp::<Mapping>::Erase(0, 100_000).pack(&mut w);
+        let b = LOG_OFFSET + LOG_HEADER_SIZE;
+        data.rw()[b..b + w.len()].copy_from_slice(&w);
+        drop(data);
+        let err = LogProgramNode::open(0, cache.shared_lock(0)?)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("bad op"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_erase_no_space() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let cache = mk_cache()?;
+        let mut node = mk_node(&cache, 0)?;
+
+        // Fill the inner node, then overfill it with logged inserts.
+        let mut k = 1_000_000;
+        while let NodeInsertOutcome::Success = node.append(&[k], &[mk_mapping(&mut rng)]) {
+            k += 8;
+        }
+        let mut k = 1_000_000;
+        loop {
+            k -= 8;
+            if let NodeInsertOutcome::NoSpace = node.insert(0, k, &mk_mapping(&mut rng)) {
+                break;
+            }
+        }
+
+        // Erases use up the reserve, after which they can't compact.
+        let mut outcome = NodeInsertOutcome::Success;
+        for _ in 0..LOG_SIZE {
+            let (nr_entries, log_len) = (node.nr_entries(), node.log_len());
+            outcome = node.erase(0, 1);
+            if let NodeInsertOutcome::NoSpace = outcome {
+                assert_eq!(node.nr_entries(), nr_entries);
+                assert_eq!(node.log_len(), log_len);
+                break;
+            }
+        }
+        assert!(matches!(outcome, NodeInsertOutcome::NoSpace));
+
+        // Erasing half the entries, as splitting the node does, fits.
+        let nr_entries = node.nr_entries();
+        assert!(matches!(
+            node.erase(0, nr_entries / 2),
+            NodeInsertOutcome::Success
+        ));
+        assert_eq!(node.nr_entries(), nr_entries - nr_entries / 2);
+        Ok(())
+    }
+
+    fn pack(v: &Mapping) -> Vec<u8> {
+        let mut w = Vec::new();
+        v.pack(&mut w).unwrap();
+        w
+    }
+
+    #[test]
+    fn test_replay() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let cache = mk_cache()?;
+        let mut node = mk_node(&cache, 0)?;
+        mk_node(&cache, 1)?;
+        let mut replay = LogProgramNodeReplay::new(1, cache.exclusive_lock(1)?)?;
+
+        let mut model = Vec::new();
+        for _ in 0..2000 {
+            match random_op(&mut node, &mut model, &mut rng) {
+                Some(Op::Overwrite(idx, k, v)) => {
+                    replay.apply_overwrite(idx as u32, k, &pack(&v))?
+                }
+                Some(Op::Insert(idx, k, v)) => replay.apply_insert(idx as u32, k, &pack(&v))?,
+                Some(Op::Append(ks, vs)) => {
+                    replay.apply_append(&ks, &vs.iter().map(pack).collect::<Vec<_>>())?
+                }
+                Some(Op::Erase(b, e)) => replay.apply_erase(b as u32, e as u32)?,
+                None => {}
+            }
+        }
+
+        drop(node);
+        drop(replay);
+        assert_eq!(cache.shared_lock(0)?.r(), cache.shared_lock(1)?.r());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree() -> Result<()> {
+        let dir = TempDir::new()?;
+        let journal = Arc::new(Mutex::new(Journal::create(dir.path().join("journal"))?));
+        let mk_tm = || -> Result<Arc<TransactionManager>> {
+            Ok(Arc::new(TransactionManager::new(
+                journal.clone(),
+                Arc::new(BlockCache::new(Arc::new(CoreIoEngine::new(1024)), 16)?),
+                BuddyAllocator::new(1024),
+                BuddyAllocator::new(1 << 32),
+            )))
+        };
+
+        let tm = mk_tm()?;
+        let ctx = BatchContext::new();
+        let mut tree = LogProgramMappingTree::empty_tree(tm.clone(), &ctx)?;
+
+        let mut rng = rand::thread_rng();
+        let mut model = std::collections::BTreeMap::new();
+        for _ in 0..5000 {
+            let k = rng.gen_range(0..100_000) * 8;
+            if rng.gen_ratio(1, 4) {
+                tree.remove(&ctx, k)?;
+                model.remove(&k);
+            } else {
+                let v = mk_mapping(&mut rng);
+                tree.insert(&ctx, k, &v)?;
+                model.insert(k, v);
+            }
+        }
+        assert_eq!(tree.check()?, model.len() as u64);
+
+        // Replaying the journal onto a blank node file gives the same tree.
+        let tm2 = mk_tm()?;
+        tm2.replay_entries(&ctx.end()?)?;
+        let tree2 = LogProgramMappingTree::open_tree(tm2, tree.root());
+        assert_eq!(tree2.check()?, model.len() as u64);
+        for (k, v) in &model {
+            assert_eq!(tree.lookup(*k)?, Some(*v));
+            assert_eq!(tree2.lookup(*k)?, Some(*v));
+        }
+        Ok(())
+    }
+
+    // Walks down from the root to the leaf that holds key, returning the
+    // leaf and the depth of the tree.
+    fn leaf_for(
+        tm: &TransactionManager,
+        tree: &LogProgramMappingTree,
+        key: Key,
+    ) -> Result<(LogProgramNode<SharedProxy>, usize)> {
+        let mut n_ptr = tree.root();
+        let mut depth = 1;
+        while tm.is_internal(n_ptr)? {
+            let node: crate::btree::nodes::simple::SimpleNode<NodePtr, SharedProxy> =
+                tm.read(n_ptr)?;
+            n_ptr = node.get_value(node.lower_bound(key).max(0) as usize);
+            depth += 1;
+        }
+        Ok((tm.read(n_ptr)?, depth))
+    }
+
+    #[test]
+    fn test_tree_erase_no_space() -> Result<()> {
+        const STRIDE: u64 = 4096;
+        const INSERT_SIZE: usize = 33;
+        const ERASE_SIZE: usize = 9;
+
+        let dir = TempDir::new()?;
+        let journal = Arc::new(Mutex::new(Journal::create(dir.path().join("journal"))?));
+        let tm = Arc::new(TransactionManager::new(
+            journal,
+            Arc::new(BlockCache::new(Arc::new(CoreIoEngine::new(16384)), 1024)?),
+            BuddyAllocator::new(16384),
+            BuddyAllocator::new(1 << 32),
+        ));
+        let ctx = BatchContext::new();
+        let mut tree = LogProgramMappingTree::empty_tree(tm.clone(), &ctx)?;
+
+        // Appending keys STRIDE apart builds a tree at least three levels
+        // deep, so a leaf that splits has a grandparent.
+        let mut rng = rand::thread_rng();
+        let mut model = std::collections::BTreeMap::new();
+        let mut nr_keys = 0;
+        while leaf_for(&tm, &tree, 0)?.1 < 3 {
+            let k = nr_keys * STRIDE;
+            let v = mk_mapping(&mut rng);
+            tree.insert(&ctx, k, &v)?;
+            model.insert(k, v);
+            nr_keys += 1;
+        }
+
+        // Fill leaves with logged inserts.  Each time an insert would
+        // have to compact we remove enough of them to use up the log, so
+        // the inner node fills up until an erase can't compact and the
+        // leaf has to split under the remove.
+        let mut nr_splits = 0;
+        let mut filled = Vec::new();
+        let mut base = 0;
+        while nr_splits < 4 && base < nr_keys {
+            let k0 = base * STRIDE;
+            filled.push(k0);
+            let mut added = Vec::new();
+            'leaf: for k in (k0 + 8..k0 + STRIDE).step_by(8) {
+                let before = leaf_for(&tm, &tree, k0)?.0.nr_entries();
+                let v = mk_mapping(&mut rng);
+                tree.insert(&ctx, k, &v)?;
+                model.insert(k, v);
+                added.push(k);
+
+                let (leaf, _) = leaf_for(&tm, &tree, k0)?;
+                if leaf.nr_entries() != before + 1 {
+                    // The insert split the leaf.
+                    break;
+                }
+                if LOG_HEADER_SIZE + leaf.log_len() + INSERT_SIZE <= LOG_SIZE - LOG_RESERVE {
+                    continue;
+                }
+
+                for _ in 0..LOG_RESERVE / ERASE_SIZE + 1 {
+                    let k = added.pop().unwrap();
+                    let before = leaf_for(&tm, &tree, k0)?.0.nr_entries();
+                    tree.remove(&ctx, k)?;
+                    model.remove(&k);
+                    if leaf_for(&tm, &tree, k0)?.0.nr_entries() != before - 1 {
+                        nr_splits += 1;
+                        break 'leaf;
+                    }
+                }
+            }
+            base += 101;
+        }
+        assert!(nr_splits > 0);
+
+        assert_eq!(tree.check()?, model.len() as u64);
+        for k0 in filled {
+            for k in (k0..k0 + STRIDE).step_by(8) {
+                assert_eq!(tree.lookup(k)?, model.get(&k).cloned());
+            }
+        }
+        for (k, v) in &model {
+            assert_eq!(tree.lookup(*k)?, Some(*v));
+        }
+        Ok(())
+    }
+}
@@ -1,2 +1,6 @@
+pub mod delta;
 pub mod journal;
+pub mod log;
+pub mod program;
+pub mod replay;
 pub mod simple;
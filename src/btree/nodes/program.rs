@@ -0,0 +1,1022 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::block_cache::*;
+use crate::btree::node::*;
+use crate::btree::nodes::replay::*;
+use crate::byte_types::*;
+use crate::packed_array::*;
+use crate::thin::mapping::Mapping;
+
+//-------------------------------------------------------------------------
+
+// A leaf node for mapping trees that stores its entries as programs for a
+// tiny abstract machine, rather than as arrays (see doc/metadata-use.md).
+//
+// The machine has registers for the thin block, data block, run length,
+// snap time and a shift.  Instructions are bit strings, the commonest
+// getting the shortest codes:
+//
+//    0        emit      output (thin, data..data + len, time),
+//                       then thin += len, data += len
+//    10       data      data += zigzag(gamma) << shift
+//    110      len       len = gamma << shift
+//    1110     skip      thin += gamma << shift
+//    11110    time      time = int
+//    111110   shift     shift = 6 bits
+//    111111   keyframe  thin, data, time = int, int, int; len = 0
+//
+// 'gamma' is the Elias gamma code of n + 1, 'int' is a 7 bit count
+// followed by that many bits.
+//
+// A node holds several programs of at most MAX_PROGRAM_ENTRIES entries
+// each, so a lookup only runs one of them.  After the standard header
+// comes:
+//
+//    nr_programs u16 | pad u16 | directory | program bytes
+//
+// where each directory entry holds the first key, nr entries, offset and
+// length in bytes of a program.  The node uses however much data it's
+// given, which needn't be a whole block (see LogNode).
+
+pub const PROGRAM_NODE_KIND: u16 = 1;
+
+const NR_ENTRIES_OFFSET: usize = 12;
+const NR_PROGRAMS_OFFSET: usize = NODE_HEADER_SIZE;
+const DIRECTORY_OFFSET: usize = NODE_HEADER_SIZE + 4;
+const DIRECTORY_ENTRY_SIZE: usize = 8 + 2 + 2 + 2;
+
+const MAX_PROGRAM_ENTRIES: usize = 64;
+
+// Every program starts with a keyframe, so small ones waste space.  Any
+// smaller than this are merged with a neighbour when rewritten.
+const MIN_PROGRAM_ENTRIES: usize = 16;
+
+// Erasing or overwriting entries can make a program longer, since the
+// deltas either side of a removed entry combine into a bigger one.  So
+// the ops that add entries leave this much space free, and erase and
+// overwrite are allowed to use it.
+const RESERVE: usize = 256;
+
+const MAX_SHIFT: u32 = 63;
+
+//-------------------------------------------------------------------------
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    nr_bits: usize,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            nr_bits: 0,
+        }
+    }
+
+    fn put(&mut self, v: u64, nr_bits: usize) {
+        for i in 0..nr_bits {
+            if self.nr_bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (v >> i) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 1 << (self.nr_bits % 8);
+            }
+            self.nr_bits += 1;
+        }
+    }
+
+    fn put_gamma(&mut self, n: u64) {
+        let v = n as u128 + 1;
+        let nr_bits = 127 - v.leading_zeros() as usize;
+        self.put(0, nr_bits);
+        self.put(1, 1);
+        self.put(v as u64, nr_bits);
+    }
+
+    fn put_int(&mut self, v: u64) {
+        let nr_bits = 64 - v.leading_zeros() as usize;
+        self.put(nr_bits as u64, 7);
+        self.put(v, nr_bits);
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn get_bit(&mut self) -> Result<bool> {
+        let byte = self
+            .bytes
+            .get(self.pos / 8)
+            .ok_or_else(|| anyhow!("program runs off the end"))?;
+        let bit = (byte >> (self.pos % 8)) & 1 == 1;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn get(&mut self, nr_bits: usize) -> Result<u64> {
+        let mut v = 0;
+        for i in 0..nr_bits {
+            if self.get_bit()? {
+                v |= 1 << i;
+            }
+        }
+        Ok(v)
+    }
+
+    fn get_gamma(&mut self) -> Result<u64> {
+        let mut nr_bits = 0;
+        while !self.get_bit()? {
+            nr_bits += 1;
+            if nr_bits > 64 {
+                return Err(anyhow!("bad gamma code"));
+            }
+        }
+        let v = (1u128 << nr_bits) | self.get(nr_bits)? as u128;
+        Ok((v - 1) as u64)
+    }
+
+    fn get_int(&mut self) -> Result<u64> {
+        let nr_bits = self.get(7)? as usize;
+        if nr_bits > 64 {
+            return Err(anyhow!("bad int of {} bits", nr_bits));
+        }
+        self.get(nr_bits)
+    }
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+//-------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Instruction {
+    Emit,
+    Data(i64),
+    Len(u64),
+    Skip(u64),
+    Time(u32),
+    Shift(u32),
+    Keyframe { thin: u64, data: u64, time: u32 },
+}
+
+use Instruction::*;
+
+const KEYFRAME_OP: usize = 6;
+
+impl Instruction {
+    // The opcode is the number of leading 1 bits.
+    fn op(&self) -> usize {
+        match self {
+            Emit => 0,
+            Data(_) => 1,
+            Len(_) => 2,
+            Skip(_) => 3,
+            Time(_) => 4,
+            Shift(_) => 5,
+            Keyframe { .. } => KEYFRAME_OP,
+        }
+    }
+
+    fn encode(&self, w: &mut BitWriter) {
+        let op = self.op();
+        w.put((1 << op) - 1, op);
+        if op < KEYFRAME_OP {
+            w.put(0, 1);
+        }
+
+        match self {
+            Emit => {}
+            Data(delta) => w.put_gamma(zigzag(*delta)),
+            Len(len) => w.put_gamma(*len),
+            Skip(delta) => w.put_gamma(*delta),
+            Time(time) => w.put_int(*time as u64),
+            Shift(shift) => w.put(*shift as u64, 6),
+            Keyframe { thin, data, time } => {
+                w.put_int(*thin);
+                w.put_int(*data);
+                w.put_int(*time as u64);
+            }
+        }
+    }
+
+    fn decode(r: &mut BitReader) -> Result<Self> {
+        let mut op = 0;
+        while op < KEYFRAME_OP && r.get_bit()? {
+            op += 1;
+        }
+
+        let inst = match op {
+            0 => Emit,
+            1 => Data(unzigzag(r.get_gamma()?)),
+            2 => Len(r.get_gamma()?),
+            3 => Skip(r.get_gamma()?),
+            4 => Time(r.get_int()? as u32),
+            5 => Shift(r.get(6)? as u32),
+            _ => Keyframe {
+                thin: r.get_int()?,
+                data: r.get_int()?,
+                time: r.get_int()? as u32,
+            },
+        };
+        Ok(inst)
+    }
+}
+
+type Entries = Vec<(Key, Mapping)>;
+
+#[derive(Default)]
+struct Machine {
+    thin: u64,
+    data: u64,
+    len: u64,
+    time: u32,
+    shift: u32,
+}
+
+impl Machine {
+    fn exec(&mut self, inst: &Instruction, out: &mut Entries) {
+        match inst {
+            Emit => {
+                let e = self.data.wrapping_add(self.len);
+                out.push((
+                    self.thin,
+                    Mapping {
+                        b: self.data,
+                        e,
+                        snap_time: self.time,
+                    },
+                ));
+                self.thin = self.thin.wrapping_add(self.len);
+                self.data = e;
+            }
+            Data(delta) => {
+                self.data = self
+                    .data
+                    .wrapping_add((*delta as u64).wrapping_shl(self.shift))
+            }
+            Len(len) => self.len = len.wrapping_shl(self.shift),
+            Skip(delta) => self.thin = self.thin.wrapping_add(delta.wrapping_shl(self.shift)),
+            Time(time) => self.time = *time,
+            Shift(shift) => self.shift = *shift,
+            Keyframe { thin, data, time } => {
+                self.thin = *thin;
+                self.data = *data;
+                self.time = *time;
+                self.len = 0;
+            }
+        }
+    }
+}
+
+// Every key, data block and length in the program must be a multiple of
+// 1 << shift.
+fn choose_shift(entries: &[(Key, Mapping)]) -> u32 {
+    entries
+        .iter()
+        .map(|(k, m)| {
+            k.trailing_zeros()
+                .min(m.b.trailing_zeros())
+                .min(m.e.wrapping_sub(m.b).trailing_zeros())
+        })
+        .min()
+        .unwrap_or(0)
+        .min(MAX_SHIFT)
+}
+
+fn compile(entries: &[(Key, Mapping)]) -> Vec<Instruction> {
+    let shift = choose_shift(entries);
+    let mut prog = Vec::new();
+    let mut m = Machine::default();
+    let mut out = Vec::new();
+
+    let mut push = |m: &mut Machine, inst: Instruction| {
+        m.exec(&inst, &mut out);
+        prog.push(inst);
+    };
+
+    for (i, (k, v)) in entries.iter().enumerate() {
+        // Keys only go up, but a run may overlap the next key.
+        if i == 0 || *k < m.thin {
+            push(
+                &mut m,
+                Keyframe {
+                    thin: *k,
+                    data: v.b,
+                    time: v.snap_time,
+                },
+            );
+            if i == 0 && shift > 0 {
+                push(&mut m, Shift(shift));
+            }
+        }
+
+        if *k != m.thin {
+            let delta = (*k - m.thin) >> shift;
+            push(&mut m, Skip(delta));
+        }
+        if v.snap_time != m.time {
+            push(&mut m, Time(v.snap_time));
+        }
+        let len = v.e.wrapping_sub(v.b);
+        if len != m.len {
+            push(&mut m, Len(len >> shift));
+        }
+        if v.b != m.data {
+            let delta = (v.b.wrapping_sub(m.data) as i64) >> shift;
+            push(&mut m, Data(delta));
+        }
+        push(&mut m, Emit);
+    }
+
+    prog
+}
+
+fn assemble(prog: &[Instruction]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    for inst in prog {
+        inst.encode(&mut w);
+    }
+    w.bytes
+}
+
+fn run(bytes: &[u8], nr_entries: usize) -> Result<Entries> {
+    let mut r = BitReader::new(bytes);
+    let mut m = Machine::default();
+    let mut out = Vec::with_capacity(nr_entries);
+    while out.len() < nr_entries {
+        m.exec(&Instruction::decode(&mut r)?, &mut out);
+    }
+    Ok(out)
+}
+
+//-------------------------------------------------------------------------
+
+#[derive(Clone, Copy)]
+struct Program {
+    first_key: Key,
+    first_idx: usize,
+    nr_entries: usize,
+    offset: usize,
+    len: usize,
+}
+
+impl Program {
+    fn end_idx(&self) -> usize {
+        self.first_idx + self.nr_entries
+    }
+}
+
+fn read_directory(loc: u32, data: &[u8]) -> Result<Vec<Program>> {
+    let mut r = &data[NR_PROGRAMS_OFFSET..];
+    let nr_programs = r.read_u16::<LittleEndian>()? as usize;
+    let _pad = r.read_u16::<LittleEndian>()?;
+
+    let mut programs = Vec::with_capacity(nr_programs);
+    let mut first_idx = 0;
+    for _ in 0..nr_programs {
+        let first_key = r.read_u64::<LittleEndian>()?;
+        let nr_entries = r.read_u16::<LittleEndian>()? as usize;
+        let offset = r.read_u16::<LittleEndian>()? as usize;
+        let len = r.read_u16::<LittleEndian>()? as usize;
+        if nr_entries == 0 || offset + len > data.len() {
+            return Err(anyhow!("program node {} has a bad directory", loc));
+        }
+
+        programs.push(Program {
+            first_key,
+            first_idx,
+            nr_entries,
+            offset,
+            len,
+        });
+        first_idx += nr_entries;
+    }
+
+    let hdr = read_node_header(&mut &data[..])?;
+    if hdr.nr_entries as usize != first_idx {
+        return Err(anyhow!(
+            "program node {} has {} entries, but its programs hold {}",
+            loc,
+            hdr.nr_entries,
+            first_idx
+        ));
+    }
+
+    Ok(programs)
+}
+
+/// A leaf node for `Mapping`s that compiles its entries into programs.
+/// Holds many more entries than a `SimpleNode<Mapping>` when the mappings
+/// are fragmented, at the cost of decoding on every access.
+pub struct ProgramNode<Data: Readable> {
+    loc: u32,
+    data: Data,
+    programs: Vec<Program>,
+
+    // The last program run, accesses tend to cluster.
+    cache: RefCell<Option<(usize, Rc<Entries>)>>,
+}
+
+impl<Data: Readable> ProgramNode<Data> {
+    // Index of the program holding entry idx, or nr_programs if idx is
+    // past the end.
+    fn find_program(&self, idx: usize) -> usize {
+        self.programs.partition_point(|p| p.end_idx() <= idx)
+    }
+
+    fn decode(&self, p: usize) -> Rc<Entries> {
+        if let Some((cached, entries)) = &*self.cache.borrow() {
+            if *cached == p {
+                return entries.clone();
+            }
+        }
+
+        let prog = &self.programs[p];
+        let bytes = &self.data.r()[prog.offset..prog.offset + prog.len];
+        let entries = Rc::new(
+            run(bytes, prog.nr_entries)
+                .unwrap_or_else(|e| panic!("program node {} is corrupt: {}", self.loc, e)),
+        );
+        *self.cache.borrow_mut() = Some((p, entries.clone()));
+        entries
+    }
+
+    fn decode_range(&self, pb: usize, pe: usize) -> Entries {
+        let mut entries = Vec::new();
+        for p in pb..pe {
+            entries.extend(self.decode(p).iter());
+        }
+        entries
+    }
+
+    fn entry(&self, idx: usize) -> (Key, Mapping) {
+        let p = self.find_program(idx);
+        self.decode(p)[idx - self.programs[p].first_idx]
+    }
+
+    fn size(&self) -> usize {
+        self.data.r().len()
+    }
+}
+
+impl<Data: Readable> NodeR<Mapping, Data> for ProgramNode<Data> {
+    fn open(loc: MetadataBlock, data: Data) -> Result<Self> {
+        let hdr = read_node_header(&mut data.r())?;
+        if hdr.kind != PROGRAM_NODE_KIND {
+            return Err(anyhow!(
+                "node {} is of kind {}, not a program node",
+                loc,
+                hdr.kind
+            ));
+        }
+        let programs = read_directory(loc, data.r())?;
+
+        Ok(Self {
+            loc,
+            data,
+            programs,
+            cache: RefCell::new(None),
+        })
+    }
+
+    fn n_ptr(&self) -> NodePtr {
+        let hdr = read_node_header(&mut self.data.r()).unwrap();
+        NodePtr {
+            loc: self.loc,
+            seq_nr: hdr.seq_nr,
+        }
+    }
+
+    fn nr_entries(&self) -> usize {
+        self.programs.last().map_or(0, |p| p.end_idx())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.programs.is_empty()
+    }
+
+    fn get_key(&self, idx: usize) -> Key {
+        self.entry(idx).0
+    }
+
+    fn get_key_safe(&self, idx: usize) -> Option<Key> {
+        if idx < self.nr_entries() {
+            Some(self.get_key(idx))
+        } else {
+            None
+        }
+    }
+
+    fn get_value(&self, idx: usize) -> Mapping {
+        self.entry(idx).1
+    }
+
+    fn get_value_safe(&self, idx: usize) -> Option<Mapping> {
+        if idx < self.nr_entries() {
+            Some(self.get_value(idx))
+        } else {
+            None
+        }
+    }
+
+    fn lower_bound(&self, key: Key) -> isize {
+        let p = self.programs.partition_point(|p| p.first_key <= key);
+        if p == 0 {
+            return -1;
+        }
+
+        let p = p - 1;
+        let entries = self.decode(p);
+        let i = entries.partition_point(|(k, _)| *k <= key);
+        (self.programs[p].first_idx + i - 1) as isize
+    }
+
+    fn get_entries(&self, b_idx: usize, e_idx: usize) -> (Vec<Key>, Vec<Mapping>) {
+        let mut keys = Vec::with_capacity(e_idx - b_idx);
+        let mut values = Vec::with_capacity(e_idx - b_idx);
+        if b_idx == e_idx {
+            return (keys, values);
+        }
+
+        for p in self.find_program(b_idx)..=self.find_program(e_idx - 1) {
+            let prog = self.programs[p];
+            let entries = self.decode(p);
+            let b = b_idx.max(prog.first_idx) - prog.first_idx;
+            let e = e_idx.min(prog.end_idx()) - prog.first_idx;
+            for (k, v) in &entries[b..e] {
+                keys.push(*k);
+                values.push(*v);
+            }
+        }
+        (keys, values)
+    }
+
+    fn get_flags(&self) -> BTreeFlags {
+        let hdr = read_node_header(&mut self.data.r()).unwrap();
+        hdr.flags
+    }
+
+    fn used_space(&self) -> usize {
+        DIRECTORY_OFFSET
+            + self.programs.len() * DIRECTORY_ENTRY_SIZE
+            + self.programs.iter().map(|p| p.len).sum::<usize>()
+    }
+}
+
+//-------------------------------------------------------------------------
+
+impl<Data: Writeable> ProgramNode<Data> {
+    // Replaces programs [pb, pe) with ones holding the given entries.
+    // Nothing changes if the result would use more than limit bytes.
+    fn splice(
+        &mut self,
+        mut pb: usize,
+        mut pe: usize,
+        mut entries: Entries,
+        limit: usize,
+    ) -> NodeInsertOutcome {
+        if !entries.is_empty() && entries.len() < MIN_PROGRAM_ENTRIES {
+            if pe < self.programs.len() {
+                entries.extend(self.decode(pe).iter());
+                pe += 1;
+            } else if pb > 0 {
+                pb -= 1;
+                let mut merged = self.decode(pb).to_vec();
+                merged.extend(entries);
+                entries = merged;
+            }
+        }
+
+        // (first key, nr entries, bytes)
+        let mut new_programs = Vec::new();
+        let nr_chunks = entries.len().div_ceil(MAX_PROGRAM_ENTRIES);
+        let mut b = 0;
+        for c in 0..nr_chunks {
+            let e = b + entries.len() / nr_chunks + usize::from(c < entries.len() % nr_chunks);
+            let chunk = &entries[b..e];
+            new_programs.push((chunk[0].0, chunk.len(), assemble(&compile(chunk))));
+            b = e;
+        }
+
+        let data = self.data.r();
+        let old = |p: &Program| {
+            (
+                p.first_key,
+                p.nr_entries,
+                data[p.offset..p.offset + p.len].to_vec(),
+            )
+        };
+        let programs: Vec<_> = self.programs[..pb]
+            .iter()
+            .map(old)
+            .chain(new_programs)
+            .chain(self.programs[pe..].iter().map(old))
+            .collect();
+
+        let mut offset = DIRECTORY_OFFSET + programs.len() * DIRECTORY_ENTRY_SIZE;
+        let size = offset + programs.iter().map(|p| p.2.len()).sum::<usize>();
+        if size > limit {
+            return NodeInsertOutcome::NoSpace;
+        }
+
+        let mut dir = Vec::with_capacity(self.size() - NR_PROGRAMS_OFFSET);
+        dir.write_u16::<LittleEndian>(programs.len() as u16)
+            .unwrap();
+        dir.write_u16::<LittleEndian>(0).unwrap();
+        for (first_key, nr_entries, bytes) in &programs {
+            dir.write_u64::<LittleEndian>(*first_key).unwrap();
+            dir.write_u16::<LittleEndian>(*nr_entries as u16).unwrap();
+            dir.write_u16::<LittleEndian>(offset as u16).unwrap();
+            dir.write_u16::<LittleEndian>(bytes.len() as u16).unwrap();
+            offset += bytes.len();
+        }
+        for (_, _, bytes) in &programs {
+            dir.extend(bytes);
+        }
+        dir.resize(self.size() - NR_PROGRAMS_OFFSET, 0);
+
+        let nr_entries: usize = programs.iter().map(|p| p.1).sum();
+        let block = self.data.rw();
+        block[NR_PROGRAMS_OFFSET..].copy_from_slice(&dir);
+        (&mut block[NR_ENTRIES_OFFSET..])
+            .write_u32::<LittleEndian>(nr_entries as u32)
+            .unwrap();
+
+        self.programs = read_directory(self.loc, self.data.r()).unwrap();
+        *self.cache.borrow_mut() = None;
+        NodeInsertOutcome::Success
+    }
+}
+
+fn zip_entries(keys: &[Key], values: &[Mapping]) -> Entries {
+    keys.iter().cloned().zip(values.iter().cloned()).collect()
+}
+
+impl<Data: Writeable> NodeW<Mapping, Data> for ProgramNode<Data> {
+    fn init(_loc: MetadataBlock, mut data: Data, is_leaf: bool) -> Result<()> {
+        let mut w = std::io::Cursor::new(data.rw());
+        let hdr = NodeHeader {
+            seq_nr: 0,
+            snap_time: 0,
+            flags: if is_leaf {
+                BTreeFlags::Leaf
+            } else {
+                BTreeFlags::Internal
+            },
+            kind: PROGRAM_NODE_KIND,
+            nr_entries: 0,
+        };
+
+        write_node_header(&mut w, &hdr)?;
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_u16::<LittleEndian>(0)?;
+
+        Ok(())
+    }
+
+    fn overwrite(&mut self, idx: usize, k: Key, value: &Mapping) -> NodeInsertOutcome {
+        let p = self.find_program(idx);
+        let mut entries = self.decode(p).to_vec();
+        entries[idx - self.programs[p].first_idx] = (k, *value);
+        self.splice(p, p + 1, entries, self.size())
+    }
+
+    fn insert(&mut self, idx: usize, k: Key, value: &Mapping) -> NodeInsertOutcome {
+        if self.programs.is_empty() {
+            return self.splice(0, 0, vec![(k, *value)], self.size() - RESERVE);
+        }
+
+        let p = self.find_program(idx).min(self.programs.len() - 1);
+        let mut entries = self.decode(p).to_vec();
+        entries.insert(idx - self.programs[p].first_idx, (k, *value));
+        self.splice(p, p + 1, entries, self.size() - RESERVE)
+    }
+
+    fn prepend(&mut self, keys: &[Key], values: &[Mapping]) -> NodeInsertOutcome {
+        let pe = self.programs.len().min(1);
+        let mut entries = zip_entries(keys, values);
+        entries.extend(self.decode_range(0, pe));
+        self.splice(0, pe, entries, self.size() - RESERVE)
+    }
+
+    fn append(&mut self, keys: &[Key], values: &[Mapping]) -> NodeInsertOutcome {
+        let pe = self.programs.len();
+        let pb = pe.saturating_sub(1);
+        let mut entries = self.decode_range(pb, pe);
+        entries.extend(zip_entries(keys, values));
+        self.splice(pb, pe, entries, self.size() - RESERVE)
+    }
+
+    fn erase(&mut self, idx_b: usize, idx_e: usize) -> NodeInsertOutcome {
+        if idx_b == idx_e {
+            return NodeInsertOutcome::Success;
+        }
+
+        let pb = self.find_program(idx_b);
+        let pe = self.find_program(idx_e - 1) + 1;
+        let first_idx = self.programs[pb].first_idx;
+        let mut entries = self.decode_range(pb, pe);
+        entries.drain((idx_b - first_idx)..(idx_e - first_idx));
+        self.splice(pb, pe, entries, self.size())
+    }
+}
+
+//-------------------------------------------------------------------------
+
+/// Applies journalled ops to a ProgramNode during replay.
+pub type ProgramNodeReplay<Data> = NodeReplay<Mapping, ProgramNode<Data>, Data>;
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use rand::Rng;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+    use thinp::io_engine::*;
+
+    use crate::allocators::*;
+    use crate::btree::transaction_manager::*;
+    use crate::core::CoreIoEngine;
+    use crate::journal::batch::BatchContext;
+    use crate::journal::Journal;
+    use crate::thin::mapping::CompressedMappingTree;
+
+    fn mk_cache() -> Result<Arc<BlockCache>> {
+        let engine: Arc<dyn IoEngine> = Arc::new(CoreIoEngine::new(16));
+        Ok(Arc::new(BlockCache::new(engine, 16)?))
+    }
+
+    fn mk_node(cache: &Arc<BlockCache>, loc: u32) -> Result<ProgramNode<ExclusiveProxy>> {
+        let data = cache.zero_lock(loc)?;
+        ProgramNode::init(loc, data.clone(), true)?;
+        ProgramNode::open(loc, data)
+    }
+
+    // Runs of 8 to 32 blocks, mostly following on from each other in the
+    // thin device, but scattered across a small area of the data device.
+    fn fragmented(nr: usize) -> Entries {
+        let mut rng = rand::thread_rng();
+        let mut thin = 29232;
+        let mut entries = Vec::new();
+        for _ in 0..nr {
+            if rng.gen_ratio(1, 8) {
+                thin += rng.gen_range(1..16) * 8;
+            }
+            let b = 25248200 + rng.gen_range(0..512) * 8;
+            let len = rng.gen_range(1..5) * 8;
+            entries.push((
+                thin,
+                Mapping {
+                    b,
+                    e: b + len,
+                    snap_time: if rng.gen_ratio(1, 16) { 4560 } else { 4559 },
+                },
+            ));
+            thin += len;
+        }
+        entries
+    }
+
+    fn node_entries<Data: Readable>(node: &ProgramNode<Data>) -> Entries {
+        let (keys, values) = node.get_entries(0, node.nr_entries());
+        zip_entries(&keys, &values)
+    }
+
+    #[test]
+    fn test_instruction_roundtrip() -> Result<()> {
+        let prog = vec![
+            Keyframe {
+                thin: u64::MAX,
+                data: 0,
+                time: u32::MAX,
+            },
+            Shift(63),
+            Emit,
+            Data(i64::MIN),
+            Data(i64::MAX),
+            Data(-1),
+            Len(0),
+            Len(u64::MAX),
+            Skip(12345),
+            Time(0),
+            Emit,
+        ];
+
+        let bytes = assemble(&prog);
+        let mut r = BitReader::new(&bytes);
+        for inst in &prog {
+            assert_eq!(Instruction::decode(&mut r)?, *inst);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_roundtrip() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let mut entries = fragmented(rng.gen_range(1..200));
+
+            // Some with arbitrary values, which overlap each other and
+            // defeat the shift.
+            if rng.gen_ratio(1, 2) {
+                for (k, m) in &mut entries {
+                    *k += rng.gen_range(0..3);
+                    m.b = rng.gen();
+                    m.e = m.b.wrapping_add(rng.gen_range(0..100));
+                }
+            }
+
+            let bytes = assemble(&compile(&entries));
+            assert_eq!(run(&bytes, entries.len())?, entries);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_capacity() -> Result<()> {
+        let cache = mk_cache()?;
+        let mut node = mk_node(&cache, 0)?;
+
+        let entries = fragmented(5000);
+        for (k, v) in &entries {
+            if let NodeInsertOutcome::NoSpace = node.append(&[*k], &[*v]) {
+                break;
+            }
+        }
+
+        // A SimpleNode<Mapping> holds 145.
+        let nr_entries = node.nr_entries();
+        assert!(nr_entries > 5 * 145, "only {} entries fit", nr_entries);
+        assert!(node.used_space() <= NODE_SIZE - RESERVE);
+        assert_eq!(node_entries(&node), entries[..nr_entries]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_ops() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let cache = mk_cache()?;
+        let mut node = mk_node(&cache, 0)?;
+        let mut model: Entries = Vec::new();
+
+        // Keys are spaced out so there's room to insert between them.
+        let mut pool = fragmented(2000);
+        for (k, _) in &mut pool {
+            *k *= 4;
+        }
+
+        for _ in 0..5000 {
+            let nr = model.len();
+            match rng.gen_range(0..6) {
+                0 | 1 => {
+                    let (k, v) = pool[rng.gen_range(0..pool.len())];
+                    let idx = (node.lower_bound(k) + 1) as usize;
+                    if model.iter().any(|(mk, _)| *mk == k) {
+                        continue;
+                    }
+                    if let NodeInsertOutcome::Success = node.insert(idx, k, &v) {
+                        model.insert(idx, (k, v));
+                    }
+                }
+                2 if nr > 0 => {
+                    let idx = rng.gen_range(0..nr);
+                    let (k, mut v) = model[idx];
+                    v.e = v.b + rng.gen_range(1..4);
+                    if let NodeInsertOutcome::Success = node.overwrite(idx, k, &v) {
+                        model[idx] = (k, v);
+                    }
+                }
+                3 if nr > 0 => {
+                    let b = rng.gen_range(0..nr);
+                    let e = rng.gen_range(b..=nr.min(b + 40));
+                    node.erase(b, e);
+                    model.drain(b..e);
+                }
+                4 if nr > 0 => {
+                    let count = rng.gen_range(0..=nr.min(100));
+                    let (keys, values) = node.remove_right(count).unwrap();
+                    let removed = model.split_off(nr - count);
+                    assert_eq!(zip_entries(&keys, &values), removed);
+                }
+                5 if nr > 0 => {
+                    let count = rng.gen_range(0..=nr.min(100));
+                    let (keys, values) = node.shift_left(count).unwrap();
+                    let removed: Entries = model.drain(0..count).collect();
+                    assert_eq!(zip_entries(&keys, &values), removed);
+                }
+                _ => {}
+            }
+
+            assert_eq!(node.nr_entries(), model.len());
+            if !model.is_empty() {
+                let idx = rng.gen_range(0..model.len());
+                assert_eq!(node.get_key(idx), model[idx].0);
+                assert_eq!(node.get_value(idx), model[idx].1);
+                assert_eq!(node.lower_bound(model[idx].0 + 1), idx as isize);
+                assert_eq!(node.lower_bound(model[0].0 - 1), -1);
+            }
+        }
+
+        // Reopening goes through the directory on disk.
+        drop(node);
+        let node = ProgramNode::open(0, cache.shared_lock(0)?)?;
+        assert_eq!(node_entries(&node), model);
+        Ok(())
+    }
+
+    fn pack(v: &Mapping) -> Vec<u8> {
+        let mut w = Vec::new();
+        v.pack(&mut w).unwrap();
+        w
+    }
+
+    #[test]
+    fn test_replay() -> Result<()> {
+        let cache = mk_cache()?;
+        let mut node = mk_node(&cache, 0)?;
+        mk_node(&cache, 1)?;
+        let mut replay = ProgramNodeReplay::new(1, cache.exclusive_lock(1)?)?;
+
+        let entries = fragmented(400);
+        let (keys, values): (Vec<Key>, Vec<Mapping>) = entries.iter().cloned().unzip();
+        let packed: Vec<_> = values.iter().map(pack).collect();
+
+        node.append(&keys[200..300], &values[200..300]);
+        replay.apply_append(&keys[200..300], &packed[200..300])?;
+        node.prepend(&keys[100..200], &values[100..200]);
+        replay.apply_prepend(&keys[100..200], &packed[100..200])?;
+        node.insert(0, keys[0], &values[0]);
+        replay.apply_insert(0, keys[0], &packed[0])?;
+        node.overwrite(50, keys[150], &values[1]);
+        replay.apply_overwrite(50, keys[150], &packed[1])?;
+        node.erase(10, 60);
+        replay.apply_erase(10, 60)?;
+
+        drop(node);
+        drop(replay);
+        assert_eq!(cache.shared_lock(0)?.r(), cache.shared_lock(1)?.r());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree() -> Result<()> {
+        let dir = TempDir::new()?;
+        let journal = Arc::new(Mutex::new(Journal::create(dir.path().join("journal"))?));
+        let mk_tm = |engine: Arc<dyn IoEngine>| -> Result<Arc<TransactionManager>> {
+            Ok(Arc::new(TransactionManager::new(
+                journal.clone(),
+                Arc::new(BlockCache::new(engine, 16)?),
+                BuddyAllocator::new(1024),
+                BuddyAllocator::new(1 << 32),
+            )))
+        };
+
+        let tm = mk_tm(Arc::new(CoreIoEngine::new(1024)))?;
+        let ctx = BatchContext::new();
+        let mut tree = CompressedMappingTree::empty_tree(tm.clone(), &ctx)?;
+
+        let entries = fragmented(5000);
+        for (k, v) in &entries {
+            tree.insert(&ctx, *k, v)?;
+        }
+        for (k, _) in entries.iter().step_by(3) {
+            tree.remove(&ctx, *k)?;
+        }
+        assert_eq!(
+            tree.check()?,
+            entries.len() as u64 - entries.len().div_ceil(3) as u64
+        );
+
+        // Replaying the journal onto a blank node file gives the same tree.
+        let tm2 = mk_tm(Arc::new(CoreIoEngine::new(1024)))?;
+        tm2.replay_entries(&ctx.end()?)?;
+        let tree2 = CompressedMappingTree::open_tree(tm2, tree.root());
+
+        for (i, (k, v)) in entries.iter().enumerate() {
+            let expected = if i % 3 == 0 { None } else { Some(*v) };
+            assert_eq!(tree.lookup(*k)?, expected);
+            assert_eq!(tree2.lookup(*k)?, expected);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{anyhow, Result};
+use std::marker::PhantomData;
+
+use crate::block_cache::*;
+use crate::btree::node::*;
+use crate::byte_types::*;
+use crate::packed_array::*;
+
+//-------------------------------------------------------------------------
+
+/// Applies journalled ops to a node during replay by unpacking the values
+/// and calling the node's own methods.  This only works for nodes that
+/// change their bytes deterministically.  The value type has to be known
+/// up front.
+pub struct NodeReplay<V, N, Data> {
+    loc: u32,
+    kind: u16,
+    node: N,
+    phantom_v: PhantomData<V>,
+    phantom_data: PhantomData<Data>,
+}
+
+impl<V: Serializable, N: NodeW<V, Data>, Data: Writeable> NodeReplay<V, N, Data> {
+    pub fn new(loc: u32, data: Data) -> Result<Self> {
+        let kind = read_node_header(&mut data.r())?.kind;
+        Ok(Self {
+            loc,
+            kind,
+            node: N::open(loc, data)?,
+            phantom_v: PhantomData,
+            phantom_data: PhantomData,
+        })
+    }
+
+    fn unpack(&self, value: &[u8]) -> Result<V> {
+        if value.len() != V::packed_len() {
+            return Err(anyhow!(
+                "node {}: value is {} bytes, expected {}",
+                self.loc,
+                value.len(),
+                V::packed_len()
+            ));
+        }
+        Ok(V::unpack(&mut &value[..])?)
+    }
+
+    fn unpack_many(&self, values: &[Vec<u8>]) -> Result<Vec<V>> {
+        values.iter().map(|v| self.unpack(v)).collect()
+    }
+
+    fn check(&self, outcome: NodeInsertOutcome) -> Result<()> {
+        match outcome {
+            NodeInsertOutcome::Success => Ok(()),
+            NodeInsertOutcome::NoSpace => Err(anyhow!("node {}: no space", self.loc)),
+        }
+    }
+}
+
+impl<V: Serializable, N: NodeW<V, Data>, Data: Writeable> ReplayableNode
+    for NodeReplay<V, N, Data>
+{
+    fn get_kind(&self) -> u16 {
+        self.kind
+    }
+
+    fn get_loc(&self) -> u32 {
+        self.loc
+    }
+
+    fn get_seq_nr(&self) -> u32 {
+        self.node.n_ptr().seq_nr
+    }
+
+    fn apply_overwrite(&mut self, idx: u32, key: Key, value: &[u8]) -> Result<()> {
+        if idx as usize >= self.node.nr_entries() {
+            return Err(anyhow!("node {}: overwrite of idx {}", self.loc, idx));
+        }
+        let value = self.unpack(value)?;
+        let r = self.node.overwrite(idx as usize, key, &value);
+        self.check(r)
+    }
+
+    fn apply_insert(&mut self, idx: u32, key: Key, value: &[u8]) -> Result<()> {
+        if idx as usize > self.node.nr_entries() {
+            return Err(anyhow!("node {}: insert at idx {}", self.loc, idx));
+        }
+        let value = self.unpack(value)?;
+        let r = self.node.insert(idx as usize, key, &value);
+        self.check(r)
+    }
+
+    fn apply_prepend(&mut self, keys: &[Key], values: &[Vec<u8>]) -> Result<()> {
+        let values = self.unpack_many(values)?;
+        let r = self.node.prepend(keys, &values);
+        self.check(r)
+    }
+
+    fn apply_append(&mut self, keys: &[Key], values: &[Vec<u8>]) -> Result<()> {
+        let values = self.unpack_many(values)?;
+        let r = self.node.append(keys, &values);
+        self.check(r)
+    }
+
+    fn apply_erase(&mut self, idx_b: u32, idx_e: u32) -> Result<()> {
+        let (b, e) = (idx_b as usize, idx_e as usize);
+        let nr_entries = self.node.nr_entries();
+        if b > e || e > nr_entries {
+            return Err(anyhow!(
+                "node {}: erase of [{}, {}) from {} entries",
+                self.loc,
+                b,
+                e,
+                nr_entries
+            ));
+        }
+
+        let r = self.node.erase(b, e);
+        self.check(r)
+    }
+}
+
+//-------------------------------------------------------------------------
@@ -1,4 +1,5 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::block_cache::*;
 use crate::btree::node::*;
@@ -7,7 +8,32 @@ use crate::packed_array::*;
 
 //-------------------------------------------------------------------------
 
-pub const SIMPLE_NODE_KIND: u16 = 0;
+// The keys and values are packed arrays that start after an extended
+// header:
+//
+//    header | value_size u16 | pad u16 * 3 | keys | values
+//
+// The values start after room for as many keys as the node can hold.
+// Recording the value size means the layout can be worked out from the
+// node alone, so it can be replayed without knowing the value type.
+//
+// Kind 0 nodes have no value size, and the keys start straight after the
+// standard header.  Room was left for keys and values of their in core
+// size, so they can still be opened by a tree that knows the value type,
+// and changed in place.  They can't be replayed though.
+pub const SIMPLE_NODE_KIND: u16 = 4;
+const OLD_SIMPLE_NODE_KIND: u16 = 0;
+
+const VALUE_SIZE_OFFSET: usize = NODE_HEADER_SIZE;
+const SIMPLE_HEADER_SIZE: usize = VALUE_SIZE_OFFSET + 8;
+
+fn max_entries(value_size: usize) -> usize {
+    (NODE_SIZE - SIMPLE_HEADER_SIZE) / (std::mem::size_of::<Key>() + value_size)
+}
+
+fn old_max_entries<V>() -> usize {
+    (NODE_SIZE - NODE_HEADER_SIZE) / (std::mem::size_of::<Key>() + std::mem::size_of::<V>())
+}
 
 #[allow(dead_code)]
 pub struct SimpleNode<V: Serializable, Data: Readable> {
@@ -23,16 +49,21 @@ pub struct SimpleNode<V: Serializable, Data: Readable> {
 
     pub keys: PArray<u64, Data>,
     pub values: PArray<V, Data>,
+
+    // These depend on the kind of node, and aren't written to disk.
+    header_size: usize,
+    max_entries: usize,
 }
 
 impl<V: Serializable, Data: Readable> SimpleNode<V, Data> {
-    pub fn new(loc: u32, data: Data) -> Self {
+    fn new(loc: u32, data: Data, header_size: usize, max_entries: usize) -> Self {
         let (seq_nr, data) = data.split_at(4);
         let (snap_time, data) = data.split_at(4);
         let (flags, data) = data.split_at(2);
         let (kind, data) = data.split_at(2);
-        let (nr_entries, data) = data.split_at(4);
-        let (keys, values) = data.split_at(Self::max_entries() * std::mem::size_of::<Key>());
+        let (nr_entries, _) = data.split_at(4);
+        let (_, data) = data.split_at(header_size - NR_ENTRIES_OFFSET);
+        let (keys, values) = data.split_at(max_entries * std::mem::size_of::<Key>());
 
         let seq_nr = U32::new(seq_nr);
         let snap_time = U32::new(snap_time);
@@ -51,21 +82,51 @@ impl<V: Serializable, Data: Readable> SimpleNode<V, Data> {
             nr_entries,
             keys,
             values,
+            header_size,
+            max_entries,
         }
     }
 
-    fn max_entries() -> usize {
-        (NODE_SIZE - NODE_HEADER_SIZE) / (std::mem::size_of::<Key>() + std::mem::size_of::<V>())
-    }
-
     pub fn has_space(&self, count: usize) -> bool {
-        self.nr_entries.get() as usize + count <= Self::max_entries()
+        self.nr_entries.get() as usize + count <= self.max_entries
     }
 }
 
 impl<V: Serializable, Data: Readable> NodeR<V, Data> for SimpleNode<V, Data> {
     fn open(loc: MetadataBlock, data: Data) -> Result<Self> {
-        Ok(Self::new(loc, data))
+        let hdr = read_node_header(&mut data.r())?;
+        let node = match hdr.kind {
+            SIMPLE_NODE_KIND => {
+                let mut r = &data.r()[VALUE_SIZE_OFFSET..];
+                let value_size = r.read_u16::<LittleEndian>()? as usize;
+                if value_size != V::packed_len() {
+                    return Err(anyhow!(
+                        "simple node {} has values of {} bytes, expected {}",
+                        loc,
+                        value_size,
+                        V::packed_len()
+                    ));
+                }
+                Self::new(loc, data, SIMPLE_HEADER_SIZE, max_entries(value_size))
+            }
+            OLD_SIMPLE_NODE_KIND => Self::new(loc, data, NODE_HEADER_SIZE, old_max_entries::<V>()),
+            kind => {
+                return Err(anyhow!(
+                    "node {} is of kind {}, not a simple node",
+                    loc,
+                    kind
+                ));
+            }
+        };
+
+        if hdr.nr_entries as usize > node.max_entries {
+            return Err(anyhow!(
+                "simple node {} has too many entries ({})",
+                loc,
+                hdr.nr_entries
+            ));
+        }
+        Ok(node)
     }
 
     fn n_ptr(&self) -> NodePtr {
@@ -113,6 +174,10 @@ impl<V: Serializable, Data: Readable> NodeR<V, Data> for SimpleNode<V, Data> {
     fn get_flags(&self) -> BTreeFlags {
         BTreeFlags::from(self.flags.get())
     }
+
+    fn used_space(&self) -> usize {
+        self.header_size + self.nr_entries() * (std::mem::size_of::<Key>() + V::packed_len())
+    }
 }
 
 impl<V: Serializable, Data: Writeable> NodeW<V, Data> for SimpleNode<V, Data> {
@@ -132,6 +197,7 @@ impl<V: Serializable, Data: Writeable> NodeW<V, Data> for SimpleNode<V, Data> {
         };
 
         write_node_header(&mut w, &hdr)?;
+        w.write_u16::<LittleEndian>(V::packed_len() as u16)?;
 
         Ok(())
     }
@@ -175,11 +241,294 @@ impl<V: Serializable, Data: Writeable> NodeW<V, Data> for SimpleNode<V, Data> {
         }
     }
 
-    fn erase(&mut self, idx_b: usize, idx_e: usize) {
+    fn erase(&mut self, idx_b: usize, idx_e: usize) -> NodeInsertOutcome {
         self.keys.erase(idx_b, idx_e);
         self.values.erase(idx_b, idx_e);
         self.nr_entries.dec((idx_e - idx_b) as u32);
+        NodeInsertOutcome::Success
     }
 }
 
 //-------------------------------------------------------------------------
+
+/// Applies journalled ops to a SimpleNode during replay.  The value type
+/// isn't known at this point, so the size of the packed values is read
+/// from the node.
+pub struct SimpleNodeReplay<Data: Writeable> {
+    loc: u32,
+    data: Data,
+    value_size: usize,
+}
+
+const SEQ_NR_OFFSET: usize = 0;
+const KIND_OFFSET: usize = 10;
+const NR_ENTRIES_OFFSET: usize = 12;
+
+impl<Data: Writeable> SimpleNodeReplay<Data> {
+    pub fn new(loc: u32, data: Data) -> Result<Self> {
+        let hdr = read_node_header(&mut data.r())?;
+        if hdr.kind == OLD_SIMPLE_NODE_KIND {
+            return Err(anyhow!(
+                "can't replay old format simple node {}, its layout depends on the value type",
+                loc
+            ));
+        }
+        if hdr.kind != SIMPLE_NODE_KIND {
+            return Err(anyhow!(
+                "node {} is of kind {}, not a simple node",
+                loc,
+                hdr.kind
+            ));
+        }
+
+        let mut r = &data.r()[VALUE_SIZE_OFFSET..];
+        let value_size = r.read_u16::<LittleEndian>()? as usize;
+        if value_size == 0 {
+            return Err(anyhow!("simple node {} has no value size", loc));
+        }
+
+        Ok(Self {
+            loc,
+            data,
+            value_size,
+        })
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        let mut r = &self.data.r()[offset..];
+        r.read_u32::<LittleEndian>().unwrap()
+    }
+
+    fn get_nr_entries(&self) -> usize {
+        self.read_u32(NR_ENTRIES_OFFSET) as usize
+    }
+
+    fn set_nr_entries(&mut self, n: usize) {
+        let mut w = &mut self.data.rw()[NR_ENTRIES_OFFSET..];
+        w.write_u32::<LittleEndian>(n as u32).unwrap();
+    }
+
+    fn max_entries(&self) -> usize {
+        max_entries(self.value_size)
+    }
+
+    fn key_offset(&self, idx: usize) -> usize {
+        SIMPLE_HEADER_SIZE + idx * std::mem::size_of::<Key>()
+    }
+
+    fn value_offset(&self, idx: usize) -> usize {
+        self.key_offset(self.max_entries()) + idx * self.value_size
+    }
+
+    fn check_value(&self, value: &[u8]) -> Result<()> {
+        if value.len() != self.value_size {
+            return Err(anyhow!(
+                "node {}: value is {} bytes, expected {}",
+                self.loc,
+                value.len(),
+                self.value_size
+            ));
+        }
+        Ok(())
+    }
+
+    fn set_entry(&mut self, idx: usize, key: Key, value: &[u8]) -> Result<()> {
+        self.check_value(value)?;
+        let k_off = self.key_offset(idx);
+        let v_off = self.value_offset(idx);
+        let data = self.data.rw();
+        data[k_off..k_off + 8].copy_from_slice(&key.to_le_bytes());
+        data[v_off..v_off + value.len()].copy_from_slice(value);
+        Ok(())
+    }
+
+    // Moves entries [b, nr_entries) to start at idx dest.
+    fn move_entries(&mut self, b: usize, dest: usize) {
+        let nr_entries = self.get_nr_entries();
+        let (kb, ke, kd) = (
+            self.key_offset(b),
+            self.key_offset(nr_entries),
+            self.key_offset(dest),
+        );
+        let (vb, ve, vd) = (
+            self.value_offset(b),
+            self.value_offset(nr_entries),
+            self.value_offset(dest),
+        );
+
+        let data = self.data.rw();
+        data.copy_within(kb..ke, kd);
+        data.copy_within(vb..ve, vd);
+    }
+
+    fn check_space(&self, count: usize) -> Result<()> {
+        if self.get_nr_entries() + count > self.max_entries() {
+            return Err(anyhow!("node {}: no space for {} entries", self.loc, count));
+        }
+        Ok(())
+    }
+}
+
+impl<Data: Writeable> ReplayableNode for SimpleNodeReplay<Data> {
+    fn get_kind(&self) -> u16 {
+        let mut r = &self.data.r()[KIND_OFFSET..];
+        r.read_u16::<LittleEndian>().unwrap()
+    }
+
+    fn get_loc(&self) -> u32 {
+        self.loc
+    }
+
+    fn get_seq_nr(&self) -> u32 {
+        self.read_u32(SEQ_NR_OFFSET)
+    }
+
+    fn apply_overwrite(&mut self, idx: u32, key: Key, value: &[u8]) -> Result<()> {
+        if idx as usize >= self.get_nr_entries() {
+            return Err(anyhow!("node {}: overwrite of idx {}", self.loc, idx));
+        }
+        self.set_entry(idx as usize, key, value)
+    }
+
+    fn apply_insert(&mut self, idx: u32, key: Key, value: &[u8]) -> Result<()> {
+        let idx = idx as usize;
+        let nr_entries = self.get_nr_entries();
+        if idx > nr_entries {
+            return Err(anyhow!("node {}: insert at idx {}", self.loc, idx));
+        }
+        self.check_space(1)?;
+        self.check_value(value)?;
+
+        self.move_entries(idx, idx + 1);
+        self.set_nr_entries(nr_entries + 1);
+        self.set_entry(idx, key, value)
+    }
+
+    fn apply_prepend(&mut self, keys: &[Key], values: &[Vec<u8>]) -> Result<()> {
+        let count = keys.len();
+        self.check_space(count)?;
+        for v in values {
+            self.check_value(v)?;
+        }
+
+        self.move_entries(0, count);
+        self.set_nr_entries(self.get_nr_entries() + count);
+        for (i, (k, v)) in keys.iter().zip(values).enumerate() {
+            self.set_entry(i, *k, v)?;
+        }
+        Ok(())
+    }
+
+    fn apply_append(&mut self, keys: &[Key], values: &[Vec<u8>]) -> Result<()> {
+        self.check_space(keys.len())?;
+        for v in values {
+            self.check_value(v)?;
+        }
+
+        let nr_entries = self.get_nr_entries();
+        self.set_nr_entries(nr_entries + keys.len());
+        for (i, (k, v)) in keys.iter().zip(values).enumerate() {
+            self.set_entry(nr_entries + i, *k, v)?;
+        }
+        Ok(())
+    }
+
+    fn apply_erase(&mut self, idx_b: u32, idx_e: u32) -> Result<()> {
+        let (b, e) = (idx_b as usize, idx_e as usize);
+        let nr_entries = self.get_nr_entries();
+        if b > e || e > nr_entries {
+            return Err(anyhow!(
+                "node {}: erase of [{}, {}) from {} entries",
+                self.loc,
+                b,
+                e,
+                nr_entries
+            ));
+        }
+
+        self.move_entries(e, b);
+        self.set_nr_entries(nr_entries - (e - b));
+        Ok(())
+    }
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use thinp::io_engine::*;
+
+    use crate::core::CoreIoEngine;
+    use crate::thin::mapping::Mapping;
+
+    fn mk_cache() -> Result<Arc<BlockCache>> {
+        let engine: Arc<dyn IoEngine> = Arc::new(CoreIoEngine::new(16));
+        Ok(Arc::new(BlockCache::new(engine, 16)?))
+    }
+
+    #[test]
+    fn test_value_size_recorded() -> Result<()> {
+        let cache = mk_cache()?;
+        let data = cache.zero_lock(0)?;
+        SimpleNode::<Mapping, ExclusiveProxy>::init(0, data.clone(), true)?;
+        let node = SimpleNode::<Mapping, ExclusiveProxy>::open(0, data.clone())?;
+        let mut r = &data.r()[VALUE_SIZE_OFFSET..];
+        assert_eq!(
+            r.read_u16::<LittleEndian>()? as usize,
+            Mapping::packed_len()
+        );
+        assert_eq!(node.max_entries, 145);
+
+        // The layout depends on the value size, so a node can't be opened
+        // with the wrong value type.
+        assert!(SimpleNode::<u64, ExclusiveProxy>::open(0, data).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_old_format() -> Result<()> {
+        // An old format node, laid out by hand.
+        let cache = mk_cache()?;
+        let mut data = cache.zero_lock(0)?;
+        let hdr = NodeHeader {
+            seq_nr: 0,
+            snap_time: 0,
+            flags: BTreeFlags::Leaf,
+            kind: OLD_SIMPLE_NODE_KIND,
+            nr_entries: 2,
+        };
+        write_node_header(&mut std::io::Cursor::new(data.rw()), &hdr)?;
+
+        let max = old_max_entries::<Mapping>();
+        assert_eq!(max, 127);
+        let values_offset = NODE_HEADER_SIZE + max * 8;
+        let m = Mapping {
+            b: 100,
+            e: 110,
+            snap_time: 3,
+        };
+        for i in 0..2 {
+            let k_off = NODE_HEADER_SIZE + i * 8;
+            data.rw()[k_off..k_off + 8].copy_from_slice(&(i as u64 * 10).to_le_bytes());
+            let v_off = values_offset + i * Mapping::packed_len();
+            m.pack(&mut &mut data.rw()[v_off..v_off + Mapping::packed_len()])?;
+        }
+
+        // The in core size of the values says where they start.
+        let mut node = SimpleNode::<Mapping, ExclusiveProxy>::open(0, data.clone())?;
+        assert_eq!(node.max_entries, max);
+        assert_eq!(node.get_entries(0, 2), (vec![0, 10], vec![m, m]));
+
+        // It can be changed in place, and keeps its kind.
+        assert!(matches!(node.insert(1, 5, &m), NodeInsertOutcome::Success));
+        let node = SimpleNode::<Mapping, ExclusiveProxy>::open(0, data.clone())?;
+        assert_eq!(node.get_entries(0, 3), (vec![0, 5, 10], vec![m, m, m]));
+        assert_eq!(node.kind.get(), OLD_SIMPLE_NODE_KIND);
+
+        // Replay can't tell where the values are.
+        assert!(SimpleNodeReplay::new(0, data).is_err());
+        Ok(())
+    }
+}
@@ -16,6 +16,9 @@ where
     fn select_geq(&self, k_old: Key, k_new: Key) -> Option<(Key, Self)>;
     fn select_lt(&self, k_old: Key, k_new: Key) -> Option<(Key, Self)>;
     fn merge(&self, rhs: &Self) -> Option<Self>;
+
+    /// The nr of keys covered by the value.
+    fn range_len(&self) -> Key;
 }
 
 //-------------------------------------------------------------------------
@@ -1,4 +1,3 @@
-use anyhow::anyhow;
 use anyhow::Result;
 
 use crate::block_cache::*;
@@ -7,10 +6,86 @@ use crate::btree::nodes::journal::*;
 use crate::btree::range_value::RangeValue;
 use crate::btree::transaction_manager::*;
 use crate::btree::BTree;
+use crate::journal::batch::BatchContext;
 use crate::packed_array::*;
 
 //-------------------------------------------------------------------------
 
+// Moves all the entries of right into left if they fit, returning true if
+// so.  Otherwise moves entries from the fuller node into the other until
+// they have roughly the same nr of entries.  Left and right are adjacent
+// siblings.  If the entries can't be erased from the fuller node they're
+// left where they are.
+pub(crate) fn merge_or_borrow<V: Serializable, N: NodeW<V, ExclusiveProxy>>(
+    left: &mut N,
+    right: &mut N,
+) -> Result<bool> {
+    use NodeInsertOutcome::*;
+
+    let nr_left = left.nr_entries();
+    let (keys, values) = right.get_entries(0, right.nr_entries());
+    if let Success = left.append(&keys, &values) {
+        // Erasing every entry always has space.
+        right
+            .erase(0, right.nr_entries())
+            .into_result(right.n_ptr().loc)?;
+        return Ok(true);
+    }
+
+    // Nodes that aren't a fixed size may not take as many entries as we'd
+    // like, so we back off until they fit.  Undoing a move only erases the
+    // entries just added, so there's room for it.
+    let nr_right = right.nr_entries();
+    if left.used_space() < right.used_space() {
+        let mut count = nr_right.saturating_sub(nr_left) / 2;
+        while count > 0 {
+            let (keys, values) = right.get_entries(0, count);
+            if let Success = left.append(&keys, &values) {
+                if let NoSpace = right.erase(0, count) {
+                    left.erase(nr_left, nr_left + count)
+                        .into_result(left.n_ptr().loc)?;
+                }
+                break;
+            }
+            count /= 2;
+        }
+    } else {
+        let mut count = nr_left.saturating_sub(nr_right) / 2;
+        while count > 0 {
+            let (keys, values) = left.get_entries(nr_left - count, nr_left);
+            if let Success = right.prepend(&keys, &values) {
+                if let NoSpace = left.erase(nr_left - count, nr_left) {
+                    right.erase(0, count).into_result(right.n_ptr().loc)?;
+                }
+                break;
+            }
+            count /= 2;
+        }
+    }
+    Ok(false)
+}
+
+// Points entry idx of an internal node at the node given, if it doesn't
+// already.  Rebalancing has no way to split the parent, so internal nodes
+// must be a format that always has room for an overwrite, eg, SimpleNode.
+fn update_child<
+    NV: Serializable,
+    C: NodeR<NV, ExclusiveProxy>,
+    N: NodeW<NodePtr, ExclusiveProxy>,
+>(
+    parent: &mut N,
+    idx: usize,
+    child: &C,
+) -> Result<()> {
+    let key = child.get_key(0);
+    let n_ptr = child.n_ptr();
+    if parent.get_key(idx) != key || parent.get_value(idx) != n_ptr {
+        let loc = parent.n_ptr().loc;
+        parent.overwrite(idx, key, &n_ptr).into_result(loc)?;
+    }
+    Ok(())
+}
+
 impl<
         V: Serializable + Copy,
         INodeR: NodeR<NodePtr, SharedProxy>,
@@ -19,8 +94,160 @@ impl<
         LNodeW: NodeW<V, ExclusiveProxy>,
     > BTree<V, INodeR, INodeW, LNodeR, LNodeW>
 {
-    fn remove_internal(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
-        let mut node = self.tm.shadow::<NodePtr, INodeW>(n_ptr, self.snap_time)?;
+    // Merges, or rebalances, the children at idx and idx + 1.  Returns
+    // true if they were merged.
+    pub(crate) fn rebalance_pair<NV: Serializable, N: NodeW<NV, ExclusiveProxy>>(
+        &mut self,
+        ctx: &BatchContext,
+        parent: &mut JournalNode<INodeW, NodePtr, ExclusiveProxy>,
+        idx: usize,
+    ) -> Result<bool> {
+        let mut left = self.tm.shadow::<NV, N>(
+            ctx,
+            self.local_alloc(),
+            parent.get_value(idx),
+            self.snap_time,
+        )?;
+        let mut right = self.tm.shadow::<NV, N>(
+            ctx,
+            self.local_alloc(),
+            parent.get_value(idx + 1),
+            self.snap_time,
+        )?;
+
+        let merged = merge_or_borrow(&mut left, &mut right)?;
+        if merged {
+            let loc = parent.n_ptr().loc;
+            parent.remove_at(idx + 1).into_result(loc)?;
+            if left.is_empty() {
+                parent.remove_at(idx).into_result(loc)?;
+            } else {
+                update_child(parent, idx, &left)?;
+            }
+        } else {
+            update_child(parent, idx, &left)?;
+            update_child(parent, idx + 1, &right)?;
+        }
+        Ok(merged)
+    }
+
+    // If the child at idx is underfull, merges it with a sibling, or takes
+    // entries from one.  Returns true if the parent lost an entry.
+    fn rebalance_child(
+        &mut self,
+        ctx: &BatchContext,
+        parent: &mut JournalNode<INodeW, NodePtr, ExclusiveProxy>,
+        idx: usize,
+    ) -> Result<bool> {
+        if parent.nr_entries() < 2 {
+            return Ok(false);
+        }
+
+        let child = parent.get_value(idx);
+        let is_internal = self.tm.is_internal(child)?;
+        let underfull = if is_internal {
+            self.tm.read::<NodePtr, INodeR>(child)?.is_underfull()
+        } else {
+            self.tm.read::<V, LNodeR>(child)?.is_underfull()
+        };
+        if !underfull {
+            return Ok(false);
+        }
+
+        let left = if idx + 1 < parent.nr_entries() {
+            idx
+        } else {
+            idx - 1
+        };
+
+        if !is_internal {
+            return self.rebalance_pair::<V, LNodeW>(ctx, parent, left);
+        }
+
+        // Moving entries between internal nodes can leave an underfull
+        // grandchild next to new siblings, eg, the only child of the
+        // underfull node, so we check them too.
+        let merged = self.rebalance_pair::<NodePtr, INodeW>(ctx, parent, left)?;
+        let nr = if merged { 1 } else { 2 };
+        for i in left..(left + nr).min(parent.nr_entries()) {
+            let mut node = self.tm.shadow::<NodePtr, INodeW>(
+                ctx,
+                self.local_alloc(),
+                parent.get_value(i),
+                self.snap_time,
+            )?;
+            self.rebalance_children(ctx, &mut node)?;
+            update_child(parent, i, &node)?;
+        }
+        Ok(merged)
+    }
+
+    pub(crate) fn rebalance_children(
+        &mut self,
+        ctx: &BatchContext,
+        node: &mut JournalNode<INodeW, NodePtr, ExclusiveProxy>,
+    ) -> Result<()> {
+        let mut idx = 0;
+        while idx < node.nr_entries() {
+            if !self.rebalance_child(ctx, node, idx)? {
+                idx += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Rebalances the child whose first key is key, if it's still there.
+    pub(crate) fn rebalance_key(
+        &mut self,
+        ctx: &BatchContext,
+        parent: &mut JournalNode<INodeW, NodePtr, ExclusiveProxy>,
+        key: Key,
+    ) -> Result<()> {
+        let idx = parent.lower_bound(key);
+        if idx >= 0 && parent.get_key(idx as usize) == key {
+            self.rebalance_child(ctx, parent, idx as usize)?;
+        }
+        Ok(())
+    }
+
+    // The first key of a child that has had entries removed, if it
+    // still has any.
+    pub(crate) fn shrunk_child(res: &NodeResult) -> Option<Key> {
+        match res {
+            NodeResult::Single(NodeInfo { key_min, .. }) => *key_min,
+            _ => None,
+        }
+    }
+
+    // Removes internal roots with a single child, so the tree gets
+    // shorter as entries are removed.  An internal root with no children
+    // is replaced by an empty leaf.
+    pub(crate) fn shrink_root(&self, ctx: &BatchContext, mut root: NodePtr) -> Result<NodePtr> {
+        while self.tm.is_internal(root)? {
+            let node: INodeR = self.tm.read(root)?;
+            match node.nr_entries() {
+                0 => {
+                    let leaf: JournalNode<LNodeW, V, ExclusiveProxy> =
+                        self.tm
+                            .new_node(ctx, self.local_alloc(), true, self.snap_time)?;
+                    return Ok(leaf.n_ptr());
+                }
+                1 => root = node.get_value(0),
+                _ => break,
+            }
+        }
+        Ok(root)
+    }
+
+    fn remove_internal(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+    ) -> Result<NodeResult> {
+        let mut node =
+            self.tm
+                .shadow::<NodePtr, INodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
 
         let mut idx = node.lower_bound(key);
         if idx < 0 {
@@ -34,50 +261,64 @@ impl<
         let idx = idx as usize;
 
         let child = node.get_value(idx);
-        let res = self.remove_recurse(child, key)?;
-        self.node_insert_result(&mut node, idx, &res)
+        let res = self.remove_recurse(ctx, child, key)?;
+        let r = self.node_insert_result(&mut node, idx, &res)?;
+        match (r, Self::shrunk_child(&res)) {
+            (NodeResult::Single(_), Some(k)) => {
+                self.rebalance_key(ctx, &mut node, k)?;
+                Ok(NodeResult::single(&node))
+            }
+            (r, _) => Ok(r),
+        }
     }
 
-    fn remove_leaf(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
-        let mut node = self.tm.shadow::<V, LNodeW>(n_ptr, 0)?;
+    fn remove_leaf(&mut self, ctx: &BatchContext, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
+        let mut node =
+            self.tm
+                .shadow::<V, LNodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
 
         let idx = node.lower_bound(key);
         if (idx >= 0) && ((idx as usize) < node.nr_entries()) {
             let idx = idx as usize;
             if node.get_key(idx) == key {
-                node.remove_at(idx);
+                if let NodeInsertOutcome::NoSpace = node.remove_at(idx) {
+                    return self.split(&mut node);
+                }
             }
         }
         Ok(NodeResult::single(&node))
     }
 
-    fn remove_recurse(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
+    fn remove_recurse(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+    ) -> Result<NodeResult> {
         if self.tm.is_internal(n_ptr)? {
-            self.remove_internal(n_ptr, key)
+            self.remove_internal(ctx, n_ptr, key)
         } else {
-            self.remove_leaf(n_ptr, key)
+            self.remove_leaf(ctx, n_ptr, key)
         }
     }
 
-    pub fn remove_(&mut self, key: Key) -> Result<NodePtr> {
+    // A node that had to split may not have finished the remove, so we
+    // repeat it until it goes through without a Retry.
+    pub fn remove_(&mut self, ctx: &BatchContext, key: Key) -> Result<NodePtr> {
         use NodeResult::*;
 
-        match self.remove_recurse(self.root, key)? {
-            Single(NodeInfo { n_ptr, .. }) => Ok(n_ptr),
-            Pair(left, right) => {
-                let mut parent: JournalNode<INodeW, NodePtr, ExclusiveProxy> =
-                    self.tm.new_node(false)?;
-                parent.append(
-                    &[left.key_min.unwrap(), right.key_min.unwrap()],
-                    &[left.n_ptr, right.n_ptr],
-                );
-                Ok(parent.n_ptr())
+        let mut root = self.root;
+        loop {
+            match self.remove_recurse(ctx, root, key)? {
+                Single(NodeInfo { n_ptr, .. }) => return self.shrink_root(ctx, n_ptr),
+                Pair(left, right) => return self.grow_root(ctx, &left, &right),
+                Retry(left, right) => root = self.retry_root(ctx, &left, right.as_ref())?,
             }
         }
     }
 
-    pub fn remove(&mut self, key: Key) -> Result<()> {
-        let root = self.remove_(key)?;
+    pub fn remove(&mut self, ctx: &BatchContext, key: Key) -> Result<()> {
+        let root = self.remove_(ctx, key)?;
         self.root = root;
         Ok(())
     }
@@ -124,13 +365,21 @@ impl<
         LNodeW: NodeW<V, ExclusiveProxy>,
     > BTree<V, INodeR, INodeW, LNodeR, LNodeW>
 {
-    fn remove_lt_internal(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
+    fn remove_lt_internal(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+    ) -> Result<NodeResult> {
         use NodeOp::*;
 
-        let mut node = self.tm.shadow::<NodePtr, INodeW>(n_ptr, self.snap_time)?;
+        let mut node =
+            self.tm
+                .shadow::<NodePtr, INodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
         let prog = lt_prog(&node, key);
 
         let mut delta = 0;
+        let mut shrunk = None;
         for op in prog {
             match op {
                 Recurse(_) => {
@@ -138,28 +387,46 @@ impl<
                 }
                 TrimLt(idx) => {
                     let idx = idx - delta;
-                    let res = self.remove_lt_recurse(node.get_value(idx), key)?;
-
-                    // remove_lt cannot cause a Pair result, so we don't need to preserve the result
-                    self.node_insert_result(&mut node, idx, &res)?;
+                    let res = self.remove_lt_recurse(ctx, node.get_value(idx), key)?;
+
+                    // Anything but a Single means a node had to split, and
+                    // the op will be repeated.
+                    if let r @ (NodeResult::Pair(..) | NodeResult::Retry(..)) =
+                        self.node_insert_result(&mut node, idx, &res)?
+                    {
+                        return Ok(r.retry());
+                    }
+                    shrunk = Self::shrunk_child(&res);
                 }
                 TrimGeq(_) => {
                     panic!("unexpected trim geq");
                 }
                 Erase(idx_b, idx_e) => {
-                    node.erase(idx_b - delta, idx_e - delta);
+                    if let NodeInsertOutcome::NoSpace = node.erase(idx_b - delta, idx_e - delta) {
+                        return self.split(&mut node);
+                    }
                     delta += idx_e - idx_b;
                 }
             }
         }
 
+        if let Some(k) = shrunk {
+            self.rebalance_key(ctx, &mut node, k)?;
+        }
         Ok(NodeResult::single(&node))
     }
 
-    fn remove_lt_leaf(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
+    fn remove_lt_leaf(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+    ) -> Result<NodeResult> {
         use NodeOp::*;
 
-        let mut node = self.tm.shadow::<V, LNodeW>(n_ptr, self.snap_time)?;
+        let mut node =
+            self.tm
+                .shadow::<V, LNodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
         let prog = lt_prog(&node, key);
 
         let mut delta = 0;
@@ -168,19 +435,23 @@ impl<
                 Recurse(_) => {
                     panic!("unexpected recurse");
                 }
-                TrimLt(idx) => match node.get_value(idx).select_geq(node.get_key(idx), key) {
-                    None => {
-                        node.remove_at(idx);
-                    }
-                    Some((new_key, new_value)) => {
-                        node.overwrite(idx, new_key, &new_value);
+                TrimLt(idx) => {
+                    let idx = idx - delta;
+                    let r = match node.get_value(idx).select_geq(node.get_key(idx), key) {
+                        None => node.remove_at(idx),
+                        Some((new_key, new_value)) => node.overwrite(idx, new_key, &new_value),
+                    };
+                    if let NodeInsertOutcome::NoSpace = r {
+                        return self.split(&mut node);
                     }
-                },
+                }
                 TrimGeq(_) => {
                     panic!("unexpected trim geq");
                 }
                 Erase(idx_b, idx_e) => {
-                    node.erase(idx_b - delta, idx_e - delta);
+                    if let NodeInsertOutcome::NoSpace = node.erase(idx_b - delta, idx_e - delta) {
+                        return self.split(&mut node);
+                    }
                     delta += idx_e - idx_b;
                 }
             }
@@ -189,23 +460,36 @@ impl<
         Ok(NodeResult::single(&node))
     }
 
-    pub fn remove_lt_recurse(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
+    pub fn remove_lt_recurse(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+    ) -> Result<NodeResult> {
         if self.tm.is_internal(n_ptr)? {
-            self.remove_lt_internal(n_ptr, key)
+            self.remove_lt_internal(ctx, n_ptr, key)
         } else {
-            self.remove_lt_leaf(n_ptr, key)
+            self.remove_lt_leaf(ctx, n_ptr, key)
         }
     }
 
-    fn remove_lt_(&mut self, root: NodePtr, key: Key) -> Result<NodePtr> {
-        match self.remove_lt_recurse(root, key)? {
-            NodeResult::Single(NodeInfo { n_ptr, .. }) => Ok(n_ptr),
-            NodeResult::Pair(_, _) => Err(anyhow!("remove_lt increase nr entries somehow")),
+    // remove_lt only grows the tree if a node has to split, in which case
+    // it gives a Retry and we repeat it, as with remove_().
+    fn remove_lt_(&mut self, ctx: &BatchContext, root: NodePtr, key: Key) -> Result<NodePtr> {
+        let mut root = root;
+        loop {
+            match self.remove_lt_recurse(ctx, root, key)? {
+                NodeResult::Single(NodeInfo { n_ptr, .. }) => return self.shrink_root(ctx, n_ptr),
+                NodeResult::Pair(left, right) => return self.grow_root(ctx, &left, &right),
+                NodeResult::Retry(left, right) => {
+                    root = self.retry_root(ctx, &left, right.as_ref())?
+                }
+            }
         }
     }
 
-    pub fn remove_lt(&mut self, key: Key) -> Result<()> {
-        self.root = self.remove_lt_(self.root, key)?;
+    pub fn remove_lt(&mut self, ctx: &BatchContext, key: Key) -> Result<()> {
+        self.root = self.remove_lt_(ctx, self.root, key)?;
         Ok(())
     }
 }
@@ -246,13 +530,21 @@ impl<
         LNodeW: NodeW<V, ExclusiveProxy>,
     > BTree<V, INodeR, INodeW, LNodeR, LNodeW>
 {
-    fn remove_geq_internal(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
+    fn remove_geq_internal(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+    ) -> Result<NodeResult> {
         use NodeOp::*;
 
-        let mut node = self.tm.shadow::<NodePtr, INodeW>(n_ptr, self.snap_time)?;
+        let mut node =
+            self.tm
+                .shadow::<NodePtr, INodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
         let prog = geq_prog(&node, key);
 
         let mut delta = 0;
+        let mut shrunk = None;
         for op in prog {
             match op {
                 Recurse(_) => {
@@ -263,25 +555,43 @@ impl<
                 }
                 TrimGeq(idx) => {
                     let idx = idx - delta;
-                    let res = self.remove_geq_recurse(node.get_value(idx), key)?;
-
-                    // remove_geq cannot cause a Pair result, so this can't split node.
-                    self.node_insert_result(&mut node, idx, &res)?;
+                    let res = self.remove_geq_recurse(ctx, node.get_value(idx), key)?;
+
+                    // Anything but a Single means a node had to split, and
+                    // the op will be repeated.
+                    if let r @ (NodeResult::Pair(..) | NodeResult::Retry(..)) =
+                        self.node_insert_result(&mut node, idx, &res)?
+                    {
+                        return Ok(r.retry());
+                    }
+                    shrunk = Self::shrunk_child(&res);
                 }
                 Erase(idx_b, idx_e) => {
-                    node.erase(idx_b - delta, idx_e - delta);
+                    if let NodeInsertOutcome::NoSpace = node.erase(idx_b - delta, idx_e - delta) {
+                        return self.split(&mut node);
+                    }
                     delta += idx_e - idx_b;
                 }
             }
         }
 
+        if let Some(k) = shrunk {
+            self.rebalance_key(ctx, &mut node, k)?;
+        }
         Ok(NodeResult::single(&node))
     }
 
-    fn remove_geq_leaf(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
+    fn remove_geq_leaf(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+    ) -> Result<NodeResult> {
         use NodeOp::*;
 
-        let mut node = self.tm.shadow::<V, LNodeW>(n_ptr, self.snap_time)?;
+        let mut node =
+            self.tm
+                .shadow::<V, LNodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
         let prog = geq_prog(&node, key);
 
         let mut delta = 0;
@@ -293,16 +603,19 @@ impl<
                 TrimLt(_) => {
                     panic!("unexpected trim lt");
                 }
-                TrimGeq(idx) => match node.get_value(idx).select_lt(node.get_key(idx), key) {
-                    None => {
-                        node.remove_at(idx);
-                    }
-                    Some((new_key, new_value)) => {
-                        node.overwrite(idx, new_key, &new_value);
+                TrimGeq(idx) => {
+                    let r = match node.get_value(idx).select_lt(node.get_key(idx), key) {
+                        None => node.remove_at(idx),
+                        Some((new_key, new_value)) => node.overwrite(idx, new_key, &new_value),
+                    };
+                    if let NodeInsertOutcome::NoSpace = r {
+                        return self.split(&mut node);
                     }
-                },
+                }
                 Erase(idx_b, idx_e) => {
-                    node.erase(idx_b - delta, idx_e - delta);
+                    if let NodeInsertOutcome::NoSpace = node.erase(idx_b - delta, idx_e - delta) {
+                        return self.split(&mut node);
+                    }
                     delta += idx_e - idx_b;
                 }
             }
@@ -311,23 +624,35 @@ impl<
         Ok(NodeResult::single(&node))
     }
 
-    fn remove_geq_recurse(&mut self, n_ptr: NodePtr, key: Key) -> Result<NodeResult> {
+    fn remove_geq_recurse(
+        &mut self,
+        ctx: &BatchContext,
+        n_ptr: NodePtr,
+        key: Key,
+    ) -> Result<NodeResult> {
         if self.tm.is_internal(n_ptr)? {
-            self.remove_geq_internal(n_ptr, key)
+            self.remove_geq_internal(ctx, n_ptr, key)
         } else {
-            self.remove_geq_leaf(n_ptr, key)
+            self.remove_geq_leaf(ctx, n_ptr, key)
         }
     }
 
-    fn remove_geq_(&mut self, root: NodePtr, key: Key) -> Result<NodePtr> {
-        match self.remove_geq_recurse(root, key)? {
-            NodeResult::Single(NodeInfo { n_ptr, .. }) => Ok(n_ptr),
-            NodeResult::Pair(_, _) => Err(anyhow!("remove_geq increased nr of entries")),
+    // As with remove_lt_(), a Retry means a node had to split.
+    fn remove_geq_(&mut self, ctx: &BatchContext, root: NodePtr, key: Key) -> Result<NodePtr> {
+        let mut root = root;
+        loop {
+            match self.remove_geq_recurse(ctx, root, key)? {
+                NodeResult::Single(NodeInfo { n_ptr, .. }) => return self.shrink_root(ctx, n_ptr),
+                NodeResult::Pair(left, right) => return self.grow_root(ctx, &left, &right),
+                NodeResult::Retry(left, right) => {
+                    root = self.retry_root(ctx, &left, right.as_ref())?
+                }
+            }
         }
     }
 
-    pub fn remove_geq(&mut self, key: Key) -> Result<()> {
-        self.root = self.remove_geq_(self.root, key)?;
+    pub fn remove_geq(&mut self, ctx: &BatchContext, key: Key) -> Result<()> {
+        self.root = self.remove_geq_(ctx, self.root, key)?;
         Ok(())
     }
 }
@@ -426,55 +751,90 @@ impl<
 {
     fn remove_range_internal(
         &mut self,
+        ctx: &BatchContext,
         n_ptr: NodePtr,
         key_begin: Key,
         key_end: Key,
     ) -> Result<NodeResult> {
         use NodeOp::*;
 
-        let mut node = self.tm.shadow::<NodePtr, INodeW>(n_ptr, self.snap_time)?;
+        let mut node =
+            self.tm
+                .shadow::<NodePtr, INodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
         let prog = range_split(&node, key_begin, key_end);
         let prog_len = prog.len();
 
         let mut delta = 0;
+        let mut shrunk = Vec::new();
         for op in prog {
             match op {
                 Recurse(idx) => {
                     assert!(prog_len == 1);
-                    return self.remove_range_recurse(node.get_value(idx), key_begin, key_end);
+
+                    // The child may have been shadowed, or split.
+                    let res =
+                        self.remove_range_recurse(ctx, node.get_value(idx), key_begin, key_end)?;
+                    let r = self.node_insert_result(&mut node, idx, &res)?;
+                    return match (r, Self::shrunk_child(&res)) {
+                        (NodeResult::Single(_), Some(k)) => {
+                            self.rebalance_key(ctx, &mut node, k)?;
+                            Ok(NodeResult::single(&node))
+                        }
+                        (r, _) => Ok(r),
+                    };
                 }
 
-                // The rest of the ops are guaranteed to return a Single, so we don't need
-                // to do anything fancy aggregating them.
+                // The rest of the ops only give something other than a
+                // Single if a node had to split, in which case we give up
+                // and the op is repeated.
                 TrimLt(idx) => {
                     let idx = idx - delta;
 
-                    let res = self.remove_lt_recurse(node.get_value(idx), key_end)?;
-                    self.node_insert_result(&mut node, idx, &res)?;
+                    let res = self.remove_lt_recurse(ctx, node.get_value(idx), key_end)?;
+                    if let r @ (NodeResult::Pair(..) | NodeResult::Retry(..)) =
+                        self.node_insert_result(&mut node, idx, &res)?
+                    {
+                        return Ok(r.retry());
+                    }
+                    shrunk.extend(Self::shrunk_child(&res));
                 }
                 TrimGeq(idx) => {
                     let idx = idx - delta;
-                    let res = self.remove_geq_recurse(node.get_value(idx), key_begin)?;
-                    self.node_insert_result(&mut node, idx, &res)?;
+                    let res = self.remove_geq_recurse(ctx, node.get_value(idx), key_begin)?;
+                    if let r @ (NodeResult::Pair(..) | NodeResult::Retry(..)) =
+                        self.node_insert_result(&mut node, idx, &res)?
+                    {
+                        return Ok(r.retry());
+                    }
+                    shrunk.extend(Self::shrunk_child(&res));
                 }
                 Erase(idx_b, idx_e) => {
-                    node.erase(idx_b - delta, idx_e - delta);
+                    if let NodeInsertOutcome::NoSpace = node.erase(idx_b - delta, idx_e - delta) {
+                        return self.split(&mut node);
+                    }
                     delta += idx_e - idx_b;
                 }
             }
         }
+
+        for k in shrunk {
+            self.rebalance_key(ctx, &mut node, k)?;
+        }
         Ok(NodeResult::single(&node))
     }
 
     fn remove_range_leaf(
         &mut self,
+        ctx: &BatchContext,
         n_ptr: NodePtr,
         key_begin: Key,
         key_end: Key,
     ) -> Result<NodeResult> {
         use NodeOp::*;
 
-        let mut node = self.tm.shadow::<V, LNodeW>(n_ptr, self.snap_time)?;
+        let mut node =
+            self.tm
+                .shadow::<V, LNodeW>(ctx, self.local_alloc(), n_ptr, self.snap_time)?;
         let prog = range_split(&node, key_begin, key_end);
         let prog_len = prog.len();
 
@@ -488,51 +848,53 @@ impl<
                     // So we'll have to split it in two.
                     let k = node.get_key(idx);
                     let v = node.get_value(idx);
-                    match (v.select_lt(k, key_begin), v.select_geq(k, key_end)) {
-                        (None, None) => {
-                            node.remove_at(idx);
-                            return Ok(NodeResult::single(&node));
-                        }
-                        (Some((k, v)), None) => {
-                            node.overwrite(idx, k, &v);
-                            return Ok(NodeResult::single(&node));
-                        }
-                        (None, Some((k, v))) => {
-                            node.overwrite(idx, k, &v);
-                            return Ok(NodeResult::single(&node));
-                        }
+                    let r = match (v.select_lt(k, key_begin), v.select_geq(k, key_end)) {
+                        (None, None) => node.remove_at(idx),
+                        (Some((k, v)), None) => node.overwrite(idx, k, &v),
+                        (None, Some((k, v))) => node.overwrite(idx, k, &v),
                         (Some((k1, v1)), Some((k2, v2))) => {
-                            node.overwrite(idx, k1, &v1);
-                            return ensure_space(self.tm.as_ref(), &mut node, idx, |node, idx| {
-                                node.insert(idx + 1, k2, &v2)
-                            });
+                            if let NodeInsertOutcome::NoSpace = node.overwrite(idx, k1, &v1) {
+                                return self.split(&mut node);
+                            }
+                            return ensure_space(
+                                self.tm.as_ref(),
+                                self.local_alloc(),
+                                self.snap_time,
+                                &mut node,
+                                idx,
+                                |node, idx| node.insert(idx + 1, k2, &v2),
+                            );
                         }
-                    }
+                    };
+                    return match r {
+                        NodeInsertOutcome::Success => Ok(NodeResult::single(&node)),
+                        NodeInsertOutcome::NoSpace => self.split(&mut node),
+                    };
                 }
                 TrimLt(idx) => {
                     let idx = idx - delta;
-                    match node.get_value(idx).select_geq(node.get_key(idx), key_end) {
-                        None => {
-                            node.remove_at(idx);
-                        }
-                        Some((new_key, v)) => {
-                            node.overwrite(idx, new_key, &v);
-                        }
+                    let r = match node.get_value(idx).select_geq(node.get_key(idx), key_end) {
+                        None => node.remove_at(idx),
+                        Some((new_key, v)) => node.overwrite(idx, new_key, &v),
+                    };
+                    if let NodeInsertOutcome::NoSpace = r {
+                        return self.split(&mut node);
                     }
                 }
                 TrimGeq(idx) => {
                     let idx = idx - delta;
-                    match node.get_value(idx).select_lt(node.get_key(idx), key_begin) {
-                        None => {
-                            node.remove_at(idx);
-                        }
-                        Some((new_key, v)) => {
-                            node.overwrite(idx, new_key, &v);
-                        }
+                    let r = match node.get_value(idx).select_lt(node.get_key(idx), key_begin) {
+                        None => node.remove_at(idx),
+                        Some((new_key, v)) => node.overwrite(idx, new_key, &v),
+                    };
+                    if let NodeInsertOutcome::NoSpace = r {
+                        return self.split(&mut node);
                     }
                 }
                 Erase(idx_b, idx_e) => {
-                    node.erase(idx_b - delta, idx_e - delta);
+                    if let NodeInsertOutcome::NoSpace = node.erase(idx_b - delta, idx_e - delta) {
+                        return self.split(&mut node);
+                    }
                     delta += idx_e - idx_b;
                 }
             }
@@ -543,41 +905,40 @@ impl<
 
     fn remove_range_recurse(
         &mut self,
+        ctx: &BatchContext,
         n_ptr: NodePtr,
         key_begin: Key,
         key_end: Key,
     ) -> Result<NodeResult> {
         if self.tm.is_internal(n_ptr)? {
-            self.remove_range_internal(n_ptr, key_begin, key_end)
+            self.remove_range_internal(ctx, n_ptr, key_begin, key_end)
         } else {
-            self.remove_range_leaf(n_ptr, key_begin, key_end)
+            self.remove_range_leaf(ctx, n_ptr, key_begin, key_end)
         }
     }
 
     pub fn remove_range_(
         &mut self,
+        ctx: &BatchContext,
         root: NodePtr,
         key_begin: Key,
         key_end: Key,
     ) -> Result<NodePtr> {
         use NodeResult::*;
 
-        match self.remove_range_recurse(root, key_begin, key_end)? {
-            Single(NodeInfo { n_ptr, .. }) => Ok(n_ptr),
-            Pair(left, right) => {
-                let mut parent: JournalNode<INodeW, NodePtr, ExclusiveProxy> =
-                    self.tm.new_node(false)?;
-                parent.append(
-                    &[left.key_min.unwrap(), right.key_min.unwrap()],
-                    &[left.n_ptr, right.n_ptr],
-                );
-                Ok(parent.n_ptr())
+        // As with remove_(), a Retry means the range may not all be gone.
+        let mut root = root;
+        loop {
+            match self.remove_range_recurse(ctx, root, key_begin, key_end)? {
+                Single(NodeInfo { n_ptr, .. }) => return self.shrink_root(ctx, n_ptr),
+                Pair(left, right) => return self.grow_root(ctx, &left, &right),
+                Retry(left, right) => root = self.retry_root(ctx, &left, right.as_ref())?,
             }
         }
     }
 
-    pub fn remove_range(&mut self, key_begin: Key, key_end: Key) -> Result<()> {
-        self.root = self.remove_range_(self.root, key_begin, key_end)?;
+    pub fn remove_range(&mut self, ctx: &BatchContext, key_begin: Key, key_end: Key) -> Result<()> {
+        self.root = self.remove_range_(ctx, self.root, key_begin, key_end)?;
         Ok(())
     }
 }
@@ -6,19 +6,25 @@ mod test {
     use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
     use rand::seq::SliceRandom;
     use rand::Rng;
+    use std::collections::BTreeMap;
     use std::io::{self, Read, Write};
     use std::path::PathBuf;
     use std::sync::{Arc, Mutex};
+    use std::thread;
     use thinp::io_engine::*;
 
+    use crate::allocators::metadata_alloc::*;
     use crate::allocators::*;
     use crate::block_cache::*;
+    use crate::btree::compact::CompactStats;
     use crate::btree::node::*;
     use crate::btree::nodes::simple::*;
     use crate::btree::range_value::RangeValue;
     use crate::btree::transaction_manager::*;
     use crate::btree::BTree;
     use crate::core::*;
+    use crate::journal::batch::BatchContext;
+    use crate::journal::entry::*;
     use crate::journal::*;
     use crate::packed_array::*;
 
@@ -35,7 +41,9 @@ mod test {
 
     impl RangeValue for Value {
         fn select_geq(&self, k_old: Key, k_new: Key) -> Option<(Key, Self)> {
-            if k_old < k_new && k_old + self.len >= k_new {
+            if k_old >= k_new {
+                Some((k_old, *self))
+            } else if k_old + self.len > k_new {
                 Some((
                     k_new,
                     Value {
@@ -44,12 +52,14 @@ mod test {
                     },
                 ))
             } else {
-                Some((k_old, self.clone()))
+                None
             }
         }
 
         fn select_lt(&self, k_old: Key, k_new: Key) -> Option<(Key, Self)> {
-            if k_old + self.len > k_new {
+            if k_old >= k_new {
+                None
+            } else if k_old + self.len > k_new {
                 Some((
                     k_old,
                     Value {
@@ -58,12 +68,25 @@ mod test {
                     },
                 ))
             } else {
-                Some((k_old, self.clone()))
+                Some((k_old, *self))
             }
         }
 
+        // Trimming a value keeps its v, so neighbours with the same v
+        // merge.
         fn merge(&self, rhs: &Self) -> Option<Self> {
-            None
+            if self.v == rhs.v {
+                Some(Value {
+                    v: self.v,
+                    len: self.len + rhs.len,
+                })
+            } else {
+                None
+            }
+        }
+
+        fn range_len(&self) -> Key {
+            self.len
         }
     }
 
@@ -98,6 +121,7 @@ mod test {
         engine: Arc<dyn IoEngine>,
         journal: Arc<Mutex<Journal>>,
         tm: Arc<TransactionManager>,
+        ctx: BatchContext,
         tree: TestTree,
         snap_time: u32,
     }
@@ -108,7 +132,7 @@ mod test {
             assert!(nr_metadata_blocks.count_ones() == 1);
 
             // The batch will be ended in drop()
-            batch::begin_batch()?;
+            let ctx = BatchContext::new();
 
             let journal_path = PathBuf::from("./journal.log");
             let journal = Arc::new(Mutex::new(Journal::create(journal_path)?));
@@ -122,12 +146,13 @@ mod test {
                 metadata_alloc,
                 data_alloc,
             ));
-            let tree = BTree::empty_tree(tm.clone())?;
+            let tree = BTree::empty_tree(tm.clone(), &ctx)?;
 
             Ok(Self {
                 engine,
                 journal,
                 tm,
+                ctx,
                 tree,
                 snap_time: 0,
             })
@@ -160,11 +185,11 @@ mod test {
         }
 
         fn insert(&mut self, key: Key, value: &Value) -> Result<()> {
-            self.tree.insert(key, value)
+            self.tree.insert(&self.ctx, key, value)
         }
 
         fn remove(&mut self, key: Key) -> Result<()> {
-            self.tree.remove(key)
+            self.tree.remove(&self.ctx, key)
         }
 
         fn commit(&mut self) -> Result<()> {
@@ -177,7 +202,7 @@ mod test {
 
     impl Drop for Fixture {
         fn drop(&mut self) {
-            let entries = batch::end_batch().unwrap();
+            let entries = std::mem::take(&mut self.ctx).end().unwrap();
             let batch = Batch {
                 ops: entries,
                 completion: None,
@@ -190,6 +215,25 @@ mod test {
         Value { v, len: 3 }
     }
 
+    // The test value has to trim the same way Mapping does, or the remove
+    // tests check the wrong thing.
+    #[test]
+    fn value_select() {
+        let v = Value { v: 7, len: 10 };
+
+        // Entirely above the new key.
+        assert_eq!(v.select_geq(100, 50), Some((100, v)));
+        assert_eq!(v.select_lt(100, 50), None);
+
+        // Entirely below the new key.
+        assert_eq!(v.select_geq(100, 110), None);
+        assert_eq!(v.select_lt(100, 110), Some((100, v)));
+
+        // Straddling the new key.
+        assert_eq!(v.select_geq(100, 104), Some((104, Value { v: 7, len: 6 })));
+        assert_eq!(v.select_lt(100, 104), Some((100, Value { v: 7, len: 4 })));
+    }
+
     #[test]
     fn empty_btree() -> Result<()> {
         const NR_BLOCKS: u32 = 1024;
@@ -267,6 +311,14 @@ mod test {
         insert_test(&keys)
     }
 
+    // Every insert goes in front of the first entry, so splits happen on
+    // the prepend path.
+    #[test]
+    fn insert_reverse_sequence() -> Result<()> {
+        let count = 100_000;
+        insert_test(&(0..count).rev().collect::<Vec<Key>>())
+    }
+
     #[test]
     fn remove_single() -> Result<()> {
         let mut fix = Fixture::new(1024, 102400)?;
@@ -340,7 +392,7 @@ mod test {
         let mut fix = Fixture::new(1024, 102400)?;
         fix.commit()?;
 
-        fix.tree.remove_geq(100)?;
+        fix.tree.remove_geq(&fix.ctx, 100)?;
         ensure!(fix.tree.check()? == 0);
         Ok(())
     }
@@ -352,7 +404,7 @@ mod test {
 
         let no_split = |k: Key, v: Value| Some((k, v));
 
-        fix.tree.remove_lt(100)?;
+        fix.tree.remove_lt(&fix.ctx, 100)?;
         ensure!(fix.tree.check()? == 0);
         Ok(())
     }
@@ -368,41 +420,31 @@ mod test {
     }
 
     fn remove_geq_and_verify(fix: &mut Fixture, cut: Key) -> Result<()> {
-        fix.tree.remove_geq(cut)?;
+        fix.tree.remove_geq(&fix.ctx, cut)?;
         ensure!(fix.tree.check()? == cut as u64);
 
-        // FIXME: use lookup_range() to verify
-        /*
-                let mut c = fix.tree.cursor(0)?;
-
-                // Check all entries are below `cut`
-                for i in 0..cut {
-                    let (k, v) = c.get()?.unwrap();
-                    ensure!(k == i);
-                    ensure!(v.v == i * 3);
-                    c.next_entry()?;
-                }
-        */
+        // Check all entries are below `cut`
+        let entries = fix.tree.lookup_range(0, Key::MAX)?;
+        ensure!(entries.len() as u64 == cut);
+        for (i, (k, v)) in entries.into_iter().enumerate() {
+            ensure!(k == i as u64);
+            ensure!(v.v == k * 3);
+        }
 
         Ok(())
     }
 
     fn remove_lt_and_verify(fix: &mut Fixture, count: u64, cut: Key) -> Result<()> {
-        fix.tree.remove_lt(cut)?;
+        fix.tree.remove_lt(&fix.ctx, cut)?;
         ensure!(fix.tree.check()? == count - cut);
 
-        // FIXME: use lookup_range() to verify
-        /*
-                let mut c = fix.tree.cursor(0)?;
-
-                // Check all entries are above `cut`
-                for i in cut..count {
-                    let (k, v) = c.get()?.unwrap();
-                    ensure!(k == i);
-                    ensure!(v.v == i * 3);
-                    c.next_entry()?;
-                }
-        */
+        // Check all entries are above `cut`
+        let entries = fix.tree.lookup_range(0, Key::MAX)?;
+        ensure!(entries.len() as u64 == count - cut);
+        for (i, (k, v)) in entries.into_iter().enumerate() {
+            ensure!(k == cut + i as u64);
+            ensure!(v.v == k * 3);
+        }
 
         Ok(())
     }
@@ -464,7 +506,7 @@ mod test {
         let mut fix = Fixture::new(1024, 102400)?;
 
         fix.insert(100, &Value { v: 200, len: 100 })?;
-        fix.tree.remove_geq(150)?;
+        fix.tree.remove_geq(&fix.ctx, 150)?;
 
         ensure!(fix.tree.check()? == 1);
         ensure!(fix.tree.lookup(100)?.unwrap() == Value { v: 200, len: 50 });
@@ -477,7 +519,7 @@ mod test {
         let mut fix = Fixture::new(1024, 102400)?;
 
         fix.insert(100, &Value { v: 200, len: 100 })?;
-        fix.tree.remove_lt(150)?;
+        fix.tree.remove_lt(&fix.ctx, 150)?;
 
         ensure!(fix.tree.check()? == 1);
         ensure!(fix.tree.lookup(150)?.unwrap() == Value { v: 200, len: 50 });
@@ -492,7 +534,7 @@ mod test {
         let range_end = 175;
 
         fix.insert(100, &Value { v: 200, len: 100 })?;
-        fix.tree.remove_range(range_begin, range_end)?;
+        fix.tree.remove_range(&fix.ctx, range_begin, range_end)?;
 
         ensure!(fix.tree.check()? == 2);
         ensure!(fix.tree.lookup(100)?.unwrap() == Value { v: 200, len: 50 });
@@ -514,28 +556,869 @@ mod test {
         let range_begin = 1001;
         let range_end = 2005;
 
-        fix.tree.remove_range(range_begin, range_end)?;
-        // fix.tree.remove_lt(range_end, split_high)?;
+        fix.tree.remove_range(&fix.ctx, range_begin, range_end)?;
+        // fix.tree.remove_lt(&fix.ctx, range_end, split_high)?;
 
-        // FIXME: use lookup_range() to verify
-        /*
-                let mut c = fix.tree.cursor(0)?;
-                loop {
-                    let (k, v) = c.get()?.unwrap();
-                    eprintln!("{}: {:?}", k, v);
+        // The entries either side of the range are trimmed.
+        let mut expected = Vec::new();
+        for i in 0..nr_entries {
+            let k = i * 10;
+            let v = Value { v: i * 3, len: 10 };
+            if k + 10 <= range_begin || k >= range_end {
+                expected.push((k, v));
+            } else if k < range_begin {
+                expected.push((
+                    k,
+                    Value {
+                        len: range_begin - k,
+                        ..v
+                    },
+                ));
+            } else if k + 10 > range_end {
+                expected.push((
+                    range_end,
+                    Value {
+                        len: k + 10 - range_end,
+                        ..v
+                    },
+                ));
+            }
+        }
+        ensure!(fix.tree.check()? == expected.len() as u64);
+        ensure!(fix.tree.lookup_range(0, Key::MAX)? == expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_range_within_child() -> Result<()> {
+        let mut fix = Fixture::new(1024, 102400)?;
+        let nr_entries = 1000;
+        for i in 0..nr_entries {
+            fix.insert(i * 10, &Value { v: i, len: 10 })?;
+        }
+
+        // Only touches one leaf, so the root has to be kept.
+        fix.tree.remove_range(&fix.ctx, 5000, 5010)?;
+
+        ensure!(fix.tree.check()? == nr_entries - 1);
+        ensure!(fix.lookup(5000).is_none());
+        ensure!(fix.lookup(0) == Some(Value { v: 0, len: 10 }));
+        ensure!(fix.lookup(9990) == Some(Value { v: 999, len: 10 }));
+        Ok(())
+    }
+
+    // The cut falls inside an entry, so entries below it are erased before
+    // that one is trimmed.
+    #[test]
+    fn remove_lt_trims_after_erase() -> Result<()> {
+        let mut fix = Fixture::new(1024, 102400)?;
+        for i in 0..10 {
+            fix.insert(i * 10, &Value { v: i, len: 10 })?;
+        }
+
+        fix.tree.remove_lt(&fix.ctx, 55)?;
+
+        ensure!(fix.tree.check()? == 5);
+        ensure!(fix.lookup(50).is_none());
+        ensure!(fix.lookup(55) == Some(Value { v: 5, len: 5 }));
+        ensure!(fix.lookup(60) == Some(Value { v: 6, len: 10 }));
+        Ok(())
+    }
+
+    // Returns the (height, nr nodes) of the tree.
+    fn tree_shape(fix: &Fixture) -> Result<(usize, usize)> {
+        fn walk(tm: &TransactionManager, n_ptr: NodePtr) -> Result<(usize, usize)> {
+            if !tm.is_internal(n_ptr)? {
+                return Ok((1, 1));
+            }
+
+            let node: SimpleNode<NodePtr, SharedProxy> = tm.read(n_ptr)?;
+            let mut height = 0;
+            let mut nr_nodes = 1;
+            for i in 0..node.nr_entries() {
+                let (h, n) = walk(tm, node.get_value(i))?;
+                height = h + 1;
+                nr_nodes += n;
+            }
+            Ok((height, nr_nodes))
+        }
+
+        walk(&fix.tm, fix.tree.root())
+    }
+
+    #[test]
+    fn remove_merges_sparse_leaves() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let count = 20_000;
+        build_tree(&mut fix, count)?;
+        let (_, nodes_before) = tree_shape(&fix)?;
+
+        for k in 0..count {
+            if k % 50 != 0 {
+                fix.remove(k)?;
+            }
+        }
+
+        // check() makes sure no node other than the root is underfull.
+        ensure!(fix.check()? == count / 50);
+        let (_, nr_nodes) = tree_shape(&fix)?;
+        ensure!(nr_nodes * 10 < nodes_before);
+
+        for k in (0..count).step_by(50) {
+            ensure!(fix.lookup(k) == Some(mk_value(k * 3)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn remove_all_shrinks_root() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let count = 20_000;
+        build_tree(&mut fix, count)?;
+
+        let mut keys: Vec<Key> = (0..count).collect();
+        keys.shuffle(&mut rand::thread_rng());
+        for (i, k) in keys.iter().enumerate() {
+            fix.remove(*k)?;
+            if i % 1000 == 0 {
+                ensure!(fix.check()? == count - i as u64 - 1);
+            }
+        }
+
+        ensure!(fix.check()? == 0);
+        ensure!(tree_shape(&fix)? == (1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn remove_range_merges_edges() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let nr_entries = 20_000;
+        for i in 0..nr_entries {
+            fix.insert(i * 10, &Value { v: i, len: 10 })?;
+        }
+
+        // Leaves a few entries at either end, in what were separate leaves.
+        fix.tree
+            .remove_range(&fix.ctx, 55, (nr_entries - 5) * 10 + 5)?;
+
+        ensure!(fix.check()? == 11);
+        ensure!(tree_shape(&fix)? == (1, 1));
+        ensure!(fix.lookup(50) == Some(Value { v: 5, len: 5 }));
+        ensure!(
+            fix.lookup((nr_entries - 5) * 10 + 5)
+                == Some(Value {
+                    v: nr_entries - 5,
+                    len: 5
+                })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn remove_lt_and_geq_rebalance() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let count = 20_000;
+        build_tree(&mut fix, count)?;
+
+        // Cut part way into leaves at both ends, several times.
+        let (mut b, mut e) = (0, count);
+        for _ in 0..10 {
+            b += 901;
+            e -= 899;
+            fix.tree.remove_lt(&fix.ctx, b)?;
+            fix.tree.remove_geq(&fix.ctx, e)?;
+            ensure!(fix.check()? == e - b);
+        }
+        ensure!(fix.lookup(b) == Some(mk_value(b * 3)));
+        ensure!(fix.lookup(e - 1) == Some(mk_value((e - 1) * 3)));
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_packs_leaves() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let count = 50_000;
+        fix.tree = TestTree::bulk_load(
+            fix.tm.clone(),
+            &fix.ctx,
+            (0..count).map(|i| (i * 5, mk_value(i))),
+        )?;
+
+        ensure!(fix.check()? == count);
+        for i in 0..count {
+            ensure!(fix.lookup(i * 5) == Some(mk_value(i)));
+            ensure!(fix.lookup(i * 5 + 1).is_none());
+        }
+
+        // Inserting in order leaves every node half full.
+        let (height, nr_nodes) = tree_shape(&fix)?;
+        let mut fix2 = Fixture::new(4096, 102400)?;
+        for i in 0..count {
+            fix2.insert(i * 5, &mk_value(i))?;
+        }
+        let (height2, nr_nodes2) = tree_shape(&fix2)?;
+        ensure!(height <= height2);
+        ensure!(nr_nodes * 3 < nr_nodes2 * 2);
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_small() -> Result<()> {
+        let mut fix = Fixture::new(1024, 102400)?;
+        fix.tree = TestTree::bulk_load(fix.tm.clone(), &fix.ctx, std::iter::empty())?;
+        ensure!(fix.check()? == 0);
+        ensure!(tree_shape(&fix)? == (1, 1));
+
+        // Just over a leaf's worth, so the second leaf has to be topped up.
+        for count in [1, 50, 171, 200, 400] {
+            fix.tree = TestTree::bulk_load(
+                fix.tm.clone(),
+                &fix.ctx,
+                (0..count).map(|i| (i, mk_value(i))),
+            )?;
+            ensure!(fix.check()? == count);
+            for i in 0..count {
+                ensure!(fix.lookup(i) == Some(mk_value(i)));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_out_of_order() -> Result<()> {
+        let fix = Fixture::new(1024, 102400)?;
+        let keys = [1, 2, 3, 3, 4];
+        let r = TestTree::bulk_load(
+            fix.tm.clone(),
+            &fix.ctx,
+            keys.iter().map(|k| (*k, mk_value(*k))),
+        );
+        ensure!(r.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_then_modify() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let count = 20_000;
+        fix.tree = TestTree::bulk_load(
+            fix.tm.clone(),
+            &fix.ctx,
+            (0..count).map(|i| (i * 2, mk_value(i))),
+        )?;
+
+        // Full leaves split straight away.
+        for i in (0..count).step_by(7) {
+            fix.insert(i * 2 + 1, &mk_value(i))?;
+        }
+        for i in (0..count).step_by(3) {
+            fix.remove(i * 2)?;
+        }
+
+        let nr_inserted = count.div_ceil(7);
+        let nr_removed = count.div_ceil(3);
+        ensure!(fix.check()? == count + nr_inserted - nr_removed);
+        for i in 0..count {
+            let expected = if i % 3 == 0 { None } else { Some(mk_value(i)) };
+            ensure!(fix.lookup(i * 2) == expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_replay() -> Result<()> {
+        let fix = Fixture::new(1024, 102400)?;
+        let ctx = BatchContext::new();
+        let count = 10_000;
+        let tree = TestTree::bulk_load(
+            fix.tm.clone(),
+            &ctx,
+            (0..count).map(|i| (i * 3, mk_value(i))),
+        )?;
+
+        // The leaves are journalled with appends rather than an entry per
+        // key.
+        let entries = ctx.end()?;
+        let nr_appends = entries
+            .iter()
+            .filter(|e| matches!(e, Entry::Append(..)))
+            .count();
+        ensure!(nr_appends > 0);
+        ensure!((nr_appends as u64) < count / 20);
+
+        let tm2 = Arc::new(TransactionManager::new(
+            fix.journal.clone(),
+            Arc::new(BlockCache::new(mk_engine(1024), 16)?),
+            BuddyAllocator::new(1024),
+            BuddyAllocator::new(102400),
+        ));
+        tm2.replay_entries(&entries)?;
+        let tree2 = TestTree::open_tree(tm2, tree.root());
+
+        ensure!(tree2.check()? == count);
+        for i in 0..count {
+            ensure!(tree2.lookup(i * 3)? == Some(mk_value(i)));
+        }
+        Ok(())
+    }
+
+    // The entries of a sorted vec that overlap [key_begin, key_end),
+    // clipped to the range.
+    fn model_range(entries: &[(Key, Value)], key_begin: Key, key_end: Key) -> Vec<(Key, Value)> {
+        let first = entries
+            .iter()
+            .rposition(|(k, _)| *k <= key_begin)
+            .unwrap_or(0);
+
+        let mut results = Vec::new();
+        for (k, v) in &entries[first..] {
+            if *k >= key_end {
+                break;
+            }
+            let (k, v) = if *k < key_begin {
+                match v.select_geq(*k, key_begin) {
+                    Some(kv) => kv,
+                    None => continue,
+                }
+            } else {
+                (*k, *v)
+            };
+            results.extend(v.select_lt(k, key_end));
+        }
+        results
+    }
+
+    fn build_range_tree(fix: &mut Fixture, count: u64) -> Result<Vec<(Key, Value)>> {
+        let entries: Vec<(Key, Value)> = (0..count)
+            .map(|i| (i * 10, Value { v: i, len: 7 }))
+            .collect();
+        for (k, v) in &entries {
+            fix.insert(*k, v)?;
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn cursor_matches_model() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let count = 20_000;
+        let entries = build_range_tree(&mut fix, count)?;
+
+        let mut rng = rand::thread_rng();
+        let mut ranges = vec![(0, Key::MAX), (0, 1), (3, 4), (count * 10 - 3, Key::MAX)];
+        for _ in 0..100 {
+            let b = rng.gen_range(0..count * 10 + 20);
+            let len = if rng.gen_bool(0.5) {
+                rng.gen_range(1..100)
+            } else {
+                rng.gen_range(1..count * 10)
+            };
+            ranges.push((b, b + len));
+        }
+
+        for (b, e) in ranges {
+            let expected = model_range(&entries, b, e);
 
-                    if !c.next_entry()? {
-                        break;
+            let forward: Vec<(Key, Value)> = fix.tree.cursor(b, e).collect::<Result<_>>()?;
+            ensure!(forward == expected, "forward [{}, {})", b, e);
+
+            let mut backward: Vec<(Key, Value)> =
+                fix.tree.cursor(b, e).rev().collect::<Result<_>>()?;
+            backward.reverse();
+            ensure!(backward == expected, "backward [{}, {})", b, e);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_ends_meet() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let count = 5_000;
+        let entries = build_range_tree(&mut fix, count)?;
+        let (b, e) = (1234, 45678);
+        let expected = model_range(&entries, b, e);
+
+        // Take from each end in turn, until they run into each other.
+        let mut rng = rand::thread_rng();
+        let mut c = fix.tree.cursor(b, e);
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        loop {
+            let next = if rng.gen_bool(0.5) {
+                c.next().map(|r| r.map(|kv| front.push(kv)))
+            } else {
+                c.next_back().map(|r| r.map(|kv| back.push(kv)))
+            };
+            match next {
+                Some(r) => r?,
+                None => break,
+            }
+        }
+        ensure!(c.next().is_none());
+        ensure!(c.next_back().is_none());
+
+        back.reverse();
+        front.extend(back);
+        ensure!(front == expected);
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_empty() -> Result<()> {
+        let mut fix = Fixture::new(1024, 102400)?;
+        ensure!(fix.tree.cursor(0, Key::MAX).next().is_none());
+        ensure!(fix.tree.cursor(0, Key::MAX).next_back().is_none());
+
+        build_range_tree(&mut fix, 1000)?;
+        for (b, e) in [(100, 100), (200, 100), (10_000, 20_000), (7, 10), (17, 20)] {
+            ensure!(fix.tree.cursor(b, e).next().is_none());
+            ensure!(fix.tree.cursor(b, e).next_back().is_none());
+        }
+        Ok(())
+    }
+
+    // As insert_range(), but on a map.  Neighbours are always merged.
+    fn model_insert_range(model: &mut BTreeMap<Key, Value>, key: Key, value: &Value) {
+        let key_end = key + value.len;
+        let overlapping: Vec<(Key, Value)> = model
+            .range(..key_end)
+            .rev()
+            .take_while(|(k, v)| *k + v.len > key)
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        for (k, v) in overlapping {
+            model.remove(&k);
+            if let Some((k, v)) = v.select_lt(k, key) {
+                model.insert(k, v);
+            }
+            if let Some((k, v)) = v.select_geq(k, key_end) {
+                model.insert(k, v);
+            }
+        }
+        model.insert(key, *value);
+    }
+
+    // Merges adjacent entries wherever possible, since the tree only
+    // merges entries within a leaf.
+    fn coalesce(entries: impl IntoIterator<Item = (Key, Value)>) -> Vec<(Key, Value)> {
+        let mut results: Vec<(Key, Value)> = Vec::new();
+        for (k, v) in entries {
+            if let Some((last_k, last_v)) = results.last_mut() {
+                if *last_k + last_v.len == k {
+                    if let Some(merged) = last_v.merge(&v) {
+                        *last_v = merged;
+                        continue;
                     }
                 }
-        */
+            }
+            results.push((k, v));
+        }
+        results
+    }
 
-        /*
-        ensure!(fix.tree.check()? == 2);
-        ensure!(fix.tree.lookup(100)?.unwrap() == Value { v: 200, len: 50 });
-        ensure!(fix.tree.lookup(175)?.unwrap() == Value { v: 200, len: 25 });
-        */
+    fn check_insert_range(fix: &Fixture, model: &BTreeMap<Key, Value>) -> Result<()> {
+        let nr_entries = fix.check()?;
+        let entries = fix.tree.lookup_range(0, Key::MAX)?;
+        ensure!(nr_entries == entries.len() as u64);
+        ensure!(coalesce(entries) == coalesce(model.iter().map(|(k, v)| (*k, *v))));
+        Ok(())
+    }
 
+    #[test]
+    fn insert_range_splits_entry() -> Result<()> {
+        let mut fix = Fixture::new(1024, 102400)?;
+        fix.tree
+            .insert_range(&fix.ctx, 100, &Value { v: 0, len: 100 })?;
+        fix.tree
+            .insert_range(&fix.ctx, 140, &Value { v: 1000, len: 20 })?;
+
+        ensure!(
+            fix.tree.lookup_range(0, Key::MAX)?
+                == vec![
+                    (100, Value { v: 0, len: 40 }),
+                    (140, Value { v: 1000, len: 20 }),
+                    (160, Value { v: 0, len: 40 }),
+                ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn insert_range_merges() -> Result<()> {
+        let mut fix = Fixture::new(1024, 102400)?;
+
+        // Each range follows on from the last, so they all merge.
+        for i in 0..1000 {
+            fix.tree
+                .insert_range(&fix.ctx, i * 10, &Value { v: 7, len: 10 })?;
+        }
+        ensure!(fix.check()? == 1);
+
+        // Filling the gap between two entries merges all three.
+        fix.tree
+            .insert_range(&fix.ctx, 20_000, &Value { v: 5, len: 10 })?;
+        fix.tree
+            .insert_range(&fix.ctx, 20_020, &Value { v: 5, len: 10 })?;
+        ensure!(fix.check()? == 3);
+        fix.tree
+            .insert_range(&fix.ctx, 20_010, &Value { v: 5, len: 10 })?;
+        ensure!(fix.check()? == 2);
+        ensure!(fix.lookup(20_000) == Some(Value { v: 5, len: 30 }));
+        Ok(())
+    }
+
+    #[test]
+    fn insert_range_across_leaves() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let mut model = BTreeMap::new();
+        for i in 0..20_000 {
+            let v = Value { v: i * 100, len: 7 };
+            fix.insert(i * 10, &v)?;
+            model.insert(i * 10, v);
+        }
+
+        // Overwrite a big chunk of the middle, leaving a little of the
+        // entries at either end.
+        let v = Value { v: 1, len: 150_000 };
+        fix.tree.insert_range(&fix.ctx, 25_003, &v)?;
+        model_insert_range(&mut model, 25_003, &v);
+        check_insert_range(&fix, &model)?;
+
+        // And a range that's within a single entry.
+        let v = Value { v: 2, len: 1000 };
+        fix.tree.insert_range(&fix.ctx, 30_000, &v)?;
+        model_insert_range(&mut model, 30_000, &v);
+        check_insert_range(&fix, &model)
+    }
+
+    #[test]
+    fn insert_range_random() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let mut model = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+
+        for i in 0..10_000 {
+            let key = rng.gen_range(0..100_000);
+            let len = if rng.gen_bool(0.9) {
+                rng.gen_range(1..20)
+            } else {
+                rng.gen_range(1..5000)
+            };
+
+            // Plenty of values share a v, so get merged.
+            let v = if rng.gen_bool(0.5) {
+                0
+            } else {
+                rng.gen_range(1..1000)
+            };
+            let value = Value { v, len };
+
+            fix.tree.insert_range(&fix.ctx, key, &value)?;
+            model_insert_range(&mut model, key, &value);
+            if i % 500 == 0 {
+                check_insert_range(&fix, &model)?;
+            }
+        }
+        check_insert_range(&fix, &model)
+    }
+
+    // Runs of run_len entries that could be merged, but aren't since
+    // insert() doesn't merge.
+    fn build_fragmented(
+        fix: &mut Fixture,
+        count: u64,
+        run_len: u64,
+    ) -> Result<BTreeMap<Key, Value>> {
+        let mut model = BTreeMap::new();
+        for i in 0..count {
+            let v = Value {
+                v: i / run_len,
+                len: 10,
+            };
+            fix.insert(i * 10, &v)?;
+            model.insert(i * 10, v);
+        }
+        Ok(model)
+    }
+
+    #[test]
+    fn compact_merges_runs() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let model = build_fragmented(&mut fix, 20_000, 100)?;
+        let (_, nodes_before) = tree_shape(&fix)?;
+
+        let stats = fix.tree.compact(&fix.ctx)?;
+        ensure!(
+            stats
+                == CompactStats {
+                    nr_keys: 200_000,
+                    nr_entries_before: 20_000,
+                    nr_entries_after: 200,
+                }
+        );
+        ensure!(stats.run_length_before() == 10.0);
+        ensure!(stats.run_length_after() == 1000.0);
+        ensure!(stats.improvement() == 100.0);
+
+        // The runs cross leaves, so most of the leaves empty.
+        let (_, nodes_after) = tree_shape(&fix)?;
+        ensure!(nodes_after * 10 < nodes_before);
+
+        ensure!(fix.check()? == 200);
+        ensure!(
+            fix.tree.lookup_range(0, Key::MAX)? == coalesce(model.iter().map(|(k, v)| (*k, *v)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compact_twice() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        build_fragmented(&mut fix, 5_000, 7)?;
+        fix.tree.compact(&fix.ctx)?;
+        let entries = fix.tree.lookup_range(0, Key::MAX)?;
+
+        // Nothing left to merge.
+        let stats = fix.tree.compact(&fix.ctx)?;
+        ensure!(stats.nr_entries_before == stats.nr_entries_after);
+        ensure!(stats.improvement() == 1.0);
+        ensure!(fix.tree.lookup_range(0, Key::MAX)? == entries);
+        Ok(())
+    }
+
+    #[test]
+    fn compact_leaves_snapshot() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let model = build_fragmented(&mut fix, 10_000, 50)?;
+
+        fix.snap_time += 1;
+        let snap = fix.tree.snap(fix.snap_time);
+        let stats = fix.tree.compact(&fix.ctx)?;
+        ensure!(stats.nr_entries_after == 200);
+
+        ensure!(fix.check()? == 200);
+        ensure!(snap.check()? == 10_000);
+        let entries: Vec<(Key, Value)> = model.into_iter().collect();
+        ensure!(snap.lookup_range(0, Key::MAX)? == entries);
+        ensure!(fix.tree.lookup_range(0, Key::MAX)? == coalesce(entries));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_diverge() -> Result<()> {
+        let mut fix = Fixture::new(4096, 102400)?;
+        let count = 5_000;
+        for k in 0..count {
+            fix.insert(k * 10, &mk_value(k))?;
+        }
+
+        fix.snap_time += 1;
+        let mut snap = fix.tree.snap(fix.snap_time);
+        let shared_root = snap.root();
+
+        // The origin overwrites the even keys, and drops one of the odd ones.
+        for k in (0..count).step_by(2) {
+            fix.insert(k * 10, &mk_value(k + 1_000_000))?;
+        }
+        fix.remove(10)?;
+
+        // The snapshot overwrites the odd keys, and drops a range.
+        for k in (1..count).step_by(2) {
+            snap.insert(&fix.ctx, k * 10, &mk_value(k + 2_000_000))?;
+        }
+        snap.remove_range(&fix.ctx, 1000, 2000)?;
+
+        ensure!(fix.tree.root() != shared_root);
+        ensure!(snap.root() != shared_root);
+        ensure!(fix.tree.root() != snap.root());
+
+        ensure!(fix.check()? == count - 1);
+        ensure!(snap.check()? == count - 100);
+
+        for k in 0..count {
+            let key = k * 10;
+            let origin = if k == 1 {
+                None
+            } else if k % 2 == 0 {
+                Some(mk_value(k + 1_000_000))
+            } else {
+                Some(mk_value(k))
+            };
+            ensure!(fix.lookup(key) == origin);
+
+            let snapshot = if (1000..2000).contains(&key) {
+                None
+            } else if k % 2 == 1 {
+                Some(mk_value(k + 2_000_000))
+            } else {
+                Some(mk_value(k))
+            };
+            ensure!(snap.lookup(key)? == snapshot);
+        }
+
+        // The tree as it was at the snapshot is untouched.
+        let original = TestTree::open_tree(fix.tm.clone(), shared_root);
+        ensure!(original.check()? == count);
+        for k in 0..count {
+            ensure!(original.lookup(k * 10)? == Some(mk_value(k)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn shadow_copies_once() -> Result<()> {
+        let mut fix = Fixture::new(1024, 102400)?;
+        for k in 0..100 {
+            fix.insert(k, &mk_value(k))?;
+        }
+
+        fix.snap_time += 1;
+        let _snap = fix.tree.snap(fix.snap_time);
+
+        // The first write after the snapshot copies the leaf, later ones
+        // change the copy in place.
+        fix.insert(0, &mk_value(1000))?;
+        let root = fix.tree.root();
+        fix.insert(1, &mk_value(1001))?;
+        ensure!(fix.tree.root() == root);
+        Ok(())
+    }
+
+    // Every node in the tree.
+    fn tree_nodes(
+        tm: &TransactionManager,
+        n_ptr: NodePtr,
+        nodes: &mut Vec<MetadataBlock>,
+    ) -> Result<()> {
+        nodes.push(n_ptr.loc);
+        if tm.is_internal(n_ptr)? {
+            let node: SimpleNode<NodePtr, SharedProxy> = tm.read(n_ptr)?;
+            for i in 0..node.nr_entries() {
+                tree_nodes(tm, node.get_value(i), nodes)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Each thread builds its own tree, with its own metadata allocator, on
+    // top of a shared transaction manager.  See
+    // src/bin/concurrent_provision.rs for timings.
+    #[test]
+    fn concurrent_insert() -> Result<()> {
+        const NR_KEYS: u64 = 10_000;
+        const NR_THREADS: u64 = 8;
+
+        let fix = Fixture::new(16384, 102400)?;
+        let mut handles = Vec::new();
+        for t in 0..NR_THREADS {
+            let tm = fix.tm.clone();
+            let journal = fix.journal.clone();
+            handles.push(thread::spawn(move || -> Result<NodePtr> {
+                let ctx = BatchContext::new();
+                let alloc = Arc::new(Mutex::new(MetadataAlloc::new(tm.get_metadata_alloc(), 32)));
+                let mut tree = TestTree::empty_tree(tm.clone(), &ctx)?.with_metadata_alloc(alloc);
+                for k in 0..NR_KEYS {
+                    tree.insert(&ctx, k, &mk_value(k * NR_THREADS + t))?;
+                }
+
+                let batch = Batch {
+                    ops: ctx.end()?,
+                    completion: None,
+                };
+                journal.lock().unwrap().add_batch(batch);
+                Ok(tree.root())
+            }));
+        }
+
+        let mut roots = Vec::new();
+        for h in handles {
+            roots.push(h.join().unwrap()?);
+        }
+
+        let mut nodes = Vec::new();
+        for (t, root) in roots.into_iter().enumerate() {
+            let tree = TestTree::open_tree(fix.tm.clone(), root);
+            ensure!(tree.check()? == NR_KEYS);
+            for k in 0..NR_KEYS {
+                ensure!(tree.lookup(k)? == Some(mk_value(k * NR_THREADS + t as u64)));
+            }
+            tree_nodes(&fix.tm, root, &mut nodes)?;
+        }
+
+        // No two threads were handed the same block.
+        let nr_nodes = nodes.len();
+        nodes.sort();
+        nodes.dedup();
+        ensure!(nodes.len() == nr_nodes);
+
+        Ok(())
+    }
+
+    // Packs the journal entries from a run of inserts, a few inserts per
+    // batch, and a number of batches per slab.  Returns the raw and zstd
+    // compressed sizes for each encoding.
+    fn journal_sizes(keys: &[Key], encoding: Encoding) -> Result<(usize, usize)> {
+        const INSERTS_PER_BATCH: usize = 4;
+        const BATCHES_PER_SLAB: usize = 64;
+
+        let fix = Fixture::new(4096, 102400)?;
+        let mut tree = TestTree::empty_tree(fix.tm.clone(), &fix.ctx)?;
+
+        let mut raw = 0;
+        let mut compressed = 0;
+        for slab_keys in keys.chunks(INSERTS_PER_BATCH * BATCHES_PER_SLAB) {
+            let mut slab = Vec::new();
+            for batch_keys in slab_keys.chunks(INSERTS_PER_BATCH) {
+                let ctx = BatchContext::new();
+                for k in batch_keys {
+                    tree.insert(&ctx, *k, &mk_value(*k * 2))?;
+                }
+                pack_ops(&mut slab, &ctx.end()?, encoding)?;
+            }
+            raw += slab.len();
+            compressed += zstd::encode_all(&slab[..], 0)?.len();
+        }
+
+        Ok((raw, compressed))
+    }
+
+    #[test]
+    fn journal_encoding_sizes() -> Result<()> {
+        let count = 20_000;
+        let seq: Vec<Key> = (0..count).collect();
+        let mut rnd = seq.clone();
+        rnd.shuffle(&mut rand::thread_rng());
+
+        for (name, keys) in [("sequential", &seq), ("random", &rnd)] {
+            let (fixed_raw, fixed_z) = journal_sizes(keys, Encoding::Fixed)?;
+            let (compact_raw, compact_z) = journal_sizes(keys, Encoding::Compact)?;
+            eprintln!(
+                "{} inserts: fixed {} bytes ({} zstd), compact {} bytes ({} zstd)",
+                name, fixed_raw, fixed_z, compact_raw, compact_z
+            );
+            ensure!(compact_raw < fixed_raw);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn replay_double_alloc_fails() -> Result<()> {
+        let fix = Fixture::new(1024, 102400)?;
+        fix.tm.replay_entry(&Entry::AllocData(10, 20))?;
+        fix.tm.replay_entry(&Entry::FreeData(10, 15))?;
+        fix.tm.replay_entry(&Entry::AllocData(10, 15))?;
+
+        let err = fix.tm.replay_entry(&Entry::AllocData(18, 30)).unwrap_err();
+        ensure!(err.to_string().contains("data allocation of [18, 30)"));
+        ensure!(fix.tm.replay_entry(&Entry::AllocMetadata(500, 501)).is_ok());
+        ensure!(fix
+            .tm
+            .replay_entry(&Entry::AllocMetadata(500, 501))
+            .is_err());
         Ok(())
     }
 }
@@ -1,13 +1,20 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
 use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
 use crate::allocators::journal::*;
+use crate::allocators::metadata_alloc::*;
 use crate::allocators::{self, *};
 use crate::block_cache::*;
 use crate::btree::node::*;
+use crate::btree::nodes::delta::*;
 use crate::btree::nodes::journal::*;
+use crate::btree::nodes::log::*;
+use crate::btree::nodes::program::*;
+use crate::btree::nodes::simple::*;
 use crate::byte_types::*;
+use crate::journal::batch::BatchContext;
 use crate::journal::entry::*;
 use crate::journal::BatchCompletion;
 use crate::journal::*;
@@ -15,15 +22,29 @@ use crate::packed_array::*;
 
 //-------------------------------------------------------------------------
 
-// FIXME: is NodeCache the new transaction manager?  Should we rename?
-pub struct TransactionManagerInner {
+// There is no global lock in here.  Nodes are protected by the per block
+// shared/exclusive locks in the block cache, and each allocator has its own
+// mutex that is only held for the duration of a single call.  So operations
+// on disjoint trees (eg, two different thins) can run in parallel.
+pub struct TransactionManager {
     journal: Arc<Mutex<Journal>>,
-    metadata_alloc: Arc<Mutex<dyn Allocator>>,
-    data_alloc: Arc<Mutex<dyn Allocator>>,
+    metadata_alloc: SharedJournalAlloc,
+    data_alloc: SharedJournalAlloc,
     cache: Arc<BlockCache>,
 }
 
-impl TransactionManagerInner {
+type BatchId = u64;
+
+// Where the snap_time lives in the standard node header.
+const SNAP_TIME_OFFSET: usize = 4;
+
+fn stamp_snap_time(data: &mut ExclusiveProxy, snap_time: u32) -> Result<()> {
+    let mut w = &mut data.rw()[SNAP_TIME_OFFSET..];
+    w.write_u32::<LittleEndian>(snap_time)?;
+    Ok(())
+}
+
+impl TransactionManager {
     pub fn new(
         journal: Arc<Mutex<Journal>>,
         cache: Arc<BlockCache>,
@@ -44,23 +65,40 @@ impl TransactionManagerInner {
         }
     }
 
-    pub fn alloc_data(&mut self, len: u64) -> allocators::Result<(u64, Vec<(u64, u64)>)> {
+    pub fn get_metadata_alloc(&self) -> SharedJournalAlloc {
+        self.metadata_alloc.clone()
+    }
+
+    pub fn get_data_alloc(&self) -> SharedJournalAlloc {
+        self.data_alloc.clone()
+    }
+
+    pub fn alloc_data(
+        &self,
+        ctx: &BatchContext,
+        len: u64,
+    ) -> allocators::Result<(u64, Vec<(u64, u64)>)> {
         let mut alloc = self.data_alloc.lock().unwrap();
-        alloc.alloc_many(len, 0)
+        alloc.alloc_many(ctx, len, 0)
     }
 
-    pub fn free_data(&mut self, b: u64, len: u64) -> allocators::Result<()> {
+    pub fn free_data(&self, ctx: &BatchContext, b: u64, len: u64) -> allocators::Result<()> {
         let mut alloc = self.data_alloc.lock().unwrap();
-        alloc.free(b, len)
+        alloc.free(ctx, b, len)
     }
 
-    pub fn is_internal(&mut self, n_ptr: NodePtr) -> Result<bool> {
+    pub fn is_internal(&self, n_ptr: NodePtr) -> Result<bool> {
         let b = self.cache.shared_lock(n_ptr.loc)?;
         Ok(read_flags(&b)? == BTreeFlags::Internal)
     }
 
+    pub fn node_kind(&self, n_ptr: NodePtr) -> Result<u16> {
+        let b = self.cache.shared_lock(n_ptr.loc)?;
+        Ok(read_node_header(&mut b.r())?.kind)
+    }
+
     pub fn read<V: Serializable, Node: NodeR<V, SharedProxy>>(
-        &mut self,
+        &self,
         n_ptr: NodePtr,
     ) -> Result<Node> {
         // FIXME: check seq_nr and replay journal if necc.
@@ -69,29 +107,60 @@ impl TransactionManagerInner {
     }
 
     fn wrap_node<V: Serializable, Node: NodeW<V, ExclusiveProxy>>(
-        &mut self,
+        &self,
+        ctx: &BatchContext,
         loc: u32,
         data: ExclusiveProxy,
     ) -> Result<JournalNode<Node, V, ExclusiveProxy>> {
         let node = Node::open(loc, data)?;
-        Ok(JournalNode::new(node))
+        Ok(JournalNode::new(ctx.clone(), node))
     }
 
-    fn new_metadata_block(&mut self) -> allocators::Result<MetadataBlock> {
-        let mut alloc = self.metadata_alloc.lock().unwrap();
-        let b = alloc.alloc(1)?;
-        Ok(b as MetadataBlock)
+    // If the caller has a per-thin metadata allocator we use that, which
+    // avoids contention on the global allocator.
+    fn new_metadata_block(
+        &self,
+        ctx: &BatchContext,
+        local: Option<&Mutex<MetadataAlloc>>,
+    ) -> allocators::Result<MetadataBlock> {
+        match local {
+            Some(local) => local.lock().unwrap().alloc(ctx),
+            None => {
+                let mut alloc = self.metadata_alloc.lock().unwrap();
+                let b = alloc.alloc(ctx, 1)?;
+                Ok(b as MetadataBlock)
+            }
+        }
     }
 
+    /// New nodes are stamped with the snap_time of the tree they belong
+    /// to, so shadowing them again at that snap_time doesn't copy.
     pub fn new_node<V: Serializable, Node: NodeW<V, ExclusiveProxy>>(
-        &mut self,
+        &self,
+        ctx: &BatchContext,
+        local: Option<&Mutex<MetadataAlloc>>,
         is_leaf: bool,
+        snap_time: u32,
     ) -> Result<JournalNode<Node, V, ExclusiveProxy>> {
-        match self.new_metadata_block() {
+        match self.new_metadata_block(ctx, local) {
             Ok(loc) => {
-                let new = self.cache.zero_lock(loc as u32)?;
-                Node::init(loc as u32, new.clone(), is_leaf)?;
-                self.wrap_node(loc as u32, new)
+                let mut new = self.cache.zero_lock(loc)?;
+                Node::init(loc, new.clone(), is_leaf)?;
+                stamp_snap_time(&mut new, snap_time)?;
+
+                // The block may have been used before, so replay needs to
+                // zero it too.  Some nodes initialise more than the
+                // header, eg, LogNode.
+                ctx.add_entry(Entry::Zero(loc, 0, NODE_SIZE));
+                let len = new
+                    .r()
+                    .iter()
+                    .rposition(|b| *b != 0)
+                    .map_or(0, |i| i + 1)
+                    .max(NODE_HEADER_SIZE);
+                ctx.add_entry(Entry::Literal(loc, 0, new.r()[..len].to_vec()));
+
+                self.wrap_node(ctx, loc, new)
             }
             Err(MemErr::OutOfSpace) => {
                 // FIXME: resize the node file and kick off the gc
@@ -101,8 +170,14 @@ impl TransactionManagerInner {
         }
     }
 
+    /// Returns a writeable version of the node.  If the node is older than
+    /// `snap_time`, the tree's snap_time, it may be shared with a
+    /// snapshot so a copy is made.  The caller must update the parent to
+    /// point at the node returned.
     pub fn shadow<V: Serializable, Node: NodeW<V, ExclusiveProxy>>(
-        &mut self,
+        &self,
+        ctx: &BatchContext,
+        local: Option<&Mutex<MetadataAlloc>>,
         n_ptr: NodePtr,
         snap_time: u32,
     ) -> Result<JournalNode<Node, V, ExclusiveProxy>> {
@@ -111,91 +186,128 @@ impl TransactionManagerInner {
 
         if snap_time > hdr.snap_time {
             // copy needed
-            if let Ok(loc) = self.new_metadata_block() {
-                let mut new = self.cache.zero_lock(loc as u32)?;
+            if let Ok(loc) = self.new_metadata_block(ctx, local) {
+                let mut new = self.cache.zero_lock(loc)?;
                 new.rw()[0..].copy_from_slice(&old.r()[0..]);
-                self.wrap_node(loc as u32, new)
+                ctx.add_entry(Entry::Shadow(loc, n_ptr));
+
+                // The copy belongs to this tree alone.
+                stamp_snap_time(&mut new, snap_time)?;
+                let stamp = new.r()[SNAP_TIME_OFFSET..SNAP_TIME_OFFSET + 4].to_vec();
+                ctx.add_entry(Entry::Literal(loc, SNAP_TIME_OFFSET, stamp));
+
+                self.wrap_node(ctx, loc, new)
             } else {
                 Err(anyhow::anyhow!("out of metadata blocks"))
             }
         } else {
-            self.wrap_node(n_ptr.loc, old)
+            self.wrap_node(ctx, n_ptr.loc, old)
         }
     }
 
-    fn replay_node(&mut self, loc: MetadataBlock) -> Result<Box<dyn ReplayableNode>> {
-        todo!();
+    fn replay_node(&self, loc: MetadataBlock) -> Result<Box<dyn ReplayableNode>> {
+        let data = self.cache.exclusive_lock(loc)?;
+        let hdr = read_node_header(&mut data.r())?;
+        match hdr.kind {
+            SIMPLE_NODE_KIND => Ok(Box::new(SimpleNodeReplay::new(loc, data)?)),
+            DELTA_NODE_KIND => Ok(Box::new(DeltaNodeReplay::new(loc, data)?)),
+            PROGRAM_NODE_KIND => Ok(Box::new(ProgramNodeReplay::new(loc, data)?)),
+            // The value type is whatever the inner node holds.
+            LOG_NODE_KIND => match read_inner_kind(&data)? {
+                PROGRAM_NODE_KIND => Ok(Box::new(LogNodeReplay::<
+                    _,
+                    ProgramNode<ExclusiveProxy>,
+                    ExclusiveProxy,
+                >::new(loc, data)?)),
+                kind => Err(anyhow!("can't replay log node {} of kind {}", loc, kind)),
+            },
+            kind => Err(anyhow!("can't replay node {} of kind {}", loc, kind)),
+        }
     }
 
-    pub fn replay_entry(&mut self, entry: &Entry) -> Result<()> {
+    pub fn replay_entry(&self, entry: &Entry) -> Result<()> {
         use Entry::*;
 
         match entry {
             AllocMetadata(b, e) => {
                 let mut alloc = self.metadata_alloc.lock().unwrap();
-                alloc.alloc_specific(*b as u64, (e - b) as u64);
+                replay_alloc(&mut alloc, "metadata", *b as u64, *e as u64)?;
             }
             FreeMetadata(b, e) => {
                 let mut alloc = self.metadata_alloc.lock().unwrap();
-                alloc.free(*b as u64, (e - b) as u64);
+                alloc.free_unjournalled(*b as u64, (e - b) as u64)?;
             }
             GrowMetadata(delta) => {
-                todo!()
+                let mut alloc = self.metadata_alloc.lock().unwrap();
+                alloc.grow_unjournalled(*delta as u64)?;
             }
 
             AllocData(b, e) => {
                 let mut alloc = self.data_alloc.lock().unwrap();
-                alloc.alloc_specific(*b as u64, (e - b) as u64);
+                replay_alloc(&mut alloc, "data", *b, *e)?;
             }
             FreeData(b, e) => {
                 let mut alloc = self.data_alloc.lock().unwrap();
-                alloc.free(*b as u64, (e - b) as u64);
+                alloc.free_unjournalled(*b, e - b)?;
             }
             GrowData(delta) => {
-                todo!()
+                let mut alloc = self.data_alloc.lock().unwrap();
+                alloc.grow_unjournalled(*delta)?;
             }
 
-            UpdateInfoRoot(root) => {
-                // FIXME: suggests we need to move the info tree to within the tm, which is
-                // obviously not good.  Should the tm hold all tree roots?  We'd still need
-                // the pool to update the info tree.
-                todo!()
+            UpdateInfoRoot(_) => {
+                // The info root lives in the pool, not the tm.  Callers
+                // that replay need to watch for these themselves.
+            }
+            Checkpoint(_) => {
+                // Nothing to do, the node file is already up to date.
             }
 
             SetSeq(loc, seq_nr) => {
-                todo!()
+                let mut data = self.cache.exclusive_lock(*loc)?;
+                let mut w = &mut data.rw()[..];
+                w.write_u32::<LittleEndian>(*seq_nr)?;
             }
             Zero(loc, b, e) => {
                 let mut data = self.cache.exclusive_lock(*loc)?;
-                &data.rw()[*b..*e].fill(0);
+                data.rw()[*b..*e].fill(0);
             }
 
             Literal(loc, offset, lit) => {
                 let mut data = self.cache.exclusive_lock(*loc)?;
-                &data.rw()[*offset..(*offset + lit.len())].copy_from_slice(lit);
+                data.rw()[*offset..(*offset + lit.len())].copy_from_slice(lit);
             }
 
-            Shadow(loc, dest) => {
-                todo!()
+            Shadow(loc, origin) => {
+                let old = self.cache.shared_lock(origin.loc)?;
+                let mut new = self.cache.exclusive_lock(*loc)?;
+                new.rw().copy_from_slice(old.r());
             }
 
             Overwrite(loc, idx, key, value) => {
                 let mut n = self.replay_node(*loc)?;
-                n.apply_overwrite(*idx, *key, &value)?;
+                n.apply_overwrite(*idx, *key, value)?;
             }
             Insert(loc, idx, k, v) => {
                 let mut n = self.replay_node(*loc)?;
                 n.apply_insert(*idx, *k, v)?;
             }
             Prepend(loc, ks, vs) => {
-                let mut n = self.replay_node(*loc)?;
-                n.apply_prepend(ks, &vs)?;
+                if !vs.is_empty() {
+                    let mut n = self.replay_node(*loc)?;
+                    n.apply_prepend(ks, vs)?;
+                }
             }
             Append(loc, ks, vs) => {
-                let mut n = self.replay_node(*loc)?;
-                n.apply_append(ks, &vs)?;
+                if !vs.is_empty() {
+                    let mut n = self.replay_node(*loc)?;
+                    n.apply_append(ks, vs)?;
+                }
             }
             Erase(loc, idx_b, idx_e) => {
+                if idx_b == idx_e {
+                    return Ok(());
+                }
                 let mut n = self.replay_node(*loc)?;
                 n.apply_erase(*idx_b, *idx_e)?;
             }
@@ -204,77 +316,31 @@ impl TransactionManagerInner {
         Ok(())
     }
 
-    pub fn replay_entries(&mut self, entries: &[Entry]) -> Result<()> {
-        for e in entries {
-            self.replay_entry(e)?;
-        }
-
-        Ok(())
+    /// Writes every pending batch, flushes all dirty nodes to the node
+    /// file, and then starts a new journal segment.  Replay will only need
+    /// the new segment.  The caller must ensure no other thread is
+    /// modifying nodes while this runs.
+    pub fn checkpoint(&self) -> Result<u64> {
+        self.checkpoint_with(|| Ok(()))
     }
-}
-
-//-------------------------------------------------------------------------
 
-type BatchId = u64;
-
-pub struct TransactionManager {
-    inner: Arc<Mutex<TransactionManagerInner>>,
-}
-
-impl TransactionManager {
-    pub fn new(
-        journal: Arc<Mutex<Journal>>,
-        cache: Arc<BlockCache>,
-        metadata_alloc: BuddyAllocator,
-        data_alloc: BuddyAllocator,
-    ) -> Self {
-        let inner = Arc::new(Mutex::new(TransactionManagerInner::new(
-            journal,
-            cache,
-            metadata_alloc,
-            data_alloc,
-        )));
-        Self { inner }
-    }
-
-    pub fn get_metadata_alloc(&self) -> Arc<Mutex<dyn Allocator>> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.metadata_alloc.clone()
-    }
-
-    pub fn get_data_alloc(&self) -> Arc<Mutex<dyn Allocator>> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.data_alloc.clone()
-    }
-
-    pub fn is_internal(&self, n_ptr: NodePtr) -> Result<bool> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.is_internal(n_ptr)
-    }
-
-    pub fn read<V: Serializable, Node: NodeR<V, SharedProxy>>(
-        &self,
-        n_ptr: NodePtr,
-    ) -> Result<Node> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.read(n_ptr)
+    /// Like checkpoint(), but calls sync_nodes once the nodes have been
+    /// written back, before the journal moves on.  For node stores that
+    /// buffer writes themselves.
+    pub fn checkpoint_with<F: FnOnce() -> Result<()>>(&self, sync_nodes: F) -> Result<u64> {
+        let mut journal = self.journal.lock().unwrap();
+        journal.sync()?;
+        self.cache.flush()?;
+        sync_nodes()?;
+        journal.new_segment()
     }
 
-    pub fn new_node<V: Serializable, Node: NodeW<V, ExclusiveProxy>>(
-        &self,
-        is_leaf: bool,
-    ) -> Result<JournalNode<Node, V, ExclusiveProxy>> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.new_node(is_leaf)
-    }
+    pub fn replay_entries(&self, entries: &[Entry]) -> Result<()> {
+        for e in entries {
+            self.replay_entry(e)?;
+        }
 
-    pub fn shadow<V: Serializable, Node: NodeW<V, ExclusiveProxy>>(
-        &self,
-        n_ptr: NodePtr,
-        snap_time: u32,
-    ) -> Result<JournalNode<Node, V, ExclusiveProxy>> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.shadow(n_ptr, snap_time)
+        Ok(())
     }
 
     pub fn get_batch_id(&self) -> BatchId {
@@ -287,6 +353,20 @@ impl TransactionManager {
     }
 }
 
+// Every free is journalled, including the preallocated blocks handed back
+// by release(), so allocating a block that replay thinks is already in use
+// means the journal doesn't match the allocator.
+fn replay_alloc(
+    alloc: &mut JournalAlloc<BuddyAllocator>,
+    kind: &str,
+    b: u64,
+    e: u64,
+) -> Result<()> {
+    alloc
+        .alloc_specific(b, e - b)
+        .map_err(|err| anyhow!("replaying {} allocation of [{}, {}): {:?}", kind, b, e, err))
+}
+
 //-------------------------------------------------------------------------
 
 pub struct CacheCompletion {
@@ -312,7 +392,7 @@ impl BatchCompletion for CacheCompletion {
 pub fn redistribute2<V: Serializable, Node: NodeW<V, ExclusiveProxy>>(
     left: &mut JournalNode<Node, V, ExclusiveProxy>,
     right: &mut JournalNode<Node, V, ExclusiveProxy>,
-) {
+) -> Result<()> {
     let nr_left = left.nr_entries();
     let nr_right = right.nr_entries();
     let total = nr_left + nr_right;
@@ -322,17 +402,24 @@ pub fn redistribute2<V: Serializable, Node: NodeW<V, ExclusiveProxy>>(
         std::cmp::Ordering::Less => {
             // Move entries from right to left
             let nr_move = target_left - nr_left;
-            let (keys, values) = right.shift_left(nr_move);
-            left.append(&keys, &values);
+            let (keys, values) = right
+                .shift_left(nr_move)
+                .ok_or_else(|| anyhow!("no space to redistribute node {}", right.n_ptr().loc))?;
+            let loc = left.n_ptr().loc;
+            left.append(&keys, &values).into_result(loc)?;
         }
         std::cmp::Ordering::Greater => {
             // Move entries from left to right
             let nr_move = nr_left - target_left;
-            let (keys, values) = left.remove_right(nr_move);
-            right.prepend(&keys, &values);
+            let (keys, values) = left
+                .remove_right(nr_move)
+                .ok_or_else(|| anyhow!("no space to redistribute node {}", left.n_ptr().loc))?;
+            let loc = right.n_ptr().loc;
+            right.prepend(&keys, &values).into_result(loc)?;
         }
         std::cmp::Ordering::Equal => { /* do nothing */ }
     }
+    Ok(())
 }
 
 // FIXME: do we want to move this into BTree? and redistribute2?
@@ -342,6 +429,8 @@ pub fn ensure_space<
     M: Fn(&mut JournalNode<Node, V, ExclusiveProxy>, usize) -> NodeInsertOutcome,
 >(
     cache: &TransactionManager,
+    local: Option<&Mutex<MetadataAlloc>>,
+    snap_time: u32,
     left: &mut JournalNode<Node, V, ExclusiveProxy>,
     idx: usize,
     mutator: M,
@@ -351,13 +440,16 @@ pub fn ensure_space<
     match mutator(left, idx) {
         Success => Ok(NodeResult::single(left)),
         NoSpace => {
-            let mut right = cache.new_node(left.is_leaf())?;
-            redistribute2(left, &mut right);
+            let mut right = cache.new_node(left.batch(), local, left.is_leaf(), snap_time)?;
+            redistribute2(left, &mut right)?;
 
+            // Half a node always has room for the change, so if it still
+            // doesn't fit we fail rather than lose it.
             if idx < left.nr_entries() {
-                mutator(left, idx);
+                mutator(left, idx).into_result(left.n_ptr().loc)?;
             } else {
-                mutator(&mut right, idx - left.nr_entries());
+                let loc = right.n_ptr().loc;
+                mutator(&mut right, idx - left.nr_entries()).into_result(loc)?;
             }
 
             Ok(NodeResult::pair(left, &right))
@@ -365,4 +457,23 @@ pub fn ensure_space<
     }
 }
 
+// Splits a node that has no space for a change, eg, a LogNode that can't
+// compact after an erase.  Unlike ensure_space() the change isn't made,
+// since it may span both halves; the caller abandons it and repeats it on
+// the tree with the split applied.
+pub fn split_node<V: Serializable, Node: NodeW<V, ExclusiveProxy>>(
+    cache: &TransactionManager,
+    local: Option<&Mutex<MetadataAlloc>>,
+    snap_time: u32,
+    left: &mut JournalNode<Node, V, ExclusiveProxy>,
+) -> Result<NodeResult> {
+    if left.nr_entries() < 2 {
+        return Err(anyhow!("no space to change node {}", left.n_ptr().loc));
+    }
+
+    let mut right = cache.new_node(left.batch(), local, left.is_leaf(), snap_time)?;
+    redistribute2(left, &mut right)?;
+    Ok(NodeResult::pair(left, &right))
+}
+
 //-------------------------------------------------------------------------
@@ -0,0 +1,656 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use thinp::io_engine::{Block, IoEngine, SyncIoEngine, BLOCK_SIZE};
+
+//-------------------------------------------------------------------------
+
+// An io engine that stores blocks zstd compressed, packed into the pages
+// of a file.  Since blocks are no longer a fixed size, an index maps each
+// block to the (page, offset, len) holding it.
+//
+//    superblock | index pages | data pages
+//
+// The data area starts out sized for the blocks compressing by
+// EXPECTED_RATIO, and the file is grown if they don't.
+//
+// Blocks are appended to an open page, so rewriting a block leaves a
+// hole where the old copy was.  Pages with nothing live are reused, and
+// if less than half of the space in use is live, the emptiest page is
+// cleaned by moving its blocks to the open page.
+//
+// The index and open page are only written back by flush(), which the
+// pool calls when it checkpoints.  Until then the index on disk may refer
+// to pages that have been reused, so, like the plain node file, crash
+// recovery relies on the journal.
+
+const MAGIC: u64 = 0x636f6d7072657373; // "compress"
+const VERSION: u32 = 1;
+
+const PAGE_SIZE: usize = BLOCK_SIZE;
+const INDEX_ENTRY_SIZE: usize = 8;
+const ENTRIES_PER_INDEX_PAGE: usize = PAGE_SIZE / INDEX_ENTRY_SIZE;
+
+// An extent this long holds the block uncompressed.
+const RAW_LEN: usize = PAGE_SIZE;
+
+// Blocks per data page to size a new file for.  Nodes are mostly
+// compressible, and a poor guess only costs growing the file.
+const EXPECTED_RATIO: u64 = 4;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct Extent {
+    // 0 means the block has never been written (page 0 is the superblock).
+    page: u32,
+    offset: u16,
+    len: u16,
+}
+
+#[derive(Default)]
+struct PageInfo {
+    live: usize,
+
+    // The blocks with extents in this page.
+    blocks: Vec<u64>,
+}
+
+struct OpenPage {
+    page: u32,
+    data: Vec<u8>,
+    used: usize,
+}
+
+fn nr_index_pages(nr_blocks: u64) -> u64 {
+    nr_blocks.div_ceil(ENTRIES_PER_INDEX_PAGE as u64)
+}
+
+fn first_data_page(nr_blocks: u64) -> u64 {
+    1 + nr_index_pages(nr_blocks)
+}
+
+// The most pages the file can need, even if no blocks compress.  With one
+// more data page than blocks there's always a free page to open.
+fn max_nr_pages(nr_blocks: u64) -> u64 {
+    first_data_page(nr_blocks) + nr_blocks + 1
+}
+
+fn corrupt(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct State {
+    // Replaced whenever the file grows.
+    backing: Arc<dyn IoEngine>,
+
+    index: Vec<Extent>,
+    dirty_index_pages: BTreeSet<u64>,
+
+    // Indexed by page - first_data_page.
+    pages: Vec<PageInfo>,
+    free: BTreeSet<u32>,
+    open: Option<OpenPage>,
+    cleaning: bool,
+}
+
+//-------------------------------------------------------------------------
+
+/// How much space the blocks written so far take up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpaceStats {
+    pub nr_blocks: u64,
+    pub nr_compressed_bytes: u64,
+    pub nr_pages: u64,
+
+    /// The size of the file, including the superblock, index and free
+    /// pages.
+    pub nr_file_pages: u64,
+}
+
+impl SpaceStats {
+    /// Blocks written per page used.
+    pub fn ratio(&self) -> f64 {
+        if self.nr_pages == 0 {
+            1.0
+        } else {
+            self.nr_blocks as f64 / self.nr_pages as f64
+        }
+    }
+}
+
+pub struct CompressedIoEngine {
+    path: PathBuf,
+    nr_blocks: u64,
+    level: i32,
+    state: Mutex<State>,
+}
+
+impl CompressedIoEngine {
+    /// The nr of pages a new file holding nr_blocks blocks starts with.
+    pub fn initial_nr_pages(nr_blocks: u64) -> u64 {
+        let nr_data_pages = nr_blocks.div_ceil(EXPECTED_RATIO) + 1;
+        (first_data_page(nr_blocks) + nr_data_pages).min(max_nr_pages(nr_blocks))
+    }
+
+    fn new(path: &Path, nr_blocks: u64, level: i32) -> Result<Self> {
+        let backing: Arc<dyn IoEngine> = Arc::new(SyncIoEngine::new(path, true)?);
+        let nr_pages = backing.get_nr_blocks();
+        let first = first_data_page(nr_blocks);
+        if nr_pages <= first || nr_pages > max_nr_pages(nr_blocks) {
+            return Err(anyhow!(
+                "{} blocks can't be stored in {} pages",
+                nr_blocks,
+                nr_pages
+            ));
+        }
+
+        let state = State {
+            backing,
+            index: vec![Extent::default(); nr_blocks as usize],
+            dirty_index_pages: BTreeSet::new(),
+            pages: (first..nr_pages).map(|_| PageInfo::default()).collect(),
+            free: (first as u32..nr_pages as u32).collect(),
+            open: None,
+            cleaning: false,
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            nr_blocks,
+            level,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Creates a file at path holding a fresh, empty store.
+    pub fn format<P: AsRef<Path>>(path: P, nr_blocks: u64, level: i32) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.set_len(Self::initial_nr_pages(nr_blocks) * PAGE_SIZE as u64)?;
+        drop(file);
+
+        let engine = Self::new(path, nr_blocks, level)?;
+        let state = engine.state.lock().unwrap();
+
+        let sb = Block::zeroed(0);
+        {
+            let mut w = &mut sb.get_data()[..];
+            w.write_u64::<LittleEndian>(MAGIC)?;
+            w.write_u32::<LittleEndian>(VERSION)?;
+            w.write_i32::<LittleEndian>(level)?;
+            w.write_u64::<LittleEndian>(nr_blocks)?;
+        }
+        state.backing.write(&sb)?;
+
+        for p in 1..first_data_page(nr_blocks) {
+            state.backing.write(&Block::zeroed(p))?;
+        }
+
+        drop(state);
+        Ok(engine)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let sb = SyncIoEngine::new(path, false)?.read(0)?;
+        let mut r = &sb.get_data()[..];
+        let magic = r.read_u64::<LittleEndian>()?;
+        if magic != MAGIC {
+            return Err(anyhow!("not a compressed node store"));
+        }
+        let version = r.read_u32::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(anyhow!("unsupported version {}", version));
+        }
+        let level = r.read_i32::<LittleEndian>()?;
+        let nr_blocks = r.read_u64::<LittleEndian>()?;
+
+        let engine = Self::new(path, nr_blocks, level)?;
+        {
+            let mut state = engine.state.lock().unwrap();
+            let first = first_data_page(nr_blocks);
+            let nr_pages = state.backing.get_nr_blocks();
+            for p in 1..first {
+                let page = state.backing.read(p)?;
+                let mut r = &page.get_data()[..];
+                let b = (p - 1) * ENTRIES_PER_INDEX_PAGE as u64;
+                for b in b..nr_blocks.min(b + ENTRIES_PER_INDEX_PAGE as u64) {
+                    let e = Extent {
+                        page: r.read_u32::<LittleEndian>()?,
+                        offset: r.read_u16::<LittleEndian>()?,
+                        len: r.read_u16::<LittleEndian>()?,
+                    };
+                    if e.page == 0 {
+                        continue;
+                    }
+                    if (e.page as u64) < first
+                        || e.page as u64 >= nr_pages
+                        || e.offset as usize + e.len as usize > PAGE_SIZE
+                    {
+                        return Err(anyhow!("block {} has a bad index entry", b));
+                    }
+
+                    state.index[b as usize] = e;
+                    let info = &mut state.pages[(e.page as u64 - first) as usize];
+                    info.live += e.len as usize;
+                    info.blocks.push(b);
+                    state.free.remove(&e.page);
+                }
+            }
+        }
+
+        Ok(engine)
+    }
+
+    pub fn stats(&self) -> SpaceStats {
+        let state = self.state.lock().unwrap();
+        let mut stats = SpaceStats {
+            nr_file_pages: state.backing.get_nr_blocks(),
+            ..Default::default()
+        };
+        for info in &state.pages {
+            if info.live > 0 {
+                stats.nr_blocks += info.blocks.len() as u64;
+                stats.nr_compressed_bytes += info.live as u64;
+                stats.nr_pages += 1;
+            }
+        }
+        stats
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let compressed = zstd::bulk::compress(data, self.level)?;
+        if compressed.len() < RAW_LEN {
+            Ok(compressed)
+        } else {
+            Ok(data.to_vec())
+        }
+    }
+
+    fn first_data_page(&self) -> u64 {
+        first_data_page(self.nr_blocks)
+    }
+
+    fn page_info<'a>(&self, state: &'a mut State, page: u32) -> &'a mut PageInfo {
+        &mut state.pages[(page as u64 - self.first_data_page()) as usize]
+    }
+
+    // Reads the stored bytes for a block, which may be in the open page.
+    fn read_extent(&self, state: &State, e: Extent) -> io::Result<Vec<u8>> {
+        let (b, len) = (e.offset as usize, e.len as usize);
+        match &state.open {
+            Some(open) if open.page == e.page => Ok(open.data[b..b + len].to_vec()),
+            _ => {
+                let page = state.backing.read(e.page as u64)?;
+                Ok(page.get_data()[b..b + len].to_vec())
+            }
+        }
+    }
+
+    fn release(&self, state: &mut State, b: u64) {
+        let e = state.index[b as usize];
+        if e.page == 0 {
+            return;
+        }
+
+        let is_open = state.open.as_ref().is_some_and(|o| o.page == e.page);
+        let info = self.page_info(state, e.page);
+        info.live -= e.len as usize;
+        info.blocks.retain(|x| *x != b);
+        if info.live == 0 && !is_open {
+            state.free.insert(e.page);
+        }
+        state.index[b as usize] = Extent::default();
+    }
+
+    fn seal(&self, state: &mut State) -> io::Result<()> {
+        if let Some(open) = state.open.take() {
+            let block = Block::new(open.page as u64);
+            block.get_data().copy_from_slice(&open.data);
+            state.backing.write(&block)?;
+            if self.page_info(state, open.page).live == 0 {
+                state.free.insert(open.page);
+            }
+        }
+        Ok(())
+    }
+
+    // Extends the data area by half, up to the most it can need.
+    fn grow(&self, state: &mut State) -> Result<()> {
+        let nr_pages = state.backing.get_nr_blocks();
+        let max = max_nr_pages(self.nr_blocks);
+        if nr_pages >= max {
+            return Err(anyhow!("no free pages"));
+        }
+
+        let nr_data_pages = nr_pages - self.first_data_page();
+        let new_nr_pages = (nr_pages + nr_data_pages.div_ceil(2)).min(max);
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_len(new_nr_pages * PAGE_SIZE as u64)?;
+        drop(file);
+
+        state.backing = Arc::new(SyncIoEngine::new(&self.path, true)?);
+        state
+            .pages
+            .extend((nr_pages..new_nr_pages).map(|_| PageInfo::default()));
+        state.free.extend(nr_pages as u32..new_nr_pages as u32);
+        Ok(())
+    }
+
+    fn open_page(&self, state: &mut State) -> io::Result<()> {
+        if state.free.is_empty() {
+            self.grow(state).map_err(io::Error::other)?;
+        }
+
+        let page = state
+            .free
+            .pop_first()
+            .ok_or_else(|| corrupt("no free pages".to_string()))?;
+        state.open = Some(OpenPage {
+            page,
+            data: vec![0; PAGE_SIZE],
+            used: 0,
+        });
+        Ok(())
+    }
+
+    fn store(&self, state: &mut State, b: u64, bytes: &[u8]) -> io::Result<()> {
+        self.release(state, b);
+
+        let fits = state
+            .open
+            .as_ref()
+            .is_some_and(|o| o.used + bytes.len() <= PAGE_SIZE);
+        if !fits {
+            self.seal(state)?;
+            self.open_page(state)?;
+        }
+
+        let open = state.open.as_mut().unwrap();
+        let e = Extent {
+            page: open.page,
+            offset: open.used as u16,
+            len: bytes.len() as u16,
+        };
+        open.data[open.used..open.used + bytes.len()].copy_from_slice(bytes);
+        open.used += bytes.len();
+
+        let info = self.page_info(state, e.page);
+        info.live += bytes.len();
+        info.blocks.push(b);
+        state.index[b as usize] = e;
+        state
+            .dirty_index_pages
+            .insert(1 + b / ENTRIES_PER_INDEX_PAGE as u64);
+
+        if !fits {
+            self.maybe_clean(state)?;
+        }
+        Ok(())
+    }
+
+    // Moves the blocks out of the emptiest page, if less than half the
+    // space in use is live.
+    fn maybe_clean(&self, state: &mut State) -> io::Result<()> {
+        if state.cleaning {
+            return Ok(());
+        }
+
+        let open_page = state.open.as_ref().map(|o| o.page);
+        let mut live = 0;
+        let mut nr_pages = 0;
+        let mut victim: Option<(usize, u32)> = None;
+        for (i, info) in state.pages.iter().enumerate() {
+            if info.live == 0 {
+                continue;
+            }
+            live += info.live;
+            nr_pages += 1;
+
+            let page = (self.first_data_page() + i as u64) as u32;
+            if Some(page) != open_page && victim.is_none_or(|(l, _)| info.live < l) {
+                victim = Some((info.live, page));
+            }
+        }
+
+        let Some((_, page)) = victim else {
+            return Ok(());
+        };
+        if live * 2 >= nr_pages * PAGE_SIZE {
+            return Ok(());
+        }
+
+        state.cleaning = true;
+        let data = state.backing.read(page as u64)?;
+        let blocks = self.page_info(state, page).blocks.clone();
+        let mut r = Ok(());
+        for b in blocks {
+            let e = state.index[b as usize];
+            let bytes = &data.get_data()[e.offset as usize..(e.offset + e.len) as usize];
+            r = self.store(state, b, bytes);
+            if r.is_err() {
+                break;
+            }
+        }
+        state.cleaning = false;
+        r
+    }
+
+    /// Writes back the open page and any index pages that have changed
+    /// since the last flush.
+    pub fn flush(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.sync(&mut state)?;
+        Ok(())
+    }
+
+    fn sync(&self, state: &mut State) -> io::Result<()> {
+        if let Some(open) = &state.open {
+            let block = Block::new(open.page as u64);
+            block.get_data().copy_from_slice(&open.data);
+            state.backing.write(&block)?;
+        }
+
+        for p in std::mem::take(&mut state.dirty_index_pages) {
+            let block = Block::zeroed(p);
+            let mut w = &mut block.get_data()[..];
+            let b = (p - 1) * ENTRIES_PER_INDEX_PAGE as u64;
+            for b in b..self.nr_blocks.min(b + ENTRIES_PER_INDEX_PAGE as u64) {
+                let e = state.index[b as usize];
+                w.write_u32::<LittleEndian>(e.page)?;
+                w.write_u16::<LittleEndian>(e.offset)?;
+                w.write_u16::<LittleEndian>(e.len)?;
+            }
+            state.backing.write(&block)?;
+        }
+        Ok(())
+    }
+}
+
+impl IoEngine for CompressedIoEngine {
+    fn get_nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    fn get_batch_size(&self) -> usize {
+        1
+    }
+
+    fn suggest_nr_threads(&self) -> usize {
+        1
+    }
+
+    fn read(&self, b: u64) -> io::Result<Block> {
+        if b >= self.nr_blocks {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let block = Block::new(b);
+        let bytes = {
+            let state = self.state.lock().unwrap();
+            let e = state.index[b as usize];
+            if e.page == 0 {
+                // Never written, so it reads as zeroes like a fresh file.
+                return Ok(block);
+            }
+            self.read_extent(&state, e)?
+        };
+
+        if bytes.len() == RAW_LEN {
+            block.get_data().copy_from_slice(&bytes);
+        } else {
+            let data = zstd::bulk::decompress(&bytes, BLOCK_SIZE)?;
+            if data.len() != BLOCK_SIZE {
+                return Err(corrupt(format!("block {} decompressed badly", b)));
+            }
+            block.get_data().copy_from_slice(&data);
+        }
+        Ok(block)
+    }
+
+    fn read_many(&self, blocks: &[u64]) -> io::Result<Vec<io::Result<Block>>> {
+        Ok(blocks.iter().map(|b| self.read(*b)).collect())
+    }
+
+    fn write(&self, block: &Block) -> io::Result<()> {
+        self.write_many(std::slice::from_ref(block))?.pop().unwrap()
+    }
+
+    fn write_many(&self, blocks: &[Block]) -> io::Result<Vec<io::Result<()>>> {
+        let mut compressed = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            if block.loc >= self.nr_blocks {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            compressed.push(self.compress(block.get_data())?);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for (block, bytes) in blocks.iter().zip(compressed) {
+            self.store(&mut state, block.loc, &bytes)?;
+        }
+
+        Ok(blocks.iter().map(|_| Ok(())).collect())
+    }
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use tempfile::TempDir;
+
+    fn store_path(dir: &TempDir) -> PathBuf {
+        dir.path().join("store")
+    }
+
+    fn mk_engine(nr_blocks: u64) -> Result<(TempDir, CompressedIoEngine)> {
+        let dir = TempDir::new()?;
+        let engine = CompressedIoEngine::format(store_path(&dir), nr_blocks, 3)?;
+        Ok((dir, engine))
+    }
+
+    // Half the block is a pattern, the rest noise, so it compresses to
+    // around half.
+    fn mk_block(loc: u64, seed: u8, rng: &mut impl Rng) -> Block {
+        let block = Block::new(loc);
+        let data = block.get_data();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = if i % 2 == 0 { seed } else { rng.gen() };
+        }
+        block
+    }
+
+    #[test]
+    fn test_unwritten_reads_zero() -> Result<()> {
+        let (_dir, engine) = mk_engine(16)?;
+        let block = engine.read(3)?;
+        assert!(block.get_data().iter().all(|b| *b == 0));
+        assert!(engine.read(16).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrites() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let nr_blocks = 64;
+        let (dir, engine) = mk_engine(nr_blocks)?;
+
+        let mut expected = vec![None; nr_blocks as usize];
+        for i in 0..2000 {
+            let loc = rng.gen_range(0..nr_blocks);
+            let block = if rng.gen_ratio(1, 4) {
+                Block::zeroed(loc)
+            } else {
+                mk_block(loc, i as u8, &mut rng)
+            };
+            expected[loc as usize] = Some(block.get_data().to_vec());
+            engine.write(&block)?;
+
+            let loc = rng.gen_range(0..nr_blocks);
+            if let Some(data) = &expected[loc as usize] {
+                assert_eq!(engine.read(loc)?.get_data(), &data[..]);
+            }
+        }
+
+        // Cleaning keeps at least half the space in use live.
+        let stats = engine.stats();
+        assert!(
+            stats.nr_compressed_bytes * 2
+                >= stats.nr_pages * PAGE_SIZE as u64 - 2 * PAGE_SIZE as u64
+        );
+        assert!(stats.nr_pages < nr_blocks);
+
+        // These blocks only compress to around half, so the file grew.
+        assert!(stats.nr_file_pages > CompressedIoEngine::initial_nr_pages(nr_blocks));
+        assert!(stats.nr_file_pages <= max_nr_pages(nr_blocks));
+
+        // Everything survives reopening.
+        engine.flush()?;
+        drop(engine);
+        let engine = CompressedIoEngine::open(store_path(&dir))?;
+        for (loc, data) in expected.iter().enumerate() {
+            if let Some(data) = data {
+                assert_eq!(engine.read(loc as u64)?.get_data(), &data[..]);
+            }
+        }
+        assert_eq!(engine.stats(), stats);
+        Ok(())
+    }
+
+    #[test]
+    fn test_incompressible() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let (_dir, engine) = mk_engine(8)?;
+
+        let block = Block::new(5);
+        rng.fill(block.get_data());
+        engine.write(&block)?;
+        assert_eq!(engine.read(5)?.get_data(), block.get_data());
+        assert_eq!(engine.stats().nr_compressed_bytes, PAGE_SIZE as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_written_at_flush() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let (dir, engine) = mk_engine(8)?;
+        let block = mk_block(2, 0, &mut rng);
+        engine.write(&block)?;
+
+        let reopened = CompressedIoEngine::open(store_path(&dir))?;
+        assert!(reopened.read(2)?.get_data().iter().all(|b| *b == 0));
+
+        engine.flush()?;
+        let reopened = CompressedIoEngine::open(store_path(&dir))?;
+        assert_eq!(reopened.read(2)?.get_data(), block.get_data());
+        Ok(())
+    }
+}
+
+//-------------------------------------------------------------------------
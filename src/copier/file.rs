@@ -0,0 +1,127 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::copier::base::*;
+use crate::types::PBlock;
+
+//-------------------------------------
+
+// Nr of bytes moved by a single read or write.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Copies blocks from one file, or device, to another.  Zero ops are
+/// applied to the destination.
+pub struct FileCopier {
+    src: File,
+    dst: File,
+    block_size: u64,
+}
+
+impl FileCopier {
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        src: P,
+        dst: Q,
+        block_size: u64,
+    ) -> io::Result<Self> {
+        let src = File::open(src)?;
+        let dst = OpenOptions::new().write(true).open(dst)?;
+        Ok(Self {
+            src,
+            dst,
+            block_size,
+        })
+    }
+
+    fn chunks(&self, begin: PBlock, end: PBlock) -> impl Iterator<Item = (PBlock, PBlock)> {
+        let blocks_per_chunk = (CHUNK_SIZE / self.block_size).max(1);
+        (begin..end)
+            .step_by(blocks_per_chunk as usize)
+            .map(move |b| (b, end.min(b + blocks_per_chunk)))
+    }
+
+    fn copy(&self, op: &CopyOp) -> Result<()> {
+        let delta = op.dst_begin as i64 - op.src_begin as i64;
+        for (b, e) in self.chunks(op.src_begin, op.src_end) {
+            let mut buf = vec![0; ((e - b) * self.block_size) as usize];
+            self.src
+                .read_exact_at(&mut buf, b * self.block_size)
+                .map_err(|_| CopyErr::BadIo(vec![(IoDir::Read, b)]))?;
+
+            let dst_b = (b as i64 + delta) as PBlock;
+            self.dst
+                .write_all_at(&buf, dst_b * self.block_size)
+                .map_err(|_| CopyErr::BadIo(vec![(IoDir::Write, dst_b)]))?;
+        }
+        Ok(())
+    }
+
+    fn zero(&self, op: &ZeroOp) -> Result<()> {
+        for (b, e) in self.chunks(op.begin, op.end) {
+            let buf = vec![0; ((e - b) * self.block_size) as usize];
+            self.dst
+                .write_all_at(&buf, b * self.block_size)
+                .map_err(|_| CopyErr::BadIo(vec![(IoDir::Write, b)]))?;
+        }
+        Ok(())
+    }
+}
+
+impl Copier for FileCopier {
+    fn exec(&self, ops: &[DataOp]) -> Result<()> {
+        for op in ops {
+            match op {
+                DataOp::Copy(op) => self.copy(op)?,
+                DataOp::Zero(op) => self.zero(op)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+//-------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_copy_and_zero() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        let src_data: Vec<u8> = (0..16 * 512).map(|i| (i / 512) as u8 + 1).collect();
+        fs::write(&src, &src_data)?;
+        fs::write(&dst, vec![0xff; 16 * 512])?;
+
+        let copier = FileCopier::new(&src, &dst, 512)?;
+        copier.exec(&[
+            DataOp::Copy(CopyOp {
+                src_begin: 2,
+                src_end: 5,
+                dst_begin: 10,
+            }),
+            DataOp::Zero(ZeroOp { begin: 0, end: 1 }),
+        ])?;
+
+        let dst_data = fs::read(&dst)?;
+        assert_eq!(&dst_data[..512], &[0; 512][..]);
+        assert_eq!(&dst_data[512..10 * 512], &[0xff; 9 * 512][..]);
+        assert_eq!(&dst_data[10 * 512..13 * 512], &src_data[2 * 512..5 * 512]);
+        assert_eq!(&dst_data[13 * 512..], &[0xff; 3 * 512][..]);
+
+        // Reading past the end of the source fails
+        let r = copier.exec(&[DataOp::Copy(CopyOp {
+            src_begin: 15,
+            src_end: 17,
+            dst_begin: 0,
+        })]);
+        assert!(r.is_err());
+        Ok(())
+    }
+}
+
+//-------------------------------------
@@ -1,4 +1,5 @@
 pub mod base;
 pub mod fake;
+pub mod file;
 
 pub use crate::copier::base::*;
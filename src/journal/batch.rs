@@ -1,71 +1,209 @@
 use anyhow::{anyhow, Result};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use num_enum::TryFromPrimitive;
-use std::cell::RefCell;
-use std::collections::{BTreeMap, VecDeque};
-use std::convert::TryFrom;
-use std::io::{Read, Write};
-use std::path::Path;
-use std::thread_local;
-
-use crate::block_cache::*;
-use crate::btree::node::Key;
-use crate::btree::*;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
 use crate::journal::entry::*;
-use crate::journal::format::*;
-use crate::journal::pack::*;
-use crate::slab::*;
-use crate::types::*;
 
 //-------------------------------------------------------------------------
 
-thread_local! {
-    static BATCH: RefCell<Option<Vec<Entry>>> = const {RefCell::new(None)};
+struct BatchInner {
+    // Shared by a batch and all of its sub batches.  Every entry is
+    // stamped with the next value so sub batches can be merged back into
+    // their parent in the order the entries were made.
+    next_seq: Arc<AtomicU64>,
+
+    entries: Mutex<Vec<(u64, Entry)>>,
+    parent: Option<Arc<BatchInner>>,
+
+    // Nr of sub batches that haven't been merged back into this one yet.
+    nr_live_subs: AtomicUsize,
 }
 
-pub fn begin_batch() -> Result<()> {
-    BATCH.with(|f| {
-        if f.borrow_mut().is_some() {
-            Err(anyhow!("already in batch"))
-        } else {
-            *f.borrow_mut() = Some(Vec::new());
-            Ok(())
+impl BatchInner {
+    fn take(&self) -> Vec<(u64, Entry)> {
+        std::mem::take(&mut *self.entries.lock().unwrap())
+    }
+
+    fn merge_into_parent(&self) {
+        if let Some(parent) = &self.parent {
+            let mut entries = self.take();
+            if !entries.is_empty() {
+                let mut parent_entries = parent.entries.lock().unwrap();
+                parent_entries.append(&mut entries);
+
+                // Both halves are already sorted, so this is cheap.
+                parent_entries.sort_by_key(|(seq, _)| *seq);
+            }
+            parent.nr_live_subs.fetch_sub(1, Ordering::SeqCst);
         }
-    })
+    }
 }
 
-pub fn end_batch() -> Result<Vec<Entry>> {
-    BATCH.with(|f| {
-        if f.borrow_mut().is_some() {
-            let mut entries = None;
-            std::mem::swap(&mut entries, &mut *f.borrow_mut());
-            Ok(entries.unwrap())
-        } else {
-            Err(anyhow!("not in a batch"))
-        }
-    })
+impl Drop for BatchInner {
+    fn drop(&mut self) {
+        self.merge_into_parent();
+    }
+}
+
+/// Collects the journal entries for a batch of operations.  This is a
+/// handle; clones refer to the same batch, so the work may be spread
+/// across threads.
+#[derive(Clone)]
+pub struct BatchContext {
+    inner: Arc<BatchInner>,
+}
+
+impl Default for BatchContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub fn add_entry(e: Entry) -> Result<()> {
-    BATCH.with(|f| {
-        if let Some(ref mut batch) = *f.borrow_mut() {
-            batch.push(e);
-            Ok(())
-        } else {
-            Err(anyhow!("not in a batch"))
+impl BatchContext {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(BatchInner {
+                next_seq: Arc::new(AtomicU64::new(0)),
+                entries: Mutex::new(Vec::new()),
+                parent: None,
+                nr_live_subs: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Starts a nested batch.  Its entries are merged into this one once
+    /// every handle to the sub batch has been dropped.
+    pub fn sub_batch(&self) -> Self {
+        self.inner.nr_live_subs.fetch_add(1, Ordering::SeqCst);
+        Self {
+            inner: Arc::new(BatchInner {
+                next_seq: self.inner.next_seq.clone(),
+                entries: Mutex::new(Vec::new()),
+                parent: Some(self.inner.clone()),
+                nr_live_subs: AtomicUsize::new(0),
+            }),
         }
-    })
+    }
+
+    pub fn is_sub_batch(&self) -> bool {
+        self.inner.parent.is_some()
+    }
+
+    pub fn add_entry(&self, e: Entry) {
+        // The entries lock is held while we take a seq nr so they're
+        // pushed in order.
+        let mut entries = self.inner.entries.lock().unwrap();
+        let seq = self.inner.next_seq.fetch_add(1, Ordering::SeqCst);
+        entries.push((seq, e));
+    }
+
+    pub fn add_entries(&self, es: &[Entry]) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        for e in es {
+            let seq = self.inner.next_seq.fetch_add(1, Ordering::SeqCst);
+            entries.push((seq, e.clone()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Completes a top level batch, returning its entries.  Every sub
+    /// batch must have been dropped first, so its entries are merged in.
+    pub fn end(self) -> Result<Vec<Entry>> {
+        if self.is_sub_batch() {
+            return Err(anyhow!(
+                "sub batches are merged into their parent, not ended"
+            ));
+        }
+
+        let nr_live = self.inner.nr_live_subs.load(Ordering::SeqCst);
+        if nr_live > 0 {
+            return Err(anyhow!(
+                "batch ended with {} sub batches still live",
+                nr_live
+            ));
+        }
+
+        Ok(self.inner.take().into_iter().map(|(_, e)| e).collect())
+    }
 }
 
-pub fn add_entries(es: &[Entry]) -> Result<()> {
-    BATCH.with(|f| {
-        if let Some(ref mut batch) = *f.borrow_mut() {
-            batch.extend_from_slice(es);
-            Ok(())
-        } else {
-            Err(anyhow!("not in a batch"))
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn entries(ctx: BatchContext) -> Vec<Entry> {
+        ctx.end().unwrap()
+    }
+
+    #[test]
+    fn test_sub_batch_merges_in_order() {
+        let ctx = BatchContext::new();
+        ctx.add_entry(Entry::Zero(0, 0, 1));
+        {
+            let sub = ctx.sub_batch();
+            sub.add_entry(Entry::Zero(1, 0, 1));
+            ctx.add_entry(Entry::Zero(2, 0, 1));
+            sub.add_entry(Entry::Zero(3, 0, 1));
+            assert_eq!(ctx.len(), 2);
+        }
+        assert_eq!(ctx.len(), 4);
+
+        let locs: Vec<u32> = entries(ctx)
+            .iter()
+            .map(|e| match e {
+                Entry::Zero(loc, _, _) => *loc,
+                _ => panic!("unexpected entry"),
+            })
+            .collect();
+        assert_eq!(locs, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_sub_batch_cant_end() {
+        let ctx = BatchContext::new();
+        assert!(ctx.sub_batch().end().is_err());
+    }
+
+    #[test]
+    fn test_end_with_live_sub_batch() {
+        let ctx = BatchContext::new();
+        let sub = ctx.sub_batch();
+        sub.add_entry(Entry::Zero(0, 0, 1));
+        assert!(ctx.clone().end().is_err());
+
+        // Once the sub batch has gone its entries are included.
+        drop(sub);
+        assert_eq!(entries(ctx).len(), 1);
+    }
+
+    #[test]
+    fn test_cross_thread() {
+        let ctx = BatchContext::new();
+        let mut handles = Vec::new();
+        for t in 0..4 {
+            let sub = ctx.sub_batch();
+            handles.push(thread::spawn(move || {
+                for i in 0..100 {
+                    sub.add_entry(Entry::Zero(t, i, i + 1));
+                }
+            }));
+        }
+
+        for h in handles {
+            h.join().unwrap();
         }
-    })
+        assert_eq!(entries(ctx).len(), 400);
+    }
 }
 
 //-------------------------------------------------------------------------
@@ -16,7 +16,7 @@ use crate::types::*;
 
 pub type Bytes = Vec<u8>;
 
-#[derive(Clone, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub enum Entry {
     AllocMetadata(u32, u32), // begin, end
     FreeMetadata(u32, u32),  // begin, end
@@ -29,6 +29,10 @@ pub enum Entry {
     // FIXME: Add UpdateMappingRoot
     UpdateInfoRoot(NodePtr),
 
+    // Starts each journal segment.  Everything before it has been
+    // written to the node file.
+    Checkpoint(u64), // generation
+
     SetSeq(MetadataBlock, SequenceNr), // Only used when rereading output log
     Zero(MetadataBlock, usize, usize), // begin, end (including node header)
     Literal(MetadataBlock, usize, Bytes), // offset, bytes
@@ -41,3 +45,67 @@ pub enum Entry {
 }
 
 //-------------------------------------------------------------------------
+
+/// The short names used for each kind of entry, in tag order.
+pub const ENTRY_KINDS: [&str; 17] = [
+    "alm", "frm", "grm", "ald", "frd", "grd", "uir", "seq", "zero", "lit", "shadow", "ovr", "ins",
+    "pre", "app", "era", "chk",
+];
+
+impl Entry {
+    /// Short name for the kind of entry, matching format_op().
+    pub fn kind(&self) -> &'static str {
+        use Entry::*;
+        match self {
+            AllocMetadata(..) => "alm",
+            FreeMetadata(..) => "frm",
+            GrowMetadata(..) => "grm",
+            AllocData(..) => "ald",
+            FreeData(..) => "frd",
+            GrowData(..) => "grd",
+            UpdateInfoRoot(..) => "uir",
+            Checkpoint(..) => "chk",
+            SetSeq(..) => "seq",
+            Zero(..) => "zero",
+            Literal(..) => "lit",
+            Shadow(..) => "shadow",
+            Overwrite(..) => "ovr",
+            Insert(..) => "ins",
+            Prepend(..) => "pre",
+            Append(..) => "app",
+            Erase(..) => "era",
+        }
+    }
+
+    /// The metadata block this entry modifies, if any.
+    pub fn node(&self) -> Option<MetadataBlock> {
+        use Entry::*;
+        match self {
+            SetSeq(loc, _)
+            | Zero(loc, _, _)
+            | Literal(loc, _, _)
+            | Shadow(loc, _)
+            | Overwrite(loc, _, _, _)
+            | Insert(loc, _, _, _)
+            | Prepend(loc, _, _)
+            | Append(loc, _, _)
+            | Erase(loc, _, _) => Some(*loc),
+            _ => None,
+        }
+    }
+
+    /// True if the entry refers to the given metadata block in any way;
+    /// modifying it, shadowing from it, allocating or freeing it, or
+    /// making it the info root.
+    pub fn touches_metadata(&self, b: MetadataBlock) -> bool {
+        use Entry::*;
+        match self {
+            AllocMetadata(begin, end) | FreeMetadata(begin, end) => *begin <= b && b < *end,
+            UpdateInfoRoot(root) => root.loc == b,
+            Shadow(loc, origin) => *loc == b || origin.loc == b,
+            _ => self.node() == Some(b),
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
@@ -0,0 +1,190 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::journal::entry::*;
+use crate::journal::*;
+use crate::slab::*;
+
+//-------------------------------------------------------------------------
+
+/// Tails the journal of a running pool, like `tail -f`.  Batches are
+/// decoded as the slabs holding them are completed.  This scans the data
+/// file directly, since the offsets file isn't written until the journal
+/// is closed.
+pub struct Follower {
+    path: PathBuf,
+    tail: SlabTailer,
+    encoding: Encoding,
+}
+
+impl Follower {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let tail = SlabTailer::open(&path)?;
+        let encoding = Encoding::from_flags(tail.client_flags())?;
+
+        Ok(Self {
+            path,
+            tail,
+            encoding,
+        })
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Calls `f` for each batch in the slabs that have been completed since
+    /// the last call.  As with Journal::replay(), this stops at the first
+    /// bad batch.
+    pub fn poll<F>(&mut self, mut f: F) -> Result<Option<BadBatch>>
+    where
+        F: FnMut(BatchLocation, &[Entry]) -> Result<()>,
+    {
+        loop {
+            let slab = self.tail.nr_slabs();
+            match self.tail.next_slab() {
+                Ok(Some((slab, bytes))) => {
+                    if let Some(bad) = replay_slab(slab, &bytes, self.encoding, &mut f)? {
+                        return Ok(Some(bad));
+                    }
+                }
+                Ok(None) => {
+                    // A checkpoint renames a new segment over the journal,
+                    // but only once everything has been written to the old
+                    // one.  So there's nothing more to read from it.
+                    if !self.tail.replaced()? {
+                        return Ok(None);
+                    }
+
+                    self.tail = SlabTailer::open(&self.path)?;
+                    self.encoding = Encoding::from_flags(self.tail.client_flags())?;
+                }
+                Err(e) => {
+                    let loc = BatchLocation {
+                        slab,
+                        batch: 0,
+                        offset: 0,
+                    };
+                    let reason = format!("couldn't read slab: {}", e);
+                    return Ok(Some(BadBatch { loc, reason }));
+                }
+            }
+        }
+    }
+
+    /// Polls every `interval` until a bad batch is found, or `f` fails.
+    pub fn follow<F>(&mut self, interval: Duration, mut f: F) -> Result<BadBatch>
+    where
+        F: FnMut(BatchLocation, &[Entry]) -> Result<()>,
+    {
+        loop {
+            if let Some(bad) = self.poll(&mut f)? {
+                return Ok(bad);
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    fn mk_batch(n: u32) -> Batch {
+        Batch {
+            ops: vec![Entry::AllocMetadata(n, n + 1)],
+            completion: None,
+        }
+    }
+
+    // Slabs are written by a background thread, so we may have to wait
+    // for them.
+    fn poll_for(follower: &mut Follower, nr_entries: usize) -> Result<Vec<Entry>> {
+        let start = Instant::now();
+        let mut entries = Vec::new();
+        while entries.len() < nr_entries && start.elapsed() < Duration::from_secs(10) {
+            let bad = follower.poll(|_, ops| {
+                entries.extend_from_slice(ops);
+                Ok(())
+            })?;
+            assert!(bad.is_none());
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_follow_live_journal() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("journal");
+        let mut journal = Journal::create(&path)?;
+        let mut follower = Follower::open(&path)?;
+
+        journal.add_batch(mk_batch(0));
+        journal.sync()?;
+        assert_eq!(poll_for(&mut follower, 1)?, mk_batch(0).ops);
+
+        journal.add_batch(mk_batch(1));
+        journal.add_batch(mk_batch(2));
+        journal.sync()?;
+        let entries = poll_for(&mut follower, 2)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1], mk_batch(2).ops[0]);
+
+        // Follows onto the new segment
+        let generation = journal.new_segment()?;
+        journal.add_batch(mk_batch(3));
+        journal.sync()?;
+        let entries = poll_for(&mut follower, 2)?;
+        assert_eq!(
+            entries,
+            vec![Entry::Checkpoint(generation), mk_batch(3).ops[0].clone()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_tail_slab() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("journal");
+        {
+            let mut journal = Journal::create(&path)?;
+            journal.add_batch(mk_batch(0));
+            journal.sync()?;
+            journal.add_batch(mk_batch(1));
+            journal.sync()?;
+        }
+
+        // Copy the journal a few bytes short, as though the writer was
+        // still part way through the last slab.
+        let data = fs::read(&path)?;
+        let copy = dir.path().join("copy");
+        let cut = data.len() - 3;
+        fs::write(&copy, &data[..cut])?;
+
+        let mut follower = Follower::open(&copy)?;
+        let mut entries = Vec::new();
+        let bad = follower.poll(|_, ops| {
+            entries.extend_from_slice(ops);
+            Ok(())
+        })?;
+        assert!(bad.is_none());
+        assert_eq!(entries, mk_batch(0).ops);
+
+        let mut f = OpenOptions::new().append(true).open(&copy)?;
+        f.write_all(&data[cut..])?;
+        assert_eq!(poll_for(&mut follower, 1)?, mk_batch(1).ops);
+        Ok(())
+    }
+}
+
+//-------------------------------------------------------------------------
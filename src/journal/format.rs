@@ -16,7 +16,7 @@ use crate::types::*;
 
 //-------------------------------------------------------------------------
 
-fn to_hex(bytes: &[u8]) -> String {
+pub fn to_hex(bytes: &[u8]) -> String {
     use std::fmt::Write;
 
     let mut output = "0x".to_string();
@@ -38,6 +38,7 @@ pub fn format_op(entry: &Entry) -> String {
         GrowData(extra) => format!("grd\t{}", extra),
 
         UpdateInfoRoot(root) => format!("uir {}:{}", root.loc, root.seq_nr),
+        Checkpoint(generation) => format!("chk\t{}", generation),
 
         SetSeq(loc, seq) => format!("seq\t{} <- {}", loc, seq),
         Zero(loc, begin, end) => format!("zero\t{}@{}..{}", loc, begin, end),
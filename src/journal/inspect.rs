@@ -0,0 +1,499 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::journal::entry::*;
+use crate::journal::follow::*;
+use crate::journal::format::*;
+use crate::journal::*;
+use crate::types::*;
+
+//-------------------------------------------------------------------------
+
+/// Selects which entries are shown.  Empty sets match everything.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub blocks: BTreeSet<MetadataBlock>,
+
+    // Short kind names, see ENTRY_KINDS
+    pub kinds: BTreeSet<String>,
+    pub slabs: Option<Range<u32>>,
+}
+
+impl Filter {
+    pub fn matches_slab(&self, slab: u32) -> bool {
+        self.slabs.as_ref().is_none_or(|r| r.contains(&slab))
+    }
+
+    pub fn matches(&self, e: &Entry) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(e.kind()))
+            && (self.blocks.is_empty() || self.blocks.iter().any(|b| e.touches_metadata(*b)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DumpOptions {
+    pub filter: Filter,
+
+    // Show where each batch starts
+    pub batches: bool,
+
+    // Print a Summary rather than the entries
+    pub summary: bool,
+    pub format: OutputFormat,
+}
+
+//-------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct KindStats {
+    pub count: u64,
+
+    // Packed size, excluding the batch framing
+    pub bytes: u64,
+}
+
+/// Totals for the entries that pass a filter.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub nr_slabs: u64,
+    pub nr_batches: u64,
+    pub nr_entries: u64,
+    pub kinds: BTreeMap<&'static str, KindStats>,
+
+    // Nodes modified
+    pub blocks_touched: BTreeSet<MetadataBlock>,
+
+    pub metadata_allocated: u64,
+    pub metadata_freed: u64,
+    pub data_allocated: u64,
+    pub data_freed: u64,
+
+    pub bad_batch: Option<BadBatch>,
+}
+
+impl Summary {
+    fn add_entry(&mut self, e: &Entry, bytes: usize) {
+        use Entry::*;
+
+        self.nr_entries += 1;
+        let stats = self.kinds.entry(e.kind()).or_default();
+        stats.count += 1;
+        stats.bytes += bytes as u64;
+
+        if let Some(loc) = e.node() {
+            self.blocks_touched.insert(loc);
+        }
+
+        match e {
+            AllocMetadata(b, e) => self.metadata_allocated += (e - b) as u64,
+            FreeMetadata(b, e) => self.metadata_freed += (e - b) as u64,
+            AllocData(b, e) => self.data_allocated += e - b,
+            FreeData(b, e) => self.data_freed += e - b,
+            _ => {}
+        }
+    }
+
+    /// Blocks allocated, less those freed.
+    pub fn metadata_balance(&self) -> i64 {
+        self.metadata_allocated as i64 - self.metadata_freed as i64
+    }
+
+    pub fn data_balance(&self) -> i64 {
+        self.data_allocated as i64 - self.data_freed as i64
+    }
+}
+
+pub fn summarise(journal: &mut Journal, filter: &Filter) -> Result<Summary> {
+    let encoding = journal.encoding();
+    let mut summary = Summary::default();
+    let mut last_slab = None;
+
+    summary.bad_batch = journal.replay(|loc, ops| {
+        if !filter.matches_slab(loc.slab) {
+            return Ok(());
+        }
+
+        let sizes = packed_op_sizes(ops, encoding)?;
+        let mut matched = false;
+        for (op, bytes) in ops.iter().zip(sizes) {
+            if filter.matches(op) {
+                summary.add_entry(op, bytes);
+                matched = true;
+            }
+        }
+
+        if matched {
+            summary.nr_batches += 1;
+            if last_slab != Some(loc.slab) {
+                summary.nr_slabs += 1;
+                last_slab = Some(loc.slab);
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(summary)
+}
+
+//-------------------------------------------------------------------------
+
+fn json_node_ptr(n: &NodePtr) -> Value {
+    json!({"loc": n.loc, "seq_nr": n.seq_nr})
+}
+
+fn json_bytes(bytes: &[u8]) -> Value {
+    Value::String(to_hex(bytes))
+}
+
+pub fn entry_to_json(entry: &Entry) -> Value {
+    use Entry::*;
+
+    let kind = entry.kind();
+    match entry {
+        AllocMetadata(b, e) | FreeMetadata(b, e) | AllocData(b, e) | FreeData(b, e) => {
+            json!({"kind": kind, "begin": b, "end": e})
+        }
+        GrowMetadata(extra) | GrowData(extra) => json!({"kind": kind, "extra": extra}),
+        UpdateInfoRoot(root) => json!({"kind": kind, "root": json_node_ptr(root)}),
+        Checkpoint(generation) => json!({"kind": kind, "generation": generation}),
+        SetSeq(loc, seq) => json!({"kind": kind, "loc": loc, "seq_nr": seq}),
+        Zero(loc, b, e) => json!({"kind": kind, "loc": loc, "begin": b, "end": e}),
+        Literal(loc, offset, bytes) => json!({
+            "kind": kind,
+            "loc": loc,
+            "offset": offset,
+            "bytes": json_bytes(bytes),
+        }),
+        Shadow(loc, origin) => json!({"kind": kind, "loc": loc, "origin": json_node_ptr(origin)}),
+        Overwrite(loc, idx, k, v) | Insert(loc, idx, k, v) => json!({
+            "kind": kind,
+            "loc": loc,
+            "idx": idx,
+            "key": k,
+            "value": json_bytes(v),
+        }),
+        Prepend(loc, keys, values) | Append(loc, keys, values) => {
+            let values: Vec<Value> = values.iter().map(|v| json_bytes(v)).collect();
+            json!({"kind": kind, "loc": loc, "keys": keys, "values": values})
+        }
+        Erase(loc, b, e) => json!({"kind": kind, "loc": loc, "idx_begin": b, "idx_end": e}),
+    }
+}
+
+fn json_bad_batch(bad: &BadBatch) -> Value {
+    json!({
+        "slab": bad.loc.slab,
+        "batch": bad.loc.batch,
+        "offset": bad.loc.offset,
+        "reason": bad.reason,
+    })
+}
+
+pub fn summary_to_json(summary: &Summary) -> Value {
+    let kinds: Map<String, Value> = summary
+        .kinds
+        .iter()
+        .map(|(k, s)| (k.clone(), json!({"count": s.count, "bytes": s.bytes})))
+        .collect();
+
+    let mut out = json!({
+        "slabs": summary.nr_slabs,
+        "batches": summary.nr_batches,
+        "entries": summary.nr_entries,
+        "kinds": kinds,
+        "blocks_touched": summary.blocks_touched.len(),
+        "metadata": {
+            "allocated": summary.metadata_allocated,
+            "freed": summary.metadata_freed,
+            "balance": summary.metadata_balance(),
+        },
+        "data": {
+            "allocated": summary.data_allocated,
+            "freed": summary.data_freed,
+            "balance": summary.data_balance(),
+        },
+    });
+    if let Some(bad) = &summary.bad_batch {
+        out["bad_batch"] = json_bad_batch(bad);
+    }
+    out
+}
+
+fn write_summary<W: Write>(out: &mut W, summary: &Summary) -> Result<()> {
+    writeln!(out, "slabs:          {}", summary.nr_slabs)?;
+    writeln!(out, "batches:        {}", summary.nr_batches)?;
+    writeln!(out, "entries:        {}", summary.nr_entries)?;
+    writeln!(out, "blocks touched: {}", summary.blocks_touched.len())?;
+    writeln!(
+        out,
+        "metadata:       allocated {}, freed {}, balance {}",
+        summary.metadata_allocated,
+        summary.metadata_freed,
+        summary.metadata_balance()
+    )?;
+    writeln!(
+        out,
+        "data:           allocated {}, freed {}, balance {}",
+        summary.data_allocated,
+        summary.data_freed,
+        summary.data_balance()
+    )?;
+
+    writeln!(out, "\n{:<8}{:>12}{:>14}", "kind", "count", "bytes")?;
+    for (k, s) in &summary.kinds {
+        writeln!(out, "{:<8}{:>12}{:>14}", k, s.count, s.bytes)?;
+    }
+
+    if let Some(bad) = &summary.bad_batch {
+        writeln!(out, "\n{}", bad)?;
+    }
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
+
+fn write_batch<W: Write>(
+    out: &mut W,
+    opts: &DumpOptions,
+    loc: BatchLocation,
+    ops: &[Entry],
+) -> Result<()> {
+    let filter = &opts.filter;
+    if !filter.matches_slab(loc.slab) {
+        return Ok(());
+    }
+
+    let ops: Vec<&Entry> = ops.iter().filter(|op| filter.matches(op)).collect();
+    if opts.batches && ops.is_empty() {
+        return Ok(());
+    }
+
+    match (opts.format, opts.batches) {
+        (OutputFormat::Text, true) => {
+            writeln!(
+                out,
+                "batch: slab {}, batch {}, offset {}, {} entries",
+                loc.slab,
+                loc.batch,
+                loc.offset,
+                ops.len()
+            )?;
+            for op in ops {
+                writeln!(out, "    {}", format_op(op))?;
+            }
+        }
+        (OutputFormat::Text, false) => {
+            for op in ops {
+                writeln!(out, "    {}", format_op(op))?;
+            }
+        }
+        (OutputFormat::Json, true) => {
+            let entries: Vec<Value> = ops.iter().map(|op| entry_to_json(op)).collect();
+            let batch = json!({
+                "slab": loc.slab,
+                "batch": loc.batch,
+                "offset": loc.offset,
+                "entries": entries,
+            });
+            writeln!(out, "{}", batch)?;
+        }
+        (OutputFormat::Json, false) => {
+            for op in ops {
+                let entry = json!({
+                    "slab": loc.slab,
+                    "batch": loc.batch,
+                    "entry": entry_to_json(op),
+                });
+                writeln!(out, "{}", entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_bad_batch<W: Write>(out: &mut W, opts: &DumpOptions, bad: &BadBatch) -> Result<()> {
+    match opts.format {
+        OutputFormat::Text => writeln!(out, "{}", bad)?,
+        OutputFormat::Json => writeln!(out, "{}", json!({"bad_batch": json_bad_batch(bad)}))?,
+    }
+    Ok(())
+}
+
+/// Prints the entries of a journal, or a summary of them.  Json output
+/// has one object per line.
+pub fn dump<W: Write>(journal: &mut Journal, opts: &DumpOptions, out: &mut W) -> Result<()> {
+    if opts.summary {
+        let summary = summarise(journal, &opts.filter)?;
+        match opts.format {
+            OutputFormat::Text => write_summary(out, &summary)?,
+            OutputFormat::Json => writeln!(out, "{}", summary_to_json(&summary))?,
+        }
+        return Ok(());
+    }
+
+    let bad = journal.replay(|loc, ops| write_batch(out, opts, loc, ops))?;
+    if let Some(bad) = bad {
+        write_bad_batch(out, opts, &bad)?;
+    }
+    Ok(())
+}
+
+/// Prints the entries of a journal that's being written, as they appear.
+/// This only returns if a bad batch is found, or on error.
+pub fn follow<P: AsRef<Path>, W: Write>(
+    path: P,
+    opts: &DumpOptions,
+    interval: Duration,
+    out: &mut W,
+) -> Result<()> {
+    if opts.summary {
+        return Err(anyhow!(
+            "a summary can't be produced when following a journal"
+        ));
+    }
+
+    let mut follower = Follower::open(path)?;
+    let bad = follower.follow(interval, |loc, ops| {
+        write_batch(out, opts, loc, ops)?;
+        out.flush()?;
+        Ok(())
+    })?;
+    write_bad_batch(out, opts, &bad)
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn mk_journal(dir: &TempDir) -> Result<Journal> {
+        use Entry::*;
+
+        let path = dir.path().join("journal");
+        let mut journal = Journal::create(&path)?;
+        let batches = vec![
+            vec![
+                AllocMetadata(10, 12),
+                Insert(10, 0, 5, vec![1]),
+                AllocData(0, 8),
+            ],
+            vec![Shadow(11, NodePtr { loc: 10, seq_nr: 1 }), Erase(11, 0, 1)],
+            vec![FreeMetadata(10, 11), FreeData(0, 2)],
+        ];
+        for ops in batches {
+            journal.add_batch(Batch {
+                ops,
+                completion: None,
+            });
+            journal.sync()?;
+        }
+        drop(journal);
+        Journal::open(&path, false)
+    }
+
+    fn dump_string(journal: &mut Journal, opts: &DumpOptions) -> Result<String> {
+        let mut out = Vec::new();
+        dump(journal, opts, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn test_filters() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut journal = mk_journal(&dir)?;
+
+        let mut opts = DumpOptions::default();
+        opts.filter.blocks.insert(10);
+        let out = dump_string(&mut journal, &opts)?;
+        assert_eq!(out.lines().count(), 4); // alm, ins, shadow, frm
+
+        opts.filter.kinds.insert("shadow".to_string());
+        let out = dump_string(&mut journal, &opts)?;
+        assert_eq!(out.lines().count(), 1);
+
+        let mut opts = DumpOptions::default();
+        opts.filter.slabs = Some(1..3);
+        opts.batches = true;
+        let out = dump_string(&mut journal, &opts)?;
+        assert!(out.starts_with("batch: slab 1, batch 0, offset 0, 2 entries"));
+        assert_eq!(out.lines().filter(|l| l.starts_with("batch:")).count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut journal = mk_journal(&dir)?;
+
+        let summary = summarise(&mut journal, &Filter::default())?;
+        assert_eq!(summary.nr_slabs, 3);
+        assert_eq!(summary.nr_batches, 3);
+        assert_eq!(summary.nr_entries, 7);
+        assert_eq!(summary.kinds["ins"].count, 1);
+        assert!(summary.kinds["ins"].bytes > 0);
+        assert_eq!(summary.blocks_touched.len(), 2);
+        assert_eq!(summary.metadata_balance(), 1);
+        assert_eq!(summary.data_balance(), 6);
+        assert!(summary.bad_batch.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_json() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut journal = mk_journal(&dir)?;
+
+        let opts = DumpOptions {
+            format: OutputFormat::Json,
+            ..Default::default()
+        };
+        let out = dump_string(&mut journal, &opts)?;
+        assert_eq!(
+            out.lines().nth(1).unwrap(),
+            r#"{"slab":0,"batch":0,"entry":{"kind":"ins","loc":10,"idx":0,"key":5,"value":"0x01"}}"#
+        );
+
+        let opts = DumpOptions {
+            format: OutputFormat::Json,
+            summary: true,
+            ..Default::default()
+        };
+        let out = dump_string(&mut journal, &opts)?;
+        assert!(out.starts_with(r#"{"slabs":3,"batches":3,"entries":7,"#));
+        assert!(out.contains(r#""data":{"allocated":8,"freed":2,"balance":6}"#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_escapes() {
+        let bad = BadBatch {
+            loc: BatchLocation {
+                slab: 1,
+                batch: 2,
+                offset: 3,
+            },
+            reason: "a\"b\\c\n\u{1}".to_string(),
+        };
+        let out = json_bad_batch(&bad).to_string();
+        assert!(out.contains(r#""reason":"a\"b\\c\n\u0001""#));
+
+        let v: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(v["reason"], bad.reason);
+    }
+}
+
+//-------------------------------------------------------------------------
@@ -1,6 +1,8 @@
 pub mod batch;
 pub mod entry;
+pub mod follow;
 mod format;
+pub mod inspect;
 mod pack;
 
 //-------------------------------------------------------------------------
@@ -8,10 +10,12 @@ mod pack;
 use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_enum::TryFromPrimitive;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::convert::TryFrom;
+use std::fs;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
 
 use crate::block_cache::*;
 use crate::btree::node::Key;
@@ -19,7 +23,9 @@ use crate::btree::*;
 use crate::journal::entry::*;
 use crate::journal::format::*;
 use crate::journal::pack::*;
+pub use crate::journal::pack::{pack_ops, unpack_ops, Encoding};
 use crate::slab::*;
+pub use crate::slab::{BadSlab, RepairOptions, RepairReport, StaleOffsets};
 use crate::types::*;
 
 //-------------------------------------------------------------------------
@@ -32,13 +38,155 @@ pub trait BatchCompletion {
 
 pub struct Batch {
     pub ops: Vec<Entry>,
-    pub completion: Option<Box<dyn BatchCompletion>>,
+    pub completion: Option<Box<dyn BatchCompletion + Send>>,
 }
 
+/// Pending batches are queued against a transaction stream.  Each thin has
+/// its own stream so it can be committed without including the other
+/// thins' changes.  Changes to metadata shared by all thins (eg, the info
+/// tree) go in the Shared stream, which is written with every commit.
+///
+/// A stream may depend on another's pending batches, eg, if it allocates
+/// a block the other has freed.  Committing a stream also writes the
+/// streams it depends on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Stream {
+    Shared,
+    Thin(ThinID),
+}
+
+/// Where a batch lives within the current journal segment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BatchLocation {
+    pub slab: u32,
+    pub batch: usize,
+
+    // Byte offset of the batch within the (uncompressed) slab
+    pub offset: u64,
+}
+
+/// Replay stops at the first batch that can't be decoded.
+#[derive(Error, Clone, Debug)]
+#[error("bad journal batch at slab {}, batch {}, offset {}: {reason}", loc.slab, loc.batch, loc.offset)]
+pub struct BadBatch {
+    pub loc: BatchLocation,
+    pub reason: String,
+}
+
+// A slab holds one or more packed batches.
+fn replay_slab<F>(
+    slab: u32,
+    bytes: &[u8],
+    encoding: Encoding,
+    f: &mut F,
+) -> Result<Option<BadBatch>>
+where
+    F: FnMut(BatchLocation, &[Entry]) -> Result<()>,
+{
+    let mut r = std::io::Cursor::new(bytes);
+    let mut batch = 0;
+    while (r.position() as usize) < bytes.len() {
+        let loc = BatchLocation {
+            slab,
+            batch,
+            offset: r.position(),
+        };
+
+        match unpack_ops(&mut r, encoding) {
+            Ok(ops) => f(loc, &ops)?,
+            Err(e) => {
+                let reason = e.to_string();
+                return Ok(Some(BadBatch { loc, reason }));
+            }
+        }
+        batch += 1;
+    }
+
+    Ok(None)
+}
+
+//-------------------------------------------------------------------------
+
+// Makes a rename within the directory durable.
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
+
+// The metadata and data blocks allocated, or freed, by a stream's pending
+// batches.
+#[derive(Default)]
+struct Claims {
+    metadata: Vec<(u64, u64)>,
+    data: Vec<(u64, u64)>,
+}
+
+fn overlaps(ranges: &[(u64, u64)], b: u64, e: u64) -> bool {
+    ranges.iter().any(|(rb, re)| *rb < e && b < *re)
+}
+
+impl Claims {
+    fn new(ops: &[Entry]) -> Self {
+        use Entry::*;
+        let mut claims = Self::default();
+        for op in ops {
+            match op {
+                AllocMetadata(b, e) | FreeMetadata(b, e) => {
+                    claims.metadata.push((*b as u64, *e as u64))
+                }
+                AllocData(b, e) | FreeData(b, e) => claims.data.push((*b, *e)),
+                _ => {}
+            }
+        }
+        claims
+    }
+
+    fn is_empty(&self) -> bool {
+        self.metadata.is_empty() && self.data.is_empty()
+    }
+
+    fn conflicts(&self, other: &Claims) -> bool {
+        self.metadata
+            .iter()
+            .any(|(b, e)| overlaps(&other.metadata, *b, *e))
+            || self.data.iter().any(|(b, e)| overlaps(&other.data, *b, *e))
+    }
+
+    fn extend(&mut self, other: Claims) {
+        self.metadata.extend(other.metadata);
+        self.data.extend(other.data);
+    }
+}
+
+//-------------------------------------------------------------------------
+
 pub struct Journal {
+    path: PathBuf,
     slab: SlabFile,
-    batches: Vec<Batch>,
+    batches: BTreeMap<Stream, Vec<(u64, Batch)>>,
+
+    // Orders the pending batches across streams.
+    next_batch: u64,
+
+    // The streams each stream's pending batches depend on, and the blocks
+    // allocated or freed by them.
+    deps: BTreeMap<Stream, BTreeSet<Stream>>,
+    claims: BTreeMap<Stream, Claims>,
     seqs: BTreeMap<MetadataBlock, SequenceNr>,
+    encoding: Encoding,
+
+    // Incremented by every checkpoint.
+    generation: u64,
+
+    // The most recent info root to hit the journal.  This is carried
+    // over into each new segment.
+    info_root: Option<NodePtr>,
 }
 
 impl Drop for Journal {
@@ -49,54 +197,193 @@ impl Drop for Journal {
 }
 
 impl Journal {
-    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let slab = SlabFileBuilder::create(path)
+    fn create_slab(path: &Path, encoding: Encoding) -> Result<SlabFile> {
+        SlabFileBuilder::create(path)
             .read(true)
             .write(true)
             .compressed(true)
+            .client_flags(encoding.to_flags())
             .cache_nr_entries(16)
             .queue_depth(4)
-            .build()?;
-
-        Ok(Self {
-            slab,
-            batches: Vec::new(),
-            seqs: BTreeMap::new(),
-        })
+            .build()
     }
 
-    pub fn open<P: AsRef<Path>>(path: P, write: bool) -> Result<Self> {
-        let slab = SlabFileBuilder::open(path)
+    fn open_slab(path: &Path, write: bool) -> Result<SlabFile> {
+        SlabFileBuilder::open(path)
             .read(true)
             .write(write)
             .cache_nr_entries(16)
             .queue_depth(4)
-            .build()?;
+            .build()
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_with_encoding(path, Encoding::Compact)
+    }
+
+    pub fn create_with_encoding<P: AsRef<Path>>(path: P, encoding: Encoding) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let slab = Self::create_slab(&path, encoding)?;
 
         Ok(Self {
+            path,
             slab,
-            batches: Vec::new(),
+            batches: BTreeMap::new(),
+            next_batch: 0,
+            deps: BTreeMap::new(),
+            claims: BTreeMap::new(),
             seqs: BTreeMap::new(),
+            encoding,
+            generation: 0,
+            info_root: None,
         })
     }
 
+    pub fn open<P: AsRef<Path>>(path: P, write: bool) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let slab = match Self::open_slab(&path, write) {
+            // We crashed in new_segment() after switching to the new
+            // data file, but before its offsets followed it.
+            Err(e) if write && e.is::<StaleOffsets>() => {
+                Self::repair(&path, &RepairOptions::default())?;
+                Self::open_slab(&path, write)?
+            }
+            r => r?,
+        };
+        let encoding = Encoding::from_flags(slab.client_flags())?;
+
+        let mut journal = Self {
+            path,
+            slab,
+            batches: BTreeMap::new(),
+            next_batch: 0,
+            deps: BTreeMap::new(),
+            claims: BTreeMap::new(),
+            seqs: BTreeMap::new(),
+            encoding,
+            generation: 0,
+            info_root: None,
+        };
+
+        // A checkpointed segment starts with the checkpoint record.
+        let mut first = None;
+        journal.replay(|_, ops| {
+            if first.is_none() {
+                first = ops.first().cloned();
+            }
+            Ok(())
+        })?;
+        if let Some(Entry::Checkpoint(generation)) = first {
+            journal.generation = generation;
+        }
+
+        Ok(journal)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Queues a batch against the shared stream.
     pub fn add_batch(&mut self, batch: Batch) {
-        self.batches.push(batch)
+        self.add_stream_batch(Stream::Shared, batch)
     }
 
+    pub fn add_stream_batch(&mut self, stream: Stream, batch: Batch) {
+        // A block allocated or freed by another stream's pending batches
+        // must have that change replayed first.
+        let claims = Claims::new(&batch.ops);
+        if !claims.is_empty() {
+            let others: Vec<Stream> = self
+                .claims
+                .iter()
+                .filter(|(s, c)| **s != stream && claims.conflicts(c))
+                .map(|(s, _)| *s)
+                .collect();
+            for on in others {
+                self.add_dependency(stream, on);
+            }
+            self.claims.entry(stream).or_default().extend(claims);
+        }
+
+        let n = self.next_batch;
+        self.next_batch += 1;
+        self.batches.entry(stream).or_default().push((n, batch))
+    }
+
+    /// Records that `stream`'s pending batches refer to changes made by
+    /// `on`'s, so committing `stream` must write `on` too.
+    pub fn add_dependency(&mut self, stream: Stream, on: Stream) {
+        if stream != on && self.nr_pending(on) > 0 {
+            self.deps.entry(stream).or_default().insert(on);
+        }
+    }
+
+    pub fn nr_pending(&self, stream: Stream) -> usize {
+        self.batches.get(&stream).map_or(0, |bs| bs.len())
+    }
+
+    /// Makes a single thin's batches, and any shared batches, durable.
+    /// Batches belonging to other thins are left pending, unless the
+    /// written ones depend on them.
+    pub fn commit(&mut self, id: ThinID) -> Result<()> {
+        self.write_streams(&[Stream::Thin(id), Stream::Shared])
+    }
+
+    /// Makes every pending batch durable.
     pub fn sync(&mut self) -> Result<()> {
-        // hack
-        if self.batches.is_empty() {
-            return Ok(());
+        let streams: Vec<Stream> = self.batches.keys().cloned().collect();
+        self.write_streams(&streams)
+    }
+
+    // The streams, and every stream they depend on, are written as a
+    // single slab.  Since the set is closed under dependencies, writing
+    // the batches in the order they were added, whatever their stream,
+    // replays dependent changes in the order they were made (eg, a thin's
+    // mapping root is initialised by a shared batch).
+    fn write_streams(&mut self, streams: &[Stream]) -> Result<()> {
+        let mut written: BTreeSet<Stream> = BTreeSet::new();
+        let mut todo: Vec<Stream> = streams.to_vec();
+        while let Some(s) = todo.pop() {
+            if written.insert(s) {
+                if let Some(deps) = self.deps.remove(&s) {
+                    todo.extend(deps);
+                }
+            }
         }
 
-        let mut batches: Vec<Batch> = Vec::new();
+        let mut batches: Vec<(u64, Batch)> = Vec::new();
+        for s in &written {
+            if let Some(bs) = self.batches.remove(s) {
+                batches.extend(bs);
+            }
+            self.claims.remove(s);
+        }
+        self.deps.retain(|_, deps| {
+            deps.retain(|s| !written.contains(s));
+            !deps.is_empty()
+        });
+        batches.sort_by_key(|(n, _)| *n);
+        let batches: Vec<Batch> = batches.into_iter().map(|(_, b)| b).collect();
 
-        std::mem::swap(&mut batches, &mut self.batches);
+        // hack
+        if batches.is_empty() {
+            return Ok(());
+        }
 
         let mut w: Vec<u8> = Vec::new();
         for b in &batches {
-            pack_ops(&mut w, &b.ops)?;
+            pack_ops(&mut w, &b.ops, self.encoding)?;
+
+            for op in &b.ops {
+                if let Entry::UpdateInfoRoot(root) = op {
+                    self.info_root = Some(*root);
+                }
+            }
         }
 
         // FIXME: use rio
@@ -111,6 +398,104 @@ impl Journal {
         Ok(())
     }
 
+    /// Replaces the journal file with a new segment that holds just a
+    /// checkpoint record, discarding every slab written so far.  The caller
+    /// must have already written all dirty nodes to the node file (see
+    /// TransactionManager::checkpoint()).
+    pub fn new_segment(&mut self) -> Result<u64> {
+        self.sync()?;
+        self.slab.close()?;
+
+        let generation = self.generation + 1;
+        let mut ops = vec![Entry::Checkpoint(generation)];
+        if let Some(root) = self.info_root {
+            ops.push(Entry::UpdateInfoRoot(root));
+        }
+
+        // The new segment is built to one side, and then renamed over the
+        // old one.  Renaming the data file is what switches segments, so a
+        // crash leaves one or the other intact.  If we crash before the
+        // offsets file follows it, open() sees they don't match and
+        // rebuilds them from the data.
+        let mut new_path = self.path.clone();
+        new_path.set_extension("new");
+        {
+            let mut slab = Self::create_slab(&new_path, self.encoding)?;
+            let mut w: Vec<u8> = Vec::new();
+            pack_ops(&mut w, &ops, self.encoding)?;
+            slab.write_slab(&w)?;
+            slab.close()?;
+        }
+        fs::File::open(&new_path)?.sync_all()?;
+        fs::File::open(offsets_path(&new_path))?.sync_all()?;
+
+        fs::rename(&new_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+        fs::rename(offsets_path(&new_path), offsets_path(&self.path))?;
+        sync_parent_dir(&self.path)?;
+
+        self.slab = Self::open_slab(&self.path, true)?;
+        self.generation = generation;
+        Ok(generation)
+    }
+
+    /// Salvages a journal that can't be opened, eg, because the offsets
+    /// file is missing or the last slab was torn.  Batches depend on the
+    /// ones before them, so the journal is truncated at the first bad
+    /// slab; everything from there on is lost.
+    pub fn repair<P: AsRef<Path>>(path: P, opts: &RepairOptions) -> Result<RepairReport> {
+        let opts = RepairOptions {
+            truncate: true,
+            ..opts.clone()
+        };
+        crate::slab::repair(path, &opts)
+    }
+
+    /// Calls `f` for each batch in the current segment, in order.  Replay
+    /// stops at the first batch that fails to decode, and its location is
+    /// returned.  Everything after it is suspect, since batches may depend
+    /// on earlier ones.
+    pub fn replay<F>(&mut self, mut f: F) -> Result<Option<BadBatch>>
+    where
+        F: FnMut(BatchLocation, &[Entry]) -> Result<()>,
+    {
+        let encoding = self.encoding;
+        for s in 0..self.slab.get_nr_slabs() as u32 {
+            let bytes = match self.slab.read(s) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    let loc = BatchLocation {
+                        slab: s,
+                        batch: 0,
+                        offset: 0,
+                    };
+                    let reason = format!("couldn't read slab: {}", e);
+                    return Ok(Some(BadBatch { loc, reason }));
+                }
+            };
+
+            if let Some(bad) = replay_slab(s, &bytes, encoding, &mut f)? {
+                return Ok(Some(bad));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns every entry in the current segment.  The first will be the
+    /// checkpoint record if the journal has ever been checkpointed.  Fails
+    /// with a BadBatch error if any batch is corrupt.
+    pub fn entries(&mut self) -> Result<Vec<Entry>> {
+        let mut ops = Vec::new();
+        if let Some(bad) = self.replay(|_, batch| {
+            ops.extend_from_slice(batch);
+            Ok(())
+        })? {
+            return Err(bad.into());
+        }
+        Ok(ops)
+    }
+
     pub fn up_to_date(&mut self, n: &NodePtr) -> Result<bool> {
         if let Some(seq) = self.seqs.get(&n.loc) {
             if n.seq_nr == *seq {
@@ -132,15 +517,251 @@ impl Journal {
         todo!()
     }
 
+    /// Prints every entry.  See inspect::dump() for more options.
     pub fn dump<W: Write>(&mut self, out: &mut W) -> Result<()> {
-        for s in 0..self.slab.get_nr_slabs() {
-            let mut bytes = self.slab.read(s as u32)?;
-            let mut r = std::io::Cursor::new(bytes.as_ref());
-            let ops = unpack_ops(&mut r)?;
-            for op in &ops {
-                writeln!(out, "    {}", format_op(op))?;
+        inspect::dump(self, &inspect::DumpOptions::default(), out)
+    }
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn mk_batch(n: u32) -> Batch {
+        Batch {
+            ops: vec![Entry::AllocMetadata(n, n + 1)],
+            completion: None,
+        }
+    }
+
+    #[test]
+    fn test_replay_stops_at_bad_batch() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("journal");
+        let mut journal = Journal::create(&path)?;
+
+        journal.add_batch(mk_batch(0));
+        journal.sync()?;
+
+        // A slab with one good batch followed by a corrupt one
+        let mut w = Vec::new();
+        pack_ops(&mut w, &[Entry::AllocMetadata(1, 2)], journal.encoding)?;
+        let offset = w.len() as u64;
+        pack_ops(&mut w, &[Entry::AllocMetadata(2, 3)], journal.encoding)?;
+        let last = w.len() - 1;
+        w[last] ^= 0xff;
+        journal.slab.write_slab(&w)?;
+
+        journal.add_batch(mk_batch(3));
+        journal.sync()?;
+        drop(journal);
+
+        let mut journal = Journal::open(&path, false)?;
+        let mut seen = Vec::new();
+        let bad = journal
+            .replay(|loc, _| {
+                seen.push(loc);
+                Ok(())
+            })?
+            .unwrap();
+
+        assert_eq!(seen.len(), 2);
+        assert_eq!(
+            bad.loc,
+            BatchLocation {
+                slab: 1,
+                batch: 1,
+                offset
+            }
+        );
+        assert!(bad.to_string().contains("slab 1, batch 1"));
+
+        let err = journal.entries().unwrap_err();
+        assert!(err.downcast_ref::<BadBatch>().is_some());
+        Ok(())
+    }
+
+    fn stream_batch(ops: Vec<Entry>) -> Batch {
+        Batch {
+            ops,
+            completion: None,
+        }
+    }
+
+    #[test]
+    fn test_commit_writes_streams_it_depends_on() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("journal");
+        let mut journal = Journal::create(&path)?;
+
+        // Thin 2 frees a block that thin 1 then reuses, so the free has to
+        // be replayed first.
+        journal.add_stream_batch(Stream::Thin(3), stream_batch(vec![Entry::AllocData(0, 4)]));
+        journal.add_stream_batch(
+            Stream::Thin(2),
+            stream_batch(vec![Entry::FreeMetadata(5, 6)]),
+        );
+        journal.add_stream_batch(
+            Stream::Thin(1),
+            stream_batch(vec![Entry::AllocMetadata(4, 8)]),
+        );
+        journal.commit(1)?;
+
+        assert_eq!(journal.nr_pending(Stream::Thin(2)), 0);
+        assert_eq!(journal.nr_pending(Stream::Thin(3)), 1);
+        drop(journal);
+
+        let mut journal = Journal::open(&path, false)?;
+        assert_eq!(
+            journal.entries()?,
+            vec![
+                Entry::FreeMetadata(5, 6),
+                Entry::AllocMetadata(4, 8),
+                Entry::AllocData(0, 4)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_dependency() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("journal");
+        let mut journal = Journal::create(&path)?;
+
+        // Nothing is recorded against a stream with no pending batches.
+        journal.add_dependency(Stream::Shared, Stream::Thin(2));
+        journal.add_stream_batch(Stream::Thin(2), mk_batch(0));
+        journal.add_stream_batch(Stream::Thin(3), mk_batch(1));
+        journal.commit(1)?;
+        assert_eq!(journal.nr_pending(Stream::Thin(2)), 1);
+
+        journal.add_dependency(Stream::Shared, Stream::Thin(2));
+        journal.add_batch(mk_batch(2));
+        journal.commit(1)?;
+        assert_eq!(journal.nr_pending(Stream::Thin(2)), 0);
+        assert_eq!(journal.nr_pending(Stream::Thin(3)), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoding_recorded_in_header() -> Result<()> {
+        let dir = TempDir::new()?;
+        for encoding in [Encoding::Fixed, Encoding::Compact] {
+            let path = dir.path().join(format!("journal-{:?}", encoding));
+            let mut journal = Journal::create_with_encoding(&path, encoding)?;
+            journal.add_batch(mk_batch(0));
+            drop(journal);
+
+            let mut journal = Journal::open(&path, false)?;
+            assert_eq!(journal.encoding(), encoding);
+            assert_eq!(journal.entries()?, mk_batch(0).ops);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_torn_journal() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("journal");
+        {
+            let mut journal = Journal::create(&path)?;
+            for n in 0..3 {
+                journal.add_batch(mk_batch(n));
+                journal.sync()?;
             }
         }
+
+        // A crash leaves a torn slab, and no offsets file.
+        let mut data = fs::read(&path)?;
+        let torn = data[16..30].to_vec();
+        data.extend(torn);
+        fs::write(&path, &data)?;
+        fs::remove_file(offsets_path(&path))?;
+        assert!(Journal::open(&path, false).is_err());
+
+        let report = Journal::repair(&path, &RepairOptions::default())?;
+        assert_eq!(report.nr_slabs, 3);
+        assert_eq!(report.bad.len(), 1);
+
+        let mut journal = Journal::open(&path, false)?;
+        let expected: Vec<Entry> = (0..3).flat_map(|n| mk_batch(n).ops).collect();
+        assert_eq!(journal.entries()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_crash_between_segment_renames() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("journal");
+        {
+            let mut journal = Journal::create(&path)?;
+            for n in 0..3 {
+                journal.add_batch(mk_batch(n));
+                journal.sync()?;
+            }
+        }
+        let old_offsets = fs::read(offsets_path(&path))?;
+
+        {
+            let mut journal = Journal::open(&path, true)?;
+            assert_eq!(journal.new_segment()?, 1);
+        }
+
+        // The data file was switched, but the offsets file wasn't.
+        fs::write(offsets_path(&path), &old_offsets)?;
+        let e = Journal::open(&path, false).err().unwrap();
+        assert!(e.is::<StaleOffsets>());
+
+        let mut journal = Journal::open(&path, true)?;
+        assert_eq!(journal.generation(), 1);
+        assert!(journal.entries()? == vec![Entry::Checkpoint(1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_truncates_at_bad_slab() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("journal");
+        {
+            let mut journal = Journal::create(&path)?;
+            journal.add_batch(mk_batch(0));
+            journal.sync()?;
+        }
+        let second = fs::metadata(&path)?.len();
+        {
+            let mut journal = Journal::open(&path, true)?;
+            for n in 1..4 {
+                journal.add_batch(mk_batch(n));
+                journal.sync()?;
+            }
+        }
+
+        // Corrupt the hash of the second slab.
+        let mut data = fs::read(&path)?;
+        let len = data.len() as u64;
+        data[second as usize + 20] ^= 0xff;
+        fs::write(&path, &data)?;
+
+        // Repair to a copy, so the original is left alone.
+        let repaired = dir.path().join("repaired");
+        let opts = RepairOptions {
+            output: Some(repaired.clone()),
+            ..Default::default()
+        };
+        let report = Journal::repair(&path, &opts)?;
+        assert_eq!(report.nr_slabs, 1);
+        assert_eq!(report.bad.len(), 1);
+        assert_eq!(report.bad[0].offset, second);
+        assert_eq!(report.bad[0].len, len - second);
+        assert_eq!(fs::read(&path)?, data);
+
+        // Nothing after the bad slab is kept.
+        let mut journal = Journal::open(&repaired, false)?;
+        assert_eq!(journal.entries()?, mk_batch(0).ops);
         Ok(())
     }
 }
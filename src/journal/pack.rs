@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_enum::TryFromPrimitive;
 use std::collections::{BTreeMap, VecDeque};
@@ -12,31 +12,36 @@ use crate::btree::*;
 use crate::journal::entry::*;
 use crate::slab::*;
 use crate::types::*;
+use crate::varint::*;
 
 //-------------------------------------------------------------------------
 
+// These values are on disk, so never renumber them.  New tags should be
+// added at the end, with a bump of FORMAT_VERSION.
 #[derive(Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
 enum Tag {
-    AllocMetadata,
-    FreeMetadata,
-    GrowMetadata,
+    AllocMetadata = 0,
+    FreeMetadata = 1,
+    GrowMetadata = 2,
 
-    AllocData,
-    FreeData,
-    GrowData,
+    AllocData = 3,
+    FreeData = 4,
+    GrowData = 5,
 
-    UpdateInfoRoot,
+    UpdateInfoRoot = 6,
 
-    SetSeq,
-    Zero,
-    Literal,
-    Shadow,
-    Overwrite,
-    Insert,
-    Prepend,
-    Append,
-    Erase,
+    SetSeq = 7,
+    Zero = 8,
+    Literal = 9,
+    Shadow = 10,
+    Overwrite = 11,
+    Insert = 12,
+    Prepend = 13,
+    Append = 14,
+    Erase = 15,
+
+    Checkpoint = 16,
 }
 
 fn pack_tag<W: Write>(w: &mut W, tag: Tag) -> Result<()> {
@@ -122,6 +127,10 @@ fn pack_op<W: Write>(w: &mut W, op: &Entry) -> Result<()> {
             w.write_u32::<LittleEndian>(root.loc)?;
             w.write_u32::<LittleEndian>(root.seq_nr)?;
         }
+        Checkpoint(generation) => {
+            pack_tag(w, Tag::Checkpoint)?;
+            w.write_u64::<LittleEndian>(*generation)?;
+        }
 
         SetSeq(loc, seq) => {
             pack_tag(w, Tag::SetSeq)?;
@@ -174,7 +183,7 @@ fn pack_op<W: Write>(w: &mut W, op: &Entry) -> Result<()> {
         Append(loc, keys, values) => {
             assert!(keys.len() == values.len());
 
-            pack_tag(w, Tag::Prepend)?;
+            pack_tag(w, Tag::Append)?;
             w.write_u32::<LittleEndian>(*loc)?;
             w.write_u16::<LittleEndian>(keys.len() as u16)?;
             for (k, v) in keys.iter().zip(values.iter()) {
@@ -229,6 +238,10 @@ fn unpack_op<R: Read>(r: &mut R) -> Result<Entry> {
 
             Ok(UpdateInfoRoot(NodePtr { loc, seq_nr }))
         }
+        Tag::Checkpoint => {
+            let generation = r.read_u64::<LittleEndian>()?;
+            Ok(Checkpoint(generation))
+        }
 
         Tag::SetSeq => {
             let loc = r.read_u32::<LittleEndian>()?;
@@ -304,22 +317,641 @@ fn unpack_op<R: Read>(r: &mut R) -> Result<Entry> {
     }
 }
 
-pub fn pack_ops<W: Write>(w: &mut W, ops: &[Entry]) -> Result<()> {
+//-------------------------------------------------------------------------
+
+// The compact encoding uses varints throughout, and exploits the fact
+// that consecutive ops tend to touch the same node:
+//
+// - the top bit of the tag says the op is for the same block as the
+//   previous op, in which case the location is elided.
+// - keys are zigzag encoded deltas against the last key written for the
+//   same node within the batch.
+// - alloc/free ranges, and zero/erase ranges, are stored as a begin and
+//   a length rather than a begin and end.
+//
+// All state is reset at the start of each batch, so batches can still be
+// decoded independently.
+const SAME_LOC: u8 = 0x80;
+
+fn zigzag(delta: i64) -> u64 {
+    ((delta << 1) ^ (delta >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn pack_varint<W: Write>(w: &mut W, v: u64) -> Result<()> {
+    write_varint(w, v)?;
+    Ok(())
+}
+
+fn unpack_varint<R: Read>(r: &mut R) -> Result<u64> {
+    Ok(read_varint(r)?)
+}
+
+fn unpack_varint_32<R: Read>(r: &mut R) -> Result<u32> {
+    let v = read_varint(r)?;
+    u32::try_from(v).map_err(|_| anyhow!("varint {} too large for u32", v))
+}
+
+fn pack_range<W: Write>(w: &mut W, begin: u64, end: u64) -> Result<()> {
+    pack_varint(w, begin)?;
+    pack_varint(w, end.wrapping_sub(begin))
+}
+
+fn unpack_range<R: Read>(r: &mut R) -> Result<(u64, u64)> {
+    let b = unpack_varint(r)?;
+    let len = unpack_varint(r)?;
+    Ok((b, b.wrapping_add(len)))
+}
+
+fn unpack_range_32<R: Read>(r: &mut R) -> Result<(u32, u32)> {
+    let (b, e) = unpack_range(r)?;
+    let b = u32::try_from(b).map_err(|_| anyhow!("range begin {} too large for u32", b))?;
+    let e = u32::try_from(e).map_err(|_| anyhow!("range end {} too large for u32", e))?;
+    Ok((b, e))
+}
+
+fn pack_varint_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> Result<()> {
+    pack_varint(w, bytes.len() as u64)?;
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+fn unpack_varint_bytes<R: Read>(r: &mut R) -> Result<Bytes> {
+    let len = unpack_varint(r)? as usize;
+    let mut buffer = Vec::new();
+    r.take(len as u64).read_to_end(&mut buffer)?;
+    if buffer.len() != len {
+        return Err(anyhow!("bytes truncated"));
+    }
+    Ok(buffer)
+}
+
+#[derive(Default)]
+struct CompactPacker {
+    last_loc: Option<MetadataBlock>,
+    last_keys: BTreeMap<MetadataBlock, Key>,
+}
+
+impl CompactPacker {
+    fn pack_tag_loc<W: Write>(&mut self, w: &mut W, tag: Tag, loc: MetadataBlock) -> Result<()> {
+        if self.last_loc == Some(loc) {
+            w.write_u8(tag as u8 | SAME_LOC)?;
+        } else {
+            w.write_u8(tag as u8)?;
+            pack_varint(w, loc as u64)?;
+            self.last_loc = Some(loc);
+        }
+        Ok(())
+    }
+
+    fn pack_key<W: Write>(&mut self, w: &mut W, loc: MetadataBlock, k: Key) -> Result<()> {
+        let prev = self.last_keys.get(&loc).cloned().unwrap_or(0);
+        pack_varint(w, zigzag(k.wrapping_sub(prev) as i64))?;
+        self.last_keys.insert(loc, k);
+        Ok(())
+    }
+
+    fn pack_op<W: Write>(&mut self, w: &mut W, op: &Entry) -> Result<()> {
+        use Entry::*;
+
+        match op {
+            AllocMetadata(b, e) => {
+                pack_tag(w, Tag::AllocMetadata)?;
+                pack_range(w, *b as u64, *e as u64)?;
+            }
+            FreeMetadata(b, e) => {
+                pack_tag(w, Tag::FreeMetadata)?;
+                pack_range(w, *b as u64, *e as u64)?;
+            }
+            GrowMetadata(extra) => {
+                pack_tag(w, Tag::GrowMetadata)?;
+                pack_varint(w, *extra as u64)?;
+            }
+
+            AllocData(b, e) => {
+                pack_tag(w, Tag::AllocData)?;
+                pack_range(w, *b, *e)?;
+            }
+            FreeData(b, e) => {
+                pack_tag(w, Tag::FreeData)?;
+                pack_range(w, *b, *e)?;
+            }
+            GrowData(extra) => {
+                pack_tag(w, Tag::GrowData)?;
+                pack_varint(w, *extra)?;
+            }
+
+            UpdateInfoRoot(root) => {
+                pack_tag(w, Tag::UpdateInfoRoot)?;
+                pack_varint(w, root.loc as u64)?;
+                pack_varint(w, root.seq_nr as u64)?;
+            }
+            Checkpoint(generation) => {
+                pack_tag(w, Tag::Checkpoint)?;
+                pack_varint(w, *generation)?;
+            }
+
+            SetSeq(loc, seq) => {
+                self.pack_tag_loc(w, Tag::SetSeq, *loc)?;
+                pack_varint(w, *seq as u64)?;
+            }
+            Zero(loc, begin, end) => {
+                self.pack_tag_loc(w, Tag::Zero, *loc)?;
+                pack_range(w, *begin as u64, *end as u64)?;
+            }
+            Literal(loc, offset, bytes) => {
+                self.pack_tag_loc(w, Tag::Literal, *loc)?;
+                pack_varint(w, *offset as u64)?;
+                pack_varint_bytes(w, bytes)?;
+            }
+            Shadow(loc, origin) => {
+                self.pack_tag_loc(w, Tag::Shadow, *loc)?;
+                pack_varint(w, origin.loc as u64)?;
+                pack_varint(w, origin.seq_nr as u64)?;
+
+                // The shadow starts out with the origin's keys.
+                if let Some(k) = self.last_keys.get(&origin.loc).cloned() {
+                    self.last_keys.insert(*loc, k);
+                }
+            }
+            Overwrite(loc, idx, k, v) => {
+                self.pack_tag_loc(w, Tag::Overwrite, *loc)?;
+                pack_varint(w, *idx as u64)?;
+                self.pack_key(w, *loc, *k)?;
+                pack_varint_bytes(w, v)?;
+            }
+            Insert(loc, idx, k, v) => {
+                self.pack_tag_loc(w, Tag::Insert, *loc)?;
+                pack_varint(w, *idx as u64)?;
+                self.pack_key(w, *loc, *k)?;
+                pack_varint_bytes(w, v)?;
+            }
+            Prepend(loc, keys, values) | Append(loc, keys, values) => {
+                assert!(keys.len() == values.len());
+
+                let tag = if matches!(op, Prepend(..)) {
+                    Tag::Prepend
+                } else {
+                    Tag::Append
+                };
+                self.pack_tag_loc(w, tag, *loc)?;
+                pack_varint(w, keys.len() as u64)?;
+                for (k, v) in keys.iter().zip(values.iter()) {
+                    self.pack_key(w, *loc, *k)?;
+                    pack_varint_bytes(w, v)?;
+                }
+            }
+            Erase(loc, idx_b, idx_e) => {
+                self.pack_tag_loc(w, Tag::Erase, *loc)?;
+                pack_range(w, *idx_b as u64, *idx_e as u64)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct CompactUnpacker {
+    last_loc: Option<MetadataBlock>,
+    last_keys: BTreeMap<MetadataBlock, Key>,
+}
+
+impl CompactUnpacker {
+    fn unpack_loc<R: Read>(&mut self, r: &mut R, same: bool) -> Result<MetadataBlock> {
+        let loc = if same {
+            self.last_loc
+                .ok_or_else(|| anyhow!("op refers to previous block, but there isn't one"))?
+        } else {
+            unpack_varint_32(r)?
+        };
+        self.last_loc = Some(loc);
+        Ok(loc)
+    }
+
+    fn unpack_key<R: Read>(&mut self, r: &mut R, loc: MetadataBlock) -> Result<Key> {
+        let prev = self.last_keys.get(&loc).cloned().unwrap_or(0);
+        let k = prev.wrapping_add(unzigzag(unpack_varint(r)?) as u64);
+        self.last_keys.insert(loc, k);
+        Ok(k)
+    }
+
+    fn unpack_keys_values<R: Read>(
+        &mut self,
+        r: &mut R,
+        loc: MetadataBlock,
+    ) -> Result<(Vec<Key>, Vec<Bytes>)> {
+        let len = unpack_varint(r)? as usize;
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for _ in 0..len {
+            keys.push(self.unpack_key(r, loc)?);
+            values.push(unpack_varint_bytes(r)?);
+        }
+        Ok((keys, values))
+    }
+
+    fn unpack_op<R: Read>(&mut self, r: &mut R) -> Result<Entry> {
+        use Entry::*;
+
+        let b = r.read_u8()?;
+        let same = b & SAME_LOC != 0;
+        let tag = Tag::try_from(b & !SAME_LOC)?;
+        let has_loc = matches!(
+            tag,
+            Tag::SetSeq
+                | Tag::Zero
+                | Tag::Literal
+                | Tag::Shadow
+                | Tag::Overwrite
+                | Tag::Insert
+                | Tag::Prepend
+                | Tag::Append
+                | Tag::Erase
+        );
+        if same && !has_loc {
+            return Err(anyhow!("same block flag set on an op without a block"));
+        }
+
+        match tag {
+            Tag::AllocMetadata => {
+                let (b, e) = unpack_range_32(r)?;
+                Ok(AllocMetadata(b, e))
+            }
+            Tag::FreeMetadata => {
+                let (b, e) = unpack_range_32(r)?;
+                Ok(FreeMetadata(b, e))
+            }
+            Tag::GrowMetadata => Ok(GrowMetadata(unpack_varint_32(r)?)),
+
+            Tag::AllocData => {
+                let (b, e) = unpack_range(r)?;
+                Ok(AllocData(b, e))
+            }
+            Tag::FreeData => {
+                let (b, e) = unpack_range(r)?;
+                Ok(FreeData(b, e))
+            }
+            Tag::GrowData => Ok(GrowData(unpack_varint(r)?)),
+
+            Tag::UpdateInfoRoot => {
+                let loc = unpack_varint_32(r)?;
+                let seq_nr = unpack_varint_32(r)?;
+                Ok(UpdateInfoRoot(NodePtr { loc, seq_nr }))
+            }
+            Tag::Checkpoint => Ok(Checkpoint(unpack_varint(r)?)),
+
+            Tag::SetSeq => {
+                let loc = self.unpack_loc(r, same)?;
+                let seq = unpack_varint_32(r)?;
+                Ok(SetSeq(loc, seq))
+            }
+            Tag::Zero => {
+                let loc = self.unpack_loc(r, same)?;
+                let (begin, end) = unpack_range(r)?;
+                Ok(Zero(loc, begin as usize, end as usize))
+            }
+            Tag::Literal => {
+                let loc = self.unpack_loc(r, same)?;
+                let offset = unpack_varint(r)? as usize;
+                let bytes = unpack_varint_bytes(r)?;
+                Ok(Literal(loc, offset, bytes))
+            }
+            Tag::Shadow => {
+                let loc = self.unpack_loc(r, same)?;
+                let origin = unpack_varint_32(r)?;
+                let seq_nr = unpack_varint_32(r)?;
+                if let Some(k) = self.last_keys.get(&origin).cloned() {
+                    self.last_keys.insert(loc, k);
+                }
+                Ok(Shadow(
+                    loc,
+                    NodePtr {
+                        loc: origin,
+                        seq_nr,
+                    },
+                ))
+            }
+            Tag::Overwrite => {
+                let loc = self.unpack_loc(r, same)?;
+                let idx = unpack_varint_32(r)?;
+                let k = self.unpack_key(r, loc)?;
+                let v = unpack_varint_bytes(r)?;
+                Ok(Overwrite(loc, idx, k, v))
+            }
+            Tag::Insert => {
+                let loc = self.unpack_loc(r, same)?;
+                let idx = unpack_varint_32(r)?;
+                let k = self.unpack_key(r, loc)?;
+                let v = unpack_varint_bytes(r)?;
+                Ok(Insert(loc, idx, k, v))
+            }
+            Tag::Prepend => {
+                let loc = self.unpack_loc(r, same)?;
+                let (keys, values) = self.unpack_keys_values(r, loc)?;
+                Ok(Prepend(loc, keys, values))
+            }
+            Tag::Append => {
+                let loc = self.unpack_loc(r, same)?;
+                let (keys, values) = self.unpack_keys_values(r, loc)?;
+                Ok(Append(loc, keys, values))
+            }
+            Tag::Erase => {
+                let loc = self.unpack_loc(r, same)?;
+                let (b, e) = unpack_range_32(r)?;
+                Ok(Erase(loc, b, e))
+            }
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+
+// Each batch of ops is framed so a torn or corrupt batch can be detected
+// before we try and decode it.
+//
+// batch := <version u8> <payload len u32> <crc32c of payload u32> <payload>
+// payload := <nr ops u32> <op>*
+//
+// Version 1 is the first framed format.  How the ops are encoded within
+// the payload is a property of the whole journal file (see Encoding).
+pub const FORMAT_VERSION: u8 = 1;
+
+const FRAME_HEADER_LEN: usize = 1 + 4 + 4;
+
+/// How ops are encoded within a batch.  This is recorded in the client
+/// flags of the slab file header, so every batch in a journal file uses
+/// the same encoding.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Fixed width little endian fields.
+    Fixed,
+
+    /// Varints, with delta encoded keys and elided block locations.
+    Compact,
+}
+
+const ENCODING_COMPACT: u16 = 1;
+
+impl Encoding {
+    pub fn to_flags(self) -> u16 {
+        match self {
+            Encoding::Fixed => 0,
+            Encoding::Compact => ENCODING_COMPACT,
+        }
+    }
+
+    pub fn from_flags(flags: u16) -> Result<Self> {
+        match flags {
+            0 => Ok(Encoding::Fixed),
+            ENCODING_COMPACT => Ok(Encoding::Compact),
+            _ => Err(anyhow!("unknown journal encoding flags {:#x}", flags)),
+        }
+    }
+}
+
+fn pack_payload(ops: &[Entry], encoding: Encoding) -> Result<Vec<u8>> {
+    let mut w = Vec::new();
     w.write_u32::<LittleEndian>(ops.len() as u32)?;
-    for op in ops {
-        pack_op(w, op)?;
+    match encoding {
+        Encoding::Fixed => {
+            for op in ops {
+                pack_op(&mut w, op)?;
+            }
+        }
+        Encoding::Compact => {
+            let mut packer = CompactPacker::default();
+            for op in ops {
+                packer.pack_op(&mut w, op)?;
+            }
+        }
     }
-    Ok(())
+    Ok(w)
 }
 
-pub fn unpack_ops<R: Read>(r: &mut R) -> Result<Vec<Entry>> {
+fn unpack_payload(payload: &[u8], encoding: Encoding) -> Result<Vec<Entry>> {
+    let mut r = std::io::Cursor::new(payload);
     let nr_ops = r.read_u32::<LittleEndian>()? as usize;
-    let mut ops = Vec::with_capacity(nr_ops);
-    for _ in 0..nr_ops {
-        let op = unpack_op(r)?;
-        ops.push(op);
+
+    // nr_ops hasn't been validated, so don't trust it for the capacity.
+    let mut ops = Vec::with_capacity(nr_ops.min(payload.len()));
+    let mut unpacker = CompactUnpacker::default();
+    for i in 0..nr_ops {
+        let op = match encoding {
+            Encoding::Fixed => unpack_op(&mut r),
+            Encoding::Compact => unpacker.unpack_op(&mut r),
+        };
+        ops.push(op.map_err(|e| anyhow!("op {}: {}", i, e))?);
+    }
+
+    if r.position() as usize != payload.len() {
+        return Err(anyhow!(
+            "{} trailing bytes after ops",
+            payload.len() - r.position() as usize
+        ));
     }
     Ok(ops)
 }
 
+/// The number of bytes each op takes up within a packed batch.  With the
+/// compact encoding this depends on the ops that precede it.
+pub fn packed_op_sizes(ops: &[Entry], encoding: Encoding) -> Result<Vec<usize>> {
+    let mut sizes = Vec::with_capacity(ops.len());
+    let mut w = Vec::new();
+    let mut packer = CompactPacker::default();
+    for op in ops {
+        let before = w.len();
+        match encoding {
+            Encoding::Fixed => pack_op(&mut w, op)?,
+            Encoding::Compact => packer.pack_op(&mut w, op)?,
+        }
+        sizes.push(w.len() - before);
+    }
+    Ok(sizes)
+}
+
+pub fn pack_ops<W: Write>(w: &mut W, ops: &[Entry], encoding: Encoding) -> Result<()> {
+    let payload = pack_payload(ops, encoding)?;
+    w.write_u8(FORMAT_VERSION)?;
+    w.write_u32::<LittleEndian>(payload.len() as u32)?;
+    w.write_u32::<LittleEndian>(crc32c::crc32c(&payload))?;
+    w.write_all(&payload)?;
+    Ok(())
+}
+
+/// Unpacks a single batch.  Nothing is decoded unless the checksum
+/// matches.
+pub fn unpack_ops<R: Read>(r: &mut R, encoding: Encoding) -> Result<Vec<Entry>> {
+    let version = r.read_u8().context("batch header truncated")?;
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(anyhow!(
+            "unsupported batch format version {} (expected 1..={})",
+            version,
+            FORMAT_VERSION
+        ));
+    }
+
+    let len = r
+        .read_u32::<LittleEndian>()
+        .context("batch header truncated")? as usize;
+    let expected_csum = r
+        .read_u32::<LittleEndian>()
+        .context("batch header truncated")?;
+
+    let mut payload = Vec::new();
+    r.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Err(anyhow!(
+            "batch truncated, {} bytes of payload, expected {}",
+            payload.len(),
+            len
+        ));
+    }
+
+    let actual_csum = crc32c::crc32c(&payload);
+    if actual_csum != expected_csum {
+        return Err(anyhow!(
+            "batch checksum mismatch, {:#x} != {:#x}",
+            actual_csum,
+            expected_csum
+        ));
+    }
+
+    unpack_payload(&payload, encoding)
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_ops() -> Vec<Entry> {
+        use Entry::*;
+
+        let root = NodePtr { loc: 1, seq_nr: 2 };
+        vec![
+            AllocMetadata(1, 2),
+            FreeMetadata(3, 4),
+            GrowMetadata(5),
+            AllocData(6, 7),
+            FreeData(8, 9),
+            GrowData(10),
+            UpdateInfoRoot(root),
+            Checkpoint(11),
+            SetSeq(12, 13),
+            Zero(14, 15, 16),
+            Literal(17, 18, vec![1, 2, 3]),
+            Shadow(19, root),
+            Overwrite(20, 21, 22, vec![4, 5]),
+            Insert(23, 24, 25, vec![6]),
+            Prepend(26, vec![1, 2], vec![vec![7], vec![8]]),
+            Append(27, vec![3, 4], vec![vec![9], vec![10]]),
+            Erase(28, 29, 30),
+        ]
+    }
+
+    fn round_trip(ops: &[Entry], encoding: Encoding) -> Result<usize> {
+        let mut w = Vec::new();
+        pack_ops(&mut w, ops, encoding)?;
+        pack_ops(&mut w, &ops[0..3], encoding)?;
+
+        let mut r = std::io::Cursor::new(&w);
+        assert!(unpack_ops(&mut r, encoding)? == ops);
+        assert!(unpack_ops(&mut r, encoding)? == ops[0..3]);
+        Ok(w.len())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        round_trip(&all_ops(), Encoding::Fixed)?;
+        round_trip(&all_ops(), Encoding::Compact)?;
+        Ok(())
+    }
+
+    // Runs of ops against the same node, with keys going down as well as
+    // up, and ones that wrap.
+    fn node_ops() -> Vec<Entry> {
+        use Entry::*;
+
+        let root = NodePtr { loc: 5, seq_nr: 0 };
+        vec![
+            AllocMetadata(6, 7),
+            Shadow(6, root),
+            Insert(6, 0, 1000, vec![1; 16]),
+            Insert(6, 1, 1010, vec![2; 16]),
+            Overwrite(6, 0, 990, vec![3; 16]),
+            Insert(7, 0, u64::MAX, vec![4]),
+            Insert(7, 1, 0, vec![5]),
+            Append(6, vec![2000, 2001, 1999], vec![vec![], vec![6], vec![7]]),
+            Prepend(7, vec![u64::MAX - 1, 3], vec![vec![8], vec![9]]),
+            Erase(6, 1, 3),
+            SetSeq(6, 1),
+            Zero(6, 0, 4096),
+            AllocData(u64::MAX - 10, u64::MAX),
+        ]
+    }
+
+    #[test]
+    fn test_compact_deltas() -> Result<()> {
+        let fixed = round_trip(&node_ops(), Encoding::Fixed)?;
+        let compact = round_trip(&node_ops(), Encoding::Compact)?;
+        assert!(compact < fixed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_no_previous_loc() -> Result<()> {
+        let mut w = Vec::new();
+        pack_ops(&mut w, &[Entry::SetSeq(1, 2)], Encoding::Compact)?;
+
+        // Set the same block flag on the first op and fix up the crc.
+        let payload_offset = FRAME_HEADER_LEN;
+        w[payload_offset + 4] |= SAME_LOC;
+        let crc = crc32c::crc32c(&w[payload_offset..]);
+        w[5..9].copy_from_slice(&crc.to_le_bytes());
+
+        let err = unpack_ops(&mut std::io::Cursor::new(&w), Encoding::Compact).unwrap_err();
+        assert!(err.to_string().contains("previous block"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_payload() -> Result<()> {
+        let mut w = Vec::new();
+        pack_ops(&mut w, &all_ops(), Encoding::Compact)?;
+        let last = w.len() - 1;
+        w[last] ^= 0xff;
+
+        let err = unpack_ops(&mut std::io::Cursor::new(&w), Encoding::Compact).unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated() -> Result<()> {
+        let mut w = Vec::new();
+        pack_ops(&mut w, &all_ops(), Encoding::Compact)?;
+        w.truncate(w.len() / 2);
+
+        let err = unpack_ops(&mut std::io::Cursor::new(&w), Encoding::Compact).unwrap_err();
+        assert!(err.to_string().contains("truncated"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_future_version() -> Result<()> {
+        let mut w = Vec::new();
+        pack_ops(&mut w, &all_ops(), Encoding::Compact)?;
+        w[0] = FORMAT_VERSION + 1;
+
+        let err = unpack_ops(&mut std::io::Cursor::new(&w), Encoding::Compact).unwrap_err();
+        assert!(err.to_string().contains("version"));
+        Ok(())
+    }
+}
+
 //-------------------------------------------------------------------------
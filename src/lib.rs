@@ -5,6 +5,7 @@ mod allocators;
 mod block_cache;
 mod btree;
 mod byte_types;
+pub mod compressed_io;
 mod copier;
 mod core;
 mod hash;
@@ -12,7 +13,7 @@ mod iovec;
 pub mod journal;
 mod lru;
 mod packed_array;
-mod slab;
+pub mod slab;
 pub mod thin;
 mod types;
 mod varint;
@@ -4,11 +4,14 @@ use std::collections::BTreeMap;
 use std::env;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use thiserror::Error;
 use threadpool::ThreadPool;
+use zstd::dict::EncoderDictionary;
 
 use crate::hash::*;
 use crate::lru::*;
@@ -93,6 +96,34 @@ impl SlabOffsets {
         Ok(Self { offsets })
     }
 
+    // Checks the offsets describe the data file: the first slab follows
+    // the file header, and the last one ends at the end of the file.
+    fn check(&self, data: &mut File, header_len: u64, file_size: u64) -> Result<()> {
+        if self.offsets.first().is_some_and(|o| *o != header_len) {
+            return Err(StaleOffsets.into());
+        }
+
+        let end = match self.offsets.last() {
+            None => header_len,
+            Some(last) => {
+                if last + SLAB_HEADER_LEN > file_size {
+                    return Err(StaleOffsets.into());
+                }
+
+                data.seek(SeekFrom::Start(*last))?;
+                if data.read_u64::<LittleEndian>()? != SLAB_MAGIC {
+                    return Err(StaleOffsets.into());
+                }
+                last + SLAB_HEADER_LEN + data.read_u64::<LittleEndian>()?
+            }
+        };
+
+        if end != file_size {
+            return Err(StaleOffsets.into());
+        }
+        Ok(())
+    }
+
     fn write_offset_file<P: AsRef<Path>>(&self, p: P) -> Result<()> {
         let mut w = OpenOptions::new()
             .read(false)
@@ -119,29 +150,73 @@ impl SlabOffsets {
 // derived data, and can be rebuilt with the repair fn.
 //
 // file := <header> <slab>*
-// header := <magic nr> <slab format version>
-// slab := <magic nr> <len> <checksum> <compressed data>
+// header := <magic nr> <slab format version> <flags> [<dict len> <dict>]
+// slab := <magic nr> <len> <hash> <compressed data>
+//
+// The hash is of the uncompressed data, so it checks the compression as
+// well as the disk.  Version 0 files hash the compressed data instead;
+// they can still be read, and appended to, in that format.
+//
+// The low 16 bits of the flags belong to the slab file (bit 0 is
+// compression, bit 1 says a zstd dictionary follows the flags).  The high
+// 16 bits are for the client to describe the contents of the slabs.
 
 const FILE_MAGIC: u64 = 0xb927f96a6b611180;
 const SLAB_MAGIC: u64 = 0x20565137a3100a7c;
 
-const FORMAT_VERSION: u32 = 0;
+const FORMAT_VERSION: u32 = 1;
+
+// Hashes the compressed data of each slab.
+const V0_FORMAT_VERSION: u32 = 0;
+
+const FLAG_COMPRESSED: u32 = 1;
+const FLAG_DICTIONARY: u32 = 2;
+const CLIENT_FLAGS_SHIFT: u32 = 16;
+
+// Anything bigger is assumed to be a corrupt header.
+const MAX_DICTIONARY_LEN: u32 = 16 * 1024 * 1024;
 
 pub type SlabIndex = u64;
+type Dictionary = Arc<Vec<u8>>;
 
 pub struct SlabData {
     pub index: SlabIndex,
     pub data: Vec<u8>,
+
+    // Of the uncompressed data
+    pub hash: Hash64,
 }
 
+/// Returned, wrapped in an anyhow::Error, when a slab fails its integrity
+/// check.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[error("slab {slab} is corrupt: {reason}")]
+pub struct CorruptSlab {
+    pub slab: u32,
+    pub reason: String,
+}
+
+/// Returned, wrapped in an anyhow::Error, when a slab file is opened with
+/// an offsets file that doesn't describe it, eg, because of a crash part
+/// way through replacing both.  repair() rebuilds the offsets.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[error("offsets file doesn't match the slab file")]
+pub struct StaleOffsets;
+
 struct SlabShared {
     data: File,
     offsets: SlabOffsets,
     file_size: u64,
+
+    // Set for v0 files
+    hash_compressed: bool,
 }
 
 pub struct SlabFile {
     compressed: bool,
+    hash_compressed: bool,
+    dict: Option<Dictionary>,
+    client_flags: u16,
     compressor: Option<CompressionService>,
     offsets_path: PathBuf,
     pending_index: u64,
@@ -168,11 +243,17 @@ impl Drop for SlabFile {
     }
 }
 
-fn write_slab(shared: &Arc<Mutex<SlabShared>>, data: &[u8]) -> Result<()> {
+fn write_slab(shared: &Arc<Mutex<SlabShared>>, data: &[u8], hash: &Hash64) -> Result<()> {
     assert!(!data.is_empty());
 
     let mut shared = shared.lock().unwrap();
 
+    let hash = if shared.hash_compressed {
+        hash_64(data)
+    } else {
+        *hash
+    };
+
     let offset = shared.file_size;
     shared.offsets.offsets.push(offset);
     shared.file_size += 8 + 8 + 8 + data.len() as u64;
@@ -180,8 +261,7 @@ fn write_slab(shared: &Arc<Mutex<SlabShared>>, data: &[u8]) -> Result<()> {
     shared.data.seek(SeekFrom::End(0))?;
     shared.data.write_u64::<LittleEndian>(SLAB_MAGIC)?;
     shared.data.write_u64::<LittleEndian>(data.len() as u64)?;
-    let csum = hash_64(data);
-    shared.data.write_all(csum.as_slice())?;
+    shared.data.write_all(hash.as_slice())?;
     shared.data.write_all(data)?;
 
     Ok(())
@@ -201,11 +281,11 @@ fn writer_(shared: Arc<Mutex<SlabShared>>, rx: Receiver<SlabData>) -> Result<()>
 
         let buf = buf.unwrap();
         if buf.index == write_index {
-            write_slab(&shared, &buf.data)?;
+            write_slab(&shared, &buf.data, &buf.hash)?;
             write_index += 1;
 
             while let Some(buf) = queued.remove(&write_index) {
-                write_slab(&shared, &buf.data)?;
+                write_slab(&shared, &buf.data, &buf.hash)?;
                 write_index += 1;
             }
         } else {
@@ -222,14 +302,53 @@ fn writer(shared: Arc<Mutex<SlabShared>>, rx: Receiver<SlabData>) {
     writer_(shared, rx).expect("write of slab failed");
 }
 
-fn offsets_path<P: AsRef<Path>>(p: P) -> PathBuf {
+pub fn offsets_path<P: AsRef<Path>>(p: P) -> PathBuf {
     let mut offsets_path = PathBuf::new();
     offsets_path.push(p);
     offsets_path.set_extension("offsets");
     offsets_path
 }
 
-fn read_slab_header(data: &mut std::fs::File) -> Result<u32> {
+struct FileHeader {
+    version: u32,
+    flags: u32,
+    dict: Option<Dictionary>,
+
+    // Where the first slab starts
+    len: u64,
+}
+
+impl FileHeader {
+    fn compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    fn client_flags(&self) -> u16 {
+        (self.flags >> CLIENT_FLAGS_SHIFT) as u16
+    }
+
+    fn hash_compressed(&self) -> bool {
+        self.version == V0_FORMAT_VERSION
+    }
+}
+
+fn write_slab_header<W: Write>(data: &mut W, flags: u32, dict: Option<&[u8]>) -> Result<()> {
+    let mut flags = flags;
+    if dict.is_some() {
+        flags |= FLAG_DICTIONARY;
+    }
+
+    data.write_u64::<LittleEndian>(FILE_MAGIC)?;
+    data.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+    data.write_u32::<LittleEndian>(flags)?;
+    if let Some(dict) = dict {
+        data.write_u32::<LittleEndian>(dict.len() as u32)?;
+        data.write_all(dict)?;
+    }
+    Ok(())
+}
+
+fn read_slab_header<R: Read>(data: &mut R) -> Result<FileHeader> {
     let magic = data
         .read_u64::<LittleEndian>()
         .context("couldn't read magic")?;
@@ -248,21 +367,92 @@ fn read_slab_header(data: &mut std::fs::File) -> Result<u32> {
         ));
     }
 
-    if version != FORMAT_VERSION {
+    if version != FORMAT_VERSION && version != V0_FORMAT_VERSION {
         return Err(anyhow!(
-            "slab file version actual {} != {} expected",
+            "slab file version actual {} != {} or {} expected",
             version,
+            V0_FORMAT_VERSION,
             FORMAT_VERSION
         ));
     }
 
-    if !(flags == 0 || flags == 1) {
+    let slab_flags = flags & ((1 << CLIENT_FLAGS_SHIFT) - 1);
+    let valid = [0, FLAG_COMPRESSED, FLAG_COMPRESSED | FLAG_DICTIONARY];
+    if !valid.contains(&slab_flags) {
         return Err(anyhow!(
-            "slab file flag value unexpected {} != 0 or 1",
-            flags
+            "slab file flag value unexpected {} != 0, 1 or 3",
+            slab_flags
         ));
     }
-    Ok(flags)
+
+    let mut len = FILE_HEADER_LEN;
+    let mut dict = None;
+    if slab_flags & FLAG_DICTIONARY != 0 {
+        let dict_len = data
+            .read_u32::<LittleEndian>()
+            .context("couldn't read dictionary length")?;
+        if dict_len > MAX_DICTIONARY_LEN {
+            return Err(anyhow!(
+                "slab file dictionary too large ({} bytes)",
+                dict_len
+            ));
+        }
+
+        let mut buf = vec![0; dict_len as usize];
+        data.read_exact(&mut buf)
+            .context("couldn't read dictionary")?;
+        len += 4 + dict_len as u64;
+        dict = Some(Arc::new(buf));
+    }
+
+    Ok(FileHeader {
+        version,
+        flags,
+        dict,
+        len,
+    })
+}
+
+// Decompresses the data of a slab, and checks it against the hash.
+fn check_slab(
+    slab: u32,
+    compressed: bool,
+    hash_compressed: bool,
+    dict: Option<&[u8]>,
+    buf: Vec<u8>,
+    expected: &Hash64,
+) -> std::result::Result<Vec<u8>, CorruptSlab> {
+    let corrupt = |reason| CorruptSlab { slab, reason };
+
+    if hash_compressed && hash_64(&buf) != *expected {
+        return Err(corrupt("hash mismatch".to_string()));
+    }
+
+    let data = unpack_slab_data(compressed, dict, buf)
+        .map_err(|e| corrupt(format!("couldn't decompress: {}", e)))?;
+    if !hash_compressed && hash_64(&data) != *expected {
+        return Err(corrupt("hash mismatch".to_string()));
+    }
+    Ok(data)
+}
+
+fn unpack_slab_data(compressed: bool, dict: Option<&[u8]>, buf: Vec<u8>) -> Result<Vec<u8>> {
+    if compressed {
+        let decompress_buff_size_mb: usize = env::var("BLK_ARCHIVE_DECOMPRESS_BUFF_SIZE_MB")
+            .unwrap_or(String::from("4"))
+            .parse::<usize>()
+            .unwrap_or(4);
+        let mut buffer = Vec::with_capacity(decompress_buff_size_mb * 1024 * 1024);
+        match dict {
+            Some(dict) => {
+                zstd::Decoder::with_dictionary(&buf[..], dict)?.read_to_end(&mut buffer)?
+            }
+            None => zstd::Decoder::new(&buf[..])?.read_to_end(&mut buffer)?,
+        };
+        Ok(buffer)
+    } else {
+        Ok(buf)
+    }
 }
 
 impl SlabFile {
@@ -270,6 +460,8 @@ impl SlabFile {
         data_path: P,
         queue_depth: usize,
         compressed: bool,
+        compression: Compression,
+        client_flags: u16,
         cache_nr_entries: usize,
     ) -> Result<Self> {
         let offsets_path = offsets_path(&data_path);
@@ -282,10 +474,12 @@ impl SlabFile {
             .open(data_path)?;
 
         let (tx, rx) = sync_channel(queue_depth);
-        let flags = if compressed { 1 } else { 0 };
-        data.write_u64::<LittleEndian>(FILE_MAGIC)?;
-        data.write_u32::<LittleEndian>(FORMAT_VERSION)?;
-        data.write_u32::<LittleEndian>(flags)?;
+        let mut flags = (client_flags as u32) << CLIENT_FLAGS_SHIFT;
+        if compressed {
+            flags |= FLAG_COMPRESSED;
+        }
+        write_slab_header(&mut data, flags, compression.dict.as_ref().map(|d| &d[..]))?;
+        let hash_compressed = false;
 
         let offsets = SlabOffsets::default();
         let file_size = data.metadata()?.len();
@@ -293,10 +487,12 @@ impl SlabFile {
             data,
             offsets,
             file_size,
+            hash_compressed,
         }));
 
-        let (compressor, tx) = if flags == 1 {
-            let (c, tx) = CompressionService::new(1, tx);
+        let dict = compression.dict.clone();
+        let (compressor, tx) = if compressed {
+            let (c, tx) = CompressionService::new(1, compression, tx);
             (Some(c), tx)
         } else {
             (None, tx)
@@ -309,6 +505,9 @@ impl SlabFile {
 
         Ok(Self {
             compressed,
+            hash_compressed,
+            dict,
+            client_flags,
             compressor,
             offsets_path,
             pending_index: 0,
@@ -322,6 +521,7 @@ impl SlabFile {
     fn open_for_write<P: AsRef<Path>>(
         data_path: P,
         queue_depth: usize,
+        compression_level: i32,
         cache_nr_entries: usize,
     ) -> Result<Self> {
         let offsets_path = offsets_path(&data_path);
@@ -333,12 +533,19 @@ impl SlabFile {
             .open(data_path)
             .context("open offsets")?;
 
-        let flags = read_slab_header(&mut data)?;
+        let header = read_slab_header(&mut data)?;
 
-        let compressed = flags == 1;
+        let compressed = header.compressed();
+        let hash_compressed = header.hash_compressed();
+        let client_flags = header.client_flags();
+        let dict = header.dict;
         let (tx, rx) = sync_channel(queue_depth);
-        let (compressor, tx) = if flags == 1 {
-            let (c, tx) = CompressionService::new(4, tx);
+        let (compressor, tx) = if compressed {
+            let compression = Compression {
+                level: compression_level,
+                dict: dict.clone(),
+            };
+            let (c, tx) = CompressionService::new(4, compression, tx);
             (Some(c), tx)
         } else {
             (None, tx)
@@ -346,10 +553,12 @@ impl SlabFile {
 
         let offsets = SlabOffsets::read_offset_file(&offsets_path)?;
         let file_size = data.metadata()?.len();
+        offsets.check(&mut data, header.len, file_size)?;
         let shared = Arc::new(Mutex::new(SlabShared {
             data,
             offsets,
             file_size,
+            hash_compressed,
         }));
 
         let tid = {
@@ -359,6 +568,9 @@ impl SlabFile {
 
         Ok(Self {
             compressed,
+            hash_compressed,
+            dict,
+            client_flags,
             compressor,
             offsets_path,
             pending_index: 0,
@@ -378,20 +590,28 @@ impl SlabFile {
             .create(false)
             .open(data_path)?;
 
-        let flags = read_slab_header(&mut data)?;
-        let compressed = flags == 1;
+        let header = read_slab_header(&mut data)?;
+        let compressed = header.compressed();
+        let hash_compressed = header.hash_compressed();
+        let client_flags = header.client_flags();
+        let dict = header.dict;
         let compressor = None;
 
         let offsets = SlabOffsets::read_offset_file(&offsets_path)?;
         let file_size = data.metadata()?.len();
+        offsets.check(&mut data, header.len, file_size)?;
         let shared = Arc::new(Mutex::new(SlabShared {
             data,
             offsets,
             file_size,
+            hash_compressed,
         }));
 
         Ok(Self {
             compressed,
+            hash_compressed,
+            dict,
+            client_flags,
             compressor,
             offsets_path,
             pending_index: 0,
@@ -418,34 +638,73 @@ impl SlabFile {
     pub fn read_(&mut self, slab: u32) -> Result<Vec<u8>> {
         let mut shared = self.shared.lock().unwrap();
 
-        let offset = shared.offsets.offsets[slab as usize];
+        let offset = *shared
+            .offsets
+            .offsets
+            .get(slab as usize)
+            .ok_or_else(|| anyhow!("slab {} out of range", slab))?;
+        let file_size = shared.file_size;
         shared.data.seek(SeekFrom::Start(offset))?;
 
+        let corrupt = |reason: &str| CorruptSlab {
+            slab,
+            reason: reason.to_string(),
+        };
+
         let magic = shared.data.read_u64::<LittleEndian>()?;
+        if magic != SLAB_MAGIC {
+            return Err(corrupt("bad slab magic").into());
+        }
+
         let len = shared.data.read_u64::<LittleEndian>()?;
-        assert_eq!(magic, SLAB_MAGIC);
+        if offset + SLAB_HEADER_LEN + len > file_size {
+            return Err(corrupt("slab runs past the end of the file").into());
+        }
 
-        let mut expected_csum: Hash64 = Hash64::default();
-        shared.data.read_exact(&mut expected_csum)?;
+        let mut expected: Hash64 = Hash64::default();
+        shared.data.read_exact(&mut expected)?;
 
         let mut buf = vec![0; len as usize];
         shared.data.read_exact(&mut buf)?;
 
-        let actual_csum = hash_64(&buf);
-        assert_eq!(actual_csum, expected_csum);
-
-        if self.compressed {
-            let decompress_buff_size_mb: usize = env::var("BLK_ARCHIVE_DECOMPRESS_BUFF_SIZE_MB")
-                .unwrap_or(String::from("4"))
-                .parse::<usize>()
-                .unwrap_or(4);
-            let mut z = zstd::Decoder::new(&buf[..])?;
-            let mut buffer = Vec::with_capacity(decompress_buff_size_mb * 1024 * 1024);
-            z.read_to_end(&mut buffer)?;
-            Ok(buffer)
-        } else {
-            Ok(buf)
+        let dict = self.dict.as_ref().map(|d| &d[..]);
+        Ok(check_slab(
+            slab,
+            self.compressed,
+            self.hash_compressed,
+            dict,
+            buf,
+            &expected,
+        )?)
+    }
+
+    /// Reads every slab, bypassing the cache, and returns those that fail
+    /// their integrity check.
+    pub fn verify_all(&mut self) -> Result<Vec<CorruptSlab>> {
+        let mut bad = Vec::new();
+        for slab in 0..self.get_nr_slabs() as u32 {
+            if let Err(e) = self.read_(slab) {
+                match e.downcast::<CorruptSlab>() {
+                    Ok(corrupt) => bad.push(corrupt),
+                    Err(e) => return Err(e),
+                }
+            }
         }
+        Ok(bad)
+    }
+
+    /// Trains a zstd dictionary, of at most `max_len` bytes, on the
+    /// uncompressed contents of the given slabs.  Pass it to
+    /// SlabFileBuilder::dictionary() when creating a file with similar
+    /// contents.
+    pub fn train_dictionary(&mut self, slabs: Range<u32>, max_len: usize) -> Result<Vec<u8>> {
+        let mut samples = Vec::new();
+        for slab in slabs {
+            samples.push(self.read_(slab)?);
+        }
+
+        zstd::dict::from_samples(&samples, max_len)
+            .with_context(|| format!("couldn't train a dictionary on {} slabs", samples.len()))
     }
 
     pub fn read(&mut self, slab: u32) -> Result<Arc<Vec<u8>>> {
@@ -471,6 +730,7 @@ impl SlabFile {
         tx.send(SlabData {
             index,
             data: data.to_vec(),
+            hash: hash_64(data),
         })?;
         Ok(())
     }
@@ -489,6 +749,16 @@ impl SlabFile {
         shared.file_size
     }
 
+    /// The zstd dictionary stored in the file header, if any.
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.dict.as_ref().map(|d| &d[..])
+    }
+
+    /// Flags the client asked to be stored in the file header.
+    pub fn client_flags(&self) -> u16 {
+        self.client_flags
+    }
+
     pub fn hits(&self) -> u64 {
         self.data_cache.hits
     }
@@ -507,6 +777,9 @@ pub struct SlabFileBuilder<P: AsRef<Path>> {
     read: bool,
     write: bool,
     compressed: bool,
+    compression_level: i32,
+    dict: Option<Vec<u8>>,
+    client_flags: u16,
     cache_nr_entries: usize,
 }
 
@@ -519,6 +792,9 @@ impl<P: AsRef<Path>> SlabFileBuilder<P> {
             read: true,
             write: true,
             compressed: false,
+            compression_level: 0,
+            dict: None,
+            client_flags: 0,
             cache_nr_entries: 1,
         }
     }
@@ -531,6 +807,9 @@ impl<P: AsRef<Path>> SlabFileBuilder<P> {
             read: true,
             write: false,
             compressed: false,
+            compression_level: 0,
+            dict: None,
+            client_flags: 0,
             cache_nr_entries: 1,
         }
     }
@@ -559,6 +838,28 @@ impl<P: AsRef<Path>> SlabFileBuilder<P> {
         self
     }
 
+    /// The zstd level used for slabs written from now on.  Zero selects
+    /// zstd's default.
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Compresses every slab with this dictionary, which is stored in the
+    /// header.  Implies compression.
+    pub fn dictionary(mut self, dict: Vec<u8>) -> Self {
+        assert!(self.create); // stored in the header at create time
+        self.compressed = true;
+        self.dict = Some(dict);
+        self
+    }
+
+    pub fn client_flags(mut self, flags: u16) -> Self {
+        assert!(self.create); // stored in the header at create time
+        self.client_flags = flags;
+        self
+    }
+
     pub fn cache_nr_entries(mut self, count: usize) -> Self {
         self.cache_nr_entries = count;
         self
@@ -566,14 +867,29 @@ impl<P: AsRef<Path>> SlabFileBuilder<P> {
 
     pub fn build(self) -> Result<SlabFile> {
         if self.create {
+            if !self.compressed && self.dict.is_some() {
+                return Err(anyhow!("a dictionary needs compression"));
+            }
+
+            let compression = Compression {
+                level: self.compression_level,
+                dict: self.dict.map(Arc::new),
+            };
             SlabFile::create(
                 self.path,
                 self.queue_depth,
                 self.compressed,
+                compression,
+                self.client_flags,
                 self.cache_nr_entries,
             )
         } else if self.write {
-            SlabFile::open_for_write(self.path, self.queue_depth, self.cache_nr_entries)
+            SlabFile::open_for_write(
+                self.path,
+                self.queue_depth,
+                self.compression_level,
+                self.cache_nr_entries,
+            )
         } else {
             SlabFile::open_for_read(self.path, self.cache_nr_entries)
         }
@@ -582,11 +898,26 @@ impl<P: AsRef<Path>> SlabFileBuilder<P> {
 
 //-----------------------------------------
 
+#[derive(Clone, Default)]
+struct Compression {
+    level: i32,
+    dict: Option<Dictionary>,
+}
+
 struct CompressionService {
     pool: ThreadPool,
 }
 
-fn compression_worker_(rx: Arc<Mutex<Receiver<SlabData>>>, tx: SyncSender<SlabData>) -> Result<()> {
+// The dictionary is parsed once, when the service starts, and shared by
+// all the workers.
+type PreparedDictionary = Arc<EncoderDictionary<'static>>;
+
+fn compression_worker_(
+    rx: Arc<Mutex<Receiver<SlabData>>>,
+    tx: SyncSender<SlabData>,
+    level: i32,
+    dict: Option<PreparedDictionary>,
+) -> Result<()> {
     loop {
         let data = {
             let rx = rx.lock().unwrap();
@@ -599,34 +930,53 @@ fn compression_worker_(rx: Arc<Mutex<Receiver<SlabData>>>, tx: SyncSender<SlabDa
 
         let data = data.unwrap();
 
-        let mut packer = zstd::Encoder::new(Vec::new(), 0)?;
+        let mut packer = match &dict {
+            Some(dict) => zstd::Encoder::with_prepared_dictionary(Vec::new(), dict)?,
+            None => zstd::Encoder::new(Vec::new(), level)?,
+        };
         packer.write_all(&data.data)?;
         tx.send(SlabData {
             index: data.index,
             data: packer.finish()?,
+            hash: data.hash,
         })?;
     }
 
     Ok(())
 }
 
-fn compression_worker(rx: Arc<Mutex<Receiver<SlabData>>>, tx: SyncSender<SlabData>) {
+fn compression_worker(
+    rx: Arc<Mutex<Receiver<SlabData>>>,
+    tx: SyncSender<SlabData>,
+    level: i32,
+    dict: Option<PreparedDictionary>,
+) {
     // FIXME: handle error
-    compression_worker_(rx, tx).unwrap();
+    compression_worker_(rx, tx, level, dict).unwrap();
 }
 
 impl CompressionService {
-    fn new(nr_threads: usize, tx: SyncSender<SlabData>) -> (Self, SyncSender<SlabData>) {
+    fn new(
+        nr_threads: usize,
+        compression: Compression,
+        tx: SyncSender<SlabData>,
+    ) -> (Self, SyncSender<SlabData>) {
         let pool = ThreadPool::new(nr_threads);
         let (self_tx, rx) = sync_channel(nr_threads * 64);
 
         // we can only have a single receiver
         let rx = Arc::new(Mutex::new(rx));
 
+        let level = compression.level;
+        let dict = compression
+            .dict
+            .map(|d| Arc::new(EncoderDictionary::copy(&d, level)));
+
         for _ in 0..nr_threads {
             let tx = tx.clone();
             let rx = rx.clone();
-            pool.execute(move || compression_worker(rx, tx));
+            let dict = dict.clone();
+            pool.execute(move || compression_worker(rx, tx, level, dict));
         }
 
         (Self { pool }, self_tx)
@@ -635,8 +985,640 @@ impl CompressionService {
 
 //-----------------------------------------
 
-pub fn repair<P: AsRef<Path>>(_p: P) -> Result<()> {
-    todo!();
+// The offsets file is only written when a slab file is closed, so a file
+// that is still being written has to be scanned slab by slab.
+const SLAB_HEADER_LEN: u64 = 8 + 8 + 8;
+
+// Not including any dictionary
+const FILE_HEADER_LEN: u64 = 8 + 4 + 4;
+
+/// Reads the slabs of a file that may still be being appended to, as
+/// they're completed.  A slab at the tail that is only partly written is
+/// left until the rest of it appears.
+pub struct SlabTailer {
+    path: PathBuf,
+    data: File,
+    compressed: bool,
+    hash_compressed: bool,
+    dict: Option<Dictionary>,
+    client_flags: u16,
+
+    // Where the next slab will start
+    offset: u64,
+    nr_slabs: u32,
+}
+
+impl SlabTailer {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut data = File::open(&path)?;
+        let header = read_slab_header(&mut data)?;
+
+        Ok(Self {
+            path,
+            data,
+            compressed: header.compressed(),
+            hash_compressed: header.hash_compressed(),
+            client_flags: header.client_flags(),
+            offset: header.len,
+            dict: header.dict,
+            nr_slabs: 0,
+        })
+    }
+
+    pub fn client_flags(&self) -> u16 {
+        self.client_flags
+    }
+
+    /// The number of slabs returned so far.
+    pub fn nr_slabs(&self) -> u32 {
+        self.nr_slabs
+    }
+
+    /// True if the path now refers to a different file, eg, because it's
+    /// been replaced with a rename.  The caller should open a new tailer.
+    pub fn replaced(&self) -> Result<bool> {
+        use std::os::unix::fs::MetadataExt;
+
+        let current = match std::fs::metadata(&self.path) {
+            Ok(md) => md,
+
+            // Mid rename
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let ours = self.data.metadata()?;
+        Ok(current.dev() != ours.dev() || current.ino() != ours.ino())
+    }
+
+    /// Returns the next slab, and its index, if it has been completely
+    /// written.
+    pub fn next_slab(&mut self) -> Result<Option<(u32, Vec<u8>)>> {
+        let file_size = self.data.metadata()?.len();
+        if self.offset + SLAB_HEADER_LEN > file_size {
+            return Ok(None);
+        }
+
+        self.data.seek(SeekFrom::Start(self.offset))?;
+        let magic = self.data.read_u64::<LittleEndian>()?;
+        if magic != SLAB_MAGIC {
+            return Err(anyhow!(
+                "bad slab magic at offset {} of slab {}",
+                self.offset,
+                self.nr_slabs
+            ));
+        }
+
+        let len = self.data.read_u64::<LittleEndian>()?;
+        if self.offset + SLAB_HEADER_LEN + len > file_size {
+            return Ok(None);
+        }
+
+        let mut expected: Hash64 = Hash64::default();
+        self.data.read_exact(&mut expected)?;
+        let mut buf = vec![0; len as usize];
+        self.data.read_exact(&mut buf)?;
+
+        let slab = self.nr_slabs;
+        let dict = self.dict.as_ref().map(|d| &d[..]);
+        let data = check_slab(
+            slab,
+            self.compressed,
+            self.hash_compressed,
+            dict,
+            buf,
+            &expected,
+        )?;
+        self.offset += SLAB_HEADER_LEN + len;
+        self.nr_slabs += 1;
+        Ok(Some((slab, data)))
+    }
+}
+
+//-----------------------------------------
+
+/// A region of the data file that repair() threw away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BadSlab {
+    pub offset: u64,
+    pub len: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub nr_slabs: u32,
+    pub bad: Vec<BadSlab>,
+
+    // Set if the bad regions were saved.
+    pub quarantine: Option<PathBuf>,
+}
+
+impl RepairReport {
+    pub fn is_clean(&self) -> bool {
+        self.bad.is_empty()
+    }
+}
+
+impl std::fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "good slabs: {}", self.nr_slabs)?;
+        for bad in &self.bad {
+            writeln!(
+                f,
+                "dropped {} bytes at offset {}: {}",
+                bad.len, bad.offset, bad.reason
+            )?;
+        }
+        if let Some(path) = &self.quarantine {
+            writeln!(f, "bad regions saved to {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct RepairOptions {
+    // Bad regions are appended here, as raw bytes, rather than being
+    // thrown away.
+    pub quarantine: Option<PathBuf>,
+
+    // Drop everything from the first bad slab on, for clients whose slabs
+    // depend on the ones before them.
+    pub truncate: bool,
+
+    // Write the repaired file here, leaving the original untouched.
+    pub output: Option<PathBuf>,
+}
+
+enum ScannedSlab {
+    // Length includes the slab header
+    Good(u64),
+
+    // The framing is intact, but the contents are not
+    Bad(u64, String),
+
+    // We don't know where the next slab starts
+    Lost(String),
+}
+
+fn scan_slab(
+    data: &mut File,
+    file_size: u64,
+    offset: u64,
+    slab: u32,
+    header: &FileHeader,
+) -> Result<ScannedSlab> {
+    use ScannedSlab::*;
+
+    let remaining = file_size - offset;
+    if remaining < SLAB_HEADER_LEN {
+        return Ok(Lost("torn slab header".to_string()));
+    }
+
+    data.seek(SeekFrom::Start(offset))?;
+    let magic = data.read_u64::<LittleEndian>()?;
+    if magic != SLAB_MAGIC {
+        return Ok(Lost("bad slab magic".to_string()));
+    }
+
+    let len = data.read_u64::<LittleEndian>()?;
+    let mut expected: Hash64 = Hash64::default();
+    data.read_exact(&mut expected)?;
+    if len > remaining - SLAB_HEADER_LEN {
+        return Ok(Lost(format!(
+            "slab length {} runs past the end of the file",
+            len
+        )));
+    }
+
+    let mut buf = vec![0; len as usize];
+    data.read_exact(&mut buf)?;
+    let total = SLAB_HEADER_LEN + len;
+    let dict = header.dict.as_ref().map(|d| &d[..]);
+    Ok(
+        match check_slab(
+            slab,
+            header.compressed(),
+            header.hash_compressed(),
+            dict,
+            buf,
+            &expected,
+        ) {
+            Ok(_) => Good(total),
+            Err(e) => Bad(total, e.reason),
+        },
+    )
+}
+
+// Looks for the next slab magic after the given offset, reading the file a
+// chunk at a time.
+fn resync(data: &mut File, file_size: u64, offset: u64) -> Result<u64> {
+    const CHUNK_LEN: usize = 1024 * 1024;
+
+    let magic = SLAB_MAGIC.to_le_bytes();
+    let mut b = offset + 1;
+    let mut buf = vec![0; CHUNK_LEN];
+    while b + magic.len() as u64 <= file_size {
+        let len = (file_size - b).min(CHUNK_LEN as u64) as usize;
+        data.seek(SeekFrom::Start(b))?;
+        data.read_exact(&mut buf[..len])?;
+        if let Some(pos) = buf[..len].windows(magic.len()).position(|w| w == magic) {
+            return Ok(b + pos as u64);
+        }
+
+        // Overlap the chunks so a magic nr can't straddle them.
+        b += (len - (magic.len() - 1)) as u64;
+    }
+    Ok(file_size)
+}
+
+// Appends a region of one file to another.
+fn copy_region(src: &mut File, offset: u64, len: u64, dest: &mut File) -> Result<()> {
+    src.seek(SeekFrom::Start(offset))?;
+    let copied = std::io::copy(&mut (&mut *src).take(len), dest)?;
+    if copied != len {
+        return Err(anyhow!("file shrank while it was being repaired"));
+    }
+    Ok(())
+}
+
+/// Rebuilds the offsets file by scanning the data file.  Slabs that fail
+/// their checksum, and any bytes that can't be parsed (eg, a torn slab at
+/// the tail), are dropped from the data file.
+///
+/// Unless `opts.truncate` is set, every slab after a dropped one is kept,
+/// so a client whose slabs depend on each other (like the journal) should
+/// set it.  The file is read a slab at a time, rather than all at once.
+pub fn repair<P: AsRef<Path>>(p: P, opts: &RepairOptions) -> Result<RepairReport> {
+    let path = p.as_ref();
+    let mut data = File::open(path)?;
+    let file_size = data.metadata()?.len();
+    let header = read_slab_header(&mut data)?;
+
+    let mut report = RepairReport::default();
+    let mut good = Vec::new();
+    let mut offset = header.len;
+    while offset < file_size {
+        let (len, reason) =
+            match scan_slab(&mut data, file_size, offset, good.len() as u32, &header)? {
+                ScannedSlab::Good(len) => {
+                    good.push((offset, len));
+                    offset += len;
+                    continue;
+                }
+                ScannedSlab::Bad(_, reason) | ScannedSlab::Lost(reason) if opts.truncate => {
+                    (file_size - offset, reason)
+                }
+                ScannedSlab::Bad(len, reason) => (len, reason),
+                ScannedSlab::Lost(reason) => {
+                    (resync(&mut data, file_size, offset)? - offset, reason)
+                }
+            };
+
+        // Merge adjacent bad regions, which happens when resyncing finds
+        // a stray magic number.
+        match report.bad.last_mut() {
+            Some(last) if last.offset + last.len == offset => last.len += len,
+            _ => report.bad.push(BadSlab {
+                offset,
+                len,
+                reason,
+            }),
+        }
+        offset += len;
+    }
+
+    if !report.is_clean() {
+        if let Some(qpath) = &opts.quarantine {
+            let mut q = OpenOptions::new().create(true).append(true).open(qpath)?;
+            for bad in &report.bad {
+                copy_region(&mut data, bad.offset, bad.len, &mut q)?;
+            }
+            report.quarantine = Some(qpath.clone());
+        }
+    }
+
+    let mut offsets = SlabOffsets::default();
+    let dest = match &opts.output {
+        None if report.is_clean() => {
+            offsets.offsets = good.iter().map(|(b, _)| *b).collect();
+            path.to_path_buf()
+        }
+        output => {
+            // Build the new file to one side, so a crash leaves the
+            // original.
+            let new_path = output.clone().unwrap_or_else(|| {
+                let mut new_path = path.to_path_buf();
+                new_path.set_extension("repair");
+                new_path
+            });
+
+            let mut w = File::create(&new_path)?;
+            copy_region(&mut data, 0, header.len, &mut w)?;
+            let mut new_offset = header.len;
+            for (b, len) in &good {
+                copy_region(&mut data, *b, *len, &mut w)?;
+                offsets.offsets.push(new_offset);
+                new_offset += len;
+            }
+            w.sync_all()?;
+
+            match output {
+                Some(output) => output.clone(),
+                None => {
+                    std::fs::rename(&new_path, path)?;
+                    path.to_path_buf()
+                }
+            }
+        }
+    };
+
+    offsets.write_offset_file(offsets_path(&dest))?;
+    report.nr_slabs = good.len() as u32;
+    Ok(report)
+}
+
+//------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn mk_slab(i: usize) -> Vec<u8> {
+        (0..100 + i * 10).map(|b| (b + i) as u8).collect()
+    }
+
+    fn create(path: &Path, compressed: bool, nr_slabs: usize) -> Result<()> {
+        let mut slab = SlabFileBuilder::create(path)
+            .compressed(compressed)
+            .build()?;
+        for i in 0..nr_slabs {
+            slab.write_slab(&mk_slab(i))?;
+        }
+        slab.close()
+    }
+
+    fn read_all(path: &Path) -> Result<Vec<Vec<u8>>> {
+        let mut slab = SlabFileBuilder::open(path).build()?;
+        let mut slabs = Vec::new();
+        for s in 0..slab.get_nr_slabs() {
+            slabs.push(slab.read(s as u32)?.to_vec());
+        }
+        Ok(slabs)
+    }
+
+    fn slab_offsets(path: &Path) -> Result<Vec<u64>> {
+        Ok(SlabOffsets::read_offset_file(offsets_path(path))?.offsets)
+    }
+
+    #[test]
+    fn test_read_detects_corruption() -> Result<()> {
+        let dir = TempDir::new()?;
+        for compressed in [false, true] {
+            let path = dir.path().join(format!("slabs-{}", compressed));
+            create(&path, compressed, 4)?;
+            let offsets = slab_offsets(&path)?;
+
+            // Flip a bit in the data of slab 2, leaving the header intact.
+            let mut data = fs::read(&path)?;
+            data[offsets[2] as usize + SLAB_HEADER_LEN as usize + 4] ^= 0x1;
+            fs::write(&path, &data)?;
+
+            let mut slab = SlabFileBuilder::open(&path).build()?;
+            assert_eq!(slab.read(1)?.to_vec(), mk_slab(1));
+
+            let e = slab.read(2).unwrap_err();
+            let corrupt = e.downcast::<CorruptSlab>()?;
+            assert_eq!(corrupt.slab, 2);
+
+            let bad = slab.verify_all()?;
+            assert_eq!(bad.len(), 1);
+            assert_eq!(bad[0].slab, 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_all_clean() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("slabs");
+        create(&path, true, 5)?;
+
+        let mut slab = SlabFileBuilder::open(&path).build()?;
+        assert!(slab.verify_all()?.is_empty());
+        Ok(())
+    }
+
+    // Rewrites a file in the v0 format, where the hash covers the
+    // compressed data of each slab.
+    fn downgrade_to_v0(path: &Path) -> Result<()> {
+        let mut data = fs::read(path)?;
+        data[8..12].copy_from_slice(&V0_FORMAT_VERSION.to_le_bytes());
+        for offset in slab_offsets(path)? {
+            let b = offset as usize;
+            let len = u64::from_le_bytes(data[b + 8..b + 16].try_into()?) as usize;
+            let start = b + SLAB_HEADER_LEN as usize;
+            let hash = hash_64(&data[start..start + len]);
+            data[b + 16..start].copy_from_slice(hash.as_slice());
+        }
+        fs::write(path, &data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_v0_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        for compressed in [false, true] {
+            let path = dir.path().join(format!("slabs-{}", compressed));
+            create(&path, compressed, 4)?;
+            downgrade_to_v0(&path)?;
+
+            let expected: Vec<Vec<u8>> = (0..4).map(mk_slab).collect();
+            assert_eq!(read_all(&path)?, expected);
+
+            // Appending keeps to the file's format.
+            {
+                let mut slab = SlabFileBuilder::open(&path).write(true).build()?;
+                slab.write_slab(&mk_slab(4))?;
+                slab.close()?;
+            }
+            let mut slab = SlabFileBuilder::open(&path).build()?;
+            assert!(slab.verify_all()?.is_empty());
+            assert_eq!(slab.read(4)?.to_vec(), mk_slab(4));
+
+            let mut tailer = SlabTailer::open(&path)?;
+            let mut nr_slabs = 0;
+            while tailer.next_slab()?.is_some() {
+                nr_slabs += 1;
+            }
+            assert_eq!(nr_slabs, 5);
+
+            // Corruption is still caught.
+            let offsets = slab_offsets(&path)?;
+            let mut data = fs::read(&path)?;
+            data[offsets[2] as usize + SLAB_HEADER_LEN as usize + 4] ^= 0x1;
+            fs::write(&path, &data)?;
+
+            let mut slab = SlabFileBuilder::open(&path).build()?;
+            let bad = slab.verify_all()?;
+            assert_eq!(bad.len(), 1);
+            assert_eq!(bad[0].slab, 2);
+        }
+        Ok(())
+    }
+
+    // Repetitive, like journal slabs.
+    fn mk_entries_slab(i: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for j in 0..64 {
+            let loc = ((i * 7 + j * 13) % 50) as u32;
+            data.push((j % 5) as u8);
+            data.extend_from_slice(&loc.to_le_bytes());
+            data.extend_from_slice(&[0, 0, 16, 0]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_dictionary() -> Result<()> {
+        let dir = TempDir::new()?;
+        let slabs: Vec<Vec<u8>> = (0..200).map(mk_entries_slab).collect();
+
+        let training = dir.path().join("training");
+        {
+            let mut slab = SlabFileBuilder::create(&training).build()?;
+            for data in &slabs {
+                slab.write_slab(data)?;
+            }
+            slab.close()?;
+        }
+        let dict = SlabFileBuilder::open(&training)
+            .build()?
+            .train_dictionary(0..200, 4096)?;
+
+        let path = dir.path().join("slabs");
+        {
+            let mut slab = SlabFileBuilder::create(&path)
+                .compression_level(9)
+                .dictionary(dict.clone())
+                .build()?;
+            for data in &slabs[..100] {
+                slab.write_slab(data)?;
+            }
+            slab.close()?;
+        }
+
+        // Appending picks the dictionary up from the header
+        {
+            let mut slab = SlabFileBuilder::open(&path).write(true).build()?;
+            assert_eq!(slab.dictionary(), Some(&dict[..]));
+            for data in &slabs[100..] {
+                slab.write_slab(data)?;
+            }
+            slab.close()?;
+        }
+        assert_eq!(read_all(&path)?, slabs);
+
+        let mut tail = SlabTailer::open(&path)?;
+        assert_eq!(tail.next_slab()?, Some((0, slabs[0].clone())));
+
+        // Repair has to keep the dictionary
+        fs::remove_file(offsets_path(&path))?;
+        assert!(repair(&path, &RepairOptions::default())?.is_clean());
+        assert_eq!(read_all(&path)?, slabs);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dictionary_needs_compression() -> Result<()> {
+        let dir = TempDir::new()?;
+        let r = SlabFileBuilder::create(dir.path().join("slabs"))
+            .dictionary(vec![0; 16])
+            .compressed(false)
+            .build();
+        assert!(r.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_rebuilds_offsets() -> Result<()> {
+        let dir = TempDir::new()?;
+        for compressed in [false, true] {
+            let path = dir.path().join(format!("slabs-{}", compressed));
+            create(&path, compressed, 5)?;
+            let expected = read_all(&path)?;
+
+            fs::remove_file(offsets_path(&path))?;
+            assert!(SlabFileBuilder::open(&path).build().is_err());
+
+            let report = repair(&path, &RepairOptions::default())?;
+            assert!(report.is_clean());
+            assert_eq!(report.nr_slabs, 5);
+            assert_eq!(read_all(&path)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_drops_bad_slabs() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("slabs");
+        create(&path, false, 5)?;
+        let offsets = slab_offsets(&path)?;
+
+        // Corrupt the contents of slab 2, and tear a slab at the tail.
+        let mut data = fs::read(&path)?;
+        let len = data.len();
+        data[offsets[2] as usize + 30] ^= 0xff;
+        let torn = data[offsets[0] as usize..offsets[1] as usize - 10].to_vec();
+        data.extend(torn);
+        fs::write(&path, &data)?;
+
+        let quarantine = dir.path().join("quarantine");
+        let opts = RepairOptions {
+            quarantine: Some(quarantine.clone()),
+        };
+        let report = repair(&path, &opts)?;
+        assert_eq!(report.nr_slabs, 4);
+        assert_eq!(report.bad.len(), 2);
+        assert_eq!(report.bad[0].offset, offsets[2]);
+        assert_eq!(report.bad[0].len, offsets[3] - offsets[2]);
+        assert_eq!(report.bad[1].offset, len as u64);
+
+        let dropped: u64 = report.bad.iter().map(|b| b.len).sum();
+        assert_eq!(fs::metadata(&quarantine)?.len(), dropped);
+
+        let expected: Vec<Vec<u8>> = [0, 1, 3, 4].iter().map(|i| mk_slab(*i)).collect();
+        assert_eq!(read_all(&path)?, expected);
+
+        // A second pass finds nothing wrong
+        assert!(repair(&path, &RepairOptions::default())?.is_clean());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_resyncs_after_bad_magic() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("slabs");
+        create(&path, true, 4)?;
+        let offsets = slab_offsets(&path)?;
+
+        let mut data = fs::read(&path)?;
+        data[offsets[1] as usize] ^= 0xff;
+        fs::write(&path, &data)?;
+
+        let report = repair(&path, &RepairOptions::default())?;
+        assert_eq!(report.nr_slabs, 3);
+        assert_eq!(report.bad.len(), 1);
+        assert_eq!(report.bad[0].len, offsets[2] - offsets[1]);
+
+        let expected: Vec<Vec<u8>> = [0, 2, 3].iter().map(|i| mk_slab(*i)).collect();
+        assert_eq!(read_all(&path)?, expected);
+        Ok(())
+    }
 }
 
 //------------------------------------------------
@@ -9,9 +9,14 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use thinp::io_engine::*;
 
+use crate::allocators::metadata_alloc::MetadataAlloc;
 use crate::allocators::*;
 use crate::block_cache::*;
+use crate::btree::compact::CompactStats;
 use crate::btree::node::*;
+use crate::btree::nodes::delta::*;
+use crate::btree::nodes::log::*;
+use crate::btree::nodes::program::*;
 use crate::btree::nodes::simple::*;
 use crate::btree::range_value::RangeValue;
 use crate::btree::transaction_manager::*;
@@ -20,7 +25,7 @@ use crate::btree::*;
 use crate::copier::fake::*;
 use crate::copier::*;
 use crate::core::*;
-use crate::journal::batch;
+use crate::journal::batch::{self, BatchContext};
 use crate::journal::entry::*;
 use crate::journal::*;
 use crate::packed_array::*;
@@ -69,7 +74,7 @@ impl RangeValue for Mapping {
                 k_old,
                 Mapping {
                     b: self.b,
-                    e: self.e.min(k_new),
+                    e: self.e.min(self.b.saturating_add(k_new - k_old)),
                     snap_time: self.snap_time,
                 },
             ))
@@ -89,6 +94,10 @@ impl RangeValue for Mapping {
             None
         }
     }
+
+    fn range_len(&self) -> Key {
+        self.len()
+    }
 }
 
 impl Serializable for Mapping {
@@ -112,14 +121,167 @@ impl Serializable for Mapping {
     }
 }
 
-pub type MappingTree = BTree<
+/// Which kind of node holds the leaves of a pool's mapping trees.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LeafFormat {
+    /// Delta encoded keys and values, cheap to change.
+    Delta,
+
+    /// Compiled programs with a log of recent changes, which hold many
+    /// more fragmented mappings but cost more to change.
+    LogProgram,
+}
+
+impl LeafFormat {
+    /// Works out the format of an existing tree from its leftmost leaf.
+    pub fn of_tree(tm: &TransactionManager, root: NodePtr) -> Result<Self> {
+        let mut n_ptr = root;
+        while tm.is_internal(n_ptr)? {
+            let node: SimpleNode<NodePtr, SharedProxy> = tm.read(n_ptr)?;
+            n_ptr = node.get_value(0);
+        }
+
+        match tm.node_kind(n_ptr)? {
+            DELTA_NODE_KIND => Ok(LeafFormat::Delta),
+            LOG_NODE_KIND => Ok(LeafFormat::LogProgram),
+            kind => Err(anyhow!(
+                "mapping tree leaf {} has unexpected kind {}",
+                n_ptr.loc,
+                kind
+            )),
+        }
+    }
+}
+
+pub type DeltaMappingTree = BTree<
+    Mapping,
+    SimpleNode<NodePtr, SharedProxy>,
+    SimpleNode<NodePtr, ExclusiveProxy>,
+    DeltaNode<Mapping, SharedProxy>,
+    DeltaNode<Mapping, ExclusiveProxy>,
+>;
+
+pub type LogProgramMappingTree = BTree<
+    Mapping,
+    SimpleNode<NodePtr, SharedProxy>,
+    SimpleNode<NodePtr, ExclusiveProxy>,
+    LogProgramNode<SharedProxy>,
+    LogProgramNode<ExclusiveProxy>,
+>;
+
+/// The mappings of a thin, in whichever leaf format the pool uses.
+pub enum MappingTree {
+    Delta(DeltaMappingTree),
+    LogProgram(LogProgramMappingTree),
+}
+
+// Calls the same method on whichever tree is inside.
+macro_rules! with_tree {
+    ($self:expr, $t:ident => $e:expr) => {
+        match $self {
+            MappingTree::Delta($t) => $e,
+            MappingTree::LogProgram($t) => $e,
+        }
+    };
+}
+
+// As with_tree!, for methods that return a new tree.
+macro_rules! map_tree {
+    ($self:expr, $t:ident => $e:expr) => {
+        match $self {
+            MappingTree::Delta($t) => MappingTree::Delta($e),
+            MappingTree::LogProgram($t) => MappingTree::LogProgram($e),
+        }
+    };
+}
+
+impl MappingTree {
+    pub fn open_tree(format: LeafFormat, tm: Arc<TransactionManager>, root: NodePtr) -> Self {
+        match format {
+            LeafFormat::Delta => MappingTree::Delta(BTree::open_tree(tm, root)),
+            LeafFormat::LogProgram => MappingTree::LogProgram(BTree::open_tree(tm, root)),
+        }
+    }
+
+    pub fn empty_tree(
+        format: LeafFormat,
+        tm: Arc<TransactionManager>,
+        ctx: &BatchContext,
+    ) -> Result<Self> {
+        Ok(match format {
+            LeafFormat::Delta => MappingTree::Delta(BTree::empty_tree(tm, ctx)?),
+            LeafFormat::LogProgram => MappingTree::LogProgram(BTree::empty_tree(tm, ctx)?),
+        })
+    }
+
+    pub fn format(&self) -> LeafFormat {
+        match self {
+            MappingTree::Delta(_) => LeafFormat::Delta,
+            MappingTree::LogProgram(_) => LeafFormat::LogProgram,
+        }
+    }
+
+    pub fn root(&self) -> NodePtr {
+        with_tree!(self, t => t.root())
+    }
+
+    pub fn snap(&mut self, snap_time: u32) -> Self {
+        map_tree!(self, t => t.snap(snap_time))
+    }
+
+    pub fn with_metadata_alloc(self, alloc: Arc<Mutex<MetadataAlloc>>) -> Self {
+        map_tree!(self, t => t.with_metadata_alloc(alloc))
+    }
+
+    pub fn with_snap_time(self, snap_time: u32) -> Self {
+        map_tree!(self, t => t.with_snap_time(snap_time))
+    }
+
+    pub fn lookup(&self, key: Key) -> Result<Option<Mapping>> {
+        with_tree!(self, t => t.lookup(key))
+    }
+
+    pub fn lookup_range(&self, key_begin: Key, key_end: Key) -> Result<Vec<(Key, Mapping)>> {
+        with_tree!(self, t => t.lookup_range(key_begin, key_end))
+    }
+
+    pub fn insert(&mut self, ctx: &BatchContext, key: Key, value: &Mapping) -> Result<()> {
+        with_tree!(self, t => t.insert(ctx, key, value))
+    }
+
+    pub fn insert_range(&mut self, ctx: &BatchContext, key: Key, value: &Mapping) -> Result<()> {
+        with_tree!(self, t => t.insert_range(ctx, key, value))
+    }
+
+    pub fn remove_range(&mut self, ctx: &BatchContext, key_begin: Key, key_end: Key) -> Result<()> {
+        with_tree!(self, t => t.remove_range(ctx, key_begin, key_end))
+    }
+
+    pub fn compact(&mut self, ctx: &BatchContext) -> Result<CompactStats> {
+        with_tree!(self, t => t.compact(ctx))
+    }
+
+    pub fn check(&self) -> Result<u64> {
+        with_tree!(self, t => t.check())
+    }
+}
+
+/// A mapping tree with leaves that compile their mappings into programs,
+/// which hold many more fragmented mappings than a `SimpleNode`.
+pub type CompressedMappingTree = BTree<
     Mapping,
     SimpleNode<NodePtr, SharedProxy>,
     SimpleNode<NodePtr, ExclusiveProxy>,
-    SimpleNode<Mapping, SharedProxy>,
-    SimpleNode<Mapping, ExclusiveProxy>,
+    ProgramNode<SharedProxy>,
+    ProgramNode<ExclusiveProxy>,
 >;
 
+/// A program node with a log, so it's only recompiled every few changes.
+pub type LogProgramNode<Data> = LogNode<Mapping, ProgramNode<Data>, Data>;
+
+/// Applies journalled ops to a LogProgramNode during replay.
+pub type LogProgramNodeReplay<Data> = LogNodeReplay<Mapping, ProgramNode<Data>, Data>;
+
 //-------------------------------------------------------------------------
 
 #[cfg(test)]
@@ -182,6 +344,19 @@ mod tests {
             assert_eq!(r, t.2);
         }
     }
+
+    #[test]
+    fn test_select_below_remapped() {
+        // The data blocks are nowhere near the thin blocks, so the new end
+        // has to be worked out from the length kept.
+        let m = mk_mapping(1000, 1100);
+        assert_eq!(m.select_lt(10, 60), Some((10, mk_mapping(1000, 1050))));
+        assert_eq!(m.select_lt(10, 500), Some((10, mk_mapping(1000, 1100))));
+        assert_eq!(m.select_lt(10, 10), None);
+
+        // Trimming against the top of the key space mustn't overflow.
+        assert_eq!(m.select_lt(10, Key::MAX), Some((10, m)));
+    }
 }
 
 //-------------------------------------------------------------------------
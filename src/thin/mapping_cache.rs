@@ -0,0 +1,327 @@
+use linked_hash_map::LinkedHashMap;
+use std::collections::BTreeMap;
+
+use crate::btree::range_value::RangeValue;
+use crate::thin::mapping::*;
+use crate::types::*;
+
+//-------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MappingCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub nr_regions: usize,
+    pub nr_extents: usize,
+}
+
+// A region is a range of virtual blocks for which we know every mapping.
+// Any gaps between the extents are unmapped.
+#[derive(Clone)]
+struct Region {
+    end: VBlock,
+    extents: Vec<(VBlock, Mapping)>,
+}
+
+fn clip_extents(extents: &[(VBlock, Mapping)], b: VBlock, e: VBlock) -> Vec<(VBlock, Mapping)> {
+    let mut result = Vec::new();
+    for (k, m) in extents {
+        if *k >= e {
+            break;
+        }
+
+        if let Some((k, m)) = m.select_geq(*k, b) {
+            if let Some((k, m)) = m.select_lt(k, e) {
+                result.push((k, m));
+            }
+        }
+    }
+    result
+}
+
+/// A bounded cache of (VBlock, Mapping) extents for the active thins.  This
+/// sits in front of the mapping trees so a read doesn't have to descend the
+/// btree every time.  The capacity is expressed as a number of extents, and
+/// whole regions are evicted in lru order once it is exceeded.
+pub struct MappingCache {
+    capacity: usize,
+    nr_extents: usize,
+    thins: BTreeMap<ThinID, BTreeMap<VBlock, Region>>,
+
+    // Keyed on (thin id, region begin)
+    lru: LinkedHashMap<(ThinID, VBlock), ()>,
+
+    // Bumped every time a thin's mappings are invalidated.
+    generations: BTreeMap<ThinID, u64>,
+
+    hits: u64,
+    misses: u64,
+}
+
+impl MappingCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            nr_extents: 0,
+            thins: BTreeMap::new(),
+            lru: LinkedHashMap::new(),
+            generations: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns the mappings for [b, e) if the whole range is cached.
+    pub fn lookup(&mut self, id: ThinID, b: VBlock, e: VBlock) -> Option<Vec<(VBlock, Mapping)>> {
+        let found = self
+            .thins
+            .get(&id)
+            .and_then(|regions| regions.range(..=b).next_back())
+            .and_then(|(region_b, region)| {
+                if region.end >= e {
+                    Some((*region_b, clip_extents(&region.extents, b, e)))
+                } else {
+                    None
+                }
+            });
+
+        match found {
+            Some((region_b, extents)) => {
+                self.hits += 1;
+                self.lru.get_refresh(&(id, region_b));
+                Some(extents)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// The thin's generation, which changes whenever its mappings are
+    /// invalidated.  Readers take it before looking in the mapping tree,
+    /// see insert_if_current().
+    pub fn generation(&self, id: ThinID) -> u64 {
+        self.generations.get(&id).cloned().unwrap_or(0)
+    }
+
+    fn bump_generation(&mut self, id: ThinID) {
+        *self.generations.entry(id).or_default() += 1;
+    }
+
+    /// As insert(), unless the thin has been invalidated since generation
+    /// was taken, in which case the extents may be stale and are dropped.
+    pub fn insert_if_current(
+        &mut self,
+        id: ThinID,
+        generation: u64,
+        b: VBlock,
+        e: VBlock,
+        extents: &[(VBlock, Mapping)],
+    ) {
+        if self.generation(id) == generation {
+            self.insert(id, b, e, extents);
+        }
+    }
+
+    /// Records the complete set of mappings for [b, e).  Any cached
+    /// regions that overlap are dropped.
+    pub fn insert(&mut self, id: ThinID, b: VBlock, e: VBlock, extents: &[(VBlock, Mapping)]) {
+        self.drop_regions(id, b, e);
+
+        if b >= e || extents.len() > self.capacity {
+            return;
+        }
+
+        let region = Region {
+            end: e,
+            extents: extents.to_vec(),
+        };
+        self.thins.entry(id).or_default().insert(b, region);
+        self.lru.insert((id, b), ());
+        self.nr_extents += extents.len();
+
+        while self.nr_extents > self.capacity {
+            match self.lru.pop_front() {
+                Some(((id, b), _)) => self.remove_region(id, b),
+                None => break,
+            }
+        }
+    }
+
+    fn remove_region(&mut self, id: ThinID, b: VBlock) {
+        if let Some(regions) = self.thins.get_mut(&id) {
+            if let Some(region) = regions.remove(&b) {
+                self.nr_extents -= region.extents.len();
+            }
+
+            if regions.is_empty() {
+                self.thins.remove(&id);
+            }
+        }
+        self.lru.remove(&(id, b));
+    }
+
+    /// Drops any cached regions that overlap [b, e).
+    pub fn invalidate(&mut self, id: ThinID, b: VBlock, e: VBlock) {
+        self.bump_generation(id);
+        self.drop_regions(id, b, e);
+    }
+
+    fn drop_regions(&mut self, id: ThinID, b: VBlock, e: VBlock) {
+        let mut victims = Vec::new();
+        if let Some(regions) = self.thins.get(&id) {
+            // Regions never overlap, so their ends are in the same order
+            // as their begins.
+            for (region_b, region) in regions.range(..e).rev() {
+                if region.end <= b {
+                    break;
+                }
+                victims.push(*region_b);
+            }
+        }
+
+        for region_b in victims {
+            self.remove_region(id, region_b);
+        }
+    }
+
+    /// Drops everything cached for a thin.
+    pub fn invalidate_thin(&mut self, id: ThinID) {
+        self.bump_generation(id);
+        let begins: Vec<VBlock> = match self.thins.get(&id) {
+            Some(regions) => regions.keys().cloned().collect(),
+            None => return,
+        };
+
+        for b in begins {
+            self.remove_region(id, b);
+        }
+    }
+
+    /// A snapshot starts out sharing all of the origin's mappings, so it
+    /// can start out sharing the origin's cached regions too.
+    pub fn copy_thin(&mut self, origin: ThinID, snap: ThinID) {
+        self.invalidate_thin(snap);
+
+        let regions: Vec<(VBlock, Region)> = match self.thins.get(&origin) {
+            Some(regions) => regions.iter().map(|(b, r)| (*b, r.clone())).collect(),
+            None => return,
+        };
+
+        for (b, region) in regions {
+            self.insert(snap, b, region.end, &region.extents);
+        }
+    }
+
+    pub fn stats(&self) -> MappingCacheStats {
+        MappingCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            nr_regions: self.lru.len(),
+            nr_extents: self.nr_extents,
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_mapping(b: PBlock, e: PBlock) -> Mapping {
+        Mapping { b, e, snap_time: 0 }
+    }
+
+    #[test]
+    fn test_miss_then_hit() {
+        let mut cache = MappingCache::new(16);
+        assert!(cache.lookup(0, 0, 100).is_none());
+
+        cache.insert(0, 0, 100, &[(0, mk_mapping(0, 50))]);
+        let extents = cache.lookup(0, 10, 20).unwrap();
+        assert_eq!(extents, vec![(10, mk_mapping(10, 20))]);
+
+        // Unmapped, but still known about
+        let extents = cache.lookup(0, 60, 100).unwrap();
+        assert!(extents.is_empty());
+
+        // Partially outside the region
+        assert!(cache.lookup(0, 90, 110).is_none());
+
+        // Different thin
+        assert!(cache.lookup(1, 10, 20).is_none());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.nr_regions, 1);
+        assert_eq!(stats.nr_extents, 1);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = MappingCache::new(16);
+        cache.insert(0, 0, 100, &[(0, mk_mapping(0, 50))]);
+        cache.insert(0, 100, 200, &[(100, mk_mapping(100, 200))]);
+        cache.insert(0, 200, 300, &[]);
+
+        cache.invalidate(0, 150, 160);
+        assert!(cache.lookup(0, 0, 100).is_some());
+        assert!(cache.lookup(0, 100, 110).is_none());
+        assert!(cache.lookup(0, 200, 300).is_some());
+
+        cache.invalidate_thin(0);
+        assert!(cache.lookup(0, 0, 100).is_none());
+        assert_eq!(cache.stats().nr_extents, 0);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut cache = MappingCache::new(4);
+        for i in 0..8 {
+            let b = i * 100;
+            cache.insert(0, b, b + 100, &[(b, mk_mapping(b, b + 10))]);
+            assert!(cache.stats().nr_extents <= 4);
+        }
+
+        // The oldest regions should have gone
+        assert!(cache.lookup(0, 0, 100).is_none());
+        assert!(cache.lookup(0, 700, 800).is_some());
+    }
+
+    #[test]
+    fn test_copy_thin() {
+        let mut cache = MappingCache::new(16);
+        cache.insert(0, 0, 100, &[(0, mk_mapping(0, 50))]);
+        cache.copy_thin(0, 1);
+
+        assert_eq!(cache.lookup(0, 0, 100), cache.lookup(1, 0, 100));
+        cache.invalidate(0, 0, 100);
+        assert!(cache.lookup(0, 0, 100).is_none());
+        assert!(cache.lookup(1, 0, 100).is_some());
+    }
+
+    #[test]
+    fn test_insert_if_current() {
+        let mut cache = MappingCache::new(16);
+        let generation = cache.generation(0);
+        cache.insert_if_current(0, generation, 0, 100, &[(0, mk_mapping(0, 50))]);
+        assert!(cache.lookup(0, 0, 100).is_some());
+
+        // Extents looked up before an invalidate aren't cached.
+        let generation = cache.generation(0);
+        cache.invalidate(0, 200, 300);
+        cache.insert_if_current(0, generation, 100, 200, &[]);
+        assert!(cache.lookup(0, 100, 200).is_none());
+
+        // Other thins aren't affected.
+        let generation = cache.generation(1);
+        cache.invalidate_thin(0);
+        cache.insert_if_current(1, generation, 0, 100, &[]);
+        assert!(cache.lookup(1, 0, 100).is_some());
+    }
+}
+
+//-------------------------------------------------------------------------
@@ -6,29 +6,36 @@ use std::fs::{self, OpenOptions};
 use std::io::{self, Read, Write};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use thinp::io_engine::*;
 
 use crate::allocators::data_alloc::*;
 use crate::allocators::metadata_alloc::*;
 use crate::allocators::*;
 use crate::block_cache::*;
+use crate::btree::compact::CompactStats;
 use crate::btree::node::*;
+use crate::btree::nodes::delta::*;
 use crate::btree::nodes::simple::*;
 use crate::btree::transaction_manager::*;
 use crate::btree::BTree;
 use crate::btree::*;
+use crate::compressed_io::*;
 use crate::copier::fake::*;
 use crate::copier::*;
 use crate::core::*;
-use crate::journal::batch;
+use crate::journal::batch::BatchContext;
 use crate::journal::entry::*;
 use crate::journal::*;
 use crate::packed_array::*;
 use crate::thin::mapping::*;
+use crate::thin::mapping_cache::*;
 use crate::types::*;
 
 pub mod mapping;
+pub mod mapping_cache;
+pub mod rebuild;
+pub mod ship;
 mod tests;
 
 //-------------------------------------------------------------------------
@@ -67,17 +74,36 @@ pub type InfoTree = BTree<
     ThinInfo,
     SimpleNode<NodePtr, SharedProxy>,
     SimpleNode<NodePtr, ExclusiveProxy>,
-    SimpleNode<ThinInfo, SharedProxy>,
-    SimpleNode<ThinInfo, ExclusiveProxy>,
+    DeltaNode<ThinInfo, SharedProxy>,
+    DeltaNode<ThinInfo, ExclusiveProxy>,
 >;
 
+// Reads every entry in the info tree.
+fn read_thin_infos(
+    tm: &TransactionManager,
+    n_ptr: NodePtr,
+    infos: &mut BTreeMap<ThinID, ThinInfo>,
+) -> Result<()> {
+    if tm.is_internal(n_ptr)? {
+        let node: SimpleNode<NodePtr, SharedProxy> = tm.read(n_ptr)?;
+        for i in 0..node.nr_entries() {
+            read_thin_infos(tm, node.get_value(i), infos)?;
+        }
+    } else {
+        let node: DeltaNode<ThinInfo, SharedProxy> = tm.read(n_ptr)?;
+        for i in 0..node.nr_entries() {
+            infos.insert(node.get_key(i), node.get_value(i));
+        }
+    }
+    Ok(())
+}
+
 //-------------------------------------------------------------------------
 
 #[derive(Default)]
 struct Ops {
     zeroes: Vec<(PBlock, PBlock)>,
     copies: Vec<(PBlock, PBlock, PBlock)>,
-    removes: Vec<(VBlock, VBlock)>,
     inserts: Vec<(VBlock, Mapping)>,
 }
 
@@ -91,9 +117,9 @@ impl Ops {
     }
 
     fn push_insert(&mut self, vbegin: VBlock, m: &Mapping) {
-        self.inserts.push((vbegin, *m));
-        if let Some((vbegin, last_m)) = self.inserts.last_mut() {
-            if last_m.snap_time == m.snap_time && m.b == last_m.e {
+        if let Some((last_vbegin, last_m)) = self.inserts.last_mut() {
+            let last_vend = *last_vbegin + (last_m.e - last_m.b);
+            if last_vend == vbegin && last_m.snap_time == m.snap_time && m.b == last_m.e {
                 // Merge mappings
                 last_m.e = m.e;
                 return;
@@ -103,18 +129,6 @@ impl Ops {
         self.inserts.push((vbegin, *m));
     }
 
-    fn push_remove(&mut self, b: VBlock, e: VBlock) {
-        if let Some((last_b, last_e)) = self.removes.last_mut() {
-            if *last_e == b {
-                // Merge ranges
-                *last_e = e;
-                return;
-            }
-        }
-
-        self.removes.push((b, e));
-    }
-
     fn zeroes(&self) -> &[(PBlock, PBlock)] {
         &self.zeroes
     }
@@ -123,10 +137,6 @@ impl Ops {
         &self.copies
     }
 
-    fn removes(&self) -> &[(VBlock, VBlock)] {
-        &self.removes
-    }
-
     fn inserts(&self) -> &[(VBlock, Mapping)] {
         &self.inserts
     }
@@ -144,20 +154,24 @@ impl Journaller {
         Journaller { journal, tm }
     }
 
-    fn batch<T, F: FnOnce() -> Result<T>>(&self, action: F) -> Result<T> {
+    fn batch<T, F: FnOnce(&BatchContext) -> Result<T>>(
+        &self,
+        stream: Stream,
+        action: F,
+    ) -> Result<T> {
         let batch_id = self.tm.get_batch_id();
-        batch::begin_batch();
-        let r = action();
+        let ctx = BatchContext::new();
+        let r = action(&ctx);
 
         // We need to write the batch to the journal regardless since the node will
         // have been updated.
-        let completion: Option<Box<dyn BatchCompletion>> =
+        let completion: Option<Box<dyn BatchCompletion + Send>> =
             Some(Box::new(CacheCompletion::new(self.tm.clone())));
         let b = Batch {
-            ops: batch::end_batch()?,
+            ops: ctx.end()?,
             completion,
         };
-        self.journal.lock().unwrap().add_batch(b);
+        self.journal.lock().unwrap().add_stream_batch(stream, b);
 
         r
     }
@@ -167,27 +181,104 @@ impl Journaller {
 
 pub struct ThinDev {
     id: ThinID,
-    metadata_alloc: MetadataAlloc,
+    metadata_alloc: Arc<Mutex<MetadataAlloc>>,
     data_alloc: DataAlloc,
 }
 
+impl ThinDev {
+    // Hands the preallocated blocks that haven't been used back to the
+    // global allocators.
+    fn release(&mut self, ctx: &BatchContext) -> Result<()> {
+        self.metadata_alloc.lock().unwrap().release(ctx)?;
+        self.data_alloc.release(ctx)?;
+        Ok(())
+    }
+}
+
 //-------------------------------------------------------------------------
 
 #[allow(dead_code)]
 pub struct Pool {
-    copier: Arc<dyn Copier>,
+    copier: Arc<dyn Copier + Send + Sync>,
     journal: Arc<Mutex<Journal>>,
     tm: Arc<TransactionManager>,
 
-    infos: InfoTree,
-    active_devs: BTreeMap<ThinID, MappingTree>,
+    // The info tree, and everything else that's shared by all the thins.
+    // It's only changed by batches in the shared stream.
+    shared: Mutex<SharedState>,
+
+    // Mapping trees for thins with uncommitted changes.  The info tree only
+    // ever refers to committed roots, so committing one thin never drags
+    // in the changes of another.
+    active_devs: Mutex<BTreeMap<ThinID, MappingTree>>,
 
+    // Held exclusively while changing a thin's mappings, and shared while
+    // reading them.  The block cache only locks a node at a time, and a
+    // writer restructures the tree a node at a time, so without this a
+    // reader could walk a half split tree.  Different thins can be
+    // provisioned concurrently.
+    thin_locks: Mutex<BTreeMap<ThinID, Arc<RwLock<()>>>>,
+
+    // Held shared by anything that changes the metadata, and exclusively
+    // by checkpoint(), which needs the nodes to stay put while they're
+    // written back.
+    quiesce: RwLock<()>,
+
+    data_prealloc_size: u64,
+
+    mapping_cache: Mutex<MappingCache>,
+
+    // Set for pools reconstructed from journal history.
+    read_only: bool,
+
+    // Set if the nodes are stored compressed.
+    compressed_nodes: Option<Arc<CompressedIoEngine>>,
+
+    // The leaves of every mapping tree in the pool are in this format.
+    leaf_format: LeafFormat,
+}
+
+struct SharedState {
+    infos: InfoTree,
     snap_time: u32,
     next_thin_id: ThinID,
+}
 
-    data_prealloc_size: u64,
+impl SharedState {
+    fn new(infos: InfoTree, snap_time: u32, next_thin_id: ThinID) -> Self {
+        Self {
+            infos,
+            snap_time,
+            next_thin_id,
+        }
+    }
+
+    fn new_thin_id(&mut self) -> ThinID {
+        let id = self.next_thin_id;
+        self.next_thin_id += 1;
+        id
+    }
+
+    fn update_info_root(&self, ctx: &BatchContext) {
+        ctx.add_entry(Entry::UpdateInfoRoot(self.infos.root()));
+    }
+
+    fn lookup_info(&self, id: ThinID) -> Result<ThinInfo> {
+        self.infos
+            .lookup(id)?
+            .ok_or_else(|| anyhow!("ThinID not found"))
+    }
+}
+
+fn new_node_file(path: &Path, nr_metadata_blocks: u64) -> Result<()> {
+    let node_file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    node_file.set_len(4096 * nr_metadata_blocks)?;
+    Ok(())
 }
 
+// Max nr of (VBlock, Mapping) extents held in the mapping cache.
+const MAPPING_CACHE_SIZE: usize = 64 * 1024;
+
 pub struct Map {
     data_begin: PBlock,
     len: PBlock,
@@ -204,15 +295,70 @@ impl Pool {
         dir: P,
         nr_metadata_blocks: u64,
         nr_data_blocks: u64,
+    ) -> Result<Self> {
+        Self::create_with_leaves(dir, nr_metadata_blocks, nr_data_blocks, LeafFormat::Delta)
+    }
+
+    /// Like create, but chooses the format of the mapping tree leaves.
+    pub fn create_with_leaves<P: AsRef<Path>>(
+        dir: P,
+        nr_metadata_blocks: u64,
+        nr_data_blocks: u64,
+        leaf_format: LeafFormat,
     ) -> Result<Self> {
         let dir = dir.as_ref();
         if !dir.exists() {
             return Err(anyhow::anyhow!("Directory does not exist"));
         }
         let node_file_path = Self::create_node_file(dir, nr_metadata_blocks)?;
+        let engine = Arc::new(SyncIoEngine::new(&node_file_path, true)?);
+        Self::create_with_engine(
+            dir,
+            engine,
+            None,
+            leaf_format,
+            nr_metadata_blocks,
+            nr_data_blocks,
+        )
+    }
 
+    /// Like create, but the nodes are stored zstd compressed, packed into
+    /// the pages of the node file.  The node file starts out smaller than
+    /// nr_metadata_blocks, and grows as needed.
+    pub fn create_compressed<P: AsRef<Path>>(
+        dir: P,
+        nr_metadata_blocks: u64,
+        nr_data_blocks: u64,
+        level: i32,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.exists() {
+            return Err(anyhow::anyhow!("Directory does not exist"));
+        }
+        let engine = Arc::new(CompressedIoEngine::format(
+            dir.join("node_file"),
+            nr_metadata_blocks,
+            level,
+        )?);
+        Self::create_with_engine(
+            dir,
+            engine.clone(),
+            Some(engine),
+            LeafFormat::Delta,
+            nr_metadata_blocks,
+            nr_data_blocks,
+        )
+    }
+
+    fn create_with_engine(
+        dir: &Path,
+        engine: Arc<dyn IoEngine>,
+        compressed_nodes: Option<Arc<CompressedIoEngine>>,
+        leaf_format: LeafFormat,
+        nr_metadata_blocks: u64,
+        nr_data_blocks: u64,
+    ) -> Result<Self> {
         let copier = Arc::new(FakeCopier::new());
-        let engine = Arc::new(SyncIoEngine::new(&node_file_path, true)?);
         let block_cache = Arc::new(BlockCache::new(engine, 16)?);
 
         let meta_alloc = BuddyAllocator::new(nr_metadata_blocks);
@@ -227,28 +373,27 @@ impl Pool {
         ));
         let journaller = Journaller::new(journal.clone(), tm.clone());
 
-        let infos = journaller.batch(|| BTree::empty_tree(tm.clone()))?;
+        let infos = journaller.batch(Stream::Shared, |ctx| BTree::empty_tree(tm.clone(), ctx))?;
 
         Ok(Pool {
             copier,
             journal,
             tm,
-            infos,
-            active_devs: BTreeMap::new(),
-            snap_time: 0,
-            next_thin_id: 0,
+            shared: Mutex::new(SharedState::new(infos, 0, 0)),
+            active_devs: Mutex::new(BTreeMap::new()),
+            thin_locks: Mutex::new(BTreeMap::new()),
+            quiesce: RwLock::new(()),
             data_prealloc_size: 64_000,
+            mapping_cache: Mutex::new(MappingCache::new(MAPPING_CACHE_SIZE)),
+            read_only: false,
+            compressed_nodes,
+            leaf_format,
         })
     }
 
     fn create_node_file(dir: &Path, nr_metadata_blocks: u64) -> Result<PathBuf> {
         let node_file_path = dir.join("node_file");
-        let node_file_size = 4096 * nr_metadata_blocks;
-        let node_file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&node_file_path)?;
-        node_file.set_len(node_file_size)?;
+        new_node_file(&node_file_path, nr_metadata_blocks)?;
         Ok(node_file_path)
     }
 
@@ -263,113 +408,237 @@ impl Pool {
         todo!();
     }
 
+    /// Opens a pool whose node file has been rebuilt from the journal
+    /// (see rebuild::Rebuilder).  Nothing can be changed.
+    pub(crate) fn open_read_only(
+        journal: Arc<Mutex<Journal>>,
+        tm: Arc<TransactionManager>,
+        info_root: NodePtr,
+    ) -> Result<Self> {
+        let infos = InfoTree::open_tree(tm.clone(), info_root);
+
+        let mut thins = BTreeMap::new();
+        read_thin_infos(&tm, info_root, &mut thins)?;
+        let next_thin_id = thins.keys().last().map_or(0, |id| id + 1);
+        let snap_time = thins.values().map(|info| info.snap_time).max().unwrap_or(0);
+
+        // All the thins share a leaf format, so the first one says what it is.
+        let leaf_format = match thins.values().next() {
+            Some(info) => LeafFormat::of_tree(&tm, info.root)?,
+            None => LeafFormat::Delta,
+        };
+
+        Ok(Pool {
+            copier: Arc::new(FakeCopier::new()),
+            journal,
+            tm,
+            shared: Mutex::new(SharedState::new(infos, snap_time, next_thin_id)),
+            active_devs: Mutex::new(BTreeMap::new()),
+            thin_locks: Mutex::new(BTreeMap::new()),
+            quiesce: RwLock::new(()),
+            data_prealloc_size: 0,
+            mapping_cache: Mutex::new(MappingCache::new(MAPPING_CACHE_SIZE)),
+            read_only: true,
+            compressed_nodes: None,
+            leaf_format,
+        })
+    }
+
     //----------------------
 
     pub fn close(self) -> Result<()> {
         todo!()
     }
 
-    fn new_thin_id(&mut self) -> ThinID {
-        let id = self.next_thin_id;
-        self.next_thin_id += 1;
-        id
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
     }
 
-    //----------------------
+    /// How much space the nodes take up, including how big the node file
+    /// has grown, if they're stored compressed.
+    pub fn node_space(&self) -> Option<SpaceStats> {
+        self.compressed_nodes.as_ref().map(|e| e.stats())
+    }
 
-    fn update_info_root(&mut self) -> Result<()> {
-        batch::add_entry(Entry::UpdateInfoRoot(self.infos.root()))?;
+    fn check_writeable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!("pool is read only"));
+        }
         Ok(())
     }
 
-    fn journalled<T, F: FnOnce() -> Result<T>>(&self, action: F) -> Result<T> {
+    fn info_root(&self) -> NodePtr {
+        self.shared.lock().unwrap().infos.root()
+    }
+
+    fn snap_time(&self) -> u32 {
+        self.shared.lock().unwrap().snap_time
+    }
+
+    /// Lists the thins, as of the last commit.
+    pub fn thin_ids(&self) -> Result<Vec<ThinID>> {
+        // The info tree only changes with the shared state locked.
+        let shared = self.shared.lock().unwrap();
+        let mut infos = BTreeMap::new();
+        read_thin_infos(&self.tm, shared.infos.root(), &mut infos)?;
+        Ok(infos.keys().cloned().collect())
+    }
+
+    // The caller should hold the returned lock for writing while changing
+    // the thin's mappings, and for reading while looking in them.
+    fn thin_lock(&self, id: ThinID) -> Arc<RwLock<()>> {
+        self.thin_locks
+            .lock()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .clone()
+    }
+
+    //----------------------
+
+    fn journalled<T, F: FnOnce(&BatchContext) -> Result<T>>(
+        &self,
+        stream: Stream,
+        action: F,
+    ) -> Result<T> {
         let journaller = Journaller::new(self.journal.clone(), self.tm.clone());
-        journaller.batch(action)
+        journaller.batch(stream, action)
     }
 
     fn journaller(&self) -> Journaller {
         Journaller::new(self.journal.clone(), self.tm.clone())
     }
 
-    pub fn create_thin_(&mut self, size: VBlock) -> Result<(ThinID, MappingTree)> {
+    fn create_thin_(
+        &self,
+        ctx: &BatchContext,
+        shared: &mut SharedState,
+    ) -> Result<(ThinID, MappingTree)> {
         // Choose a new id
-        let id = self.new_thin_id();
+        let id = shared.new_thin_id();
 
         // create new btree
-        let mappings = MappingTree::empty_tree(self.tm.clone())?;
+        let mappings = MappingTree::empty_tree(self.leaf_format, self.tm.clone(), ctx)?;
 
         Ok((id, mappings))
     }
 
-    pub fn create_thin(&mut self, size: VBlock) -> Result<ThinID> {
-        self.journaller().batch(|| {
-            let (id, mappings) = self.create_thin_(size)?;
+    pub fn create_thin(&self, size: VBlock) -> Result<ThinID> {
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.read().unwrap();
+        let mut shared = self.shared.lock().unwrap();
+        self.journaller().batch(Stream::Shared, |ctx| {
+            let (id, mappings) = self.create_thin_(ctx, &mut shared)?;
             // Add thin_info to btree
             let info = ThinInfo {
                 size,
-                snap_time: self.snap_time,
+                snap_time: shared.snap_time,
                 root: mappings.root(),
             };
-            self.infos.insert(id, &info)?;
-            self.update_info_root()?;
+            shared.infos.insert(ctx, id, &info)?;
+            shared.update_info_root(ctx);
             Ok(id)
         })
     }
 
-    pub fn create_thick(&mut self, size: VBlock) -> Result<ThinID> {
-        self.journaller().batch(|| {
+    pub fn create_thick(&self, size: VBlock) -> Result<ThinID> {
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.read().unwrap();
+        let mut shared = self.shared.lock().unwrap();
+
+        // Nothing else can see the new thin yet, so it's all done in the
+        // shared stream.
+        self.journaller().batch(Stream::Shared, |ctx| {
             // Create a new thin
-            let (id, mut mappings) = self.create_thin_(size)?;
+            let (id, mut mappings) = self.create_thin_(ctx, &mut shared)?;
             let mut ops = Ops::default();
 
-            // Provision the entire range
-            let mut dev = self.open_thin(id);
-            let _ = self.provision(&mut dev, 0, size, &mut ops)?;
+            // Provision the entire range.  This is the same work a write
+            // does, so it's nested in a sub batch of its own, which is
+            // merged into the shared batch once it's dropped.
+            {
+                let sub = ctx.sub_batch();
+                let mut dev = self.open_thin(id);
+                let _ = self.provision(&sub, &mut dev, 0, size, shared.snap_time, &mut ops)?;
+                self.exec_ops(&sub, &mut mappings, &ops)?;
+                dev.release(&sub)?;
+            }
 
             // Add thin_info to btree
             let info = ThinInfo {
                 size,
-                snap_time: self.snap_time,
+                snap_time: shared.snap_time,
                 root: mappings.root(),
             };
-            self.exec_ops(&mut mappings, &ops)?;
-            self.infos.insert(id, &info)?;
-            self.update_info_root()?;
+            shared.infos.insert(ctx, id, &info)?;
+            shared.update_info_root(ctx);
 
             Ok(id)
         })
     }
 
-    pub fn create_snap(&mut self, origin: ThinID) -> Result<ThinID> {
-        self.journaller().batch(|| {
-            let (mut origin_info, mut origin_mappings) = self.get_mapping_tree(origin)?;
-            let snap_mappings = origin_mappings.snap(self.snap_time);
+    pub fn create_snap(&self, origin: ThinID) -> Result<ThinID> {
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.read().unwrap();
+        let origin_lock = self.thin_lock(origin);
+        let _origin_guard = origin_lock.write().unwrap();
+
+        // The snapshot will share the origin's nodes, so they must be
+        // committed first.
+        self.commit_thin_(origin)?;
+
+        let mut shared = self.shared.lock().unwrap();
+        self.journaller().batch(Stream::Shared, |ctx| {
+            let (mut origin_info, mut origin_mappings) = self.mapping_tree_(&shared, origin)?;
 
-            let snap_id = self.new_thin_id();
+            // Everything written so far is older than the snapshot, so
+            // gets copied by whichever thin changes it next.
+            shared.snap_time += 1;
+            let snap_mappings = origin_mappings.snap(shared.snap_time);
+
+            let snap_id = shared.new_thin_id();
             let snap_info = ThinInfo {
                 size: origin_info.size,
-                snap_time: self.snap_time,
+                snap_time: shared.snap_time,
                 root: snap_mappings.root(),
             };
-            self.infos.insert(snap_id, &snap_info)?;
+            shared.infos.insert(ctx, snap_id, &snap_info)?;
 
             // Update the snap_time in the ThinInfo for the origin thin device
-            origin_info.snap_time = self.snap_time;
-            self.snap_time += 1;
-            self.infos.insert(origin, &origin_info)?;
+            origin_info.snap_time = shared.snap_time;
+            shared.infos.insert(ctx, origin, &origin_info)?;
 
             // Update the info root
-            self.update_info_root()?;
+            shared.update_info_root(ctx);
+
+            // The snapshot shares all of the origin's mappings.
+            self.mapping_cache
+                .lock()
+                .unwrap()
+                .copy_thin(origin, snap_id);
             Ok(snap_id)
         })
     }
 
-    pub fn delete_thin(&mut self, dev: ThinID) -> Result<()> {
-        self.journaller().batch(|| {
-            self.infos.remove(dev);
-            self.update_info_root()?;
+    pub fn delete_thin(&self, dev: ThinID) -> Result<()> {
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.read().unwrap();
+        let lock = self.thin_lock(dev);
+        let _guard = lock.write().unwrap();
+
+        let mut shared = self.shared.lock().unwrap();
+        self.journaller().batch(Stream::Shared, |ctx| {
+            self.active_devs.lock().unwrap().remove(&dev);
+            shared.infos.remove(ctx, dev)?;
+            shared.update_info_root(ctx);
+            self.mapping_cache.lock().unwrap().invalidate_thin(dev);
             Ok(())
-        })
+        })?;
+
+        // The thin's pending batches go out ahead of its removal, rather
+        // than staying queued against a thin that no longer exists.
+        self.journal.lock().unwrap().commit(dev)
     }
 
     /*
@@ -393,7 +662,10 @@ impl Pool {
     //---------------------
 
     pub fn open_thin(&self, id: ThinID) -> ThinDev {
-        let metadata_alloc = MetadataAlloc::new(self.tm.get_metadata_alloc(), 32);
+        let metadata_alloc = Arc::new(Mutex::new(MetadataAlloc::new(
+            self.tm.get_metadata_alloc(),
+            32,
+        )));
         let data_alloc = DataAlloc::new(self.tm.get_data_alloc(), self.data_prealloc_size);
         ThinDev {
             id,
@@ -402,19 +674,50 @@ impl Pool {
         }
     }
 
+    /// Hands the thin's preallocated, but unused, blocks back to the pool.
+    /// If a ThinDev is just dropped they stay allocated until the garbage
+    /// collector finds them.
+    pub fn close_thin(&self, mut dev: ThinDev) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let _quiesce = self.quiesce.read().unwrap();
+        self.journaller()
+            .batch(Stream::Thin(dev.id), |ctx| dev.release(ctx))
+    }
+
     //---------------------
 
     // FIXME: we should cache the infos so we don't have to keep reading them
-    fn get_mapping_tree(&self, id: ThinID) -> Result<(ThinInfo, MappingTree)> {
-        let info = self
-            .infos
-            .lookup(id)?
-            .ok_or_else(|| anyhow!("ThinID not found"))?;
-        let mappings = MappingTree::open_tree(self.tm.clone(), info.root);
+    fn mapping_tree_(&self, shared: &SharedState, id: ThinID) -> Result<(ThinInfo, MappingTree)> {
+        let mut info = shared.lookup_info(id)?;
+
+        // Uncommitted changes take precedence over the info tree.
+        if let Some(active) = self.active_devs.lock().unwrap().get(&id) {
+            info.root = active.root();
+        }
+        let mappings = MappingTree::open_tree(self.leaf_format, self.tm.clone(), info.root)
+            .with_snap_time(info.snap_time);
 
         Ok((info, mappings))
     }
 
+    fn get_mapping_tree(&self, id: ThinID) -> Result<(ThinInfo, MappingTree)> {
+        let shared = self.shared.lock().unwrap();
+        self.mapping_tree_(&shared, id)
+    }
+
+    // As get_mapping_tree(), but new nodes come from the thin's own metadata
+    // allocator.
+    fn get_dev_mapping_tree(&self, dev: &ThinDev) -> Result<(ThinInfo, MappingTree)> {
+        let (info, mappings) = self.get_mapping_tree(dev.id)?;
+        Ok((
+            info,
+            mappings.with_metadata_alloc(dev.metadata_alloc.clone()),
+        ))
+    }
+
     /*
     fn lookup_range(
         mappings: &MappingTree,
@@ -431,33 +734,57 @@ impl Pool {
         thin_begin: VBlock,
         thin_end: VBlock,
     ) -> Result<Vec<(VBlock, Mapping)>> {
+        // If a writer changes the mappings while we're looking in the tree
+        // it bumps the generation, so we don't cache what we found.
+        let generation = {
+            let mut cache = self.mapping_cache.lock().unwrap();
+            if let Some(extents) = cache.lookup(dev.id, thin_begin, thin_end) {
+                return Ok(extents);
+            }
+            cache.generation(dev.id)
+        };
+
+        let lock = self.thin_lock(dev.id);
+        let _guard = lock.read().unwrap();
         let (_, mappings) = self.get_mapping_tree(dev.id)?;
-        mappings.lookup_range(thin_begin, thin_end)
+        let extents = mappings.lookup_range(thin_begin, thin_end)?;
+        self.mapping_cache
+            .lock()
+            .unwrap()
+            .insert_if_current(dev.id, generation, thin_begin, thin_end, &extents);
+        Ok(extents)
+    }
+
+    pub fn mapping_cache_stats(&self) -> MappingCacheStats {
+        self.mapping_cache.lock().unwrap().stats()
+    }
+
+    fn invalidate_cached_mappings(&self, id: ThinID, thin_begin: VBlock, thin_end: VBlock) {
+        self.mapping_cache
+            .lock()
+            .unwrap()
+            .invalidate(id, thin_begin, thin_end);
     }
 
     //---------------------
 
-    fn update_mappings_root(
-        &mut self,
-        id: ThinID,
-        info: &mut ThinInfo,
-        mappings: &MappingTree,
-    ) -> Result<()> {
-        info.root = mappings.root();
-        self.infos.insert(id, info)?;
-        self.update_info_root()
+    // The info tree isn't touched until the thin is committed.
+    fn update_mappings_root(&self, id: ThinID, mappings: MappingTree) {
+        self.active_devs.lock().unwrap().insert(id, mappings);
     }
 
     fn provision(
-        &mut self,
+        &self,
+        ctx: &BatchContext,
         dev: &mut ThinDev,
         begin: VBlock,
         end: VBlock,
+        snap_time: u32,
         ops: &mut Ops,
     ) -> Result<Vec<(VBlock, Mapping)>> {
         let len = end - begin;
 
-        let (total, runs) = dev.data_alloc.alloc(len)?;
+        let (total, runs) = dev.data_alloc.alloc(ctx, len)?;
         if total != len {
             // Not enough space, free the allocated data and return an error
             for (b, e) in runs {
@@ -471,11 +798,7 @@ impl Pool {
         for (b, e) in runs {
             ops.push_zero(b, e);
 
-            let mapping = Mapping {
-                b,
-                e,
-                snap_time: self.snap_time,
-            };
+            let mapping = Mapping { b, e, snap_time };
             result.push((current, mapping));
             ops.push_insert(current, &mapping);
             current += e - b;
@@ -490,16 +813,16 @@ impl Pool {
     }
 
     fn break_sharing(
-        &mut self,
+        &self,
+        ctx: &BatchContext,
         dev: &mut ThinDev,
         begin: VBlock,
         end: VBlock,
+        snap_time: u32,
         ops: &mut Ops,
     ) -> Result<Vec<(VBlock, Mapping)>> {
-        ops.push_remove(begin, end);
-
         let len = end - begin;
-        let (total, runs) = dev.data_alloc.alloc(len)?;
+        let (total, runs) = dev.data_alloc.alloc(ctx, len)?;
         if total != len {
             // Not enough space, free the allocated data and return an error
             for (b, e) in runs {
@@ -513,11 +836,7 @@ impl Pool {
         for (b, e) in runs {
             ops.push_copy(current, current + (e - b), b);
 
-            let mapping = Mapping {
-                b,
-                e,
-                snap_time: self.snap_time,
-            };
+            let mapping = Mapping { b, e, snap_time };
             result.push((current, mapping));
             ops.push_insert(current, &mapping);
             current += e - b;
@@ -529,7 +848,7 @@ impl Pool {
     // Any required data ops will be completed before we start updating the metadata.  That
     // way if there's a crash there will be nothing to unroll, other than allocations which
     // can be left to the garbage collector.
-    fn exec_ops(&mut self, mappings: &mut MappingTree, ops: &Ops) -> Result<()> {
+    fn exec_ops(&self, ctx: &BatchContext, mappings: &mut MappingTree, ops: &Ops) -> Result<()> {
         let mut data_ops = Vec::new();
 
         // build zero ops
@@ -547,27 +866,34 @@ impl Pool {
         }
         self.copier.exec(&data_ops)?;
 
-        for (b, e) in ops.removes() {
-            mappings.remove_range(*b, *e)?;
-        }
-
+        // Inserting a range replaces whatever was mapped there before.
         for (vbegin, m) in ops.inserts() {
-            mappings.insert(*vbegin, m)?;
+            mappings.insert_range(ctx, *vbegin, m)?;
         }
 
         Ok(())
     }
 
     pub fn get_write_mapping(
-        &mut self,
+        &self,
         dev: &mut ThinDev,
         thin_begin: VBlock,
         thin_end: VBlock,
     ) -> Result<Vec<(VBlock, Mapping)>> {
-        let (mut info, mut mappings) = self.get_mapping_tree(dev.id)?;
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.read().unwrap();
+        let lock = self.thin_lock(dev.id);
+        let _guard = lock.write().unwrap();
+
+        let snap_time = self.snap_time();
+        let (info, mut mappings) = self.get_dev_mapping_tree(dev)?;
         let mappings_in_range = mappings.lookup_range(thin_begin, thin_end)?;
 
-        self.journaller().batch(|| {
+        // The cache is invalidated once the tree has changed, so readers
+        // that looked in it meanwhile don't cache the old mappings.  The
+        // tree may be partly updated if any of the ops fail, so this is
+        // done either way.
+        let result = self.journaller().batch(Stream::Thin(dev.id), |ctx| {
             let mut ops = Ops::default();
             let mut current = thin_begin;
             let mut result = Vec::new();
@@ -575,13 +901,20 @@ impl Pool {
             // Closure to process mappings and gaps
             let mut process_mapping = |vbegin: VBlock, m: Option<&Mapping>| -> Result<()> {
                 if current < vbegin {
-                    result.extend(self.provision(dev, current, vbegin, &mut ops)?);
+                    result.extend(self.provision(ctx, dev, current, vbegin, snap_time, &mut ops)?);
                 }
 
                 if let Some(m) = m {
                     if Self::should_break_sharing(&info, m) {
                         let len = m.e - m.b;
-                        result.extend(self.break_sharing(dev, vbegin, vbegin + len, &mut ops)?);
+                        result.extend(self.break_sharing(
+                            ctx,
+                            dev,
+                            vbegin,
+                            vbegin + len,
+                            snap_time,
+                            &mut ops,
+                        )?);
                     } else {
                         result.push((vbegin, *m));
                     }
@@ -600,33 +933,113 @@ impl Pool {
             process_mapping(thin_end, None)?;
 
             // Finalize operations
-            self.exec_ops(&mut mappings, &ops)?;
-            self.update_mappings_root(dev.id, &mut info, &mappings)?;
+            self.exec_ops(ctx, &mut mappings, &ops)?;
+            self.update_mappings_root(dev.id, mappings);
 
             Ok(result)
-        })
+        });
+        self.invalidate_cached_mappings(dev.id, thin_begin, thin_end);
+        result
     }
 
     //---------------------
 
-    pub fn discard(
-        &mut self,
-        dev: &mut ThinDev,
-        thin_begin: VBlock,
-        thin_end: VBlock,
-    ) -> Result<()> {
-        self.journaller().batch(|| {
-            let (mut info, mut mappings) = self.get_mapping_tree(dev.id)?;
-            mappings.remove_range(thin_begin, thin_end)?;
-            self.update_mappings_root(dev.id, &mut info, &mappings)
-        })
+    pub fn discard(&self, dev: &mut ThinDev, thin_begin: VBlock, thin_end: VBlock) -> Result<()> {
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.read().unwrap();
+        let lock = self.thin_lock(dev.id);
+        let _guard = lock.write().unwrap();
+
+        // As get_write_mapping(), the cache is invalidated afterwards.
+        let result = self.journaller().batch(Stream::Thin(dev.id), |ctx| {
+            let (_, mut mappings) = self.get_dev_mapping_tree(dev)?;
+            mappings.remove_range(ctx, thin_begin, thin_end)?;
+            self.update_mappings_root(dev.id, mappings);
+            Ok(())
+        });
+        self.invalidate_cached_mappings(dev.id, thin_begin, thin_end);
+        result
+    }
+
+    /// Merges mappings that are contiguous in both the thin and the data
+    /// device, and repacks the mapping tree's leaves.  Metadata size is
+    /// inversely proportional to the mean run length, which the stats
+    /// report before and after.
+    pub fn compact_mappings(&self, dev: &mut ThinDev) -> Result<CompactStats> {
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.read().unwrap();
+        let lock = self.thin_lock(dev.id);
+        let _guard = lock.write().unwrap();
+
+        let result = self.journaller().batch(Stream::Thin(dev.id), |ctx| {
+            let (_, mut mappings) = self.get_dev_mapping_tree(dev)?;
+            let stats = mappings.compact(ctx)?;
+            self.update_mappings_root(dev.id, mappings);
+            Ok(stats)
+        });
+        self.mapping_cache.lock().unwrap().invalidate_thin(dev.id);
+        result
     }
 
     //---------------------
 
-    fn flush(&mut self, dev: ThinDev) -> Result<()> {
-        // find the latest cache pinning id and wait for it to hit the disk
-        todo!();
+    // Points the info tree at the thin's latest mappings, and then commits
+    // the thin's stream along with the shared one.  Other thins' pending
+    // batches are not written.  The caller must hold the thin's lock.
+    fn commit_thin_(&self, id: ThinID) -> Result<()> {
+        // The shared lock is taken first, so readers never see the thin
+        // missing from both active_devs and the info tree.
+        let mut shared = self.shared.lock().unwrap();
+        let active = self.active_devs.lock().unwrap().remove(&id);
+        if let Some(mappings) = active {
+            // The info tree is about to point at the thin's new nodes.
+            self.journal
+                .lock()
+                .unwrap()
+                .add_dependency(Stream::Shared, Stream::Thin(id));
+            self.journaller().batch(Stream::Shared, |ctx| {
+                let mut info = shared.lookup_info(id)?;
+                info.root = mappings.root();
+                shared.infos.insert(ctx, id, &info)?;
+                shared.update_info_root(ctx);
+                Ok(())
+            })?;
+        }
+
+        self.journal.lock().unwrap().commit(id)
+    }
+
+    fn commit_thin(&self, id: ThinID) -> Result<()> {
+        let lock = self.thin_lock(id);
+        let _guard = lock.write().unwrap();
+        self.commit_thin_(id)
+    }
+
+    /// Commits every thin, writes all dirty nodes to the node file and
+    /// starts a new journal segment, so the journal doesn't grow without
+    /// bound.  Returns the new checkpoint generation.  Waits for any
+    /// changes in progress to finish first.
+    pub fn checkpoint(&self) -> Result<u64> {
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.write().unwrap();
+        let ids: Vec<ThinID> = self.active_devs.lock().unwrap().keys().cloned().collect();
+        for id in ids {
+            self.commit_thin_(id)?;
+        }
+
+        // A compressed node store holds back its index until now.
+        self.tm.checkpoint_with(|| match &self.compressed_nodes {
+            Some(engine) => engine.flush(),
+            None => Ok(()),
+        })
+    }
+
+    /// Handles a REQ_FLUSH for a single thin.
+    pub fn flush(&self, dev: &ThinDev) -> Result<()> {
+        self.check_writeable()?;
+        let _quiesce = self.quiesce.read().unwrap();
+        // FIXME: find the latest cache pinning id and wait for it to hit the disk
+        self.commit_thin(dev.id)
     }
 }
 
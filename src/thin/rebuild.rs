@@ -0,0 +1,256 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use thinp::io_engine::*;
+
+use crate::allocators::*;
+use crate::block_cache::*;
+use crate::btree::node::*;
+use crate::btree::nodes::simple::*;
+use crate::btree::transaction_manager::*;
+use crate::journal::entry::*;
+use crate::journal::*;
+use crate::thin::mapping::*;
+use crate::thin::*;
+use crate::types::*;
+
+//-------------------------------------------------------------------------
+
+/// How much of the journal to replay.  Batches are numbered from zero
+/// within each slab, as shown by dump_journal --batches.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplayPoint {
+    End,
+
+    // Up to and including this slab.
+    Slab(u32),
+
+    // Up to and including this batch.
+    Batch(u32, usize), // slab, batch
+}
+
+impl ReplayPoint {
+    fn includes(&self, loc: &BatchLocation) -> bool {
+        use ReplayPoint::*;
+        match self {
+            End => true,
+            Slab(s) => loc.slab <= *s,
+            Batch(s, b) => (loc.slab, loc.batch) <= (*s, *b),
+        }
+    }
+}
+
+/// What was found in a rebuilt node file.
+pub struct RebuildReport {
+    pub nr_entries: u64,
+    pub info_root: NodePtr,
+
+    // Nr of mapping entries in each thin.
+    pub thins: BTreeMap<ThinID, u64>,
+
+    // Replay stops at the first bad batch, which is usually a torn write
+    // at the tail of the journal.
+    pub bad_batch: Option<BadBatch>,
+}
+
+/// Rebuilds a node file from scratch by replaying a journal into it.  This
+/// only works if the journal has never been checkpointed, since a
+/// checkpoint throws away the history.
+pub struct Rebuilder {
+    journal: Arc<Mutex<Journal>>,
+    cache: Arc<BlockCache>,
+    tm: Arc<TransactionManager>,
+    nr_entries: u64,
+    info_root: Option<NodePtr>,
+    bad_batch: Option<BadBatch>,
+    last_batch: Option<BatchLocation>,
+}
+
+impl Rebuilder {
+    /// The node file must not already exist.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        journal_path: P,
+        node_file_path: Q,
+        nr_metadata_blocks: u64,
+        nr_data_blocks: u64,
+    ) -> Result<Self> {
+        let journal = Journal::open(journal_path, false)?;
+        if journal.generation() > 0 {
+            return Err(anyhow!(
+                "journal was checkpointed (generation {}), so doesn't hold the full history",
+                journal.generation()
+            ));
+        }
+        let journal = Arc::new(Mutex::new(journal));
+
+        let node_file_path = node_file_path.as_ref();
+        new_node_file(node_file_path, nr_metadata_blocks)?;
+        let engine = Arc::new(SyncIoEngine::new(node_file_path, true)?);
+        let cache = Arc::new(BlockCache::new(engine, 16)?);
+
+        let tm = Arc::new(TransactionManager::new(
+            journal.clone(),
+            cache.clone(),
+            BuddyAllocator::new(nr_metadata_blocks),
+            BuddyAllocator::new(nr_data_blocks),
+        ));
+
+        Ok(Self {
+            journal,
+            cache,
+            tm,
+            nr_entries: 0,
+            info_root: None,
+            bad_batch: None,
+            last_batch: None,
+        })
+    }
+
+    /// Replays every batch in the journal, and writes the nodes out.
+    pub fn replay(&mut self) -> Result<()> {
+        self.replay_to(ReplayPoint::End)
+    }
+
+    /// Replays the journal up to the given point, and writes the nodes
+    /// out.  Fails if the journal doesn't reach that far.
+    pub fn replay_to(&mut self, point: ReplayPoint) -> Result<()> {
+        let mut journal = self.journal.lock().unwrap();
+        let tm = &self.tm;
+        let nr_entries = &mut self.nr_entries;
+        let info_root = &mut self.info_root;
+        let last_batch = &mut self.last_batch;
+
+        let bad_batch = journal.replay(|loc, ops| {
+            if !point.includes(&loc) {
+                return Ok(());
+            }
+
+            for op in ops {
+                if let Entry::UpdateInfoRoot(root) = op {
+                    *info_root = Some(*root);
+                }
+                tm.replay_entry(op)
+                    .map_err(|e| anyhow!("replaying {:?} at slab {}: {}", op, loc.slab, e))?;
+            }
+            *nr_entries += ops.len() as u64;
+            *last_batch = Some(loc);
+            Ok(())
+        })?;
+        self.bad_batch = bad_batch.filter(|bad| point.includes(&bad.loc));
+
+        let reached = match (point, self.last_batch) {
+            (ReplayPoint::End, _) => true,
+            (_, None) => false,
+            (ReplayPoint::Slab(s), Some(last)) => last.slab == s,
+            (ReplayPoint::Batch(s, b), Some(last)) => (last.slab, last.batch) == (s, b),
+        };
+        if !reached && self.bad_batch.is_none() {
+            return Err(anyhow!("journal ends before {:?}", point));
+        }
+
+        self.cache.flush()?;
+        Ok(())
+    }
+
+    pub fn info_root(&self) -> Result<NodePtr> {
+        self.info_root
+            .ok_or_else(|| anyhow!("journal doesn't contain an info root"))
+    }
+
+    fn infos(&self) -> Result<InfoTree> {
+        Ok(InfoTree::open_tree(self.tm.clone(), self.info_root()?))
+    }
+
+    fn thin_infos(&self) -> Result<BTreeMap<ThinID, ThinInfo>> {
+        let mut infos = BTreeMap::new();
+        read_thin_infos(&self.tm, self.info_root()?, &mut infos)?;
+        Ok(infos)
+    }
+
+    /// Returns the mapping tree of a thin, as last committed.
+    pub fn mapping_tree(&self, id: ThinID) -> Result<MappingTree> {
+        let info = self
+            .infos()?
+            .lookup(id)?
+            .ok_or_else(|| anyhow!("thin {} not in the rebuilt info tree", id))?;
+        let format = LeafFormat::of_tree(&self.tm, info.root)?;
+        Ok(MappingTree::open_tree(format, self.tm.clone(), info.root))
+    }
+
+    /// Opens the rebuilt node file as a read only pool.
+    pub fn into_pool(self) -> Result<Pool> {
+        let info_root = self.info_root()?;
+        Pool::open_read_only(self.journal, self.tm, info_root)
+    }
+
+    /// Checks the rebuilt info tree, and the mapping tree of every thin.
+    pub fn check(&self) -> Result<RebuildReport> {
+        let info_root = self.info_root()?;
+        let nr_thins = self.infos()?.check()?;
+
+        let infos = self.thin_infos()?;
+        if infos.len() as u64 != nr_thins {
+            return Err(anyhow!(
+                "info tree holds {} thins, but {} were found",
+                nr_thins,
+                infos.len()
+            ));
+        }
+
+        let mut thins = BTreeMap::new();
+        for (id, info) in infos {
+            let format = LeafFormat::of_tree(&self.tm, info.root)?;
+            let mappings = MappingTree::open_tree(format, self.tm.clone(), info.root);
+            let nr = mappings
+                .check()
+                .map_err(|e| anyhow!("mapping tree for thin {}: {}", id, e))?;
+            thins.insert(id, nr);
+        }
+
+        Ok(RebuildReport {
+            nr_entries: self.nr_entries,
+            info_root,
+            thins,
+            bad_batch: self.bad_batch.clone(),
+        })
+    }
+}
+
+/// Replays the journal into a new node file and checks the result.
+pub fn rebuild_node_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    journal_path: P,
+    node_file_path: Q,
+    nr_metadata_blocks: u64,
+    nr_data_blocks: u64,
+) -> Result<RebuildReport> {
+    let mut rebuilder = Rebuilder::new(
+        journal_path,
+        node_file_path,
+        nr_metadata_blocks,
+        nr_data_blocks,
+    )?;
+    rebuilder.replay()?;
+    rebuilder.check()
+}
+
+/// Reconstructs the pool as it was at an earlier point in the journal.
+/// The nodes are written to a scratch node file, which must not exist.
+pub fn open_pool_at<P: AsRef<Path>, Q: AsRef<Path>>(
+    journal_path: P,
+    scratch_node_file: Q,
+    nr_metadata_blocks: u64,
+    nr_data_blocks: u64,
+    point: ReplayPoint,
+) -> Result<Pool> {
+    let mut rebuilder = Rebuilder::new(
+        journal_path,
+        scratch_node_file,
+        nr_metadata_blocks,
+        nr_data_blocks,
+    )?;
+    rebuilder.replay_to(point)?;
+    rebuilder.into_pool()
+}
+
+//-------------------------------------------------------------------------
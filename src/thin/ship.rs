@@ -0,0 +1,352 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thinp::io_engine::*;
+
+use crate::allocators::*;
+use crate::block_cache::*;
+use crate::btree::node::*;
+use crate::btree::nodes::simple::*;
+use crate::btree::transaction_manager::*;
+use crate::copier::*;
+use crate::journal::entry::*;
+use crate::journal::follow::*;
+use crate::journal::*;
+use crate::packed_array::*;
+use crate::thin::mapping::*;
+use crate::thin::*;
+use crate::types::*;
+
+//-------------------------------------------------------------------------
+
+// Journal shipping streams the batches of a primary pool's journal to a
+// replica.  The stream starts with a header, and is then a sequence of
+// messages, each starting with a tag byte:
+//
+//   MSG_BATCH: u16 encoding flags, followed by a batch as packed by pack_ops()
+//   MSG_SYNC:  everything sent so far should be made durable
+//
+// The stream just ends when the sender goes away.
+//
+// Only metadata is shipped.  The replica copies the data of newly mapped
+// ranges from the primary's data device when it syncs, since by then the
+// primary has flushed the writes to them.
+
+const SHIP_MAGIC: u32 = 0x5348_4950; // "SHIP"
+const SHIP_VERSION: u32 = 1;
+
+const MSG_BATCH: u8 = 1;
+const MSG_SYNC: u8 = 2;
+
+//-------------------------------------------------------------------------
+
+/// Reads batches from a live journal as they're written, and sends them
+/// down a pipe or socket.
+pub struct Sender {
+    follower: Follower,
+    header_sent: bool,
+    nr_batches: u64,
+}
+
+impl Sender {
+    pub fn open<P: AsRef<Path>>(journal_path: P) -> Result<Self> {
+        Ok(Self {
+            follower: Follower::open(journal_path)?,
+            header_sent: false,
+            nr_batches: 0,
+        })
+    }
+
+    pub fn nr_batches(&self) -> u64 {
+        self.nr_batches
+    }
+
+    /// Sends every batch written since the last call, followed by a sync
+    /// message if there were any.  Returns the first bad batch, after
+    /// which nothing more will be sent.
+    pub fn ship<W: Write>(&mut self, w: &mut W) -> Result<Option<BadBatch>> {
+        if !self.header_sent {
+            w.write_u32::<LittleEndian>(SHIP_MAGIC)?;
+            w.write_u32::<LittleEndian>(SHIP_VERSION)?;
+            self.header_sent = true;
+        }
+
+        let mut nr_batches = 0;
+        let bad = self.follower.poll(|_, ops| {
+            // Batches are decoded and repacked, so the stream's encoding
+            // needn't match the journal's.
+            let encoding = Encoding::Compact;
+            w.write_u8(MSG_BATCH)?;
+            w.write_u16::<LittleEndian>(encoding.to_flags())?;
+            pack_ops(w, ops, encoding)?;
+            nr_batches += 1;
+            Ok(())
+        })?;
+
+        if nr_batches > 0 {
+            w.write_u8(MSG_SYNC)?;
+            w.flush()?;
+            self.nr_batches += nr_batches;
+        }
+
+        Ok(bad)
+    }
+
+    /// Ships every `interval` until a bad batch is found, or the
+    /// receiver goes away.
+    pub fn follow<W: Write>(&mut self, interval: Duration, w: &mut W) -> Result<BadBatch> {
+        loop {
+            if let Some(bad) = self.ship(w)? {
+                return Ok(bad);
+            }
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+
+// Gathers the locations of every node in the tree.
+fn tree_nodes(
+    tm: &TransactionManager,
+    n_ptr: NodePtr,
+    nodes: &mut BTreeSet<MetadataBlock>,
+) -> Result<()> {
+    nodes.insert(n_ptr.loc);
+    if tm.is_internal(n_ptr)? {
+        let node: SimpleNode<NodePtr, SharedProxy> = tm.read(n_ptr)?;
+        for i in 0..node.nr_entries() {
+            tree_nodes(tm, node.get_value(i), nodes)?;
+        }
+    }
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
+
+/// Applies shipped batches to a replica.  The replica has its own node
+/// file and journal, in a separate directory, and must start empty since
+/// the batches are replayed on top of it.
+pub struct Receiver {
+    journal: Arc<Mutex<Journal>>,
+    tm: Arc<TransactionManager>,
+    copier: Arc<dyn Copier>,
+    info_root: Option<NodePtr>,
+
+    // The nodes of the info tree, whose leaves don't hold mappings.
+    info_nodes: BTreeSet<MetadataBlock>,
+
+    // Data ranges mapped since the last sync, which still need copying.
+    pending_copies: Vec<(PBlock, PBlock)>,
+
+    header_read: bool,
+    nr_batches: u64,
+}
+
+impl Receiver {
+    /// The copier should copy from the primary's data device to the
+    /// replica's.
+    pub fn create<P: AsRef<Path>>(
+        dir: P,
+        nr_metadata_blocks: u64,
+        nr_data_blocks: u64,
+        copier: Arc<dyn Copier>,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        let node_file_path = dir.join("node_file");
+        new_node_file(&node_file_path, nr_metadata_blocks)?;
+        let engine = Arc::new(SyncIoEngine::new(&node_file_path, true)?);
+        let cache = Arc::new(BlockCache::new(engine, 16)?);
+
+        let journal = Arc::new(Mutex::new(Journal::create(dir.join("journal"))?));
+        let tm = Arc::new(TransactionManager::new(
+            journal.clone(),
+            cache,
+            BuddyAllocator::new(nr_metadata_blocks),
+            BuddyAllocator::new(nr_data_blocks),
+        ));
+
+        Ok(Self {
+            journal,
+            tm,
+            copier,
+            info_root: None,
+            info_nodes: BTreeSet::new(),
+            pending_copies: Vec::new(),
+            header_read: false,
+            nr_batches: 0,
+        })
+    }
+
+    pub fn nr_batches(&self) -> u64 {
+        self.nr_batches
+    }
+
+    pub fn info_root(&self) -> Option<NodePtr> {
+        self.info_root
+    }
+
+    /// Applies a single batch.  The data of the ranges it maps is copied
+    /// across at the next sync.
+    pub fn apply(&mut self, ops: &[Entry]) -> Result<()> {
+        let is_checkpoint = matches!(ops.first(), Some(Entry::Checkpoint(_)));
+        if is_checkpoint && self.nr_batches == 0 {
+            return Err(anyhow!(
+                "primary journal has been checkpointed, so the replica can't be seeded from it"
+            ));
+        }
+
+        let mut info_changed = false;
+        for op in ops {
+            if let Entry::UpdateInfoRoot(root) = op {
+                self.info_root = Some(*root);
+                info_changed = true;
+            }
+        }
+        self.tm.replay_entries(ops)?;
+
+        // Every change to the info tree comes with a new info root, so the
+        // nodes are only gathered then.
+        if info_changed {
+            self.info_nodes.clear();
+            if let Some(root) = self.info_root {
+                tree_nodes(&self.tm, root, &mut self.info_nodes)?;
+            }
+        }
+        self.gather_mapped(ops)?;
+
+        // The primary has written all its nodes back, so we do the same.
+        // Our own checkpoint record starts the new segment.  The data
+        // those nodes refer to has to be there first.
+        let ops = if is_checkpoint {
+            self.copy_pending()?;
+            self.tm.checkpoint()?;
+            &ops[1..]
+        } else {
+            ops
+        };
+
+        if !ops.is_empty() {
+            let batch = Batch {
+                ops: ops.to_vec(),
+                completion: None,
+            };
+            self.journal.lock().unwrap().add_batch(batch);
+        }
+        self.nr_batches += 1;
+        Ok(())
+    }
+
+    // Notes the data ranges of the mappings that a batch writes into
+    // mapping tree leaves.  Some of these are already mapped, eg, when a
+    // mapping is extended, so they get copied again; that's harmless.
+    fn gather_mapped(&mut self, ops: &[Entry]) -> Result<()> {
+        for op in ops {
+            let (loc, values) = match op {
+                Entry::Insert(loc, _, _, v) | Entry::Overwrite(loc, _, _, v) => {
+                    (*loc, std::slice::from_ref(v))
+                }
+                Entry::Prepend(loc, _, vs) | Entry::Append(loc, _, vs) => (*loc, &vs[..]),
+                _ => continue,
+            };
+
+            if self.info_nodes.contains(&loc) || self.tm.is_internal(NodePtr { loc, seq_nr: 0 })? {
+                continue;
+            }
+
+            for v in values {
+                let m = Mapping::unpack(&mut &v[..])?;
+                self.pending_copies.push((m.b, m.e));
+            }
+        }
+        Ok(())
+    }
+
+    // Copies the data of everything mapped since the last call.
+    fn copy_pending(&mut self) -> Result<()> {
+        let mut ranges = std::mem::take(&mut self.pending_copies);
+        ranges.sort_unstable();
+
+        let mut data_ops: Vec<DataOp> = Vec::new();
+        for (b, e) in ranges {
+            if let Some(DataOp::Copy(last)) = data_ops.last_mut() {
+                if b <= last.src_end {
+                    last.src_end = last.src_end.max(e);
+                    continue;
+                }
+            }
+            data_ops.push(DataOp::Copy(CopyOp {
+                src_begin: b,
+                src_end: e,
+                dst_begin: b,
+            }));
+        }
+        self.copier.exec(&data_ops)
+    }
+
+    /// Copies the data of everything mapped since the last sync, and then
+    /// makes everything applied so far durable in the replica's journal.
+    /// Called when the primary says it has flushed.
+    pub fn sync(&mut self) -> Result<()> {
+        self.copy_pending()?;
+        self.journal.lock().unwrap().sync()
+    }
+
+    fn read_header<R: Read>(&mut self, r: &mut R) -> Result<()> {
+        let magic = r.read_u32::<LittleEndian>()?;
+        let version = r.read_u32::<LittleEndian>()?;
+        if magic != SHIP_MAGIC {
+            return Err(anyhow!("not a journal shipping stream"));
+        }
+        if version != SHIP_VERSION {
+            return Err(anyhow!("unsupported shipping version {}", version));
+        }
+        self.header_read = true;
+        Ok(())
+    }
+
+    /// Applies messages until the sender goes away, or `r` runs dry.
+    /// Returns the nr of batches applied.
+    pub fn receive<R: Read>(&mut self, r: &mut R) -> Result<u64> {
+        if !self.header_read {
+            self.read_header(r)?;
+        }
+
+        let mut nr_batches = 0;
+        loop {
+            let tag = match r.read_u8() {
+                Ok(tag) => tag,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            match tag {
+                MSG_BATCH => {
+                    let encoding = Encoding::from_flags(r.read_u16::<LittleEndian>()?)?;
+                    let ops = unpack_ops(r, encoding)?;
+                    self.apply(&ops)?;
+                    nr_batches += 1;
+                }
+                MSG_SYNC => self.sync()?,
+                _ => return Err(anyhow!("unknown shipping message {}", tag)),
+            }
+        }
+
+        self.sync()?;
+        Ok(nr_batches)
+    }
+
+    /// Opens the replica as a read only pool, eg, to check it.
+    pub fn into_pool(self) -> Result<Pool> {
+        let info_root = self
+            .info_root
+            .ok_or_else(|| anyhow!("nothing has been shipped"))?;
+        Pool::open_read_only(self.journal, self.tm, info_root)
+    }
+}
+
+//-------------------------------------------------------------------------
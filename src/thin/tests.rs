@@ -2,7 +2,11 @@
 mod tests {
     use crate::thin::*;
 
+    use crate::copier::file::FileCopier;
     use anyhow::{ensure, Result};
+    use rand::Rng;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::Arc;
     use tempfile::TempDir;
 
@@ -35,38 +39,155 @@ mod tests {
         assert!(fix._temp_dir.path().join("node_file").exists());
         assert!(fix._temp_dir.path().join("journal").exists());
 
-        assert_eq!(fix.pool.snap_time, 0);
-        assert_eq!(fix.pool.next_thin_id, 0);
-        assert!(fix.pool.active_devs.is_empty());
+        let shared = fix.pool.shared.lock().unwrap();
+        assert_eq!(shared.snap_time, 0);
+        assert_eq!(shared.next_thin_id, 0);
+        assert!(fix.pool.active_devs.lock().unwrap().is_empty());
 
         Ok(())
     }
 
     #[test]
     fn test_create_thin() -> Result<()> {
-        let mut fix = Fixture::new(1000, 10000)?;
+        let fix = Fixture::new(1000, 10000)?;
         fix.pool.create_thin(1000)?;
         Ok(())
     }
 
     #[test]
     fn test_create_thick() -> Result<()> {
-        let mut fix = Fixture::new(1000, 10000)?;
-        fix.pool.create_thick(1000)?;
+        let fix = Fixture::new(1000, 10000)?;
+        let dev = fix.pool.create_thick(1000)?;
+
+        // The provisioning done in the sub batch made it into the tree.
+        let mut thin = fix.pool.open_thin(dev);
+        let mappings = fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+        ensure!(mappings.iter().map(|(_, m)| m.len()).sum::<u64>() == 1000);
         Ok(())
     }
 
     #[test]
     fn test_create_snap() -> Result<()> {
-        let mut fix = Fixture::new(1000, 10000)?;
+        let fix = Fixture::new(1000, 10000)?;
         let origin = fix.pool.create_thick(1000)?;
         let _snap = fix.pool.create_snap(origin)?;
         Ok(())
     }
 
+    // Reads through the mapping tree, rather than the mapping cache.  Every
+    // other block is mapped, each by a separate write.
+    fn tree_mappings(pool: &Pool, id: ThinID) -> Result<Vec<(VBlock, Mapping)>> {
+        let (_, mappings) = pool.get_mapping_tree(id)?;
+        let mut result = Vec::new();
+        for b in 0..500 {
+            if let Some(m) = mappings.lookup(b * 2)? {
+                result.push((b * 2, m));
+            }
+        }
+        Ok(result)
+    }
+
+    #[test]
+    fn test_snap_diverges() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let origin = fix.pool.create_thin(1000)?;
+
+        // Separate writes, so the mapping tree has several leaves.
+        let mut thin = fix.pool.open_thin(origin);
+        for b in 0..500 {
+            fix.pool.get_write_mapping(&mut thin, b * 2, b * 2 + 1)?;
+        }
+        let before = tree_mappings(&fix.pool, origin)?;
+        ensure!(before.len() == 500);
+
+        let snap = fix.pool.create_snap(origin)?;
+        ensure!(tree_mappings(&fix.pool, snap)? == before);
+
+        // Writing to the origin breaks sharing for the first half ...
+        let mut thin = fix.pool.open_thin(origin);
+        for b in 0..250 {
+            fix.pool.get_write_mapping(&mut thin, b * 2, b * 2 + 1)?;
+        }
+        fix.pool.flush(&thin)?;
+
+        // ... and the snapshot does the same for the second half.
+        let mut snap_thin = fix.pool.open_thin(snap);
+        for b in 250..500 {
+            fix.pool
+                .get_write_mapping(&mut snap_thin, b * 2, b * 2 + 1)?;
+        }
+        fix.pool.flush(&snap_thin)?;
+
+        let origin_after = tree_mappings(&fix.pool, origin)?;
+        let snap_after = tree_mappings(&fix.pool, snap)?;
+        ensure!(origin_after.len() == 500);
+        ensure!(snap_after.len() == 500);
+
+        for i in 0..500 {
+            let (v, old) = before[i];
+            ensure!(origin_after[i].0 == v && snap_after[i].0 == v);
+            if i < 250 {
+                ensure!(origin_after[i].1.b != old.b);
+                ensure!(snap_after[i].1 == old);
+            } else {
+                ensure!(origin_after[i].1 == old);
+                ensure!(snap_after[i].1.b != old.b);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_mappings() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let origin = fix.pool.create_thin(1000)?;
+
+        // Inserting the mappings one at a time leaves a run of 500
+        // separate entries that are contiguous in both devices.
+        let (_, mut mappings) = fix.pool.get_mapping_tree(origin)?;
+        fix.pool.journaller().batch(Stream::Thin(origin), |ctx| {
+            for b in 0..500 {
+                let m = Mapping {
+                    b: 5000 + b * 2,
+                    e: 5002 + b * 2,
+                    snap_time: 0,
+                };
+                mappings.insert(ctx, b * 2, &m)?;
+            }
+            Ok(())
+        })?;
+        fix.pool.update_mappings_root(origin, mappings);
+        let before = tree_mappings(&fix.pool, origin)?;
+        ensure!(before.len() == 500);
+
+        let snap = fix.pool.create_snap(origin)?;
+        let mut thin = fix.pool.open_thin(origin);
+        ensure!(fix.pool.get_read_mapping(&mut thin, 0, 1000)?.len() == 500);
+
+        let stats = fix.pool.compact_mappings(&mut thin)?;
+        ensure!(stats.nr_keys == 1000);
+        ensure!(stats.nr_entries_before == 500);
+        ensure!(stats.nr_entries_after == 1);
+        ensure!(stats.run_length_after() == 1000.0);
+
+        // The cached mappings are dropped.
+        let expected = Mapping {
+            b: 5000,
+            e: 6000,
+            snap_time: 0,
+        };
+        ensure!(fix.pool.get_read_mapping(&mut thin, 0, 1000)? == vec![(0, expected)]);
+        fix.pool.flush(&thin)?;
+
+        // The snapshot still has its own copy of the old entries.
+        ensure!(tree_mappings(&fix.pool, snap)? == before);
+        Ok(())
+    }
+
     #[test]
     fn test_provision() -> Result<()> {
-        let mut fix = Fixture::new(1000, 256_000_000)?;
+        let fix = Fixture::new(1000, 256_000_000)?;
         let dev = fix.pool.create_thin(1000)?;
 
         let mut thin = fix.pool.open_thin(dev);
@@ -88,4 +209,680 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_mapping_cache() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let dev = fix.pool.create_thin(1000)?;
+        let mut thin = fix.pool.open_thin(dev);
+
+        fix.pool.get_write_mapping(&mut thin, 0, 500)?;
+
+        let stats = fix.pool.mapping_cache_stats();
+        fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+        ensure!(fix.pool.mapping_cache_stats().misses == stats.misses + 1);
+
+        // Second read should come from the cache
+        let cached = fix.pool.get_read_mapping(&mut thin, 100, 600)?;
+        ensure!(fix.pool.mapping_cache_stats().hits == stats.hits + 1);
+        ensure!(cached == fix.pool.get_read_mapping(&mut thin, 100, 600)?);
+
+        // Writing to the range must invalidate the cached region
+        fix.pool.get_write_mapping(&mut thin, 500, 1000)?;
+        let stats = fix.pool.mapping_cache_stats();
+        let mappings = fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+        ensure!(fix.pool.mapping_cache_stats().misses == stats.misses + 1);
+
+        let mut total = 0;
+        for (_vblock, m) in &mappings {
+            total += m.len();
+        }
+        ensure!(total == 1000);
+
+        // Discards too
+        fix.pool.discard(&mut thin, 0, 1000)?;
+        let mappings = fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+        ensure!(mappings.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_isolated_flush() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let dev1 = fix.pool.create_thin(1000)?;
+        let dev2 = fix.pool.create_thin(1000)?;
+        let mut thin1 = fix.pool.open_thin(dev1);
+        let mut thin2 = fix.pool.open_thin(dev2);
+
+        fix.pool.get_write_mapping(&mut thin1, 0, 100)?;
+        fix.pool.get_write_mapping(&mut thin2, 0, 100)?;
+
+        let pending = |pool: &Pool, s| pool.journal.lock().unwrap().nr_pending(s);
+        ensure!(pending(&fix.pool, Stream::Thin(dev1)) > 0);
+        ensure!(pending(&fix.pool, Stream::Thin(dev2)) > 0);
+
+        fix.pool.flush(&thin1)?;
+        ensure!(pending(&fix.pool, Stream::Thin(dev1)) == 0);
+        ensure!(pending(&fix.pool, Stream::Shared) == 0);
+        ensure!(pending(&fix.pool, Stream::Thin(dev2)) > 0);
+
+        // The info tree now holds thin1's new root, but not thin2's
+        ensure!(!fix.pool.active_devs.lock().unwrap().contains_key(&dev1));
+        ensure!(fix.pool.active_devs.lock().unwrap().contains_key(&dev2));
+
+        for thin in [&mut thin1, &mut thin2] {
+            let mappings = fix.pool.get_read_mapping(thin, 0, 100)?;
+            let total: u64 = mappings.iter().map(|(_, m)| m.len()).sum();
+            ensure!(total == 100);
+        }
+
+        fix.pool.flush(&thin2)?;
+        ensure!(pending(&fix.pool, Stream::Thin(dev2)) == 0);
+        ensure!(fix.pool.active_devs.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_close_thin() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let dev = fix.pool.create_thin(1000)?;
+        let mut thin = fix.pool.open_thin(dev);
+        fix.pool.get_write_mapping(&mut thin, 0, 100)?;
+        fix.pool.flush(&thin)?;
+
+        // The unused part of the data preallocation is freed in the
+        // thin's stream.
+        fix.pool.close_thin(thin)?;
+        let pending = fix
+            .pool
+            .journal
+            .lock()
+            .unwrap()
+            .nr_pending(Stream::Thin(dev));
+        ensure!(pending == 1);
+
+        // The journal's offsets file is written when it's closed.
+        let prealloc_size = fix.pool.data_prealloc_size;
+        let Fixture { pool, _temp_dir } = fix;
+        drop(pool);
+
+        let mut journal = Journal::open(_temp_dir.path().join("journal"), false)?;
+        let nr_freed: u64 = journal
+            .entries()?
+            .iter()
+            .map(|entry| match entry {
+                Entry::FreeData(b, e) => e - b,
+                _ => 0,
+            })
+            .sum();
+        ensure!(nr_freed == prealloc_size - 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_thin_flushes() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let dev1 = fix.pool.create_thin(1000)?;
+        let dev2 = fix.pool.create_thin(1000)?;
+        let mut thin1 = fix.pool.open_thin(dev1);
+        let mut thin2 = fix.pool.open_thin(dev2);
+        fix.pool.get_write_mapping(&mut thin1, 0, 100)?;
+        fix.pool.get_write_mapping(&mut thin2, 0, 100)?;
+
+        fix.pool.delete_thin(dev1)?;
+        let journal = fix.pool.journal.lock().unwrap();
+        ensure!(journal.nr_pending(Stream::Thin(dev1)) == 0);
+        ensure!(journal.nr_pending(Stream::Shared) == 0);
+        ensure!(journal.nr_pending(Stream::Thin(dev2)) > 0);
+        drop(journal);
+
+        ensure!(fix.pool.get_mapping_tree(dev1).is_err());
+        ensure!(fix.pool.get_mapping_tree(dev2).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_provision() -> Result<()> {
+        const NR_THINS: usize = 4;
+
+        let fix = Fixture::new(4096, 256_000_000)?;
+        let mut ids = Vec::new();
+        for _ in 0..NR_THINS {
+            ids.push(fix.pool.create_thin(1000)?);
+        }
+
+        std::thread::scope(|s| -> Result<()> {
+            let mut handles = Vec::new();
+            for id in &ids {
+                let pool = &fix.pool;
+                handles.push(s.spawn(move || -> Result<()> {
+                    let mut thin = pool.open_thin(*id);
+                    for b in 0..500 {
+                        pool.get_write_mapping(&mut thin, b * 2, b * 2 + 1)?;
+                    }
+                    pool.flush(&thin)
+                }));
+            }
+            for h in handles {
+                h.join().unwrap()?;
+            }
+            Ok(())
+        })?;
+
+        // Each thin has its own data blocks.
+        let mut blocks = Vec::new();
+        for id in ids {
+            let mut thin = fix.pool.open_thin(id);
+            let mappings = fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+            ensure!(mappings.len() == 500);
+            blocks.extend(mappings.iter().map(|(_, m)| m.b));
+        }
+        let nr_blocks = blocks.len();
+        blocks.sort();
+        blocks.dedup();
+        ensure!(blocks.len() == nr_blocks);
+        ensure!(fix.pool.active_devs.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    // The vblock to pblock map for [b, e), whether or not the extents
+    // were clipped to it.
+    fn block_map(extents: &[(VBlock, Mapping)], b: VBlock, e: VBlock) -> BTreeMap<VBlock, PBlock> {
+        let mut map = BTreeMap::new();
+        for (k, m) in extents {
+            for i in 0..m.len() {
+                if (b..e).contains(&(k + i)) {
+                    map.insert(k + i, m.b + i);
+                }
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn test_concurrent_read_write() -> Result<()> {
+        let fix = Fixture::new(4096, 256_000_000)?;
+        let id = fix.pool.create_thin(1000)?;
+        let done = AtomicBool::new(false);
+
+        // Readers share the thin with a writer that provisions and
+        // discards it.  They should never see a tree part way through a
+        // change, which would make the lookups fail.
+        std::thread::scope(|s| -> Result<()> {
+            let pool = &fix.pool;
+            let done = &done;
+            let mut readers = Vec::new();
+            for _ in 0..4 {
+                readers.push(s.spawn(move || -> Result<()> {
+                    let mut rng = rand::thread_rng();
+                    let mut thin = pool.open_thin(id);
+                    while !done.load(Ordering::Relaxed) {
+                        let b = rng.gen_range(0..1000);
+                        let e = rng.gen_range(b + 1..=1000);
+                        let extents = pool.get_read_mapping(&mut thin, b, e)?;
+                        let nr_blocks: u64 = extents.iter().map(|(_, m)| m.len()).sum();
+                        ensure!(block_map(&extents, 0, 1000).len() as u64 == nr_blocks);
+                    }
+                    Ok(())
+                }));
+            }
+
+            let mut rng = rand::thread_rng();
+            let mut thin = pool.open_thin(id);
+            for _ in 0..1000 {
+                let b = rng.gen_range(0..1000);
+                let e = rng.gen_range(b + 1..=(b + 20).min(1000));
+                if rng.gen_ratio(1, 3) {
+                    pool.discard(&mut thin, b, e)?;
+                } else {
+                    pool.get_write_mapping(&mut thin, b, e)?;
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+
+            for h in readers {
+                h.join().unwrap()?;
+            }
+            Ok(())
+        })?;
+
+        // Whatever the readers left in the mapping cache agrees with the
+        // tree.
+        let (_, mappings) = fix.pool.get_mapping_tree(id)?;
+        mappings.check()?;
+        let mut thin = fix.pool.open_thin(id);
+        for b in (0..1000).step_by(10) {
+            let expected = block_map(&mappings.lookup_range(b, b + 10)?, b, b + 10);
+            let actual = block_map(&fix.pool.get_read_mapping(&mut thin, b, b + 10)?, b, b + 10);
+            ensure!(actual == expected, "mappings differ in [{}, {})", b, b + 10);
+        }
+        ensure!(fix.pool.mapping_cache_stats().hits > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let journal_path = fix._temp_dir.path().join("journal");
+        let dev = fix.pool.create_thin(1000)?;
+        let mut thin = fix.pool.open_thin(dev);
+
+        for b in 0..10 {
+            fix.pool
+                .get_write_mapping(&mut thin, b * 100, (b + 1) * 100)?;
+            fix.pool.flush(&thin)?;
+        }
+
+        ensure!(fix.pool.checkpoint()? == 1);
+        {
+            let journal = fix.pool.journal.lock().unwrap();
+            ensure!(journal.generation() == 1);
+        }
+
+        // Only the checkpoint record remains
+        let mut journal = Journal::open(&journal_path, false)?;
+        ensure!(journal.generation() == 1);
+        let entries = journal.entries()?;
+        ensure!(entries.len() == 2);
+        ensure!(entries[0] == Entry::Checkpoint(1));
+        ensure!(entries[1] == Entry::UpdateInfoRoot(fix.pool.info_root()));
+
+        // The mappings were written back to the node file
+        let mappings = fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+        let total: u64 = mappings.iter().map(|(_, m)| m.len()).sum();
+        ensure!(total == 1000);
+
+        // and we can carry on journalling
+        fix.pool.discard(&mut thin, 0, 100)?;
+        fix.pool.flush(&thin)?;
+        ensure!(fix.pool.checkpoint()? == 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_nodes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let pool = Pool::create_compressed(temp_dir.path(), 1000, 256_000_000, 3)?;
+        let stats = pool.node_space().unwrap();
+        ensure!(stats.nr_blocks == 0);
+        ensure!(stats.nr_file_pages < 1000);
+
+        let dev = pool.create_thin(1000)?;
+        let mut thin = pool.open_thin(dev);
+        for b in 0..500 {
+            pool.get_write_mapping(&mut thin, b * 2, b * 2 + 1)?;
+        }
+        pool.flush(&thin)?;
+        pool.checkpoint()?;
+
+        ensure!(tree_mappings(&pool, dev)?.len() == 500);
+
+        // Mostly empty nodes compress well.
+        let stats = pool.node_space().unwrap();
+        ensure!(stats.nr_blocks > 0);
+        ensure!(stats.ratio() > 2.0);
+
+        // The node file is smaller than an uncompressed one would be.
+        let len = std::fs::metadata(temp_dir.path().join("node_file"))?.len();
+        ensure!(len == stats.nr_file_pages * 4096);
+        ensure!(stats.nr_file_pages < 1000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_program_leaves() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let dir = temp_dir.path();
+        let pool = Pool::create_with_leaves(dir, 1000, 256_000_000, LeafFormat::LogProgram)?;
+
+        // Separate writes, so the log fills and the leaves split.
+        let dev = pool.create_thin(4000)?;
+        let mut thin = pool.open_thin(dev);
+        for b in 0..2000 {
+            pool.get_write_mapping(&mut thin, b * 2, b * 2 + 1)?;
+        }
+        pool.discard(&mut thin, 1000, 1400)?;
+        pool.flush(&thin)?;
+
+        let (_, mappings) = pool.get_mapping_tree(dev)?;
+        ensure!(mappings.format() == LeafFormat::LogProgram);
+        ensure!(pool.tm.is_internal(mappings.root())?);
+        ensure!(mappings.check()? == 1800);
+
+        let expected = pool.get_read_mapping(&mut thin, 0, 4000)?;
+        ensure!(expected.len() == 1800);
+        ensure!(expected.iter().all(|(v, _)| *v < 1000 || *v >= 1400));
+
+        // Breaking sharing in the snapshot leaves the origin alone.
+        let snap = pool.create_snap(dev)?;
+        let mut snap_thin = pool.open_thin(snap);
+        for b in 0..100 {
+            pool.get_write_mapping(&mut snap_thin, b * 2, b * 2 + 1)?;
+        }
+        pool.flush(&snap_thin)?;
+        ensure!(pool.get_read_mapping(&mut thin, 0, 4000)? == expected);
+
+        pool.close_thin(thin)?;
+        pool.close_thin(snap_thin)?;
+        drop(pool);
+
+        // The format is worked out again from the rebuilt nodes.
+        let mut rebuilder =
+            rebuild::Rebuilder::new(dir.join("journal"), dir.join("rebuilt"), 1000, 256_000_000)?;
+        rebuilder.replay()?;
+        ensure!(rebuilder.check()?.thins.get(&dev) == Some(&1800));
+        let pool = rebuilder.into_pool()?;
+        let mut thin = pool.open_thin(dev);
+        ensure!(pool.get_read_mapping(&mut thin, 0, 4000)? == expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_node_file() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let dir = fix._temp_dir.path().to_path_buf();
+        let dev1 = fix.pool.create_thin(1000)?;
+        let dev2 = fix.pool.create_thick(500)?;
+        let mut thin1 = fix.pool.open_thin(dev1);
+        let mut thin2 = fix.pool.open_thin(dev2);
+
+        for b in 0..10 {
+            fix.pool
+                .get_write_mapping(&mut thin1, b * 100, b * 100 + 50)?;
+        }
+        fix.pool.discard(&mut thin1, 120, 330)?;
+        fix.pool.discard(&mut thin2, 0, 100)?;
+        fix.pool.flush(&thin1)?;
+        fix.pool.flush(&thin2)?;
+
+        let mut expected = Vec::new();
+        for thin in [&mut thin1, &mut thin2] {
+            expected.push((thin.id, fix.pool.get_read_mapping(thin, 0, 1000)?));
+        }
+        let info_root = fix.pool.info_root();
+
+        // The journal's offsets file is written when it's closed.
+        fix.pool.close_thin(thin1)?;
+        fix.pool.close_thin(thin2)?;
+        let Fixture { pool, _temp_dir } = fix;
+        drop(pool);
+
+        let report = rebuild::rebuild_node_file(
+            dir.join("journal"),
+            dir.join("rebuilt"),
+            1000,
+            256_000_000,
+        )?;
+        ensure!(report.bad_batch.is_none());
+        ensure!(report.info_root == info_root);
+        ensure!(report.thins.values().all(|nr| *nr > 0));
+        ensure!(report.thins.keys().cloned().collect::<Vec<_>>() == vec![dev1, dev2]);
+
+        // Compare against the rebuilt trees directly
+        let mut rebuilder =
+            rebuild::Rebuilder::new(dir.join("journal"), dir.join("rebuilt2"), 1000, 256_000_000)?;
+        rebuilder.replay()?;
+        for (id, mappings) in expected {
+            let rebuilt = rebuilder.mapping_tree(id)?.lookup_range(0, 1000)?;
+            ensure!(rebuilt == mappings);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_needs_full_history() -> Result<()> {
+        let fix = Fixture::new(1000, 10000)?;
+        let dir = fix._temp_dir.path().to_path_buf();
+        fix.pool.create_thin(1000)?;
+        fix.pool.checkpoint()?;
+
+        let r = rebuild::Rebuilder::new(dir.join("journal"), dir.join("rebuilt"), 1000, 10000);
+        ensure!(r.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_pool_at() -> Result<()> {
+        let fix = Fixture::new(1000, 256_000_000)?;
+        let dir = fix._temp_dir.path().to_path_buf();
+        let dev = fix.pool.create_thin(1000)?;
+        let mut thin = fix.pool.open_thin(dev);
+
+        fix.pool.get_write_mapping(&mut thin, 0, 500)?;
+        fix.pool.flush(&thin)?;
+        let before = fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+
+        // The bad discard
+        fix.pool.discard(&mut thin, 0, 1000)?;
+        fix.pool.flush(&thin)?;
+
+        let Fixture { pool, _temp_dir } = fix;
+        drop((thin, pool));
+
+        // Each flush wrote a single slab
+        let mut last = None;
+        let mut journal = Journal::open(dir.join("journal"), false)?;
+        journal.replay(|loc, _| {
+            last = Some(loc);
+            Ok(())
+        })?;
+        let last = last.unwrap();
+
+        let point = rebuild::ReplayPoint::Slab(last.slab - 1);
+        let pool = rebuild::open_pool_at(
+            dir.join("journal"),
+            dir.join("scratch"),
+            1000,
+            256_000_000,
+            point,
+        )?;
+        ensure!(pool.is_read_only());
+        ensure!(pool.thin_ids()? == vec![dev]);
+        let mut thin = pool.open_thin(dev);
+        ensure!(pool.get_read_mapping(&mut thin, 0, 1000)? == before);
+        ensure!(pool.discard(&mut thin, 0, 1000).is_err());
+        ensure!(pool.create_thin(100).is_err());
+
+        // and at the end of the journal the mappings have gone
+        let point = rebuild::ReplayPoint::Batch(last.slab, last.batch);
+        let pool = rebuild::open_pool_at(
+            dir.join("journal"),
+            dir.join("end"),
+            1000,
+            256_000_000,
+            point,
+        )?;
+        let mut thin = pool.open_thin(dev);
+        ensure!(pool.get_read_mapping(&mut thin, 0, 1000)?.is_empty());
+
+        // Points beyond the end are rejected
+        let point = rebuild::ReplayPoint::Slab(last.slab + 1);
+        let r = rebuild::open_pool_at(
+            dir.join("journal"),
+            dir.join("beyond"),
+            1000,
+            256_000_000,
+            point,
+        );
+        ensure!(r.is_err());
+
+        Ok(())
+    }
+
+    //---------------------
+
+    const DATA_BLOCK_SIZE: u64 = 4096;
+
+    fn create_data_file(dir: &Path, nr_data_blocks: u64) -> Result<PathBuf> {
+        let path = dir.join("data");
+        let f = std::fs::File::create(&path)?;
+        f.set_len(nr_data_blocks * DATA_BLOCK_SIZE)?;
+        Ok(path)
+    }
+
+    fn read_data_block(path: &Path, b: PBlock) -> Result<Vec<u8>> {
+        use std::os::unix::fs::FileExt;
+        let mut buf = vec![0; DATA_BLOCK_SIZE as usize];
+        std::fs::File::open(path)?.read_exact_at(&mut buf, b * DATA_BLOCK_SIZE)?;
+        Ok(buf)
+    }
+
+    // Slabs are written by a background thread, but a checkpoint waits for
+    // them to hit the disk.  So everything will be there to ship.
+    fn checkpoint_and_ship(
+        pool: &Pool,
+        sender: &mut ship::Sender,
+        receiver: &mut ship::Receiver,
+    ) -> Result<()> {
+        pool.checkpoint()?;
+        let mut pipe = Vec::new();
+        ensure!(sender.ship(&mut pipe)?.is_none());
+        receiver.receive(&mut &pipe[..])?;
+        ensure!(receiver.nr_batches() == sender.nr_batches());
+        ensure!(receiver.info_root() == Some(pool.info_root()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_shipping() -> Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let nr_data_blocks = 10_000;
+        let mut fix = Fixture::new(1000, nr_data_blocks)?;
+        fix.pool.data_prealloc_size = 100;
+        let primary = fix._temp_dir.path().to_path_buf();
+        let replica = TempDir::new()?;
+        let primary_data = create_data_file(&primary, nr_data_blocks)?;
+        let replica_data = create_data_file(replica.path(), nr_data_blocks)?;
+
+        let copier = Arc::new(FileCopier::new(
+            &primary_data,
+            &replica_data,
+            DATA_BLOCK_SIZE,
+        )?);
+        let mut receiver = ship::Receiver::create(replica.path(), 1000, nr_data_blocks, copier)?;
+        let mut sender = ship::Sender::open(primary.join("journal"))?;
+
+        let dev = fix.pool.create_thin(1000)?;
+        let mut thin = fix.pool.open_thin(dev);
+
+        // Stamps each newly mapped block with its own block nr.
+        let data = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&primary_data)?;
+        let write = |pool: &Pool, thin: &mut ThinDev, b, e| -> Result<()> {
+            for (_, m) in pool.get_write_mapping(thin, b, e)? {
+                for block in m.b..m.e {
+                    let buf = vec![block as u8; DATA_BLOCK_SIZE as usize];
+                    data.write_all_at(&buf, block * DATA_BLOCK_SIZE)?;
+                }
+            }
+            pool.flush(thin)
+        };
+
+        write(&fix.pool, &mut thin, 0, 100)?;
+        checkpoint_and_ship(&fix.pool, &mut sender, &mut receiver)?;
+
+        // The sender follows the primary onto each new segment
+        write(&fix.pool, &mut thin, 200, 250)?;
+        fix.pool.discard(&mut thin, 50, 60)?;
+        fix.pool.flush(&thin)?;
+        write(&fix.pool, &mut thin, 500, 520)?;
+        checkpoint_and_ship(&fix.pool, &mut sender, &mut receiver)?;
+
+        let expected = fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+        let pool = receiver.into_pool()?;
+        let mut replica_thin = pool.open_thin(dev);
+        ensure!(pool.get_read_mapping(&mut replica_thin, 0, 1000)? == expected);
+
+        for (_, m) in &expected {
+            for block in m.b..m.e {
+                ensure!(read_data_block(&replica_data, block)? == vec![block as u8; 4096]);
+            }
+        }
+
+        Ok(())
+    }
+
+    // A thin's data allocator preallocates a large chunk up front, and
+    // later writes into it allocate nothing new.  The replica has to copy
+    // what gets mapped, not what gets allocated.
+    #[test]
+    fn test_shipping_writes_after_prealloc() -> Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let nr_data_blocks = 100_000;
+        let fix = Fixture::new(1000, nr_data_blocks)?;
+        let primary = fix._temp_dir.path().to_path_buf();
+        let replica = TempDir::new()?;
+        let primary_data = create_data_file(&primary, nr_data_blocks)?;
+        let replica_data = create_data_file(replica.path(), nr_data_blocks)?;
+
+        let copier = Arc::new(FileCopier::new(
+            &primary_data,
+            &replica_data,
+            DATA_BLOCK_SIZE,
+        )?);
+        let mut receiver = ship::Receiver::create(replica.path(), 1000, nr_data_blocks, copier)?;
+        let mut sender = ship::Sender::open(primary.join("journal"))?;
+
+        let dev = fix.pool.create_thin(1000)?;
+        let mut thin = fix.pool.open_thin(dev);
+
+        let data = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&primary_data)?;
+        let write = |pool: &Pool, thin: &mut ThinDev, b, e| -> Result<()> {
+            for (_, m) in pool.get_write_mapping(thin, b, e)? {
+                for block in m.b..m.e {
+                    let buf = vec![block as u8 ^ 0xff; DATA_BLOCK_SIZE as usize];
+                    data.write_all_at(&buf, block * DATA_BLOCK_SIZE)?;
+                }
+            }
+            pool.flush(thin)
+        };
+
+        write(&fix.pool, &mut thin, 0, 100)?;
+        checkpoint_and_ship(&fix.pool, &mut sender, &mut receiver)?;
+
+        // These come out of the chunk preallocated by the first write.
+        write(&fix.pool, &mut thin, 300, 400)?;
+        write(&fix.pool, &mut thin, 600, 650)?;
+        checkpoint_and_ship(&fix.pool, &mut sender, &mut receiver)?;
+
+        let expected = fix.pool.get_read_mapping(&mut thin, 0, 1000)?;
+        ensure!(expected.iter().map(|(_, m)| m.len()).sum::<u64>() == 250);
+        for (_, m) in &expected {
+            for block in m.b..m.e {
+                ensure!(read_data_block(&replica_data, block)? == vec![block as u8 ^ 0xff; 4096]);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shipping_needs_full_history() -> Result<()> {
+        let fix = Fixture::new(1000, 10000)?;
+        let primary = fix._temp_dir.path().to_path_buf();
+        let replica = TempDir::new()?;
+        fix.pool.create_thin(1000)?;
+        fix.pool.checkpoint()?;
+
+        let mut sender = ship::Sender::open(primary.join("journal"))?;
+        let mut receiver =
+            ship::Receiver::create(replica.path(), 1000, 10000, Arc::new(FakeCopier::new()))?;
+
+        let mut pipe = Vec::new();
+        sender.ship(&mut pipe)?;
+        ensure!(receiver.receive(&mut &pipe[..]).is_err());
+        Ok(())
+    }
 }